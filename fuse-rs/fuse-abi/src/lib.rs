@@ -47,8 +47,16 @@ pub const FUSE_KERNEL_MINOR_VERSION: u32 = 16;
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 17;
 #[cfg(all(feature = "abi-7-18", not(feature = "abi-7-19")))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 18;
-#[cfg(feature = "abi-7-19")]
+#[cfg(all(feature = "abi-7-19", not(feature = "abi-7-20")))]
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
+#[cfg(all(feature = "abi-7-20", not(feature = "abi-7-21")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 20;
+#[cfg(all(feature = "abi-7-21", not(feature = "abi-7-22")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 21;
+#[cfg(all(feature = "abi-7-22", not(feature = "abi-7-23")))]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 22;
+#[cfg(feature = "abi-7-23")]
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 23;
 
 pub const FUSE_ROOT_ID: u64 = 1;
 
@@ -166,6 +174,10 @@ pub mod consts {
     pub const FUSE_FLOCK_LOCKS: u32 = 1 << 10; // remote locking for BSD style file locks
     #[cfg(feature = "abi-7-18")]
     pub const FUSE_HAS_IOCTL_DIR: u32 = 1 << 11; // kernel supports ioctl on directories
+    #[cfg(feature = "abi-7-21")]
+    pub const FUSE_DO_READDIRPLUS: u32 = 1 << 13; // do READDIRPLUS (READDIR+LOOKUP in one)
+    #[cfg(feature = "abi-7-21")]
+    pub const FUSE_READDIRPLUS_AUTO: u32 = 1 << 14; // adaptive readdirplus
 
     #[cfg(target_os = "macos")]
     pub const FUSE_ALLOCATE: u32 = 1 << 27;
@@ -281,6 +293,10 @@ pub enum fuse_opcode {
     FUSE_BATCH_FORGET = 42,
     #[cfg(feature = "abi-7-19")]
     FUSE_FALLOCATE = 43,
+    #[cfg(feature = "abi-7-21")]
+    FUSE_READDIRPLUS = 44,
+    #[cfg(feature = "abi-7-23")]
+    FUSE_RENAME2 = 45,
 
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,
@@ -344,6 +360,10 @@ impl TryFrom<u32> for fuse_opcode {
             42 => Ok(fuse_opcode::FUSE_BATCH_FORGET),
             #[cfg(feature = "abi-7-19")]
             43 => Ok(fuse_opcode::FUSE_FALLOCATE),
+            #[cfg(feature = "abi-7-21")]
+            44 => Ok(fuse_opcode::FUSE_READDIRPLUS),
+            #[cfg(feature = "abi-7-23")]
+            45 => Ok(fuse_opcode::FUSE_RENAME2),
 
             #[cfg(target_os = "macos")]
             61 => Ok(fuse_opcode::FUSE_SETVOLNAME),
@@ -503,6 +523,15 @@ pub struct fuse_rename_in {
     pub newdir: u64,
 }
 
+#[cfg(feature = "abi-7-23")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_rename2_in {
+    pub newdir: u64,
+    pub flags: u32,
+    pub padding: u32,
+}
+
 #[cfg(target_os = "macos")]
 #[repr(C)]
 #[derive(Debug)]
@@ -835,11 +864,11 @@ pub struct fuse_notify_poll_wakeup_out {
 #[repr(C)]
 #[derive(Debug)]
 pub struct fuse_fallocate_in {
-    fh: u64,
-    offset: u64,
-    length: u64,
-    mode: u32,
-    padding: u32,
+    pub fh: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub mode: u32,
+    pub padding: u32,
 }
 
 #[repr(C)]
@@ -873,6 +902,14 @@ pub struct fuse_dirent {
     // followed by name of namelen bytes
 }
 
+#[cfg(feature = "abi-7-21")]
+#[repr(C)]
+#[derive(Debug)]
+pub struct fuse_direntplus {
+    pub entry_out: fuse_entry_out,
+    pub dirent: fuse_dirent,
+}
+
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
 #[derive(Debug)]
@@ -1,7 +1,7 @@
 //! Native FFI bindings to libfuse.
 //!
 //! This is a small set of bindings that are required to mount/unmount FUSE filesystems and
-//! open/close a fd to the FUSE kernel driver.
+//! open/close a fd to the FUSE kernel driver, plus the session loop, its exit flag and the signal handlers setting it.
 
 #![warn(missing_debug_implementations, rust_2018_idioms)]
 #![allow(missing_docs)]
@@ -61,4 +61,11 @@ extern "C" {
     pub fn fuse_session_fd(se: *mut fuse_session) -> c_int;
     pub fn fuse_session_unmount(se: *mut fuse_session);
     pub fn fuse_session_destroy(se: *mut fuse_session);
+    pub fn fuse_session_loop(se: *mut fuse_session) -> c_int;
+    pub fn fuse_session_exit(se: *mut fuse_session);
+    pub fn fuse_session_exited(se: *mut fuse_session) -> c_int;
+    pub fn fuse_session_reset(se: *mut fuse_session);
+    pub fn fuse_set_signal_handlers(se: *mut fuse_session) -> c_int;
+    pub fn fuse_remove_signal_handlers(se: *mut fuse_session);
+    pub fn fuse_version() -> c_int;
 }
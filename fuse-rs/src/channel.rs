@@ -3,18 +3,25 @@
 //! Raw communication channel to the FUSE kernel driver.
 
 use fuse_sys::{
-    fuse_args, fuse_lowlevel_op, fuse_session, fuse_session_fd, fuse_session_mount,
-    fuse_session_new,
+    fuse_args, fuse_lowlevel_op, fuse_remove_signal_handlers, fuse_session, fuse_session_exit,
+    fuse_session_exited, fuse_session_fd, fuse_session_mount, fuse_session_new,
+    fuse_set_signal_handlers, fuse_version,
 };
 use libc::{self, c_int, c_void, size_t};
 use log::error;
-use std::ffi::{CString, OsStr};
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs;
 use std::io;
 use std::mem;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::reply::ReplySender;
 
@@ -31,12 +38,76 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[&OsStr], f: F) -> T
     })
 }
 
+/// Read from `fd` into `buffer` up to its capacity, but wait at most `timeout` for it to
+/// become readable. Fails with `EAGAIN`, of kind `WouldBlock`, if nothing arrived in time.
+fn read_timeout(fd: c_int, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<usize> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = c_int::try_from(timeout.as_millis()).unwrap_or(c_int::MAX);
+    let rc = unsafe { libc::poll(&mut pollfd, 1, millis) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if rc == 0 {
+        return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+    }
+    let rc = unsafe {
+        libc::read(
+            fd,
+            buffer.as_ptr() as *mut c_void,
+            buffer.capacity() as size_t,
+        )
+    };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(rc as usize)
+    }
+}
+
+/// Receive into `buffer` with the given `read` function, which returns the number of bytes
+/// read. See `Channel::receive_timeout`.
+fn receive_with<R, E>(buffer: &mut Vec<u8>, mut read: R, exited: E) -> io::Result<bool>
+where
+    R: FnMut(&mut Vec<u8>) -> io::Result<usize>,
+    E: Fn() -> bool,
+{
+    loop {
+        match read(buffer) {
+            Ok(len) => {
+                unsafe {
+                    buffer.set_len(len);
+                }
+                return Ok(true);
+            }
+            Err(err) => match err.raw_os_error() {
+                // Interrupted system call, retry
+                Some(libc::EINTR) if !exited() => continue,
+                Some(libc::EINTR) => return Ok(false),
+                // Filesystem was unmounted
+                Some(libc::ENODEV) => return Ok(false),
+                _ => return Err(err),
+            },
+        }
+    }
+}
+
 /// A raw communication channel to the FUSE kernel driver
 #[derive(Debug)]
 pub struct Channel {
     mountpoint: PathBuf,
     fd: c_int,
     se: *mut fuse_session,
+    protocol_version: (u32, u32),
+    /// Device of the mount point before mounting, see `is_mounted_over`
+    covered_dev: u64,
+    /// Replies that failed to be sent by any sender of this channel
+    send_errors: Arc<SendErrors>,
+    /// Exit flag set through an `ExitHandle`, which may outlive the libfuse session
+    exit_requested: Arc<AtomicBool>,
 }
 
 /// # Safety: we could make sure pointer doesn't alias
@@ -49,6 +120,7 @@ impl Channel {
     /// unmounted.
     pub fn new(mountpoint: &Path, options: &[&OsStr]) -> io::Result<Channel> {
         let mountpoint = mountpoint.canonicalize()?;
+        let covered_dev = fs::metadata(&mountpoint)?.dev();
         with_fuse_args(options, |args| {
             let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
             let op = fuse_lowlevel_op::new();
@@ -72,7 +144,15 @@ impl Channel {
             }
             let fd = unsafe { fuse_session_fd(se) };
 
-            Ok(Channel { mountpoint, fd, se })
+            Ok(Channel {
+                mountpoint,
+                fd,
+                se,
+                protocol_version: (0, 0),
+                covered_dev,
+                send_errors: Arc::new(SendErrors::default()),
+                exit_requested: Arc::new(AtomicBool::new(false)),
+            })
         })
     }
 
@@ -81,23 +161,64 @@ impl Channel {
         &self.mountpoint
     }
 
-    /// Receives data up to the capacity of the given buffer (can block).
-    pub fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
-        let rc = unsafe {
-            libc::read(
-                self.fd,
-                buffer.as_ptr() as *mut c_void,
-                buffer.capacity() as size_t,
-            )
-        };
-        if rc < 0 {
-            Err(io::Error::last_os_error())
-        } else {
-            unsafe {
-                buffer.set_len(rc as usize);
-            }
-            Ok(())
+    /// Return the FUSE protocol version agreed on with the kernel by the init request, as
+    /// major and minor version. It's `(0, 0)` until the kernel sent the init request.
+    pub fn protocol_version(&self) -> (u32, u32) {
+        self.protocol_version
+    }
+
+    pub(crate) fn set_protocol_version(&mut self, version: (u32, u32)) {
+        self.protocol_version = version;
+    }
+
+    /// Set the exit flag of the underlying libfuse session. The session loop stops before
+    /// receiving the next request.
+    pub fn exit(&self) {
+        unsafe { fuse_session_exit(self.se) }
+    }
+
+    /// Return true once the exit flag of the underlying libfuse session is set, either by
+    /// `exit` or by the libfuse signal handlers, once an `ExitHandle` of this channel was
+    /// notified, or once a reply failed to be sent because the connection to the kernel is gone.
+    pub fn exited(&self) -> bool {
+        let exited = unsafe { fuse_session_exited(self.se) != 0 };
+        exited || self.exit_requested.load(Ordering::SeqCst) || self.send_errors.disconnected()
+    }
+
+    /// Return a handle setting the exit flag of this channel from any thread, see `ExitHandle`.
+    pub fn exit_handle(&self) -> ExitHandle {
+        ExitHandle(self.exit_requested.clone())
+    }
+
+    /// Return the number of replies that failed to be sent so far by the senders of this
+    /// channel.
+    pub fn failed_replies(&self) -> u64 {
+        self.send_errors.count()
+    }
+
+    /// Install the libfuse handlers for SIGHUP, SIGINT and SIGTERM, which set the exit flag
+    /// of this channel's session. The handlers stay installed until the returned guard is
+    /// dropped, which has to happen before the channel is dropped.
+    pub fn set_signal_handlers(&self) -> io::Result<SignalHandlers> {
+        if unsafe { fuse_set_signal_handlers(self.se) } < 0 {
+            return Err(io::Error::last_os_error());
         }
+        Ok(SignalHandlers { se: self.se })
+    }
+
+    /// Receives data up to the capacity of the given buffer, waiting at most `timeout` for a
+    /// request and failing with `EAGAIN` if none arrived, so that the caller gets a chance to
+    /// check the exit flag. The capacity has to be at least the max write size given in the
+    /// init reply plus 4k for the request headers, and never less than 8k, or the kernel fails
+    /// the read with EINVAL. Reads interrupted by a signal are retried unless the session was
+    /// asked to exit meanwhile. Returns `Ok(false)` if no more requests will arrive because the
+    /// filesystem was unmounted or the session exited.
+    pub fn receive_timeout(&self, buffer: &mut Vec<u8>, timeout: Duration) -> io::Result<bool> {
+        receive_with(
+            buffer,
+            |buffer| read_timeout(self.fd, buffer, timeout),
+            || self.exited(),
+        )
     }
 
     /// Returns a sender object for this channel. The sender object can be
@@ -108,32 +229,103 @@ impl Channel {
         // a sender by using the same fd and use it in other threads. Only
         // the channel closes the fd when dropped. If any sender is used after
         // dropping the channel, it'll return an EBADF error.
-        ChannelSender { fd: self.fd }
+        ChannelSender {
+            fd: self.fd,
+            errors: self.send_errors.clone(),
+        }
     }
 }
 
 impl Drop for Channel {
     fn drop(&mut self) {
+        // Tell the osxfuse kernel extension the daemon is gone, so it doesn't wait for replies
+        #[cfg(target_os = "macos")]
+        set_daemon_dead(self.fd);
         unsafe {
             fuse_sys::fuse_session_unmount(self.se);
             fuse_sys::fuse_session_destroy(self.se);
         }
-        // TODO: send ioctl FUSEDEVIOCSETDAEMONDEAD on macOS before closing the fd
-        // Close the communication channel to the kernel driver
-        // (closing it before unnmount prevents sync unmount deadlock)
-        // Unmount this channel's mount point
-        // let _ = unmount(&self.mountpoint);
+        // libfuse gives up silently, like when fusermount3 is missing, so make sure the mount
+        // point is gone. The fd is closed by now, which prevents a sync unmount deadlock.
+        if let Err(err) = unmount_if_mounted_over(&self.mountpoint, self.covered_dev) {
+            error!("Failed to unmount {}: {}", self.mountpoint.display(), err);
+        }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Handle asking the session loop of a channel to stop from another thread, like a Ctrl-C
+/// handler. Unlike `Channel::exit`, it stays safe to use after the channel is dropped.
+#[derive(Clone, Debug)]
+pub struct ExitHandle(Arc<AtomicBool>);
+
+impl ExitHandle {
+    /// Set the exit flag, the session loop stops within `EXIT_CHECK_INTERVAL` of the session.
+    pub fn notify_exit(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Return true once `notify_exit` was called on this handle or one of its clones.
+    pub fn exit_notified(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Guard of the signal handlers installed by `Channel::set_signal_handlers`. The default
+/// handlers are restored when it's dropped.
+#[derive(Debug)]
+pub struct SignalHandlers {
+    se: *mut fuse_session,
+}
+
+/// # Safety: the handlers are global to the process, the session pointer is only used to
+/// remove them
+unsafe impl Send for SignalHandlers {}
+
+impl Drop for SignalHandlers {
+    fn drop(&mut self) {
+        unsafe { fuse_remove_signal_handlers(self.se) }
+    }
+}
+
+/// Replies that failed to be sent, shared by a channel and its senders.
+#[derive(Debug, Default)]
+struct SendErrors {
+    count: AtomicU64,
+    /// Set once a reply failed with EBADF or ENODEV, after which no reply will ever get through
+    disconnected: AtomicBool,
+}
+
+impl SendErrors {
+    fn record(&self, err: &io::Error) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if let Some(libc::EBADF) | Some(libc::ENODEV) = err.raw_os_error() {
+            self.disconnected.store(true, Ordering::Relaxed);
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct ChannelSender {
     fd: c_int,
+    errors: Arc<SendErrors>,
 }
 
 impl ChannelSender {
-    /// Send all data in the slice of slice of bytes in a single write (can block).
+    /// Send all data in the slice of slice of bytes in a single write (can block). The kernel
+    /// takes a reply in one write only, so more slices than `writev` accepts at once are
+    /// joined into one buffer rather than written in batches.
     pub fn send(&self, buffer: &[&[u8]]) -> io::Result<()> {
+        if buffer.len() > iov_max() {
+            return self.send(&[&buffer.concat()]);
+        }
         let iovecs: Vec<_> = buffer
             .iter()
             .map(|d| libc::iovec {
@@ -142,100 +334,364 @@ impl ChannelSender {
             })
             .collect();
         let rc = unsafe { libc::writev(self.fd, iovecs.as_ptr(), iovecs.len() as c_int) };
+        let len: usize = buffer.iter().map(|d| d.len()).sum();
         if rc < 0 {
             Err(io::Error::last_os_error())
+        } else if rc as usize != len {
+            // The rest can't be sent in another write, it would be taken for the next reply
+            Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("short write of {} of {} reply bytes", rc, len),
+            ))
         } else {
             Ok(())
         }
     }
 }
 
+/// Max number of slices `writev` takes in one call.
+fn iov_max() -> usize {
+    let max = unsafe { libc::sysconf(libc::_SC_IOV_MAX) };
+    if max > 0 {
+        max as usize
+    } else {
+        // _XOPEN_IOV_MAX, the least any system allows
+        16
+    }
+}
+
+#[cfg(test)]
+impl ChannelSender {
+    /// Sender writing to `fd`, like a pipe standing in for the kernel driver.
+    pub(crate) fn with_fd(fd: c_int) -> Self {
+        ChannelSender {
+            fd,
+            errors: Arc::new(SendErrors::default()),
+        }
+    }
+}
+
 impl ReplySender for ChannelSender {
     fn send(&self, data: &[&[u8]]) {
         if let Err(err) = ChannelSender::send(self, data) {
             error!("Failed to send FUSE reply: {}", err);
+            self.errors.record(&err);
         }
     }
 }
 
+/// Return the version of the libfuse library in use, as major * 100 + minor like
+/// `FUSE_VERSION`. Unlike the protocol version, it's known before mounting.
+pub fn libfuse_version() -> u32 {
+    unsafe { fuse_version() as u32 }
+}
+
+/// Unmount an arbitrary mount point. The mount point is detached right away, like
+/// `fusermount3 -z`, and the filesystem is unmounted once it's not busy anymore.
 pub fn unmount(mountpoint: &Path) -> io::Result<()> {
-    Command::new("fusermount3")
-        .args(&[
+    let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
+    match libc_umount(&mnt) {
+        // Only root may unmount directly on Linux, let the setuid fusermount3 unmount for other
+        // users, or diskutil on macOS
+        Err(err) if err.raw_os_error() == Some(libc::EPERM) => fusermount_unmount(mountpoint, err),
+        res => res,
+    }
+}
+
+/// Return true while something is mounted over `mountpoint`, whose device was `covered_dev`
+/// before mounting. A FUSE mount whose session is gone fails every access with ENOTCONN.
+fn is_mounted_over(mountpoint: &Path, covered_dev: u64) -> bool {
+    match fs::metadata(mountpoint) {
+        Ok(meta) => meta.dev() != covered_dev,
+        Err(err) => err.raw_os_error() == Some(libc::ENOTCONN),
+    }
+}
+
+/// Unmount `mountpoint` unless it's already back to `covered_dev`, see `is_mounted_over`.
+fn unmount_if_mounted_over(mountpoint: &Path, covered_dev: u64) -> io::Result<()> {
+    if is_mounted_over(mountpoint, covered_dev) {
+        unmount(mountpoint)
+    } else {
+        Ok(())
+    }
+}
+
+/// `ioctl` of the osxfuse device telling the kernel extension that the daemon died,
+/// `_IOW('F', 3, u_int32_t)`.
+#[cfg(target_os = "macos")]
+const FUSEDEVIOCSETDAEMONDEAD: libc::c_ulong = 0x8004_4603;
+
+#[cfg(target_os = "macos")]
+fn set_daemon_dead(fd: c_int) {
+    let dead: u32 = 1;
+    let rc = unsafe { libc::ioctl(fd, FUSEDEVIOCSETDAEMONDEAD, &dead as *const u32) };
+    if rc < 0 {
+        error!(
+            "Failed to mark the FUSE daemon dead: {}",
+            io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn libc_umount(mnt: &CStr) -> io::Result<()> {
+    let rc = unsafe { libc::umount2(mnt.as_ptr(), libc::MNT_DETACH) };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn libc_umount(mnt: &CStr) -> io::Result<()> {
+    let rc = unsafe { libc::unmount(mnt.as_ptr(), 0) };
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Unmount with fusermount3. `umount_err` is returned when fusermount3 isn't installed, as it
+/// tells why unmounting directly failed.
+#[cfg(not(target_os = "macos"))]
+fn fusermount_unmount(mountpoint: &Path, umount_err: io::Error) -> io::Result<()> {
+    run_unmount_command(
+        "fusermount3",
+        &[
             OsStr::new("-q"),
             OsStr::new("-u"),
             OsStr::new("-z"),
             OsStr::new("--"),
             mountpoint.as_ref(),
-        ])
-        .status()?;
-    Ok(())
+        ],
+        umount_err,
+    )
 }
 
-// /// Unmount an arbitrary mount point
-// pub fn unmount(mountpoint: &Path) -> io::Result<()> {
-//     // fuse_unmount_compat22 unfortunately doesn't return a status. Additionally,
-//     // it attempts to call realpath, which in turn calls into the filesystem. So
-//     // if the filesystem returns an error, the unmount does not take place, with
-//     // no indication of the error available to the caller. So we call unmount
-//     // directly, which is what osxfuse does anyway, since we already converted
-//     // to the real path when we first mounted.
-
-//     #[cfg(any(
-//         target_os = "macos",
-//         target_os = "freebsd",
-//         target_os = "dragonfly",
-//         target_os = "openbsd",
-//         target_os = "bitrig",
-//         target_os = "netbsd"
-//     ))]
-//     #[inline]
-//     fn libc_umount(mnt: &CStr) -> c_int {
-//         unsafe {
-//             libc::close(self.fd);
-//         }
-
-//         unsafe { libc::unmount(mnt.as_ptr(), 0) }
-//     }
-
-//     #[cfg(not(any(
-//         target_os = "macos",
-//         target_os = "freebsd",
-//         target_os = "dragonfly",
-//         target_os = "openbsd",
-//         target_os = "bitrig",
-//         target_os = "netbsd"
-//     )))]
-//     #[inline]
-//     fn libc_umount(mnt: &CStr) -> c_int {
-//         use fuse_sys::fuse_unmount_compat22;
-//         use std::io::ErrorKind::PermissionDenied;
-
-//         let rc = unsafe { libc::umount(mnt.as_ptr()) };
-//         if rc < 0 && io::Error::last_os_error().kind() == PermissionDenied {
-//             // Linux always returns EPERM for non-root users.  We have to let the
-//             // library go through the setuid-root "fusermount -u" to unmount.
-//             unsafe {
-//                 fuse_unmount_compat22(mnt.as_ptr());
-//             }
-//             0
-//         } else {
-//             rc
-//         }
-//     }
-
-//     let mnt = CString::new(mountpoint.as_os_str().as_bytes())?;
-//     let rc = libc_umount(&mnt);
-//     if rc < 0 {
-//         Err(io::Error::last_os_error())
-//     } else {
-//         Ok(())
-//     }
-// }
+/// There's no fusermount3 with osxfuse, `diskutil` unmounts the volumes of the user instead.
+/// Like on Linux the mount point is unmounted even if it's busy.
+#[cfg(target_os = "macos")]
+fn fusermount_unmount(mountpoint: &Path, umount_err: io::Error) -> io::Result<()> {
+    run_unmount_command(
+        "diskutil",
+        &[
+            OsStr::new("unmount"),
+            OsStr::new("force"),
+            mountpoint.as_ref(),
+        ],
+        umount_err,
+    )
+}
+
+/// Run `program` to unmount, `umount_err` is returned when it isn't installed.
+fn run_unmount_command(program: &str, args: &[&OsStr], umount_err: io::Error) -> io::Result<()> {
+    let status = match Command::new(program).args(args).status() {
+        Ok(status) => status,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(umount_err),
+        Err(err) => return Err(err),
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} failed with {}", program, status),
+        ))
+    }
+}
 
 #[cfg(test)]
 mod test {
-    use super::with_fuse_args;
-    use std::ffi::{CStr, OsStr};
+    use super::{
+        iov_max, is_mounted_over, read_timeout, receive_with, unmount, unmount_if_mounted_over,
+        with_fuse_args, ChannelSender, ExitHandle, SendErrors,
+    };
+    use crate::reply::ReplySender;
+    use std::cell::Cell;
+    use std::ffi::{CStr, CString, OsStr};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use std::{env, fs, process, ptr};
+
+    #[test]
+    fn receive_retries_on_eintr() {
+        let mut buffer = Vec::with_capacity(16);
+        let calls = Cell::new(0);
+        let res = receive_with(
+            &mut buffer,
+            |_| {
+                calls.set(calls.get() + 1);
+                if calls.get() < 3 {
+                    Err(io::Error::from_raw_os_error(libc::EINTR))
+                } else {
+                    Ok(8)
+                }
+            },
+            || false,
+        );
+        assert!(res.unwrap());
+        assert_eq!(calls.get(), 3);
+        assert_eq!(buffer.len(), 8);
+    }
+
+    #[test]
+    fn receive_stops_on_eintr_after_exit() {
+        let mut buffer = Vec::with_capacity(16);
+        let res = receive_with(
+            &mut buffer,
+            |_| Err(io::Error::from_raw_os_error(libc::EINTR)),
+            || true,
+        );
+        assert!(!res.unwrap());
+    }
+
+    #[test]
+    fn receive_stops_on_enodev() {
+        let mut buffer = Vec::with_capacity(16);
+        let res = receive_with(
+            &mut buffer,
+            |_| Err(io::Error::from_raw_os_error(libc::ENODEV)),
+            || false,
+        );
+        assert!(!res.unwrap());
+        let res = receive_with(
+            &mut buffer,
+            |_| Err(io::Error::from_raw_os_error(libc::EIO)),
+            || false,
+        );
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::EIO));
+    }
+
+    #[test]
+    fn exit_handle_stops_receive_from_another_thread() {
+        let handle = ExitHandle(Arc::new(AtomicBool::new(false)));
+        let notifier = handle.clone();
+        let thread = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            notifier.notify_exit();
+        });
+        let mut buffer = Vec::with_capacity(16);
+        let res = receive_with(
+            &mut buffer,
+            |_| {
+                // A receive interrupted by a signal
+                thread::sleep(Duration::from_millis(5));
+                Err(io::Error::from_raw_os_error(libc::EINTR))
+            },
+            || handle.exit_notified(),
+        );
+        assert!(!res.unwrap());
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn send_joins_more_slices_than_iov_max() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let sender = ChannelSender {
+            fd: fds[1],
+            errors: Arc::new(SendErrors::default()),
+        };
+        let data: Vec<u8> = (0..iov_max() + 10).map(|i| i as u8).collect();
+        let slices: Vec<&[u8]> = data.chunks(1).collect();
+        sender.send(&slices).unwrap();
+
+        let mut received = vec![0; data.len() + 1];
+        let rc = unsafe {
+            libc::read(
+                fds[0],
+                received.as_mut_ptr() as *mut libc::c_void,
+                received.len(),
+            )
+        };
+        assert_eq!(rc as usize, data.len());
+        received.truncate(data.len());
+        assert_eq!(received, data);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn failed_send_on_dead_fd_stops_receive() {
+        let errors = Arc::new(SendErrors::default());
+        // A request interrupted by the kernel fails its reply with ENOENT, that's no reason to stop
+        errors.record(&io::Error::from_raw_os_error(libc::ENOENT));
+        assert!(!errors.disconnected());
+
+        let sender = ChannelSender {
+            fd: -1,
+            errors: errors.clone(),
+        };
+        ReplySender::send(&sender, &[b"reply"]);
+        assert_eq!(errors.count(), 2);
+        assert!(errors.disconnected());
+
+        let mut buffer = Vec::with_capacity(16);
+        let res = receive_with(
+            &mut buffer,
+            |_| Err(io::Error::from_raw_os_error(libc::EINTR)),
+            || errors.disconnected(),
+        );
+        assert!(!res.unwrap());
+    }
+
+    #[test]
+    fn read_timeout_on_empty_pipe() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut buffer = Vec::with_capacity(16);
+        let start = Instant::now();
+        let err = read_timeout(fds[0], &mut buffer, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        assert_eq!(
+            unsafe { libc::write(fds[1], b"data".as_ptr() as *const _, 4) },
+            4
+        );
+        let len = read_timeout(fds[0], &mut buffer, Duration::from_millis(50)).unwrap();
+        assert_eq!(len, 4);
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn unmount_not_mounted() {
+        assert!(unmount(Path::new("/nonexistent/fuse-rs")).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unmount_mounted_over() {
+        let dir = env::temp_dir().join(format!("fuse-rs-unmount-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let covered_dev = fs::metadata(&dir).unwrap().dev();
+        assert!(unmount_if_mounted_over(&dir, covered_dev).is_ok());
+
+        let mnt = CString::new(dir.as_os_str().as_bytes()).unwrap();
+        let tmpfs = CString::new("tmpfs").unwrap();
+        let rc =
+            unsafe { libc::mount(tmpfs.as_ptr(), mnt.as_ptr(), tmpfs.as_ptr(), 0, ptr::null()) };
+        // Mounting needs CAP_SYS_ADMIN
+        if rc == 0 {
+            assert!(is_mounted_over(&dir, covered_dev));
+            unmount_if_mounted_over(&dir, covered_dev).unwrap();
+            assert!(!is_mounted_over(&dir, covered_dev));
+        }
+        fs::remove_dir(&dir).unwrap();
+    }
 
     #[test]
     fn fuse_args() {
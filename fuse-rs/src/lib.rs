@@ -13,9 +13,14 @@ use std::io;
 use std::path::Path;
 use std::time::SystemTime;
 
-pub use channel::unmount;
+pub use channel::ExitHandle;
+pub use channel::{libfuse_version, unmount};
 pub use fuse_abi::consts;
 pub use fuse_abi::FUSE_ROOT_ID;
+pub use mount_options::MountOptions;
+pub use notify::Notifier;
+pub use reply::ReplyDirectoryPlus;
+pub use reply::ReplySender;
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
 pub use reply::ReplyXattr;
@@ -26,6 +31,8 @@ pub use session::{BackgroundSession, Session};
 
 mod channel;
 mod ll;
+mod mount_options;
+mod notify;
 mod reply;
 mod request;
 mod session;
@@ -193,6 +200,8 @@ pub trait Filesystem {
     }
 
     /// Rename a file.
+    /// `flags` are the flags of renameat2(2), such as RENAME_NOREPLACE and RENAME_EXCHANGE.
+    /// They are always 0 for a plain rename.
     fn rename(
         &mut self,
         _req: &Request<'_>,
@@ -200,6 +209,7 @@ pub trait Filesystem {
         _name: &OsStr,
         _newparent: u64,
         _newname: &OsStr,
+        _flags: u32,
         reply: ReplyEmpty,
     ) {
         reply.error(ENOSYS);
@@ -350,6 +360,22 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Read directory with the attributes of the entries, saving the kernel a lookup of each
+    /// of them. Only sent by the kernel when the session asks for it, see
+    /// `Session::readdirplus`, and it may still send readdir for the same directory. Every
+    /// entry added but `.` and `..` is a lookup the kernel forgets later, like an entry
+    /// replied to `lookup`.
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        reply: ReplyDirectoryPlus,
+    ) {
+        reply.error(ENOSYS);
+    }
+
     /// Release an open directory.
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
@@ -496,6 +522,24 @@ pub trait Filesystem {
         reply.error(ENOSYS);
     }
 
+    /// Preallocate or deallocate space to a file.
+    /// `mode` is the mode of fallocate(2). If this method returns success then later writes
+    /// to the range shall not fail due to the lack of free space on the storage. fh will
+    /// contain the value set by the open method, or will be undefined if the open method
+    /// didn't set any value.
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(ENOSYS);
+    }
+
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
     /// with the 'blkdev' option
@@ -545,7 +589,8 @@ pub trait Filesystem {
 }
 
 /// Mount the given filesystem to the given mountpoint. This function will
-/// not return until the filesystem is unmounted.
+/// not return until the filesystem is unmounted, or until the process receives
+/// SIGHUP, SIGINT or SIGTERM, which unmount it.
 ///
 /// Note that you need to lead each option with a separate `"-o"` string. See
 /// `examples/hello.rs`.
@@ -554,7 +599,24 @@ pub fn mount<FS: Filesystem, P: AsRef<Path>>(
     mountpoint: P,
     options: &[&OsStr],
 ) -> io::Result<()> {
-    Session::new(filesystem, mountpoint.as_ref(), options).and_then(|mut se| se.run())
+    let mut se = Session::new(filesystem, mountpoint.as_ref(), options)?;
+    se.set_signal_handlers()?;
+    se.run()
+}
+
+/// Mount the given filesystem to the given mountpoint like `mount`, but run the filesystem
+/// operations on `workers` threads. Every thread works on its own clone of the filesystem, so
+/// state that has to be seen by all of them must be shared between the clones. See
+/// `Session::run_multithreaded` for the order the operations run in.
+pub fn mount_multithreaded<FS: Filesystem + Clone + Send + 'static, P: AsRef<Path>>(
+    filesystem: FS,
+    mountpoint: P,
+    options: &[&OsStr],
+    workers: usize,
+) -> io::Result<()> {
+    let mut se = Session::new(filesystem, mountpoint.as_ref(), options)?;
+    se.set_signal_handlers()?;
+    se.run_multithreaded(workers)
 }
 
 /// Mount the given filesystem to the given mountpoint. This function spawns
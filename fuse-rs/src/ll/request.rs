@@ -182,6 +182,14 @@ pub enum Operation<'a> {
     FAllocate {
         arg: &'a fuse_fallocate_in,
     },
+    ReadDirPlus {
+        arg: &'a fuse_read_in,
+    },
+    Rename2 {
+        arg: &'a fuse_rename2_in,
+        name: &'a OsStr,
+        newname: &'a OsStr,
+    },
     #[cfg(target_os = "macos")]
     SetVolName {
         name: &'a OsStr,
@@ -244,6 +252,8 @@ impl<'a> fmt::Display for Operation<'a> {
             Operation::NotifyReply {..} => write!(f, "NOTIFYREPLAY"),
             Operation::Poll {..} => write!(f, "POLL"),
             Operation::FAllocate {..} => write!(f, "FALLOCATE"),
+            Operation::ReadDirPlus { arg } => write!(f, "READDIRPLUS fh {}, offset {}, size {}", arg.fh, arg.offset, arg.size),
+            Operation::Rename2 { arg, name, newname } => write!(f, "RENAME2 name {:?}, newdir {:#018x}, newname {:?}, flags {:#x}", name, arg.newdir, newname, arg.flags),
 
             #[cfg(target_os = "macos")]
             Operation::SetVolName { name } => write!(f, "SETVOLNAME name {:?}", name),
@@ -352,6 +362,12 @@ impl<'a> Operation<'a> {
                     data: data.fetch_all(),
                 },
                 fuse_opcode::FUSE_FALLOCATE => Operation::FAllocate { arg: data.fetch()? },
+                fuse_opcode::FUSE_READDIRPLUS => Operation::ReadDirPlus { arg: data.fetch()? },
+                fuse_opcode::FUSE_RENAME2 => Operation::Rename2 {
+                    arg: data.fetch()?,
+                    name: data.fetch_str()?,
+                    newname: data.fetch_str()?,
+                },
                 #[cfg(target_os = "macos")]
                 fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName {
                     name: data.fetch_str()?,
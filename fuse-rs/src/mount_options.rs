@@ -0,0 +1,227 @@
+//! Mount options
+//!
+//! Options of a mount, collected with a builder or parsed from `-o` command line flags, and
+//! rendered into the arguments libfuse expects.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+
+/// Options to mount a filesystem with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MountOptions {
+    read_only: bool,
+    allow_other: bool,
+    default_permissions: bool,
+    fsname: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    custom: Vec<String>,
+}
+
+impl MountOptions {
+    /// Create options to mount read-write, accessible only by the mounting user
+    pub fn new() -> MountOptions {
+        MountOptions::default()
+    }
+
+    /// Parse `-o` flags, each followed by a comma separated list of options. Other arguments
+    /// are ignored. Options without a builder method are kept as custom options.
+    pub fn parse(args: &[&OsStr]) -> io::Result<MountOptions> {
+        let mut options = MountOptions::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if *arg != "-o" {
+                continue;
+            }
+            let value = match args.next() {
+                Some(value) => value
+                    .to_str()
+                    .ok_or_else(|| invalid_option("mount options must be UTF-8"))?,
+                None => return Err(invalid_option("missing value for -o")),
+            };
+            for option in value.split(',').filter(|option| !option.is_empty()) {
+                options = options.parse_option(option)?;
+            }
+        }
+        Ok(options)
+    }
+
+    fn parse_option(self, option: &str) -> io::Result<MountOptions> {
+        Ok(match option {
+            "ro" => self.read_only(true),
+            "rw" => self.read_only(false),
+            "allow_other" => self.allow_other(),
+            "default_permissions" => self.default_permissions(),
+            _ => {
+                if let Some(fsname) = option.strip_prefix("fsname=") {
+                    self.fsname(fsname)
+                } else if let Some(uid) = option.strip_prefix("uid=") {
+                    self.uid(uid.parse().map_err(|_| invalid_option(option))?)
+                } else if let Some(gid) = option.strip_prefix("gid=") {
+                    self.gid(gid.parse().map_err(|_| invalid_option(option))?)
+                } else {
+                    self.custom(option)
+                }
+            }
+        })
+    }
+
+    /// Mount read-only (`ro`) instead of read-write
+    pub fn read_only(mut self, read_only: bool) -> MountOptions {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Allow other users to access the filesystem (`allow_other`)
+    pub fn allow_other(mut self) -> MountOptions {
+        self.allow_other = true;
+        self
+    }
+
+    /// Let the kernel check permissions by the file modes (`default_permissions`)
+    pub fn default_permissions(mut self) -> MountOptions {
+        self.default_permissions = true;
+        self
+    }
+
+    /// Name of the filesystem shown in the mount table (`fsname=`)
+    pub fn fsname(mut self, fsname: &str) -> MountOptions {
+        self.fsname = Some(fsname.to_owned());
+        self
+    }
+
+    /// Owner of the files (`uid=`). The low level libfuse doesn't know this option, so it
+    /// isn't rendered into the arguments and is left for the filesystem to apply.
+    pub fn uid(mut self, uid: u32) -> MountOptions {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Group of the files (`gid=`). Like `uid`, it's left for the filesystem to apply.
+    pub fn gid(mut self, gid: u32) -> MountOptions {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Any other option, passed to libfuse as is unless taken by the filesystem
+    pub fn custom(mut self, option: &str) -> MountOptions {
+        self.custom.push(option.to_owned());
+        self
+    }
+
+    /// Return true if the filesystem is mounted read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Return the name of the filesystem, if set
+    pub fn get_fsname(&self) -> Option<&str> {
+        self.fsname.as_deref()
+    }
+
+    /// Return the owner of the files, if set
+    pub fn get_uid(&self) -> Option<u32> {
+        self.uid
+    }
+
+    /// Return the group of the files, if set
+    pub fn get_gid(&self) -> Option<u32> {
+        self.gid
+    }
+
+    /// Remove the custom option `key=value` and return its value, so a filesystem can handle
+    /// its own options before the rest are passed to libfuse.
+    pub fn take_custom(&mut self, key: &str) -> Option<String> {
+        let position = self.custom.iter().position(
+            |option| matches!(option.strip_prefix(key), Some(rest) if rest.starts_with('=')),
+        )?;
+        let option = self.custom.remove(position);
+        Some(option[key.len() + 1..].to_owned())
+    }
+
+    /// Remove the custom option `option`, given without a value, and return true if it was
+    /// there.
+    pub fn take_custom_flag(&mut self, option: &str) -> bool {
+        let len = self.custom.len();
+        self.custom.retain(|custom| custom != option);
+        self.custom.len() != len
+    }
+
+    /// Render the options into the `-o` arguments libfuse expects
+    pub fn to_args(&self) -> Vec<OsString> {
+        let mut options = vec![if self.read_only { "ro" } else { "rw" }.to_owned()];
+        if self.allow_other {
+            options.push("allow_other".to_owned());
+        }
+        if self.default_permissions {
+            options.push("default_permissions".to_owned());
+        }
+        if let Some(fsname) = &self.fsname {
+            options.push(format!("fsname={}", fsname));
+        }
+        options.extend(self.custom.iter().cloned());
+        options
+            .into_iter()
+            .flat_map(|option| vec![OsString::from("-o"), OsString::from(option)])
+            .collect()
+    }
+}
+
+fn invalid_option(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid mount option: {}", message),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::MountOptions;
+    use std::ffi::{OsStr, OsString};
+
+    #[test]
+    fn to_args() {
+        let options = MountOptions::new()
+            .read_only(true)
+            .allow_other()
+            .fsname("comic")
+            .uid(1000);
+        assert_eq!(
+            options.to_args(),
+            ["-o", "ro", "-o", "allow_other", "-o", "fsname=comic"]
+                .iter()
+                .map(OsString::from)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let args = [
+            "-o",
+            "ro,uid=1000,threads=2,mmap",
+            "foo",
+            "-o",
+            "default_permissions",
+        ]
+        .iter()
+        .map(OsStr::new)
+        .collect::<Vec<_>>();
+        let mut options = MountOptions::parse(&args).unwrap();
+        assert!(options.is_read_only());
+        assert_eq!(options.get_uid(), Some(1000));
+        assert_eq!(options.get_gid(), None);
+        assert_eq!(options.take_custom("threads"), Some("2".to_owned()));
+        assert_eq!(options.take_custom("threads"), None);
+        assert!(options.take_custom_flag("mmap"));
+        assert!(!options.take_custom_flag("mmap"));
+        assert_eq!(
+            options,
+            MountOptions::new()
+                .read_only(true)
+                .default_permissions()
+                .uid(1000)
+        );
+        assert!(MountOptions::parse(&[OsStr::new("-o"), OsStr::new("uid=me")]).is_err());
+    }
+}
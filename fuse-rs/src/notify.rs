@@ -0,0 +1,133 @@
+//! Notifications to the kernel
+//!
+//! Unlike replies, notifications are sent by the filesystem on its own, to tell the kernel that
+//! something it cached changed behind its back. They're written to the kernel driver like
+//! replies, with a notify code in place of the error and no request to answer.
+
+use fuse_abi::fuse_out_header;
+use fuse_abi::{fuse_notify_code, fuse_notify_inval_entry_out, fuse_notify_inval_inode_out};
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+
+use crate::channel::ChannelSender;
+use crate::reply::as_bytes;
+
+/// Sends invalidation notifications to the kernel, see `Session::notifier`. It can be sent to
+/// other threads, like a watcher noticing changes made outside of the filesystem.
+///
+/// The kernel fails a notification with `ENOENT` when it has nothing cached for the inode or
+/// entry, which is no reason to worry. A notification about an inode must not be sent from
+/// within an operation on the same inode, the kernel may wait for that operation to finish.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    sender: ChannelSender,
+}
+
+impl Notifier {
+    pub(crate) fn new(sender: ChannelSender) -> Self {
+        Notifier { sender }
+    }
+
+    /// Drop the cached attributes of the inode `ino` and its cached content from `offset` on,
+    /// `len` bytes or up to the end if `len` is 0. A negative offset only drops the attributes.
+    /// The kernel asks for them again with `getattr` and `read`, and with `readdir` for a
+    /// directory.
+    pub fn inval_inode(&self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        let arg = fuse_notify_inval_inode_out {
+            ino,
+            off: offset,
+            len,
+        };
+        as_bytes(&arg, |bytes| {
+            self.send(fuse_notify_code::FUSE_NOTIFY_INVAL_INODE, bytes)
+        })
+    }
+
+    /// Drop the cached entry `name` of the directory `parent`, together with the cached
+    /// attributes of `parent`. The kernel asks for it again with `lookup` on the next access
+    /// by path, while open files are left alone.
+    pub fn inval_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        let arg = fuse_notify_inval_entry_out {
+            parent,
+            namelen: name.len() as u32,
+            padding: 0,
+        };
+        as_bytes(&arg, |bytes| {
+            let mut data = bytes.to_vec();
+            data.push(name.as_bytes());
+            data.push(&[0]);
+            self.send(fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY, &data)
+        })
+    }
+
+    /// Send the notification `code` with the given data, behind a header like that of a reply.
+    fn send(&self, code: fuse_notify_code, data: &[&[u8]]) -> io::Result<()> {
+        let len = data.iter().map(|d| d.len()).sum::<usize>();
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>() + len) as u32,
+            error: code as i32,
+            unique: 0,
+        };
+        as_bytes(&header, |header| {
+            let mut message = header.to_vec();
+            message.extend(data);
+            self.sender.send(&message)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Notifier;
+    use crate::channel::ChannelSender;
+    use std::ffi::OsStr;
+
+    /// Send a notification through a pipe and return what was written.
+    fn notified<F: FnOnce(&Notifier)>(notify: F) -> Vec<u8> {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        notify(&Notifier::new(ChannelSender::with_fd(fds[1])));
+        let mut data = vec![0; 256];
+        let rc = unsafe { libc::read(fds[0], data.as_mut_ptr() as *mut libc::c_void, 256) };
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+        data.truncate(rc as usize);
+        data
+    }
+
+    #[test]
+    fn inval_inode() {
+        let data = notified(|notifier| notifier.inval_inode(0x42, 0, 0).unwrap());
+        assert_eq!(
+            data,
+            [
+                0x28, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, // len, code
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // unique
+                0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ino
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // off
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // len
+            ]
+            .as_ref()
+        );
+    }
+
+    #[test]
+    fn inval_entry() {
+        let data = notified(|notifier| notifier.inval_entry(0x42, OsStr::new("ep1")).unwrap());
+        assert_eq!(
+            data,
+            [
+                0x24, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // len, code
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // unique
+                0x42, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // parent
+                0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // namelen, padding
+                0x65, 0x70, 0x31, 0x00, // name
+            ]
+            .as_ref()
+        );
+    }
+}
@@ -11,7 +11,7 @@ use fuse_abi::fuse_getxattr_out;
 use fuse_abi::fuse_getxtimes_out;
 use fuse_abi::{fuse_attr, fuse_attr_out, fuse_entry_out, fuse_file_lock, fuse_kstatfs};
 use fuse_abi::{fuse_bmap_out, fuse_lk_out, fuse_open_out, fuse_statfs_out, fuse_write_out};
-use fuse_abi::{fuse_dirent, fuse_out_header};
+use fuse_abi::{fuse_dirent, fuse_direntplus, fuse_out_header};
 use libc::{c_int, EIO, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
 use log::warn;
 use std::convert::AsRef;
@@ -43,7 +43,7 @@ pub trait Reply {
 }
 
 /// Serialize an arbitrary type to bytes (memory copy, useful for fuse_*_out types)
-fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
+pub(crate) fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     let len = mem::size_of::<T>();
     match len {
         0 => f(&[]),
@@ -55,6 +55,19 @@ fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     }
 }
 
+/// The entry of a lookup, or of an entry read by readdirplus
+fn fuse_entry_out_from_attr(ttl: &Duration, attr: &FileAttr, generation: u64) -> fuse_entry_out {
+    fuse_entry_out {
+        nodeid: attr.ino,
+        generation,
+        entry_valid: ttl.as_secs(),
+        attr_valid: ttl.as_secs(),
+        entry_valid_nsec: ttl.subsec_nanos(),
+        attr_valid_nsec: ttl.subsec_nanos(),
+        attr: fuse_attr_from_attr(attr),
+    }
+}
+
 fn time_from_system_time(system_time: &SystemTime) -> Result<(u64, u32), SystemTimeError> {
     let duration = system_time.duration_since(UNIX_EPOCH)?;
     Ok((duration.as_secs(), duration.subsec_nanos()))
@@ -279,15 +292,8 @@ impl Reply for ReplyEntry {
 impl ReplyEntry {
     /// Reply to a request with the given entry
     pub fn entry(self, ttl: &Duration, attr: &FileAttr, generation: u64) {
-        self.reply.ok(&fuse_entry_out {
-            nodeid: attr.ino,
-            generation,
-            entry_valid: ttl.as_secs(),
-            attr_valid: ttl.as_secs(),
-            entry_valid_nsec: ttl.subsec_nanos(),
-            attr_valid_nsec: ttl.subsec_nanos(),
-            attr: fuse_attr_from_attr(attr),
-        });
+        self.reply
+            .ok(&fuse_entry_out_from_attr(ttl, attr, generation));
     }
 
     /// Reply to a request with the given error code
@@ -642,6 +648,72 @@ impl ReplyDirectory {
     }
 }
 
+///
+/// Directory reply with the attributes of the entries
+///
+#[derive(Debug)]
+pub struct ReplyDirectoryPlus {
+    reply: ReplyRaw<()>,
+    data: Vec<u8>,
+}
+
+impl ReplyDirectoryPlus {
+    /// Creates a new ReplyDirectoryPlus with a specified buffer size.
+    pub fn new<S: ReplySender>(unique: u64, sender: S, size: usize) -> ReplyDirectoryPlus {
+        ReplyDirectoryPlus {
+            reply: Reply::new(unique, sender),
+            data: Vec::with_capacity(size),
+        }
+    }
+
+    /// Add an entry with its attributes to the directory reply buffer. Returns true if the
+    /// buffer is full. The offset is the same as for `ReplyDirectory::add`. Every entry added
+    /// but `.` and `..` counts as a lookup of `attr.ino`, which the kernel forgets later like
+    /// one replied to `lookup`.
+    pub fn add<T: AsRef<OsStr>>(
+        &mut self,
+        offset: i64,
+        name: T,
+        ttl: &Duration,
+        attr: &FileAttr,
+        generation: u64,
+    ) -> bool {
+        let name = name.as_ref().as_bytes();
+        let entlen = mem::size_of::<fuse_direntplus>() + name.len();
+        let entsize = (entlen + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1); // 64bit align
+        if self.data.len() + entsize > self.data.capacity() {
+            return true;
+        }
+        let entry = fuse_direntplus {
+            entry_out: fuse_entry_out_from_attr(ttl, attr, generation),
+            dirent: fuse_dirent {
+                ino: attr.ino,
+                off: offset as u64,
+                namelen: name.len() as u32,
+                typ: mode_from_kind_and_perm(attr.kind, 0) >> 12,
+            },
+        };
+        as_bytes(&entry, |bytes| {
+            for bytes in bytes {
+                self.data.extend_from_slice(bytes);
+            }
+        });
+        self.data.extend_from_slice(name);
+        self.data.resize(self.data.len() + entsize - entlen, 0);
+        false
+    }
+
+    /// Reply to a request with the filled directory buffer
+    pub fn ok(mut self) {
+        self.reply.send(0, &[&self.data]);
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error(self, err: c_int) {
+        self.reply.error(err);
+    }
+}
+
 ///
 /// Xattr reply
 ///
@@ -678,6 +750,7 @@ impl ReplyXattr {
 #[cfg(test)]
 mod test {
     use super::as_bytes;
+    use super::ReplyDirectoryPlus;
     #[cfg(target_os = "macos")]
     use super::ReplyXTimes;
     use super::ReplyXattr;
@@ -1144,12 +1217,62 @@ mod test {
         reply.ok();
     }
 
+    #[test]
+    fn reply_directory_plus() {
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let ttl = Duration::new(0x8765, 0x4321);
+        let attr = FileAttr {
+            ino: 0x11,
+            size: 0x22,
+            blocks: 0x33,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 0x55,
+            uid: 0x66,
+            gid: 0x77,
+            rdev: 0x88,
+            flags: 0x99,
+        };
+        let (tx, rx) = channel::<Vec<u8>>();
+        let reply: ReplyEntry = Reply::new(0xdeadbeef, tx.clone());
+        reply.entry(&ttl, &attr, 0xaa);
+        let entry = rx.recv().unwrap();
+
+        let mut reply = ReplyDirectoryPlus::new(0xdeadbeef, tx, 4096);
+        assert!(!reply.add(1, "hello", &ttl, &attr, 0xaa));
+        reply.ok();
+        let data = rx.recv().unwrap();
+        // After the header, the entry replied to a lookup comes first, then the directory entry
+        // padded to 64bit
+        let dirent = entry.len();
+        assert_eq!(data.len(), dirent + 32);
+        assert_eq!(data[16..dirent], entry[16..]);
+        assert_eq!(
+            data[dirent..],
+            [
+                0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x68, 0x65, 0x6c, 0x6c,
+                0x6f, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
     impl super::ReplySender for Sender<()> {
         fn send(&self, _: &[&[u8]]) {
             Sender::send(self, ()).unwrap()
         }
     }
 
+    impl super::ReplySender for Sender<Vec<u8>> {
+        fn send(&self, data: &[&[u8]]) {
+            Sender::send(self, data.concat()).unwrap()
+        }
+    }
+
     #[test]
     fn reply_xattr_size() {
         let sender = AssertSender {
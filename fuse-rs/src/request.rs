@@ -8,26 +8,27 @@
 use fuse_abi::consts::*;
 use fuse_abi::*;
 use libc::{EIO, ENOSYS, EPROTO};
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
 use std::convert::TryFrom;
 use std::path::Path;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::channel::ChannelSender;
 use crate::ll;
-use crate::reply::{Reply, ReplyDirectory, ReplyEmpty, ReplyRaw};
-use crate::session::{Session, MAX_WRITE_SIZE};
+use crate::reply::{Reply, ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyRaw};
+use crate::session::Session;
 use crate::Filesystem;
 
-/// We generally support async reads
+/// We generally support async reads, and pass `O_TRUNC` on to `open`
 #[cfg(not(target_os = "macos"))]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
+const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_ATOMIC_O_TRUNC;
 // TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
 
 /// On macOS, we additionally support case insensitiveness, volume renames and xtimes
 /// TODO: we should eventually let the filesystem implementation decide which flags to set
 #[cfg(target_os = "macos")]
-const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
+const INIT_FLAGS: u32 =
+    FUSE_ASYNC_READ | FUSE_ATOMIC_O_TRUNC | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
 // TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
 
 /// Request data structure
@@ -84,12 +85,17 @@ impl<'a> Request<'a> {
                 // Reply with our desired version and settings. If the kernel supports a
                 // larger major version, it'll re-send a matching init message. If it
                 // supports only lower major versions, we replied with an error above.
+                let flags = if se.readdirplus {
+                    INIT_FLAGS | FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO
+                } else {
+                    INIT_FLAGS
+                };
                 let init = fuse_init_out {
                     major: FUSE_KERNEL_VERSION,
                     minor: FUSE_KERNEL_MINOR_VERSION,
                     max_readahead: arg.max_readahead, // accept any readahead size
-                    flags: arg.flags & INIT_FLAGS, // use features given in INIT_FLAGS and reported as capable
-                    max_write: MAX_WRITE_SIZE as u32, // use a max write size that fits into the session's buffer
+                    flags: arg.flags & flags, // use features given in flags and reported as capable
+                    max_write: se.max_write, // use a max write size that fits into the session's buffer
                     congestion_threshold: 0,
                     max_background: 1,
                 };
@@ -97,6 +103,12 @@ impl<'a> Request<'a> {
                     "INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}",
                     init.major, init.minor, init.flags, init.max_readahead, init.max_write
                 );
+                let version = negotiate_version(arg.major, arg.minor);
+                info!(
+                    "FUSE protocol {}.{}, the kernel offered {}.{}",
+                    version.0, version.1, arg.major, arg.minor
+                );
+                se.set_protocol_version(version);
                 se.initialized = true;
                 reply.ok(&init);
             }
@@ -116,7 +128,14 @@ impl<'a> Request<'a> {
                 warn!("Ignoring FUSE operation after destroy: {}", self.request);
                 self.reply::<ReplyEmpty>().error(EIO);
             }
+            _ => self.dispatch_op(&mut se.filesystem),
+        }
+    }
 
+    /// Dispatch any operation but init and destroy to the given filesystem. These operations
+    /// don't touch the session, so they can be dispatched from worker threads.
+    pub(crate) fn dispatch_op<FS: Filesystem>(&self, filesystem: &mut FS) {
+        match self.request.operation() {
             ll::Operation::Interrupt { .. } => {
                 // TODO: handle FUSE_INTERRUPT
                 self.reply::<ReplyEmpty>().error(ENOSYS);
@@ -137,24 +156,31 @@ impl<'a> Request<'a> {
                 self.reply::<ReplyEmpty>().error(ENOSYS);
             }
 
-            ll::Operation::FAllocate { .. } => {
-                // TODO: handle FUSE_FALLOCATE
-                self.reply::<ReplyEmpty>().error(ENOSYS);
+            ll::Operation::FAllocate { arg } => {
+                filesystem.fallocate(
+                    self,
+                    self.request.nodeid(),
+                    arg.fh,
+                    arg.offset as i64,
+                    arg.length as i64,
+                    arg.mode as i32,
+                    self.reply(),
+                );
             }
 
             ll::Operation::Lookup { name } => {
-                se.filesystem
-                    .lookup(self, self.request.nodeid(), &name, self.reply());
+                filesystem.lookup(self, self.request.nodeid(), &name, self.reply());
             }
             ll::Operation::Forget { arg } => {
-                se.filesystem
-                    .forget(self, self.request.nodeid(), arg.nlookup); // no reply
+                filesystem.forget(self, self.request.nodeid(), arg.nlookup); // no reply
+            }
+            ll::Operation::BatchForget { nodes, .. } => {
+                for node in nodes.iter() {
+                    filesystem.forget(self, node.nodeid, node.nlookup); // no reply
+                }
             }
-            // FIXME: shoud reply?
-            ll::Operation::BatchForget { .. } => {}
             ll::Operation::GetAttr => {
-                se.filesystem
-                    .getattr(self, self.request.nodeid(), self.reply());
+                filesystem.getattr(self, self.request.nodeid(), self.reply());
             }
             ll::Operation::SetAttr { arg } => {
                 let mode = match arg.valid & FATTR_MODE {
@@ -226,7 +252,7 @@ impl<'a> Request<'a> {
                     (None, None, None, None)
                 }
                 let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(arg);
-                se.filesystem.setattr(
+                filesystem.setattr(
                     self,
                     self.request.nodeid(),
                     mode,
@@ -244,11 +270,10 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::ReadLink => {
-                se.filesystem
-                    .readlink(self, self.request.nodeid(), self.reply());
+                filesystem.readlink(self, self.request.nodeid(), self.reply());
             }
             ll::Operation::MkNod { arg, name } => {
-                se.filesystem.mknod(
+                filesystem.mknod(
                     self,
                     self.request.nodeid(),
                     &name,
@@ -258,19 +283,16 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::MkDir { arg, name } => {
-                se.filesystem
-                    .mkdir(self, self.request.nodeid(), &name, arg.mode, self.reply());
+                filesystem.mkdir(self, self.request.nodeid(), &name, arg.mode, self.reply());
             }
             ll::Operation::Unlink { name } => {
-                se.filesystem
-                    .unlink(self, self.request.nodeid(), &name, self.reply());
+                filesystem.unlink(self, self.request.nodeid(), &name, self.reply());
             }
             ll::Operation::RmDir { name } => {
-                se.filesystem
-                    .rmdir(self, self.request.nodeid(), &name, self.reply());
+                filesystem.rmdir(self, self.request.nodeid(), &name, self.reply());
             }
             ll::Operation::SymLink { name, link } => {
-                se.filesystem.symlink(
+                filesystem.symlink(
                     self,
                     self.request.nodeid(),
                     &name,
@@ -279,17 +301,29 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::Rename { arg, name, newname } => {
-                se.filesystem.rename(
+                filesystem.rename(
                     self,
                     self.request.nodeid(),
                     &name,
                     arg.newdir,
                     &newname,
+                    0,
+                    self.reply(),
+                );
+            }
+            ll::Operation::Rename2 { arg, name, newname } => {
+                filesystem.rename(
+                    self,
+                    self.request.nodeid(),
+                    &name,
+                    arg.newdir,
+                    &newname,
+                    arg.flags,
                     self.reply(),
                 );
             }
             ll::Operation::Link { arg, name } => {
-                se.filesystem.link(
+                filesystem.link(
                     self,
                     arg.oldnodeid,
                     self.request.nodeid(),
@@ -298,11 +332,10 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::Open { arg } => {
-                se.filesystem
-                    .open(self, self.request.nodeid(), arg.flags, self.reply());
+                filesystem.open(self, self.request.nodeid(), arg.flags, self.reply());
             }
             ll::Operation::Read { arg } => {
-                se.filesystem.read(
+                filesystem.read(
                     self,
                     self.request.nodeid(),
                     arg.fh,
@@ -313,7 +346,7 @@ impl<'a> Request<'a> {
             }
             ll::Operation::Write { arg, data } => {
                 assert!(data.len() == arg.size as usize);
-                se.filesystem.write(
+                filesystem.write(
                     self,
                     self.request.nodeid(),
                     arg.fh,
@@ -324,7 +357,7 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::Flush { arg } => {
-                se.filesystem.flush(
+                filesystem.flush(
                     self,
                     self.request.nodeid(),
                     arg.fh,
@@ -337,7 +370,7 @@ impl<'a> Request<'a> {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem.release(
+                filesystem.release(
                     self,
                     self.request.nodeid(),
                     arg.fh,
@@ -352,42 +385,45 @@ impl<'a> Request<'a> {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem
-                    .fsync(self, self.request.nodeid(), arg.fh, datasync, self.reply());
+                filesystem.fsync(self, self.request.nodeid(), arg.fh, datasync, self.reply());
             }
             ll::Operation::OpenDir { arg } => {
-                se.filesystem
-                    .opendir(self, self.request.nodeid(), arg.flags, self.reply());
+                filesystem.opendir(self, self.request.nodeid(), arg.flags, self.reply());
             }
             ll::Operation::ReadDir { arg } => {
-                se.filesystem.readdir(
+                filesystem.readdir(
                     self,
                     self.request.nodeid(),
                     arg.fh,
                     arg.offset as i64,
-                    ReplyDirectory::new(self.request.unique(), self.ch, arg.size as usize),
+                    ReplyDirectory::new(self.request.unique(), self.ch.clone(), arg.size as usize),
                 );
             }
-            ll::Operation::ReleaseDir { arg } => {
-                se.filesystem.releasedir(
+            ll::Operation::ReadDirPlus { arg } => {
+                filesystem.readdirplus(
                     self,
                     self.request.nodeid(),
                     arg.fh,
-                    arg.flags,
-                    self.reply(),
+                    arg.offset as i64,
+                    ReplyDirectoryPlus::new(
+                        self.request.unique(),
+                        self.ch.clone(),
+                        arg.size as usize,
+                    ),
                 );
             }
+            ll::Operation::ReleaseDir { arg } => {
+                filesystem.releasedir(self, self.request.nodeid(), arg.fh, arg.flags, self.reply());
+            }
             ll::Operation::FSyncDir { arg } => {
                 let datasync = match arg.fsync_flags & 1 {
                     0 => false,
                     _ => true,
                 };
-                se.filesystem
-                    .fsyncdir(self, self.request.nodeid(), arg.fh, datasync, self.reply());
+                filesystem.fsyncdir(self, self.request.nodeid(), arg.fh, datasync, self.reply());
             }
             ll::Operation::StatFs => {
-                se.filesystem
-                    .statfs(self, self.request.nodeid(), self.reply());
+                filesystem.statfs(self, self.request.nodeid(), self.reply());
             }
             ll::Operation::SetXAttr { arg, name, value } => {
                 assert!(value.len() == arg.size as usize);
@@ -401,7 +437,7 @@ impl<'a> Request<'a> {
                 fn get_position(_arg: &fuse_setxattr_in) -> u32 {
                     0
                 }
-                se.filesystem.setxattr(
+                filesystem.setxattr(
                     self,
                     self.request.nodeid(),
                     name,
@@ -412,23 +448,19 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::GetXAttr { arg, name } => {
-                se.filesystem
-                    .getxattr(self, self.request.nodeid(), name, arg.size, self.reply());
+                filesystem.getxattr(self, self.request.nodeid(), name, arg.size, self.reply());
             }
             ll::Operation::ListXAttr { arg } => {
-                se.filesystem
-                    .listxattr(self, self.request.nodeid(), arg.size, self.reply());
+                filesystem.listxattr(self, self.request.nodeid(), arg.size, self.reply());
             }
             ll::Operation::RemoveXAttr { name } => {
-                se.filesystem
-                    .removexattr(self, self.request.nodeid(), name, self.reply());
+                filesystem.removexattr(self, self.request.nodeid(), name, self.reply());
             }
             ll::Operation::Access { arg } => {
-                se.filesystem
-                    .access(self, self.request.nodeid(), arg.mask, self.reply());
+                filesystem.access(self, self.request.nodeid(), arg.mask, self.reply());
             }
             ll::Operation::Create { arg, name } => {
-                se.filesystem.create(
+                filesystem.create(
                     self,
                     self.request.nodeid(),
                     &name,
@@ -438,7 +470,7 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::GetLk { arg } => {
-                se.filesystem.getlk(
+                filesystem.getlk(
                     self,
                     self.request.nodeid(),
                     arg.fh,
@@ -451,7 +483,7 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::SetLk { arg } => {
-                se.filesystem.setlk(
+                filesystem.setlk(
                     self,
                     self.request.nodeid(),
                     arg.fh,
@@ -465,7 +497,7 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::SetLkW { arg } => {
-                se.filesystem.setlk(
+                filesystem.setlk(
                     self,
                     self.request.nodeid(),
                     arg.fh,
@@ -479,7 +511,7 @@ impl<'a> Request<'a> {
                 );
             }
             ll::Operation::BMap { arg } => {
-                se.filesystem.bmap(
+                filesystem.bmap(
                     self,
                     self.request.nodeid(),
                     arg.blocksize,
@@ -490,12 +522,11 @@ impl<'a> Request<'a> {
 
             #[cfg(target_os = "macos")]
             ll::Operation::SetVolName { name } => {
-                se.filesystem.setvolname(self, name, self.reply());
+                filesystem.setvolname(self, name, self.reply());
             }
             #[cfg(target_os = "macos")]
             ll::Operation::GetXTimes => {
-                se.filesystem
-                    .getxtimes(self, self.request.nodeid(), self.reply());
+                filesystem.getxtimes(self, self.request.nodeid(), self.reply());
             }
             #[cfg(target_os = "macos")]
             ll::Operation::Exchange {
@@ -503,7 +534,7 @@ impl<'a> Request<'a> {
                 oldname,
                 newname,
             } => {
-                se.filesystem.exchange(
+                filesystem.exchange(
                     self,
                     arg.olddir,
                     &oldname,
@@ -513,13 +544,29 @@ impl<'a> Request<'a> {
                     self.reply(),
                 );
             }
+            // Handled by `dispatch` as they change the session
+            ll::Operation::Init { .. } | ll::Operation::Destroy => unreachable!(),
         }
     }
 
+    /// Returns true for the operations that have to be dispatched with the session
+    pub(crate) fn is_session_op(&self) -> bool {
+        matches!(
+            self.request.operation(),
+            ll::Operation::Init { .. } | ll::Operation::Destroy
+        )
+    }
+
+    /// Returns the inode this request is about
+    #[inline]
+    pub(crate) fn nodeid(&self) -> u64 {
+        self.request.nodeid()
+    }
+
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply>(&self) -> T {
-        Reply::new(self.request.unique(), self.ch)
+        Reply::new(self.request.unique(), self.ch.clone())
     }
 
     /// Returns the unique identifier of this request
@@ -550,3 +597,33 @@ impl<'a> Request<'a> {
         self.request.pid()
     }
 }
+
+/// Protocol version used once the kernel, offering `major.minor`, got the init reply. Both
+/// sides go with the lower minor version. A kernel with a newer major version sends init again
+/// with ours.
+fn negotiate_version(major: u32, minor: u32) -> (u32, u32) {
+    if major > FUSE_KERNEL_VERSION {
+        (FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION)
+    } else {
+        (major, minor.min(FUSE_KERNEL_MINOR_VERSION))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::negotiate_version;
+    use fuse_abi::{FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION};
+
+    #[test]
+    fn negotiate_lower_minor_version() {
+        assert_eq!(negotiate_version(7, 6), (7, 6));
+        assert_eq!(
+            negotiate_version(7, 1000),
+            (FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION)
+        );
+        assert_eq!(
+            negotiate_version(8, 0),
+            (FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION)
+        );
+    }
+}
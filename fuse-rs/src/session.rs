@@ -5,15 +5,21 @@
 //! filesystem is mounted, the session loop receives, dispatches and replies to kernel requests
 //! for filesystem operations under its mount point.
 
-use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
+use libc::{EAGAIN, ENOENT};
 use log::{error, info};
 use std::ffi::OsStr;
 use std::fmt;
 use std::io;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 use thread_scoped::{scoped, JoinGuard};
 
-use crate::channel::{self, Channel};
+use crate::channel::{self, Channel, ExitHandle, SignalHandlers};
+use crate::notify::Notifier;
 use crate::request::Request;
 use crate::Filesystem;
 
@@ -22,15 +28,23 @@ use crate::Filesystem;
 /// and 128k on other systems.
 pub const MAX_WRITE_SIZE: usize = 16 * 1024 * 1024;
 
-/// Size of the buffer for reading a request from the kernel. Since the kernel may send
-/// up to MAX_WRITE_SIZE bytes in a write request, we use that value plus some extra space.
-const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
+/// Extra space of the receive buffer for the headers of a write request. The kernel refuses
+/// to read a request into a buffer that can't hold the largest write request.
+const BUFFER_HEADER_SIZE: usize = 4096;
+
+/// The kernel never reads a request into a buffer smaller than this.
+const MIN_BUFFER_SIZE: usize = 8192;
+
+/// How long the session loop waits for a request before checking the exit flag again.
+const EXIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// The session data structure
 #[derive(Debug)]
 pub struct Session<FS: Filesystem> {
     /// Filesystem operation implementations
     pub filesystem: FS,
+    /// Signal handlers setting the exit flag, removed before the channel is dropped
+    signal_handlers: Option<SignalHandlers>,
     /// Communication channel to the kernel driver
     ch: Channel,
     /// FUSE protocol major version
@@ -41,54 +55,151 @@ pub struct Session<FS: Filesystem> {
     pub initialized: bool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub destroyed: bool,
+    /// The max size of write requests the kernel is told in the init reply. It can be lowered
+    /// before running the session to use a smaller receive buffer, but not below 4k.
+    pub max_write: u32,
+    /// Whether the kernel is asked to read directories with readdirplus, leaving it to decide
+    /// when it's worth it. Set before running the session, for filesystems implementing
+    /// `Filesystem::readdirplus`.
+    pub readdirplus: bool,
 }
 
 impl<FS: Filesystem> Session<FS> {
     /// Create a new session by mounting the given filesystem to the given mountpoint
     pub fn new(filesystem: FS, mountpoint: &Path, options: &[&OsStr]) -> io::Result<Session<FS>> {
-        info!("Mounting {}", mountpoint.display());
+        info!(
+            "Mounting {} with libfuse {}",
+            mountpoint.display(),
+            channel::libfuse_version()
+        );
         Channel::new(mountpoint, options).map(|ch| Session {
             filesystem,
+            signal_handlers: None,
             ch,
             proto_major: 0,
             proto_minor: 0,
             initialized: false,
             destroyed: false,
+            max_write: MAX_WRITE_SIZE as u32,
+            readdirplus: false,
         })
     }
 
+    /// Size of the buffer needed to receive any request from the kernel, which is the
+    /// negotiated max write size plus the space for the request headers.
+    pub fn buffer_size(&self) -> usize {
+        (self.max_write as usize + BUFFER_HEADER_SIZE).max(MIN_BUFFER_SIZE)
+    }
+
     /// Return path of the mounted filesystem
     pub fn mountpoint(&self) -> &Path {
         &self.ch.mountpoint()
     }
 
+    /// Return the FUSE protocol version agreed on with the kernel, see
+    /// `Channel::protocol_version`. `proto_major` and `proto_minor` are the version the kernel
+    /// offered instead.
+    pub fn protocol_version(&self) -> (u32, u32) {
+        self.ch.protocol_version()
+    }
+
+    pub(crate) fn set_protocol_version(&mut self, version: (u32, u32)) {
+        self.ch.set_protocol_version(version);
+    }
+
+    /// Ask the session loop to stop. The loop returns once the request being received
+    /// right now is dispatched, right away if a signal interrupts the receive, and within
+    /// `EXIT_CHECK_INTERVAL` if no request arrives.
+    pub fn exit(&self) {
+        self.ch.exit();
+    }
+
+    /// Stop the session loop on SIGHUP, SIGINT and SIGTERM. The loop returns and the
+    /// filesystem is unmounted when the session is dropped. The handlers are removed
+    /// together with the session.
+    pub fn set_signal_handlers(&mut self) -> io::Result<()> {
+        if self.signal_handlers.is_none() {
+            self.signal_handlers = Some(self.ch.set_signal_handlers()?);
+        }
+        Ok(())
+    }
+
+    /// Return a handle to ask the session loop to stop from another thread while it's running,
+    /// see `ExitHandle`.
+    pub fn exit_handle(&self) -> ExitHandle {
+        self.ch.exit_handle()
+    }
+
+    /// Return a notifier to tell the kernel about changes made outside of the filesystem, from
+    /// any thread. Notifications are only understood once the init operation is done.
+    pub fn notifier(&self) -> Notifier {
+        Notifier::new(self.ch.sender())
+    }
+
+    /// Drop everything the kernel cached about the inode `ino`, see `Notifier::inval_inode`.
+    pub fn invalidate_inode(&self, ino: u64) -> io::Result<()> {
+        self.notifier().inval_inode(ino, 0, 0)
+    }
+
+    /// Drop the cached entry `name` of the directory `parent`, see `Notifier::inval_entry`.
+    pub fn invalidate_entry(&self, parent: u64, name: &OsStr) -> io::Result<()> {
+        self.notifier().inval_entry(parent, name)
+    }
+
+    /// Return true if the session loop was asked to stop, or the connection to the kernel
+    /// is gone.
+    pub fn exited(&self) -> bool {
+        self.ch.exited()
+    }
+
+    /// Return the number of replies that failed to be sent to the kernel so far, see
+    /// `Channel::failed_replies`.
+    pub fn failed_replies(&self) -> u64 {
+        self.ch.failed_replies()
+    }
+
     /// Run the session loop that receives kernel requests and dispatches them to method
-    /// calls into the filesystem. This read-dispatch-loop is non-concurrent to prevent
-    /// having multiple buffers (which take up much memory), but the filesystem methods
-    /// may run concurrent by spawning threads.
+    /// calls into the filesystem until the filesystem is unmounted or `exit` is called.
+    /// This read-dispatch-loop is non-concurrent to prevent having multiple buffers (which
+    /// take up much memory), but the filesystem methods may run concurrent by spawning threads.
+    ///
+    /// This is the counterpart of `fuse_session_loop`, which can't be used directly: the
+    /// session is created with an empty `fuse_lowlevel_ops` table, so libfuse would answer
+    /// every request with ENOSYS instead of calling into the filesystem. The loop honors the
+    /// same exit flag though.
     pub fn run(&mut self) -> io::Result<()> {
+        self.receive_loop(|se, req, _| {
+            req.dispatch(se);
+            Ok(())
+        })
+    }
+
+    /// Receive kernel requests until the filesystem is unmounted and hand each of them to
+    /// `dispatch` together with its raw data.
+    fn receive_loop<F>(&mut self, mut dispatch: F) -> io::Result<()>
+    where
+        F: FnMut(&mut Self, &Request<'_>, &[u8]) -> io::Result<()>,
+    {
         // Buffer for receiving requests from the kernel. Only one is allocated and
         // it is reused immediately after dispatching to conserve memory and allocations.
-        let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
-        loop {
+        let mut buffer: Vec<u8> = Vec::with_capacity(self.buffer_size());
+        while !self.ch.exited() {
             // Read the next request from the given channel to kernel driver
             // The kernel driver makes sure that we get exactly one request per read
-            match self.ch.receive(&mut buffer) {
-                Ok(()) => match Request::new(self.ch.sender(), &buffer) {
+            match self.ch.receive_timeout(&mut buffer, EXIT_CHECK_INTERVAL) {
+                Ok(true) => match Request::new(self.ch.sender(), &buffer) {
                     // Dispatch request
-                    Some(req) => req.dispatch(self),
+                    Some(req) => dispatch(self, &req, &buffer)?,
                     // Quit loop on illegal request
                     None => break,
                 },
+                // Filesystem was unmounted or the session exited, quit the loop
+                Ok(false) => break,
                 Err(err) => match err.raw_os_error() {
                     // Operation interrupted. Accordingly to FUSE, this is safe to retry
                     Some(ENOENT) => continue,
-                    // Interrupted system call, retry
-                    Some(EINTR) => continue,
-                    // Explicitly try again
+                    // Explicitly try again, or no request arrived in time
                     Some(EAGAIN) => continue,
-                    // Filesystem was unmounted, quit the loop
-                    Some(ENODEV) => break,
                     // Unhandled error
                     _ => return Err(err),
                 },
@@ -98,6 +209,67 @@ impl<FS: Filesystem> Session<FS> {
     }
 }
 
+impl<FS: Filesystem + Clone + Send + 'static> Session<FS> {
+    /// Run the session loop like `run`, but dispatch the filesystem operations to `workers`
+    /// threads, each calling into its own clone of the filesystem. Requests are routed by the
+    /// inode they are about, so operations on the same inode still run one at a time in the
+    /// order the kernel sent them, while operations on different inodes may run concurrently
+    /// and complete in any order. Operations involving a second inode, like `rename` or `link`,
+    /// are only ordered with respect to the inode of the request. Init and destroy run on the
+    /// session loop itself.
+    pub fn run_multithreaded(&mut self, workers: usize) -> io::Result<()> {
+        let ch = self.ch.sender();
+        let (senders, threads): (Vec<_>, Vec<_>) = (0..workers.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::channel::<Vec<u8>>();
+                let mut filesystem = self.filesystem.clone();
+                let ch = ch.clone();
+                let thread = thread::spawn(move || {
+                    block_exit_signals();
+                    for data in rx {
+                        if let Some(req) = Request::new(ch.clone(), &data) {
+                            req.dispatch_op(&mut filesystem);
+                        }
+                    }
+                });
+                (tx, thread)
+            })
+            .unzip();
+        let res = self.receive_loop(|se, req, data| {
+            if !se.initialized || se.destroyed || req.is_session_op() {
+                req.dispatch(se);
+                return Ok(());
+            }
+            let worker = &senders[(req.nodeid() % senders.len() as u64) as usize];
+            worker
+                .send(data.to_vec())
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "FUSE worker thread exited"))
+        });
+        // Let the workers finish the requests they already received
+        drop(senders);
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("FUSE worker thread panicked");
+            }
+        }
+        res
+    }
+}
+
+/// Block the signals handled by `Session::set_signal_handlers` on the calling thread, so they
+/// are delivered to the thread running the session loop and interrupt its receive.
+fn block_exit_signals() {
+    unsafe {
+        let mut set = mem::MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        let mut set = set.assume_init();
+        for signal in &[libc::SIGHUP, libc::SIGINT, libc::SIGTERM] {
+            libc::sigaddset(&mut set, *signal);
+        }
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+    }
+}
+
 impl<'a, FS: Filesystem + Send + 'a> Session<FS> {
     /// Run the session loop in a background thread
     pub unsafe fn spawn(self) -> io::Result<BackgroundSession<'a>> {
@@ -107,6 +279,10 @@ impl<'a, FS: Filesystem + Send + 'a> Session<FS> {
 
 impl<FS: Filesystem> Drop for Session<FS> {
     fn drop(&mut self) {
+        let failed = self.failed_replies();
+        if failed > 0 {
+            error!("{} replies failed to be sent to the kernel", failed);
+        }
         info!("Unmounted {}", self.mountpoint().display());
     }
 }
@@ -115,8 +291,10 @@ impl<FS: Filesystem> Drop for Session<FS> {
 pub struct BackgroundSession<'a> {
     /// Path of the mounted filesystem
     pub mountpoint: PathBuf,
-    /// Thread guard of the background session
-    pub guard: JoinGuard<'a, io::Result<()>>,
+    /// Thread guard of the background session, taken by `join`
+    guard: Option<JoinGuard<'a, io::Result<()>>>,
+    /// Exit flag of the session running in the background
+    exit: ExitHandle,
 }
 
 impl<'a> BackgroundSession<'a> {
@@ -127,16 +305,39 @@ impl<'a> BackgroundSession<'a> {
         se: Session<FS>,
     ) -> io::Result<BackgroundSession<'a>> {
         let mountpoint = se.mountpoint().to_path_buf();
+        let exit = se.exit_handle();
         let guard = scoped(move || {
             let mut se = se;
             se.run()
         });
-        Ok(BackgroundSession { mountpoint, guard })
+        Ok(BackgroundSession {
+            mountpoint,
+            guard: Some(guard),
+            exit,
+        })
+    }
+
+    /// Ask the session loop to stop. The filesystem is unmounted by the session once the loop
+    /// returns, instead of by path when the handle is dropped.
+    pub fn notify_exit(&self) {
+        self.exit.notify_exit();
+    }
+
+    /// Stop the session loop like `notify_exit` and wait for it to return, giving its result.
+    pub fn join(mut self) -> io::Result<()> {
+        self.notify_exit();
+        match self.guard.take() {
+            Some(guard) => guard.join(),
+            None => Ok(()),
+        }
     }
 }
 
 impl<'a> Drop for BackgroundSession<'a> {
     fn drop(&mut self) {
+        if self.exit.exit_notified() {
+            return;
+        }
         info!("Unmounting {}", self.mountpoint.display());
         // Unmounting the filesystem will eventually end the session loop,
         // drop the session and hence end the background thread.
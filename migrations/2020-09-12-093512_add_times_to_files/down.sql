@@ -0,0 +1,12 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE files_backup (
+  id INTEGER NOT NULL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  content_hash VARCHAR NOT NULL,
+  eposid_id INTEGER NOT NULL,
+  access_count INTEGER NOT NULL DEFAULT 0,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO files_backup SELECT id, name, content_hash, eposid_id, access_count, created_at FROM files;
+DROP TABLE files;
+ALTER TABLE files_backup RENAME TO files;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE files ADD COLUMN mtime DATETIME;
+ALTER TABLE files ADD COLUMN atime DATETIME;
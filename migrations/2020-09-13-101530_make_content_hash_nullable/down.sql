@@ -0,0 +1,14 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE files_new (
+  id INTEGER NOT NULL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  content_hash VARCHAR NOT NULL,
+  eposid_id INTEGER NOT NULL,
+  access_count INTEGER NOT NULL DEFAULT 0,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  mtime DATETIME,
+  atime DATETIME
+);
+INSERT INTO files_new SELECT id, name, COALESCE(content_hash, ''), eposid_id, access_count, created_at, mtime, atime FROM files;
+DROP TABLE files;
+ALTER TABLE files_new RENAME TO files;
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX comics_name;
+DROP INDEX eposides_comic_id_name;
+DROP INDEX files_eposid_id_name;
+DROP INDEX tags_name;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+CREATE UNIQUE INDEX comics_name ON comics (name);
+CREATE UNIQUE INDEX eposides_comic_id_name ON eposides (comic_id, name);
+CREATE UNIQUE INDEX files_eposid_id_name ON files (eposid_id, name);
+CREATE UNIQUE INDEX tags_name ON tags (name);
@@ -0,0 +1,43 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER comics_delete_taggables;
+DROP TRIGGER eposides_delete_taggables;
+DROP TRIGGER files_delete_taggables;
+
+-- Children are rebuilt before their parents, so no foreign key is left on a dropped table
+CREATE TABLE taggables_backup (
+  id INTEGER NOT NULL PRIMARY KEY,
+  tag_id INTEGER NOT NULL,
+  taggable_id INTEGER NOT NULL,
+  taggable_type VARCHAR NOT NULL
+);
+INSERT INTO taggables_backup SELECT id, tag_id, taggable_id, taggable_type FROM taggables;
+DROP TABLE taggables;
+ALTER TABLE taggables_backup RENAME TO taggables;
+
+CREATE TABLE files_backup (
+  id INTEGER NOT NULL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  content_hash VARCHAR,
+  eposid_id INTEGER NOT NULL,
+  access_count INTEGER NOT NULL DEFAULT 0,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  mtime DATETIME,
+  atime DATETIME,
+  size BIGINT,
+  mime_type VARCHAR
+);
+INSERT INTO files_backup SELECT id, name, content_hash, eposid_id, access_count, created_at, mtime, atime, size, mime_type FROM files;
+DROP TABLE files;
+ALTER TABLE files_backup RENAME TO files;
+CREATE UNIQUE INDEX files_eposid_id_name ON files (eposid_id, name);
+
+CREATE TABLE eposides_backup (
+  id INTEGER NOT NULL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  comic_id INTEGER NOT NULL,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO eposides_backup SELECT id, name, comic_id, created_at FROM eposides;
+DROP TABLE eposides;
+ALTER TABLE eposides_backup RENAME TO eposides;
+CREATE UNIQUE INDEX eposides_comic_id_name ON eposides (comic_id, name);
@@ -0,0 +1,61 @@
+-- Your SQL goes here
+-- Rows pointing at nothing would fail the new constraints
+DELETE FROM eposides WHERE comic_id NOT IN (SELECT id FROM comics);
+DELETE FROM files WHERE eposid_id NOT IN (SELECT id FROM eposides);
+DELETE FROM taggables WHERE tag_id NOT IN (SELECT id FROM tags);
+DELETE FROM taggables WHERE
+  (taggable_type = 'comic' AND taggable_id NOT IN (SELECT id FROM comics)) OR
+  (taggable_type = 'eposide' AND taggable_id NOT IN (SELECT id FROM eposides)) OR
+  (taggable_type = 'file' AND taggable_id NOT IN (SELECT id FROM files));
+
+-- Parents are rebuilt before their children, dropping a table referenced by a foreign key would
+-- delete the rows referencing it
+CREATE TABLE eposides_new (
+  id INTEGER NOT NULL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  comic_id INTEGER NOT NULL REFERENCES comics (id) ON DELETE CASCADE,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+INSERT INTO eposides_new SELECT id, name, comic_id, created_at FROM eposides;
+DROP TABLE eposides;
+ALTER TABLE eposides_new RENAME TO eposides;
+CREATE UNIQUE INDEX eposides_comic_id_name ON eposides (comic_id, name);
+
+CREATE TABLE files_new (
+  id INTEGER NOT NULL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  content_hash VARCHAR,
+  eposid_id INTEGER NOT NULL REFERENCES eposides (id) ON DELETE CASCADE,
+  access_count INTEGER NOT NULL DEFAULT 0,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  mtime DATETIME,
+  atime DATETIME,
+  size BIGINT,
+  mime_type VARCHAR
+);
+INSERT INTO files_new SELECT id, name, content_hash, eposid_id, access_count, created_at, mtime, atime, size, mime_type FROM files;
+DROP TABLE files;
+ALTER TABLE files_new RENAME TO files;
+CREATE UNIQUE INDEX files_eposid_id_name ON files (eposid_id, name);
+
+CREATE TABLE taggables_new (
+  id INTEGER NOT NULL PRIMARY KEY,
+  tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+  taggable_id INTEGER NOT NULL,
+  taggable_type VARCHAR NOT NULL
+);
+INSERT INTO taggables_new SELECT id, tag_id, taggable_id, taggable_type FROM taggables;
+DROP TABLE taggables;
+ALTER TABLE taggables_new RENAME TO taggables;
+CREATE INDEX taggables_tag_id ON taggables (tag_id);
+
+-- `taggable_id` points at one of three tables, which a foreign key can't express
+CREATE TRIGGER comics_delete_taggables AFTER DELETE ON comics BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'comic' AND taggable_id = OLD.id;
+END;
+CREATE TRIGGER eposides_delete_taggables AFTER DELETE ON eposides BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'eposide' AND taggable_id = OLD.id;
+END;
+CREATE TRIGGER files_delete_taggables AFTER DELETE ON files BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'file' AND taggable_id = OLD.id;
+END;
@@ -0,0 +1,25 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER comics_delete_taggables;
+DROP TRIGGER eposides_delete_taggables;
+DROP TRIGGER files_delete_taggables;
+
+CREATE TABLE taggables_backup (
+  id INTEGER NOT NULL PRIMARY KEY,
+  tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+  taggable_id INTEGER NOT NULL,
+  taggable_type VARCHAR NOT NULL
+);
+INSERT INTO taggables_backup SELECT id, tag_id, taggable_id, taggable_type FROM taggables;
+DROP TABLE taggables;
+ALTER TABLE taggables_backup RENAME TO taggables;
+CREATE INDEX taggables_tag_id ON taggables (tag_id);
+
+CREATE TRIGGER comics_delete_taggables AFTER DELETE ON comics BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'comic' AND taggable_id = OLD.id;
+END;
+CREATE TRIGGER eposides_delete_taggables AFTER DELETE ON eposides BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'eposide' AND taggable_id = OLD.id;
+END;
+CREATE TRIGGER files_delete_taggables AFTER DELETE ON files BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'file' AND taggable_id = OLD.id;
+END;
@@ -0,0 +1,30 @@
+-- Your SQL goes here
+-- Unknown types never resolved to anything
+DELETE FROM taggables WHERE taggable_type NOT IN ('comic', 'eposide', 'file');
+
+-- The triggers deleting taggables refer to the table, they're made again once it's rebuilt
+DROP TRIGGER comics_delete_taggables;
+DROP TRIGGER eposides_delete_taggables;
+DROP TRIGGER files_delete_taggables;
+
+CREATE TABLE taggables_new (
+  id INTEGER NOT NULL PRIMARY KEY,
+  tag_id INTEGER NOT NULL REFERENCES tags (id) ON DELETE CASCADE,
+  taggable_id INTEGER NOT NULL,
+  -- The names of `TaggableKind`
+  taggable_type VARCHAR NOT NULL CHECK (taggable_type IN ('comic', 'eposide', 'file'))
+);
+INSERT INTO taggables_new SELECT id, tag_id, taggable_id, taggable_type FROM taggables;
+DROP TABLE taggables;
+ALTER TABLE taggables_new RENAME TO taggables;
+CREATE INDEX taggables_tag_id ON taggables (tag_id);
+
+CREATE TRIGGER comics_delete_taggables AFTER DELETE ON comics BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'comic' AND taggable_id = OLD.id;
+END;
+CREATE TRIGGER eposides_delete_taggables AFTER DELETE ON eposides BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'eposide' AND taggable_id = OLD.id;
+END;
+CREATE TRIGGER files_delete_taggables AFTER DELETE ON files BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'file' AND taggable_id = OLD.id;
+END;
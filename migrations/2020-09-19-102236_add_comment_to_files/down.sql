@@ -0,0 +1,22 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE files_backup (
+  id INTEGER NOT NULL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  content_hash VARCHAR,
+  eposid_id INTEGER NOT NULL REFERENCES eposides (id) ON DELETE CASCADE,
+  access_count INTEGER NOT NULL DEFAULT 0,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  mtime DATETIME,
+  atime DATETIME,
+  size BIGINT,
+  mime_type VARCHAR
+);
+INSERT INTO files_backup SELECT id, name, content_hash, eposid_id, access_count, created_at, mtime, atime, size, mime_type FROM files;
+DROP TABLE files;
+ALTER TABLE files_backup RENAME TO files;
+CREATE UNIQUE INDEX files_eposid_id_name ON files (eposid_id, name);
+
+-- Dropped along with the old table
+CREATE TRIGGER files_delete_taggables AFTER DELETE ON files BEGIN
+  DELETE FROM taggables WHERE taggable_type = 'file' AND taggable_id = OLD.id;
+END;
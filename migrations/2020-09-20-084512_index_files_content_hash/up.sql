@@ -0,0 +1,2 @@
+-- Your SQL goes here
+CREATE INDEX files_content_hash ON files (content_hash);
@@ -0,0 +1,15 @@
+-- This file should undo anything in `up.sql`
+-- The trash is emptied, its rows could clash with the names of live ones
+DELETE FROM files WHERE deleted_at IS NOT NULL;
+DELETE FROM eposides WHERE deleted_at IS NOT NULL;
+DELETE FROM comics WHERE deleted_at IS NOT NULL;
+
+DROP INDEX comics_name;
+DROP INDEX eposides_comic_id_name;
+DROP INDEX files_eposid_id_name;
+ALTER TABLE comics DROP COLUMN deleted_at;
+ALTER TABLE eposides DROP COLUMN deleted_at;
+ALTER TABLE files DROP COLUMN deleted_at;
+CREATE UNIQUE INDEX comics_name ON comics (name);
+CREATE UNIQUE INDEX eposides_comic_id_name ON eposides (comic_id, name);
+CREATE UNIQUE INDEX files_eposid_id_name ON files (eposid_id, name);
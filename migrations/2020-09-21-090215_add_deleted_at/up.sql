@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- Set while the row is in the trash, see `-o trash`
+ALTER TABLE comics ADD COLUMN deleted_at DATETIME;
+ALTER TABLE eposides ADD COLUMN deleted_at DATETIME;
+ALTER TABLE files ADD COLUMN deleted_at DATETIME;
+
+-- A trashed row keeps its name, which a live one can take again
+DROP INDEX comics_name;
+DROP INDEX eposides_comic_id_name;
+DROP INDEX files_eposid_id_name;
+CREATE UNIQUE INDEX comics_name ON comics (name) WHERE deleted_at IS NULL;
+CREATE UNIQUE INDEX eposides_comic_id_name ON eposides (comic_id, name) WHERE deleted_at IS NULL;
+CREATE UNIQUE INDEX files_eposid_id_name ON files (eposid_id, name) WHERE deleted_at IS NULL;
@@ -0,0 +1,126 @@
+use libc::{EEXIST, EIO, ENOENT};
+use std::{fmt, io};
+use tracing::warn;
+
+/// Error of a filesystem operation, turned into the errno of the reply by `errno`.
+#[derive(Debug)]
+pub enum FsError {
+    /// Replied as is.
+    Errno(i32),
+    Database(diesel::result::Error),
+    /// No connection could be checked out of the pool in time.
+    Pool(diesel::r2d2::PoolError),
+    Nix(nix::Error),
+    Io(io::Error),
+}
+
+impl FsError {
+    pub fn errno(&self) -> i32 {
+        use diesel::result::{DatabaseErrorKind, Error};
+
+        match self {
+            FsError::Errno(errno) => *errno,
+            FsError::Database(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                EEXIST
+            }
+            FsError::Database(Error::NotFound) => ENOENT,
+            FsError::Database(err) => {
+                warn!(%err, "database error");
+                EIO
+            }
+            FsError::Pool(err) => {
+                warn!(%err, "no database connection");
+                EIO
+            }
+            FsError::Nix(nix::Error::Sys(errno)) => *errno as i32,
+            FsError::Nix(_) => EIO,
+            FsError::Io(err) => err.raw_os_error().unwrap_or(EIO),
+        }
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Errno(errno) => write!(f, "{}", nix::errno::Errno::from_i32(*errno)),
+            FsError::Database(err) => write!(f, "database error: {}", err),
+            FsError::Pool(err) => write!(f, "database pool error: {}", err),
+            FsError::Nix(err) => err.fmt(f),
+            FsError::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for FsError {}
+
+/// Errors are told apart by their errno, which is all a reply carries.
+impl PartialEq for FsError {
+    fn eq(&self, other: &Self) -> bool {
+        self.errno() == other.errno()
+    }
+}
+
+impl From<i32> for FsError {
+    fn from(errno: i32) -> Self {
+        FsError::Errno(errno)
+    }
+}
+
+impl From<diesel::result::Error> for FsError {
+    fn from(err: diesel::result::Error) -> Self {
+        FsError::Database(err)
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for FsError {
+    fn from(err: diesel::r2d2::PoolError) -> Self {
+        FsError::Pool(err)
+    }
+}
+
+impl From<nix::Error> for FsError {
+    fn from(err: nix::Error) -> Self {
+        FsError::Nix(err)
+    }
+}
+
+impl From<io::Error> for FsError {
+    fn from(err: io::Error) -> Self {
+        FsError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FsError;
+    use diesel::result::{DatabaseErrorKind, Error};
+    use libc::{EACCES, EEXIST, EIO, ENOENT, ENOSPC, EPERM};
+    use nix::errno::Errno;
+    use std::io;
+
+    #[test]
+    fn test_errno_of_each_source() {
+        assert_eq!(FsError::from(EPERM).errno(), EPERM);
+
+        let unique = Error::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            Box::new("UNIQUE constraint failed".to_owned()),
+        );
+        assert_eq!(FsError::from(unique).errno(), EEXIST);
+        assert_eq!(FsError::from(Error::NotFound).errno(), ENOENT);
+        assert_eq!(FsError::from(Error::RollbackTransaction).errno(), EIO);
+
+        assert_eq!(
+            FsError::from(nix::Error::Sys(Errno::EACCES)).errno(),
+            EACCES
+        );
+        assert_eq!(FsError::from(nix::Error::InvalidUtf8).errno(), EIO);
+
+        assert_eq!(
+            FsError::from(io::Error::from_raw_os_error(ENOSPC)).errno(),
+            ENOSPC
+        );
+        let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+        assert_eq!(FsError::from(eof).errno(), EIO);
+    }
+}
@@ -1,35 +1,61 @@
-use crate::hex::Hex;
+use crate::hex::{encode_bytes, Hex, HexBytes};
 use crate::{
-    models::{self, Comic, Episode, File, NewTag, Tag, Taggable, Taggables},
+    error::FsError,
+    models::{
+        self, Comic, Episode, File, NewTag, Parents, Pool, PooledConnection, Tag, Taggable,
+        TaggableKind, Taggables,
+    },
     schema,
 };
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyWrite, Request,
+    FileAttr, FileType, Filesystem, MountOptions, Notifier, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite,
+    ReplyXattr, Request,
+};
+use libc::{
+    E2BIG, EACCES, EBADF, EEXIST, EINVAL, EIO, EISDIR, ELOOP, ENODATA, ENOENT, ENOSYS, ENOTDIR,
+    ENOTEMPTY, EOPNOTSUPP, EPERM, ERANGE, EXDEV, W_OK, X_OK,
 };
-use libc::{EINVAL, EIO, EISDIR, ENOENT, ENOSYS, ENOTDIR, EPERM};
+use lru::LruCache;
 use nix::{
-    fcntl::{open, OFlag},
-    sys::stat::Mode,
-    unistd::{close, ftruncate},
+    errno::Errno,
+    fcntl::{fallocate, FallocateFlags, OFlag},
+    poll::{poll, PollFd, PollFlags},
+    sys::{
+        inotify::{AddWatchFlags, InitFlags, Inotify},
+        mman::{mmap, munmap, MapFlags, ProtFlags},
+        statvfs::statvfs,
+    },
+    unistd::{close, getgid, getuid, sysconf, SysconfVar},
 };
-use once_cell::sync::Lazy;
 use path_clean::PathClean;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{Digest, Sha256};
 use std::{
+    collections::HashMap,
     convert::{TryFrom, TryInto},
     env,
-    ffi::{CString, OsStr},
+    ffi::{CString, OsStr, OsString},
     fmt, fs,
+    io::{self, Seek, SeekFrom},
     os::unix::{
         ffi::{OsStrExt, OsStringExt},
-        fs::{FileExt, MetadataExt},
+        fs::{symlink, FileExt, MetadataExt},
+        io::AsRawFd,
     },
     path::{Path, PathBuf},
-    time::{Duration, SystemTime},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
-use tracing::{info, info_span};
+use tempfile::NamedTempFile;
+use tracing::{debug, error, info, info_span, warn};
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum InodeKind {
@@ -38,15 +64,75 @@ pub enum InodeKind {
     Comic,
     Tag,
     Tagged,
+    /// The `.tags` file of a comic or an episode.
+    TagList,
+    /// A directory under `/search`, named after the searched term.
+    Search,
+    /// A symlink in a search directory or in `/recent` to a comic, an episode or a file.
+    SearchResult,
+    /// The `cover` file of an episode.
+    Cover,
+    /// The `.reorder` file of an episode.
+    Reorder,
     Special,
 }
 
-static STORAGE_BASE: Lazy<PathBuf> = Lazy::new(|| {
-    let mut cwd = env::current_dir().unwrap();
-    let path = env::var_os("FILES_PATH").unwrap();
-    cwd.push(path);
-    cwd
-});
+/// Names of the kinds, written with `Display` and read with `parse`. Comics, episodes and
+/// files share theirs with `TaggableKind`.
+impl AsRef<str> for InodeKind {
+    fn as_ref(&self) -> &str {
+        match self {
+            InodeKind::File => models::TAGGABLE_FILE,
+            InodeKind::Eposide => models::TAGGABLE_EPISODE,
+            InodeKind::Comic => models::TAGGABLE_COMIC,
+            InodeKind::Tag => "tag",
+            InodeKind::Tagged => "tagged",
+            InodeKind::TagList => "tag_list",
+            InodeKind::Search => "search",
+            InodeKind::SearchResult => "search_result",
+            InodeKind::Cover => "cover",
+            InodeKind::Reorder => "reorder",
+            InodeKind::Special => "special",
+        }
+    }
+}
+
+impl fmt::Display for InodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+impl FromStr for InodeKind {
+    type Err = ();
+
+    fn from_str(kind: &str) -> Result<Self, ()> {
+        if let Ok(kind) = kind.parse::<TaggableKind>() {
+            return Ok(kind.into());
+        }
+        match kind {
+            "tag" => Ok(InodeKind::Tag),
+            "tagged" => Ok(InodeKind::Tagged),
+            "tag_list" => Ok(InodeKind::TagList),
+            "search" => Ok(InodeKind::Search),
+            "search_result" => Ok(InodeKind::SearchResult),
+            "cover" => Ok(InodeKind::Cover),
+            "reorder" => Ok(InodeKind::Reorder),
+            "special" => Ok(InodeKind::Special),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<TaggableKind> for InodeKind {
+    fn from(kind: TaggableKind) -> Self {
+        match kind {
+            TaggableKind::Comic => InodeKind::Comic,
+            TaggableKind::Eposide => InodeKind::Eposide,
+            TaggableKind::File => InodeKind::File,
+        }
+    }
+}
 
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct Inode(u64);
@@ -57,6 +143,27 @@ impl From<u64> for Inode {
     }
 }
 
+impl From<Inode> for u64 {
+    fn from(ino: Inode) -> Self {
+        ino.0
+    }
+}
+
+/// Inodes are exchanged as their number, mark bits included, so the same value comes back.
+impl Serialize for Inode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Inode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = u64::deserialize(deserializer)?;
+        Inode::try_new(value)
+            .map_err(|_| de::Error::custom(format_args!("invalid inode {:?}", Hex(value))))
+    }
+}
+
 impl fmt::Debug for Inode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Inode")
@@ -73,12 +180,65 @@ impl Inode {
     pub const IS_COMIC: u64 = 1 << 61;
     pub const IS_TAG: u64 = 1 << 60;
     pub const IS_TAGGED: u64 = 1 << 59;
-    pub const MARK_MASK: u64 =
-        Self::IS_COMIC | Self::IS_EPOSIDE | Self::IS_FILE | Self::IS_TAG | Self::IS_TAGGED;
+    /// Set on top of the inode of a comic or an episode for its `.tags` file.
+    pub const IS_TAG_LIST: u64 = 1 << 56;
+    /// Search directories and their results, told apart by the kind bits which only results
+    /// have, see `search_result`.
+    pub const IS_SEARCH: u64 = 1 << 55;
+    /// Set on top of the inode of an episode for its `cover` file.
+    pub const IS_COVER: u64 = 1 << 54;
+    /// Set on top of the inode of an episode for its `.reorder` file.
+    pub const IS_REORDER: u64 = 1 << 53;
+    pub const MARK_MASK: u64 = Self::IS_COMIC
+        | Self::IS_EPOSIDE
+        | Self::IS_FILE
+        | Self::IS_TAG
+        | Self::IS_TAGGED
+        | Self::IS_TAG_LIST
+        | Self::IS_SEARCH
+        | Self::IS_COVER
+        | Self::IS_REORDER;
     pub const NODE_MASK: u64 = !Self::MARK_MASK;
+    /// Marks of the rows of a table and of search directories, at most one of them is set.
+    const ROW_MASK: u64 = Self::IS_FILE
+        | Self::IS_EPOSIDE
+        | Self::IS_COMIC
+        | Self::IS_TAG
+        | Self::IS_TAGGED
+        | Self::IS_SEARCH;
+    /// Tagged inodes keep the kind of their target in the two bits below the mark bits, so
+    /// the target is known without looking up the taggable.
+    const TAGGED_KIND_SHIFT: u32 = 57;
+    pub const TAGGED_KIND_MASK: u64 = 0b11 << Self::TAGGED_KIND_SHIFT;
+
+    /// The inode `value` if it could have been handed out: at most one mark of a row, the
+    /// marks of `.tags`, `cover` and `.reorder` files only on top of the directories holding
+    /// them, and kind bits only on tagged inodes and search results. Fails with `EINVAL`.
+    pub fn try_new(value: u64) -> Result<Self, FsError> {
+        let row = value & Self::ROW_MASK;
+        let on_top = match value & (Self::IS_TAG_LIST | Self::IS_COVER | Self::IS_REORDER) {
+            0 => true,
+            Self::IS_TAG_LIST => row == Self::IS_COMIC || row == Self::IS_EPOSIDE,
+            Self::IS_COVER | Self::IS_REORDER => row == Self::IS_EPOSIDE,
+            _ => false,
+        };
+        let kind_bits =
+            value & Self::TAGGED_KIND_MASK == 0 || row == Self::IS_TAGGED || row == Self::IS_SEARCH;
+        if row.count_ones() <= 1 && on_top && kind_bits {
+            Ok(Inode(value))
+        } else {
+            Err(EINVAL.into())
+        }
+    }
 
     pub fn kind(self) -> InodeKind {
-        if self.is_file() {
+        if self.is_tag_list() {
+            InodeKind::TagList
+        } else if self.is_cover() {
+            InodeKind::Cover
+        } else if self.is_reorder() {
+            InodeKind::Reorder
+        } else if self.is_file() {
             InodeKind::File
         } else if self.is_eposide() {
             InodeKind::Eposide
@@ -88,6 +248,12 @@ impl Inode {
             InodeKind::Tag
         } else if self.is_tagged() {
             InodeKind::Tagged
+        } else if self.is_search() {
+            if self.0 & Self::TAGGED_KIND_MASK == 0 {
+                InodeKind::Search
+            } else {
+                InodeKind::SearchResult
+            }
         } else {
             InodeKind::Special
         }
@@ -113,1063 +279,7148 @@ impl Inode {
         self.0 & Self::IS_TAGGED != 0
     }
 
+    pub fn is_tag_list(self) -> bool {
+        self.0 & Self::IS_TAG_LIST != 0
+    }
+
+    pub fn is_search(self) -> bool {
+        self.0 & Self::IS_SEARCH != 0
+    }
+
+    pub fn is_cover(self) -> bool {
+        self.0 & Self::IS_COVER != 0
+    }
+
+    pub fn is_reorder(self) -> bool {
+        self.0 & Self::IS_REORDER != 0
+    }
+
     pub fn is_special(self) -> bool {
         self.0 & Self::MARK_MASK == 0
     }
 
     pub fn id(self) -> u64 {
-        self.0 & Self::NODE_MASK
+        if self.is_tagged() || self.is_search() {
+            self.0 & Self::NODE_MASK & !Self::TAGGED_KIND_MASK
+        } else {
+            self.0 & Self::NODE_MASK
+        }
+    }
+
+    /// Kind of the target of a tagged inode or a search result, `None` for other inodes.
+    pub fn tagged_kind(self) -> Option<TaggableKind> {
+        if !self.is_tagged() && !self.is_search() {
+            return None;
+        }
+        match (self.0 & Self::TAGGED_KIND_MASK) >> Self::TAGGED_KIND_SHIFT {
+            1 => Some(TaggableKind::Comic),
+            2 => Some(TaggableKind::Eposide),
+            3 => Some(TaggableKind::File),
+            _ => None,
+        }
+    }
+
+    /// Inode of the `.tags` file in a comic or an episode directory.
+    fn tag_list(self) -> Inode {
+        debug_assert!(matches!(self.kind(), InodeKind::Comic | InodeKind::Eposide));
+        Inode(self.0 | Self::IS_TAG_LIST)
+    }
+
+    /// Inode of the directory holding a `.tags` file.
+    fn tag_list_owner(self) -> Inode {
+        Inode(self.0 & !Self::IS_TAG_LIST)
+    }
+
+    /// Inode of the `cover` file in an episode directory.
+    fn cover(self) -> Inode {
+        debug_assert_eq!(self.kind(), InodeKind::Eposide);
+        Inode(self.0 | Self::IS_COVER)
+    }
+
+    /// Inode of the episode holding a `cover` file.
+    fn cover_owner(self) -> Inode {
+        Inode(self.0 & !Self::IS_COVER)
+    }
+
+    /// Inode of the `.reorder` file in an episode directory.
+    fn reorder(self) -> Inode {
+        debug_assert_eq!(self.kind(), InodeKind::Eposide);
+        Inode(self.0 | Self::IS_REORDER)
+    }
+
+    /// Inode of the episode holding a `.reorder` file.
+    fn reorder_owner(self) -> Inode {
+        Inode(self.0 & !Self::IS_REORDER)
+    }
+
+    /// Inode of the target of a tagged inode or a search result, given the id of the target
+    /// row. The id of a search result is the one of its target.
+    fn tagged_target(self, taggable_id: i32) -> Option<Inode> {
+        Some(match self.tagged_kind()? {
+            TaggableKind::Comic => Inode::comic(taggable_id),
+            TaggableKind::Eposide => Inode::eposide(taggable_id),
+            TaggableKind::File => Inode::file(taggable_id),
+        })
     }
 }
 
 impl Inode {
+    /// Pack the row `id` of a table under its `mark`, or `None` if `id` doesn't fit in
+    /// `NODE_MASK` and would overlap the mark bits. Ids are `INTEGER` primary keys read as
+    /// `i32`, so in practice they are at most `i32::MAX`, far below the 53 bits available.
+    fn checked(mark: u64, id: u64) -> Option<Self> {
+        if id & Self::MARK_MASK != 0 {
+            None
+        } else {
+            Some(Self(mark | id))
+        }
+    }
+
+    fn pack(mark: u64, id: i32) -> Self {
+        let id = u64::try_from(id).expect("row ids are positive");
+        Self::checked(mark, id).expect("row id overlaps the inode mark bits")
+    }
+
     fn comic(id: i32) -> Self {
-        Self(Self::IS_COMIC | u64::try_from(id).unwrap())
+        Self::pack(Self::IS_COMIC, id)
     }
 
     fn eposide(id: i32) -> Self {
-        Self(Self::IS_EPOSIDE | u64::try_from(id).unwrap())
+        Self::pack(Self::IS_EPOSIDE, id)
     }
 
     fn file(id: i32) -> Self {
-        Self(Self::IS_FILE | u64::try_from(id).unwrap())
+        Self::pack(Self::IS_FILE, id)
     }
 
     fn tag(id: i32) -> Self {
-        Self(Self::IS_TAG | u64::try_from(id).unwrap())
+        Self::pack(Self::IS_TAG, id)
+    }
+
+    /// `id` is the row of `taggables`, not of the tagged comic, episode or file, so it has
+    /// its own mark bit instead of sharing the ids of those tables. It has to fit below the
+    /// bits of `kind`.
+    fn tagged_with_kind(id: i32, kind: TaggableKind) -> Self {
+        Self::pack_with_kind(Self::IS_TAGGED, id, kind)
+    }
+
+    /// `id` is the id of the term in `ComicFS::searches`.
+    fn search(id: u64) -> Self {
+        Self::checked(Self::IS_SEARCH, id)
+            .filter(|inode| inode.0 & Self::TAGGED_KIND_MASK == 0)
+            .expect("too many search terms")
+    }
+
+    /// A result has the same inode in every search directory, as it only depends on the
+    /// comic, episode or file `id` it links to.
+    fn search_result(id: i32, kind: TaggableKind) -> Self {
+        Self::pack_with_kind(Self::IS_SEARCH, id, kind)
+    }
+
+    fn pack_with_kind(mark: u64, id: i32, kind: TaggableKind) -> Self {
+        let kind = match kind {
+            TaggableKind::Comic => 1,
+            TaggableKind::Eposide => 2,
+            TaggableKind::File => 3,
+        };
+        let inode = Self::pack(mark, id);
+        assert_eq!(
+            inode.0 & Self::TAGGED_KIND_MASK,
+            0,
+            "row id overlaps the kind bits"
+        );
+        Self(inode.0 | kind << Self::TAGGED_KIND_SHIFT)
+    }
+}
+
+#[derive(Default)]
+struct Handle {
+    /// The blob of the file, `None` until the file gets its first content.
+    blob: Option<fs::File>,
+    /// Copy of the content being written. Blobs are shared by every file with the same content,
+    /// so they are never written in place, the copy is hashed and moved into the storage on
+    /// `fsync`/`release`.
+    pending: Option<NamedTempFile>,
+    /// Entries of a directory opened by `opendir`, listed once so that `readdir` pages through
+    /// the same entries even when children are added in between. `None` for the comics and the
+    /// tags, which are read from their tables a page at a time instead, see `read_dir_pages`.
+    entries: Option<Vec<(u64, FileType, String)>>,
+    /// Attributes of the children of the directory, built by the first `readdirplus` from
+    /// `child_attrs` and kept for the following ones like `entries`.
+    attrs: Option<HashMap<u64, FileAttr>>,
+    /// The blob mapped into memory with the `mmap` mount option, dropped once the content is
+    /// replaced.
+    map: Option<BlobMap>,
+    /// Small writes gathered before they reach `pending`, see `WRITE_BUFFER_SIZE`.
+    buffer: Option<WriteBuffer>,
+}
+
+/// Consecutive writes of a file, written to the copy of its content at once.
+struct WriteBuffer {
+    ino: Inode,
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl WriteBuffer {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+/// Terms looked up under `/search`, by the id of their directory. A term is dropped once the
+/// kernel forgets its directory, and ids are never reused, so an inode of a dropped term
+/// can't name another one.
+#[derive(Default)]
+struct Searches {
+    terms: HashMap<u64, String>,
+    ids: HashMap<String, u64>,
+    next_id: u64,
+}
+
+/// Blob mapped read-only into memory, so `read` replies with a slice of it instead of reading
+/// into a new buffer. Blobs are never written in place nor truncated, a new content gets a new
+/// blob, so the mapping stays valid for as long as it's kept.
+struct BlobMap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is read-only and only unmapped on drop
+unsafe impl Send for BlobMap {}
+
+impl BlobMap {
+    /// Map `blob`, or `None` when it's smaller than a page and `read_at` is just as cheap.
+    fn new(blob: &fs::File) -> Result<Option<Self>, FsError> {
+        let len = usize::try_from(blob.metadata()?.len()).unwrap();
+        let page_size = sysconf(SysconfVar::PAGE_SIZE)
+            .ok()
+            .flatten()
+            .map_or(4096, |size| usize::try_from(size).unwrap());
+        if len < page_size {
+            return Ok(None);
+        }
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED,
+                blob.as_raw_fd(),
+                0,
+            )
+        }?;
+        Ok(Some(Self { ptr, len }))
+    }
+
+    /// Up to `size` bytes at `offset`, fewer only at the end of the blob.
+    fn slice(&self, offset: i64, size: u32) -> &[u8] {
+        let data = unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) };
+        let start = usize::try_from(offset).unwrap().min(self.len);
+        let end = (start + usize::try_from(size).unwrap()).min(self.len);
+        &data[start..end]
     }
+}
 
-    fn tagged(id: i32) -> Self {
-        Self(Self::IS_TAGGED | u64::try_from(id).unwrap())
+impl Drop for BlobMap {
+    fn drop(&mut self) {
+        if let Err(err) = unsafe { munmap(self.ptr, self.len) } {
+            warn!(?err, "fail to unmap blob");
+        }
     }
 }
 
-#[derive(derive_more::DebugCustom)]
-#[debug(fmt = "ComicFS {{ base: {:?} }}", base)]
+/// Cloned for every worker thread of the session, the clones share the database pool and the
+/// file handles.
+#[derive(Clone, derive_more::DebugCustom)]
+#[debug(fmt = "ComicFS {{ base: {:?}, uid: {}, gid: {} }}", base, uid, gid)]
 pub struct ComicFS {
-    conn: SqliteConnection,
+    pool: Pool,
+    /// Canonical path of the mountpoint. Absolute symlink targets are taken relative to it, and
+    /// symlinks are made relative to it when read.
     base: PathBuf,
+    /// Directory storing the file contents, see `storage_path`.
+    storage: PathBuf,
+    /// Files opened by `open`/`create`, keyed by file handle.
+    handles: Arc<Mutex<HashMap<u64, Handle>>>,
+    next_fh: Arc<AtomicU64>,
+    /// Held from finding a blob in the storage until a file refers to it, and from counting the
+    /// files referring to a blob until it's removed, see `store_pending`.
+    storage_lock: Arc<Mutex<()>>,
+    /// Owner reported for every inode, the user mounting the filesystem unless overridden by
+    /// the `uid=`/`gid=` mount options.
+    uid: u32,
+    gid: u32,
+    /// `None` when disabled by `lookup_cache=0`.
+    lookup_cache: Option<Arc<Mutex<LookupCache>>>,
+    /// Lookup count of the inodes the kernel holds, see `forget_inode`.
+    lookups: Arc<Mutex<HashMap<u64, u64>>>,
+    /// Terms looked up under `/search` and still held by the kernel.
+    searches: Arc<Mutex<Searches>>,
+    /// Files listed in `/recent`, set by the `recent=` mount option.
+    recent_limit: usize,
+    /// Map blobs on `open` and reply to `read` from the mapping, set by the `mmap` mount option.
+    mmap: bool,
+    /// When the filesystem was built, the time of the directories that are always there.
+    mounted_at: SystemTime,
+    clock: Arc<dyn Clock>,
+    /// Leave the access times of the files alone, set by the `noatime` mount option.
+    noatime: bool,
+    /// Look up comics, episodes and files ignoring the case of ASCII letters, set by the
+    /// `case_insensitive` mount option. Names differing only in case can't be created then.
+    case_insensitive: bool,
+    /// Move what `unlink` and `rmdir` remove to `/trash` instead of failing with `ENOSYS`, set
+    /// by the `trash` mount option.
+    trash: bool,
+    /// How long the kernel caches the attributes of an inode and a name it looked up, set by
+    /// the `attr_timeout=` and `entry_timeout=` mount options.
+    attr_timeout: Duration,
+    entry_timeout: Duration,
+    /// Lookups answered from the lookup cache and the ones that missed it.
+    cache_hits: Arc<AtomicU64>,
+    cache_misses: Arc<AtomicU64>,
+    /// See `stats_content`.
+    stats: Arc<Mutex<Option<StatsCache>>>,
 }
 
-static ONE_SEC: Duration = Duration::from_secs(1);
+/// Source of the current time, so tests can fix it.
+trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
 
-static ROOT_DIR_ATTR: FileAttr = FileAttr {
-    ino: 1,
-    size: 0,
-    blocks: 0,
-    atime: SystemTime::UNIX_EPOCH, // 1970-01-01 00:00:00
-    mtime: SystemTime::UNIX_EPOCH,
-    ctime: SystemTime::UNIX_EPOCH,
-    crtime: SystemTime::UNIX_EPOCH,
-    kind: FileType::Directory,
-    perm: 0o755,
-    nlink: 2,
-    uid: 1000,
-    gid: 1000,
-    rdev: 0,
-    flags: 0,
-};
+/// The time of the system.
+struct SystemClock;
 
-static SPECIAL_DIR_ATTRS: [FileAttr; 2] = [
-    FileAttr {
-        ino: 2,
-        size: 0,
-        blocks: 0,
-        atime: SystemTime::UNIX_EPOCH, // 1970-01-01 00:00:00
-        mtime: SystemTime::UNIX_EPOCH,
-        ctime: SystemTime::UNIX_EPOCH,
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::Directory,
-        perm: 0o755,
-        nlink: 2,
-        uid: 1000,
-        gid: 1000,
-        rdev: 0,
-        flags: 0,
-    },
-    FileAttr {
-        ino: 3,
-        size: 0,
-        blocks: 0,
-        atime: SystemTime::UNIX_EPOCH, // 1970-01-01 00:00:00
-        mtime: SystemTime::UNIX_EPOCH,
-        ctime: SystemTime::UNIX_EPOCH,
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::Directory,
-        perm: 0o755,
-        nlink: 2,
-        uid: 1000,
-        gid: 1000,
-        rdev: 0,
-        flags: 0,
-    },
-];
-
-fn directory_attr(inode: Inode) -> FileAttr {
-    FileAttr {
-        ino: inode.0,
-        size: 0,
-        blocks: 0,
-        atime: SystemTime::UNIX_EPOCH,
-        mtime: SystemTime::UNIX_EPOCH,
-        ctime: SystemTime::UNIX_EPOCH,
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::Directory,
-        perm: 0o755,
-        nlink: 2,
-        uid: 1000,
-        gid: 1000,
-        rdev: 0,
-        flags: 0,
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
     }
 }
 
-fn symlink_attr(inode: Inode, size: u64) -> FileAttr {
-    FileAttr {
-        ino: inode.0,
-        size,
-        blocks: 0,
-        atime: SystemTime::UNIX_EPOCH,
-        mtime: SystemTime::UNIX_EPOCH,
-        ctime: SystemTime::UNIX_EPOCH,
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::Symlink,
-        perm: 0o755,
-        nlink: 1,
-        uid: 1000,
-        gid: 1000,
-        rdev: 0,
-        flags: 0,
-    }
+/// Settings of a `ComicFS` that can't change once it's built. Unless given, the mountpoint and
+/// the storage are the current directory, the files are owned by the user running the
+/// filesystem and the time is the one of the system.
+struct ComicFSBuilder {
+    pool: Pool,
+    base: PathBuf,
+    storage: PathBuf,
+    uid: u32,
+    gid: u32,
+    clock: Arc<dyn Clock>,
 }
 
-fn file_attr(inode: Inode) -> FileAttr {
-    FileAttr {
-        ino: inode.0,
-        size: 0,
-        blocks: 0,
-        atime: SystemTime::UNIX_EPOCH,
-        mtime: SystemTime::UNIX_EPOCH,
-        ctime: SystemTime::UNIX_EPOCH,
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: FileType::RegularFile,
-        perm: 0o644,
-        nlink: 2,
-        uid: 1000,
-        gid: 1000,
-        rdev: 0,
-        flags: 0,
+impl ComicFSBuilder {
+    fn new(pool: Pool) -> Self {
+        Self {
+            pool,
+            base: PathBuf::new(),
+            storage: PathBuf::new(),
+            uid: getuid().as_raw(),
+            gid: getgid().as_raw(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Canonical path of the mountpoint, see `ComicFS::base`.
+    fn base(mut self, base: PathBuf) -> Self {
+        self.base = base;
+        self
+    }
+
+    /// Directory storing the file contents, see `ComicFS::storage_path`.
+    fn storage(mut self, storage: PathBuf) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    fn uid(mut self, uid: u32) -> Self {
+        self.uid = uid;
+        self
+    }
+
+    fn gid(mut self, gid: u32) -> Self {
+        self.gid = gid;
+        self
+    }
+
+    /// Only tests need another time than the one of the system
+    #[cfg(test)]
+    fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn build(self) -> ComicFS {
+        ComicFS {
+            pool: self.pool,
+            base: self.base,
+            storage: self.storage,
+            handles: Arc::new(Mutex::new(HashMap::new())),
+            next_fh: Arc::new(AtomicU64::new(1)),
+            storage_lock: Arc::new(Mutex::new(())),
+            uid: self.uid,
+            gid: self.gid,
+            lookup_cache: None,
+            lookups: Arc::new(Mutex::new(HashMap::new())),
+            searches: Arc::new(Mutex::new(Searches::default())),
+            recent_limit: DEFAULT_RECENT_LIMIT,
+            mmap: false,
+            mounted_at: self.clock.now(),
+            clock: self.clock,
+            noatime: false,
+            case_insensitive: false,
+            trash: false,
+            attr_timeout: ONE_SEC,
+            entry_timeout: ONE_SEC,
+            cache_hits: Arc::new(AtomicU64::new(0)),
+            cache_misses: Arc::new(AtomicU64::new(0)),
+            stats: Arc::new(Mutex::new(None)),
+        }
+        .with_lookup_cache(DEFAULT_LOOKUP_CACHE)
     }
 }
 
+/// Attributes found by `lookup` keyed by parent inode and name, with the time they were found.
+type LookupCache = LruCache<(u64, OsString), (Option<FileAttr>, Instant)>;
+/// Content of `/.comicfs/stats` with the time it was made.
+type StatsCache = (SystemTime, Vec<u8>);
+
+static ONE_SEC: Duration = Duration::from_secs(1);
+/// How long a name that wasn't found is remembered as missing, by the lookup cache and by the
+/// kernel, unless `entry_timeout` is shorter. Kept short since the crawler may add it anytime.
+const NEGATIVE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Block size reported by `statfs`, block counts of the storage are converted to this unit.
+const BLOCK_SIZE: u64 = 512;
+const NAME_MAX: u32 = 255;
+/// Free inodes reported by `statfs`. Row ids are read as `i32`, which bounds every table.
+const FREE_INODES: u64 = i32::MAX as u64;
+/// Worker threads of the session when not given by the `threads=` mount option.
+const DEFAULT_THREADS: usize = 4;
+/// Lookups cached when not given by the `lookup_cache=` mount option.
+const DEFAULT_LOOKUP_CACHE: usize = 1024;
+/// Bytes of consecutive writes gathered by a handle before they're written to the copy of the
+/// content, so writing a byte at a time doesn't cost a write and a database update per byte.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+/// Most components of a path `resolve_inode` walks through, a file is at
+/// `base/comics/<comic>/<episode>/<file>`.
+const MAX_RESOLVE_DEPTH: usize = 8;
+/// Comics or tags loaded at a time by `readdir`, which has no `opendir` snapshot of them.
+const READDIR_PAGE: usize = 64;
+/// Files listed in `/recent` when not given by the `recent=` mount option.
+const DEFAULT_RECENT_LIMIT: usize = 50;
+/// Name of the read-only file listing the tags of a comic or an episode, one per line. The name
+/// is reserved, no episode or file can take it.
+const TAG_LIST_NAME: &str = ".tags";
+/// Name of the read-only file showing the content of the first file of an episode, by name.
+/// Reserved like `TAG_LIST_NAME`.
+const COVER_NAME: &str = "cover";
+/// Name of the write-only file renumbering the files of an episode when written to, see
+/// `File::renumber`. Reserved like `TAG_LIST_NAME`, but not listed.
+const REORDER_NAME: &str = ".reorder";
+/// Largest value of the `user.comment` extended attribute, a longer one fails with `E2BIG`.
+const MAX_COMMENT_SIZE: usize = 4096;
+/// How long the database has to be left alone before `-o autorefresh` drops the caches, so
+/// that a crawler writing many rows in a row causes a single refresh.
+const AUTOREFRESH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// How long the content of `/.comicfs/stats` is reused before it's counted again.
+const STATS_REFRESH: Duration = Duration::from_secs(5);
+
+/// Content of `/.comicfs/stats`.
+#[derive(Serialize)]
+struct Stats {
+    comics: u64,
+    episodes: u64,
+    files: u64,
+    tags: u64,
+    /// Bytes of the blobs, shared ones counted once.
+    blob_bytes: u64,
+    /// Share of the lookups answered by the lookup cache, `None` before the first lookup.
+    cache_hit_rate: Option<f64>,
+    uptime_secs: u64,
+}
+
 impl ComicFS {
     const ROOT_ID: u64 = 1;
     const COMIC_ID: u64 = 2;
     const TAGS_ID: u64 = 3;
+    const SEARCH_ID: u64 = 4;
+    const RECENT_ID: u64 = 5;
+    /// `/.comicfs`, holding the read-only `stats` file.
+    const CONTROL_ID: u64 = 6;
+    const STATS_ID: u64 = 7;
+    /// `/trash`, holding what `unlink` and `rmdir` removed with the `trash` mount option.
+    const TRASH_ID: u64 = 8;
 
-    fn new(conn: SqliteConnection, base: PathBuf) -> Self {
-        Self { conn, base }
+    /// `ComicFSBuilder` with the defaults but for the mountpoint and the storage.
+    fn new(pool: Pool, base: PathBuf, storage: PathBuf) -> Self {
+        ComicFSBuilder::new(pool)
+            .base(base)
+            .storage(storage)
+            .build()
     }
 
-    fn find_comic_by_inode(&self, inode: Inode) -> Option<FileAttr> {
-        Comic::find(i32::try_from(inode.id()).unwrap(), &self.conn)
-            .map(|info| directory_attr(Inode::comic(info.id)))
+    /// Cache at most `size` lookups, 0 disables the cache.
+    fn with_lookup_cache(mut self, size: usize) -> Self {
+        self.lookup_cache = match size {
+            0 => None,
+            size => Some(Arc::new(Mutex::new(LruCache::new(size)))),
+        };
+        self
     }
 
-    fn find_eposide_by_inode(&self, inode: Inode) -> Option<FileAttr> {
-        let res = Episode::find(i32::try_from(inode.id()).unwrap(), &self.conn);
-        res.map(|info| directory_attr(Inode::eposide(info.id)))
+    /// Path of the blob with `content_hash`, which has to be a lowercase hex SHA-256 digest.
+    /// Any other value means the database is corrupted and yields `EIO`.
+    fn storage_path(&self, content_hash: &str) -> Result<PathBuf, FsError> {
+        let valid = content_hash.len() == 64
+            && content_hash
+                .bytes()
+                .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+        if !valid {
+            warn!(content_hash, "malformed content hash");
+            return Err(EIO.into());
+        }
+        let mut path = self.storage.clone();
+        path.push(&content_hash[0..2]);
+        path.push(&content_hash);
+        Ok(path)
     }
 
-    fn find_comic_by_name(&self, name: &str) -> Option<FileAttr> {
-        Comic::find_by_name(name, &self.conn).map(|info| directory_attr(Inode::comic(info.id)))
+    /// Directories the directory `inode` holds. Tags, search results and the recent files are
+    /// symlinks and episodes only hold files, so they hold none.
+    fn subdirectory_count(&self, inode: Inode) -> u64 {
+        let count = match inode.0 {
+            // comics, tags, search, recent, .comicfs and trash
+            Self::ROOT_ID => Some(if self.trash { 6 } else { 5 }),
+            Self::COMIC_ID => self.conn().ok().and_then(|conn| Comic::count(&conn)),
+            Self::TAGS_ID => self.conn().ok().and_then(|conn| Tag::count(&conn)),
+            Self::SEARCH_ID => Some(self.searches.lock().unwrap().terms.len() as u64),
+            Self::TRASH_ID => self.conn().ok().and_then(|conn| {
+                let entries = Self::trash_entries(&conn).ok()?;
+                let dirs = entries
+                    .iter()
+                    .filter(|(_, kind, _)| *kind == FileType::Directory);
+                Some(dirs.count() as u64)
+            }),
+            _ => match inode.kind() {
+                InodeKind::Comic => self.conn().ok().and_then(|conn| {
+                    Episode::count_by_comic(i32::try_from(inode.id()).unwrap(), &conn)
+                }),
+                _ => None,
+            },
+        };
+        count.unwrap_or(0)
     }
 
-    fn find_comic_eposide_by_name(&self, id: u64, name: &str) -> Option<FileAttr> {
-        Episode::find_by_comic_and_name(i32::try_from(id).unwrap(), name, &self.conn)
-            .map(|info| directory_attr(Inode::eposide(info.id)))
+    /// A file is linked from its episode and from every tag it has.
+    fn file_link_count(&self, info: &File) -> u32 {
+        let tagged = self
+            .conn()
+            .ok()
+            .and_then(|conn| Taggable::count_for(info.id, TaggableKind::File, &conn))
+            .unwrap_or(0);
+        u32::try_from(1 + tagged).unwrap_or(u32::MAX)
     }
 
-    fn find_tag_by_name(&self, name: &str) -> Option<FileAttr> {
-        Tag::find_by_name(name, &self.conn).map(|info| directory_attr(Inode::tag(info.id)))
+    fn directory_attr(&self, inode: Inode, created: SystemTime) -> FileAttr {
+        let nlink = 2 + self.subdirectory_count(inode);
+        self.linked_directory_attr(inode, created, nlink)
     }
 
-    fn inode_to_storage(&self, ino: Inode) -> Option<PathBuf> {
-        let info = File::find(i32::try_from(ino.id()).unwrap(), &self.conn)?;
-        let path = generate_storage_path(&info.content_hash);
-        Some(path)
+    /// `directory_attr` with the link count already known.
+    fn linked_directory_attr(&self, inode: Inode, created: SystemTime, nlink: u64) -> FileAttr {
+        FileAttr {
+            ino: inode.0,
+            size: 0,
+            blocks: 0,
+            atime: created,
+            mtime: created,
+            ctime: created,
+            crtime: created,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: u32::try_from(nlink).unwrap_or(u32::MAX),
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        }
     }
 
-    fn resolve(&self, path: &Path) -> Option<Inode> {
-        let mut parent = Inode::from(1);
-        for component in path.components() {
-            let id = parent.0;
-            let kind = parent.kind();
-            let name = component.as_os_str();
+    fn symlink_attr(&self, inode: Inode, size: u64) -> FileAttr {
+        FileAttr {
+            ino: inode.0,
+            size,
+            blocks: 0,
+            atime: SystemTime::UNIX_EPOCH,
+            mtime: SystemTime::UNIX_EPOCH,
+            ctime: SystemTime::UNIX_EPOCH,
+            crtime: SystemTime::UNIX_EPOCH,
+            kind: FileType::Symlink,
+            perm: 0o755,
+            nlink: 1,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
 
-            match kind {
-                InodeKind::Special => match id {
-                    Self::ROOT_ID => {
-                        if name == "comics" {
-                            parent = Inode::from(Self::COMIC_ID);
-                        } else if name == "tags" {
-                            parent = Inode::from(Self::TAGS_ID);
-                        } else {
-                            unreachable!();
-                        }
-                    }
-                    Self::COMIC_ID => {
-                        let info = Comic::find_by_name(name.to_str().unwrap(), &self.conn)?;
-                        parent = Inode::comic(info.id);
-                    }
-                    Self::TAGS_ID => {
-                        let info = Tag::find_by_name(name.to_str().unwrap(), &self.conn)?;
-                        parent = Inode::tag(info.id)
-                    }
-                    _ => unreachable!(),
-                },
-                InodeKind::Comic => {
-                    let info = Episode::find_by_comic_and_name(
-                        parent.id().try_into().unwrap(),
-                        name.to_str().unwrap(),
-                        &self.conn,
-                    )?;
-                    parent = Inode::eposide(info.id);
-                }
-                InodeKind::Eposide => {
-                    let info = File::find_by_eposide_and_name(
-                        parent.id().try_into().unwrap(),
-                        name.to_str().unwrap(),
-                        &self.conn,
-                    )?;
-                    parent = Inode::file(info.id);
-                }
-                InodeKind::Tag => todo!(),
-                InodeKind::File => {
-                    unreachable!();
-                }
-                InodeKind::Tagged => {
-                    unreachable!();
-                }
-            }
+    fn file_attr(&self, info: &File, nlink: u32) -> FileAttr {
+        let created = naive_to_systemtime(info.created_at);
+        let size = info.size.map_or(0, |size| u64::try_from(size).unwrap());
+        FileAttr {
+            ino: Inode::file(info.id).0,
+            size,
+            blocks: (size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            atime: info.atime.map_or(created, naive_to_systemtime),
+            mtime: info.mtime.map_or(created, naive_to_systemtime),
+            ctime: created,
+            crtime: created,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
         }
-        Some(parent)
     }
 
-    fn resolve_inode(&self, ino: Inode) -> Option<PathBuf> {
-        let mut next = Some(ino);
-        let mut components = vec![];
+    /// The times stored in the database win over the ones of the blob, which is shared by every
+    /// file with the same content.
+    fn convert_meta_to_attr(&self, info: &File, meta: fs::Metadata, nlink: u32) -> fuse::FileAttr {
+        FileAttr {
+            ino: Inode::file(info.id).0,
+            size: meta.len(),
+            nlink,
+            perm: cast::u16(meta.mode()).unwrap(),
+            uid: self.uid,
+            gid: self.gid,
+            blocks: meta.blocks(),
+            atime: info
+                .atime
+                .map_or_else(|| meta.accessed().unwrap(), naive_to_systemtime),
+            ctime: meta.created().unwrap(),
+            mtime: info
+                .mtime
+                .map_or_else(|| meta.modified().unwrap(), naive_to_systemtime),
+            crtime: naive_to_systemtime(info.created_at),
+            kind: convert_file_type(meta.file_type()),
+            rdev: 0,
+            flags: 0,
+        }
+    }
 
-        while let Some(ino) = next {
-            match ino.kind() {
-                InodeKind::Special => match ino.0 {
-                    Self::ROOT_ID => {
-                        components.push(self.base.clone());
-                        next = None;
-                    }
-                    Self::COMIC_ID => {
-                        components.push(PathBuf::from("comics".to_owned()));
-                        next = Some(Inode::from(Self::ROOT_ID));
-                    }
-                    Self::TAGS_ID => {
-                        components.push(PathBuf::from("tags".to_owned()));
-                        next = Some(Inode::from(Self::ROOT_ID));
-                    }
-                    _ => unreachable!(),
-                },
-                InodeKind::Comic => {
-                    let info = Comic::find(ino.id().try_into().unwrap(), &self.conn)?;
-                    components.push(PathBuf::from(info.name.clone()));
-                    next = Some(Inode::from(Self::COMIC_ID));
-                }
-                InodeKind::Eposide => {
-                    let info = Episode::find(ino.id().try_into().unwrap(), &self.conn)?;
-                    components.push(PathBuf::from(info.name.clone()));
-                    next = Some(Inode::comic(info.comic_id));
-                }
-                InodeKind::File => {
-                    let info = File::find(ino.id().try_into().unwrap(), &self.conn)?;
-                    components.push(PathBuf::from(info.name.clone()));
-                    next = Some(Inode::eposide(info.eposid_id));
-                }
-                InodeKind::Tag => {
-                    let info = Tag::find(ino.id().try_into().unwrap(), &self.conn)?;
-                    components.push(PathBuf::from(info.name.clone()));
-                    next = Some(Inode::from(Self::TAGS_ID));
-                }
-                _ => todo!(),
+    /// Number of inodes backed by a row, the comics, episodes, files and tags. Counted in one
+    /// transaction so the tables are seen at the same time.
+    fn total_inodes(conn: &SqliteConnection) -> QueryResult<u64> {
+        conn.transaction(|| {
+            let counts = [
+                schema::comics::table.count().get_result::<i64>(conn)?,
+                schema::eposides::table.count().get_result::<i64>(conn)?,
+                schema::files::table.count().get_result::<i64>(conn)?,
+                schema::tags::table.count().get_result::<i64>(conn)?,
+            ];
+            Ok(counts
+                .iter()
+                .map(|count| u64::try_from(*count).unwrap())
+                .sum())
+        })
+    }
+
+    /// Open the blob with `content_hash`. A file whose blob is gone can't be read, which is
+    /// `EIO` rather than an empty content.
+    fn open_blob(&self, content_hash: &str) -> Result<fs::File, FsError> {
+        fs::File::open(self.storage_path(content_hash)?).map_err(|err| {
+            if err.kind() == io::ErrorKind::NotFound {
+                error!(content_hash, "blob is missing");
+                FsError::Errno(EIO)
+            } else {
+                FsError::from(err)
             }
-        }
+        })
+    }
 
-        Some(components.into_iter().rev().collect::<PathBuf>())
+    /// Check out a connection, it's returned to the pool once dropped.
+    /// A connection checked out of the pool, failing with `EIO` when none is freed in time.
+    fn conn(&self) -> Result<PooledConnection, FsError> {
+        Ok(self.pool.get()?)
     }
-}
 
-impl Filesystem for ComicFS {
-    #[tracing::instrument(fields(unique = _req.unique()),skip(self, _req,  reply))]
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match parent {
-            Self::ROOT_ID => {
-                if name == "comics" {
-                    reply.entry(&ONE_SEC, &SPECIAL_DIR_ATTRS[0], 0);
-                } else if name == "tags" {
-                    reply.entry(&ONE_SEC, &SPECIAL_DIR_ATTRS[1], 0);
-                } else {
-                    reply.error(ENOENT);
-                }
+    fn insert_handle(&mut self, handle: Handle) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    fn open_handle(&mut self, ino: Inode) -> Result<u64, FsError> {
+        let info = match ino.kind() {
+            InodeKind::File => {
+                File::find(i32::try_from(ino.id()).unwrap(), &*self.conn()?).ok_or(ENOENT)?
             }
-            Self::COMIC_ID => {
-                let name = name.to_str().unwrap();
-                let attr = self.find_comic_by_name(name);
-                match attr {
-                    Some(attr) => {
-                        reply.entry(&ONE_SEC, &attr, 0);
-                    }
-                    None => {
-                        reply.error(ENOENT);
-                    }
-                }
+            // Keeps reading the same blob even if another file becomes the cover meanwhile
+            InodeKind::Cover => self.cover_file(ino)?,
+            // Read from the database on every `read`, nothing to keep open
+            InodeKind::TagList | InodeKind::Reorder => {
+                return Ok(self.insert_handle(Handle::default()))
             }
-            Self::TAGS_ID => {
-                let name = name.to_str().unwrap();
-                let attr = self.find_tag_by_name(name);
-                match attr {
-                    Some(attr) => {
-                        reply.entry(&ONE_SEC, &attr, 0);
-                    }
-                    None => {
-                        reply.error(ENOENT);
-                    }
+            InodeKind::Special if ino.0 == Self::STATS_ID => {
+                return Ok(self.insert_handle(Handle::default()))
+            }
+            _ => return Err(EISDIR.into()),
+        };
+        let blob = match &info.content_hash {
+            Some(hash) => Some(self.open_blob(hash)?),
+            None => None,
+        };
+        let map = match &blob {
+            // Reads fall back to `read_at` if the blob can't be mapped
+            Some(blob) if self.mmap => BlobMap::new(blob).unwrap_or_else(|err| {
+                warn!(%err, "fail to map blob");
+                None
+            }),
+            _ => None,
+        };
+        Ok(self.insert_handle(Handle {
+            blob,
+            pending: None,
+            entries: None,
+            attrs: None,
+            map,
+            buffer: None,
+        }))
+    }
+
+    fn write_data(
+        &mut self,
+        ino: Inode,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+    ) -> Result<u32, FsError> {
+        match ino.kind() {
+            InodeKind::File => {}
+            // What is written doesn't matter, any write renumbers the files
+            InodeKind::Reorder => {
+                self.renumber_files(ino.reorder_owner())?;
+                return Ok(u32::try_from(data.len()).unwrap());
+            }
+            InodeKind::TagList | InodeKind::Cover => return Err(EPERM.into()),
+            InodeKind::Special if ino.0 == Self::STATS_ID => return Err(EPERM.into()),
+            _ => return Err(EISDIR.into()),
+        }
+        let mut handles = self.handles.lock().unwrap();
+        let handle = handles.get_mut(&fh).ok_or(EBADF)?;
+        self.start_pending(handle)?;
+        let offset = u64::try_from(offset).unwrap();
+        let buffer = match handle.buffer.take() {
+            Some(mut buffer) if buffer.end() == offset => {
+                buffer.data.extend_from_slice(data);
+                buffer
+            }
+            other => {
+                if let Some(buffer) = other {
+                    self.write_pending(handle, buffer)?;
+                }
+                WriteBuffer {
+                    ino,
+                    offset,
+                    data: data.to_vec(),
                 }
             }
-            ino => {
-                let ino = Inode::from(ino);
-                let kind = ino.kind();
-                let attr = match kind {
-                    InodeKind::Comic => {
-                        let name = name.to_str().unwrap();
-                        self.find_comic_eposide_by_name(ino.id(), name)
-                    }
-                    InodeKind::Eposide => {
-                        let name = name.to_str().unwrap();
-                        let info = File::find_by_eposide_and_name(
-                            i32::try_from(ino.id()).unwrap(),
-                            name,
-                            &self.conn,
-                        );
-                        info.and_then(|info| {
-                            let id = info.id;
-                            if info.content_hash == "" {
-                                return Some(file_attr(Inode::file(id)));
-                            }
-                            let path = generate_storage_path(&info.content_hash);
-                            let meta = fs::metadata(&path).ok()?;
-                            Some(convert_meta_to_attr(Inode::file(id).0, meta))
-                        })
-                    }
-                    InodeKind::Special | InodeKind::File | InodeKind::Tagged => unreachable!(),
-                    InodeKind::Tag => {
-                        let span = info_span!("lookop tagged");
-                        let _guard = span.enter();
-                        let expected_name = name.to_str().unwrap();
-                        info!(expected_name);
-                        let files =
-                            Taggables::taggables(i32::try_from(ino.id()).unwrap(), &self.conn);
-                        info!(?files);
-                        let res = files.iter().find_map(|file| match file {
-                            Taggables::Comic { id, name, .. } => {
-                                if name == expected_name {
-                                    let id = *id;
-                                    info!(id, "found comic");
-                                    let path = self
-                                        .resolve_inode(Inode::comic(id.try_into().unwrap()))
-                                        .unwrap();
-                                    Some((id, path))
-                                } else {
-                                    None
-                                }
-                            }
-                            Taggables::Episode { id, name, .. } => {
-                                if name == expected_name {
-                                    let id = *id;
-                                    info!(id, "found episode");
-                                    let path = self
-                                        .resolve_inode(Inode::eposide(id.try_into().unwrap()))
-                                        .unwrap();
-                                    Some((id, path))
-                                } else {
-                                    None
-                                }
-                            }
-                            Taggables::File { id, name, .. } => {
-                                if name == expected_name {
-                                    let id = *id;
-                                    info!(id, "found file");
-                                    let path = self
-                                        .resolve_inode(Inode::file(id.try_into().unwrap()))
-                                        .unwrap();
-                                    Some((id, path))
-                                } else {
-                                    None
-                                }
-                            }
-                        });
-                        let (id, path) = match res {
-                            Some(id) => id,
-                            None => {
-                                info!("not found");
-                                reply.error(ENOENT);
-                                return;
-                            }
-                        };
-                        let ino = Inode::tagged(id);
-                        Some(symlink_attr(ino, path.as_os_str().len() as u64))
-                    }
-                };
+        };
+        if buffer.data.len() >= WRITE_BUFFER_SIZE {
+            self.write_pending(handle, buffer)?;
+        } else {
+            handle.buffer = Some(buffer);
+            // The size reported includes the buffer, see `find_file_attr`
+            self.invalidate_inode(ino);
+        }
+        Ok(u32::try_from(data.len()).unwrap())
+    }
 
-                match attr {
-                    Some(attr) => {
-                        reply.entry(&ONE_SEC, &attr, 0);
-                    }
-                    None => {
-                        reply.error(ENOENT);
-                    }
-                }
+    /// Empty file to write a new content to, on the same filesystem as the blobs so that it
+    /// can be moved among them.
+    fn new_pending(&self) -> Result<NamedTempFile, FsError> {
+        let dir = self.storage.join("tmp");
+        fs::create_dir_all(&dir)?;
+        Ok(NamedTempFile::new_in(&dir)?)
+    }
+
+    /// Copy the blob of `handle` aside for writing, unless it's already done. Blobs are shared
+    /// by every file with the same content, so they're never changed in place.
+    fn start_pending(&self, handle: &mut Handle) -> Result<(), FsError> {
+        if handle.pending.is_some() {
+            return Ok(());
+        }
+        let mut pending = self.new_pending()?;
+        if let Some(mut blob) = handle.blob.as_ref() {
+            blob.seek(SeekFrom::Start(0))?;
+            io::copy(&mut blob, &mut pending)?;
+        }
+        handle.pending = Some(pending);
+        Ok(())
+    }
+
+    /// Change the content of `ino` with `change` on a copy of its blob, and commit the copy
+    /// right away.
+    fn rewrite_content<F>(&mut self, ino: Inode, change: F) -> Result<(), FsError>
+    where
+        F: FnOnce(&fs::File) -> Result<(), FsError>,
+    {
+        let fh = self.open_handle(ino)?;
+        let res = {
+            let mut handles = self.handles.lock().unwrap();
+            let handle = handles.get_mut(&fh).unwrap();
+            self.start_pending(handle)
+                .and_then(|_| change(handle.pending.as_ref().unwrap().as_file()))
+        };
+        let res = res.and_then(|_| self.commit_handle(ino, fh));
+        self.handles.lock().unwrap().remove(&fh);
+        res
+    }
+
+    /// Write the buffered writes of `handle`, if any, to the copy of its content.
+    fn flush_buffer(&self, handle: &mut Handle) -> Result<(), FsError> {
+        match handle.buffer.take() {
+            Some(buffer) => self.write_pending(handle, buffer),
+            None => Ok(()),
+        }
+    }
+
+    /// Write `buffer` to the copy of the content of `handle`, and store the new size.
+    fn write_pending(&self, handle: &Handle, buffer: WriteBuffer) -> Result<(), FsError> {
+        // Writing past the end leaves a hole, which reads and hashes as zeros
+        handle
+            .pending
+            .as_ref()
+            .unwrap()
+            .as_file()
+            .write_all_at(&buffer.data, buffer.offset)?;
+        let info =
+            File::find(i32::try_from(buffer.ino.id()).unwrap(), &*self.conn()?).ok_or(ENOENT)?;
+        // The type is told by the first bytes, which come with the first write
+        if buffer.offset == 0 {
+            info.update_mime_type(sniff_mime_type(&buffer.data), &*self.conn()?)?;
+        }
+        // Report the new size, holes included, before the content is committed
+        let end = buffer.end();
+        if end > info.size.map_or(0, |size| u64::try_from(size).unwrap()) {
+            self.invalidate_inode(buffer.ino);
+            info.update_size(end, &*self.conn()?)?;
+        }
+        Ok(())
+    }
+
+    /// End of the writes of `ino` still gathered in a buffer, if any.
+    fn buffered_end(&self, ino: Inode) -> Option<u64> {
+        self.handles
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|handle| handle.buffer.as_ref())
+            .filter(|buffer| buffer.ino == ino)
+            .map(WriteBuffer::end)
+            .max()
+    }
+
+    /// Hash the content written through `fh` and move it to its place in the storage. The old
+    /// blob is removed once no file refers to it anymore. The handles are only locked to take
+    /// the copy and to put the blob back, not while hashing it, so the other handles aren't
+    /// held up by a large file. Requests on `ino` go to the same worker, so nothing else uses
+    /// `fh` in between.
+    fn commit_handle(&mut self, ino: Inode, fh: u64) -> Result<(), FsError> {
+        let conn = self.conn()?;
+        let pending = {
+            let mut handles = self.handles.lock().unwrap();
+            let handle = handles.get_mut(&fh).ok_or(EBADF)?;
+            self.flush_buffer(handle)?;
+            match handle.pending.take() {
+                Some(pending) => pending,
+                None => return Ok(()),
             }
+        };
+        let info = File::find(i32::try_from(ino.id()).unwrap(), &conn).ok_or(ENOENT)?;
+        let (hash, blob, stored) = self.store_pending(pending)?;
+        let size = blob.metadata()?.len();
+        if let Some(handle) = self.handles.lock().unwrap().get_mut(&fh) {
+            handle.blob = Some(blob);
+            handle.map = None;
         }
+        self.invalidate_inode(ino);
+        info.update_size(size, &conn)?;
+        if info.content_hash.as_ref() == Some(&hash) {
+            return Ok(());
+        }
+        info.update_content_hash(&hash, &conn)?;
+        drop(stored);
+        if let Some(old) = &info.content_hash {
+            self.remove_unused_blob(old, &conn);
+        }
+        Ok(())
     }
 
-    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Inode::from(ino)),skip(self, _req, ino, reply))]
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        match ino {
-            Self::ROOT_ID => reply.attr(&ONE_SEC, &ROOT_DIR_ATTR),
-            Self::COMIC_ID => reply.attr(&ONE_SEC, &SPECIAL_DIR_ATTRS[0]),
-            Self::TAGS_ID => reply.attr(&ONE_SEC, &SPECIAL_DIR_ATTRS[1]),
-            ino => {
-                let ino = Inode::from(ino);
+    /// Hash `pending` and move it to its place in the storage, unless a blob with the same
+    /// content is there already. Returns the hash, the stored blob and the storage lock, to be
+    /// held until a file refers to the blob so that `remove_unused_blob` doesn't remove it in
+    /// between.
+    fn store_pending(
+        &self,
+        mut pending: NamedTempFile,
+    ) -> Result<(String, fs::File, MutexGuard<'_, ()>), FsError> {
+        let mut hasher = Sha256::new();
+        pending.seek(SeekFrom::Start(0))?;
+        io::copy(&mut pending, &mut hasher)?;
+        let hash = encode_bytes(&hasher.finalize());
+        let stored = self.storage_lock.lock().unwrap();
+        let path = self.storage_path(&hash)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        let blob = if path.exists() {
+            // Another file has the same content already, the copy is dropped
+            let blob = self.open_blob(&hash)?;
+            let saved = pending.as_file().metadata()?.len();
+            debug!(%hash, saved, "content is deduplicated");
+            blob
+        } else {
+            pending.persist(&path).map_err(|err| err.error)?
+        };
+        Ok((hash, blob, stored))
+    }
+
+    /// Remove the blob `hash` once no file refers to it anymore. `conn` is the connection the
+    /// caller holds, so that it doesn't wait for a second one. The storage lock is held from
+    /// the count to the removal, so a file can't start referring to the blob in between.
+    fn remove_unused_blob(&self, hash: &str, conn: &SqliteConnection) {
+        let _stored = self.storage_lock.lock().unwrap();
+        if File::count_by_content_hash(hash, conn) == Some(0) {
+            let res = self
+                .storage_path(hash)
+                .and_then(|path| fs::remove_file(path).map_err(FsError::from));
+            if let Err(err) = res {
+                warn!(%err, "fail to remove unused blob");
+            }
+        }
+    }
+
+    /// Empty the file `ino` for `O_TRUNC`. The blob may be shared with other files, so it's
+    /// left as is and the file just stops referring to it.
+    fn truncate_file(&mut self, ino: Inode) -> Result<(), FsError> {
+        match ino.kind() {
+            InodeKind::File => {}
+            // Always empty, `echo > .reorder` truncates it before writing
+            InodeKind::Reorder => return Ok(()),
+            InodeKind::TagList | InodeKind::Cover => return Err(EPERM.into()),
+            _ => return Err(EISDIR.into()),
+        }
+        let conn = self.conn()?;
+        let info = File::find(i32::try_from(ino.id()).unwrap(), &conn).ok_or(ENOENT)?;
+        self.invalidate_inode(ino);
+        info.clear_content(&conn)?;
+        if let Some(hash) = &info.content_hash {
+            self.remove_unused_blob(hash, &conn);
+        }
+        Ok(())
+    }
+
+    /// Open `ino` with the flags given to `open`.
+    fn open_with_flags(&mut self, ino: Inode, flags: u32) -> Result<u64, FsError> {
+        let flags = OFlag::from_bits_truncate(i32::try_from(flags).unwrap_or(0));
+        if flags.contains(OFlag::O_TRUNC) {
+            self.truncate_file(ino)?;
+        }
+        self.open_handle(ino)
+    }
+
+    fn release_handle(&mut self, ino: Inode, fh: u64) -> Result<(), FsError> {
+        let res = self.commit_handle(ino, fh);
+        // Dropping the blob closes it
+        self.handles.lock().unwrap().remove(&fh);
+        res
+    }
+
+    /// Commit the content written through `fh` and sync its blob to disk, so it survives a crash
+    /// once `fsync` returns. `datasync` only syncs the content, like `fdatasync`.
+    fn sync_handle(&mut self, ino: Inode, fh: u64, datasync: bool) -> Result<(), FsError> {
+        self.commit_handle(ino, fh)?;
+        let handles = self.handles.lock().unwrap();
+        let handle = handles.get(&fh).ok_or(EBADF)?;
+        if let Some(blob) = &handle.blob {
+            let res = if datasync {
+                blob.sync_data()
+            } else {
+                blob.sync_all()
+            };
+            res?;
+        }
+        Ok(())
+    }
+
+    /// Write the buffered writes and sync the copy being written through `fh` on every
+    /// `close`. It's only hashed and moved into the storage by `fsync` or once the last
+    /// descriptor is released.
+    fn flush_handle(&self, fh: u64) -> Result<(), FsError> {
+        let mut handles = self.handles.lock().unwrap();
+        let handle = handles.get_mut(&fh).ok_or(EBADF)?;
+        self.flush_buffer(handle)?;
+        if let Some(pending) = &handle.pending {
+            pending.as_file().sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn find_file_attr(&self, info: &File) -> Result<FileAttr, FsError> {
+        self.linked_file_attr(info, self.file_link_count(info))
+    }
+
+    /// `find_file_attr` with the link count already known.
+    fn linked_file_attr(&self, info: &File, nlink: u32) -> Result<FileAttr, FsError> {
+        // Only files written before the size was stored need the blob
+        let mut attr = match &info.content_hash {
+            Some(hash) if info.size.is_none() => {
+                let meta = fs::metadata(self.storage_path(hash)?)?;
+                self.convert_meta_to_attr(info, meta, nlink)
+            }
+            _ => self.file_attr(info, nlink),
+        };
+        // Buffered writes aren't in the stored size yet
+        if let Some(end) = self.buffered_end(Inode::file(info.id)) {
+            if end > attr.size {
+                attr.size = end;
+                attr.blocks = (end + BLOCK_SIZE - 1) / BLOCK_SIZE;
+            }
+        }
+        Ok(attr)
+    }
+
+    /// Content of the `.tags` file `ino`, the names of the tags of its directory one per line.
+    fn tag_list_content(&self, ino: Inode) -> Result<Vec<u8>, FsError> {
+        let owner = ino.tag_list_owner();
+        let kind = match owner.kind() {
+            InodeKind::Comic => TaggableKind::Comic,
+            InodeKind::Eposide => TaggableKind::Eposide,
+            _ => return Err(ENOENT.into()),
+        };
+        let mut content = String::new();
+        for tag in Taggable::tags_for(i32::try_from(owner.id()).unwrap(), kind, &*self.conn()?) {
+            content.push_str(&tag.name);
+            content.push('\n');
+        }
+        Ok(content.into_bytes())
+    }
+
+    /// The `.tags` file shares the times of its directory and is never writable.
+    fn tag_list_attr(&self, ino: Inode) -> Result<FileAttr, FsError> {
+        let size = u64::try_from(self.tag_list_content(ino)?.len()).unwrap();
+        let owner = self.attr(ino.tag_list_owner())?;
+        Ok(FileAttr {
+            ino: ino.0,
+            size,
+            blocks: (size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            ..owner
+        })
+    }
+
+    /// JSON content of `/.comicfs/stats`. Counting goes through every table, so the content is
+    /// reused for `STATS_REFRESH`.
+    fn stats_content(&self) -> Result<Vec<u8>, FsError> {
+        let now = self.clock.now();
+        let mut cached = self.stats.lock().unwrap();
+        if let Some((made, content)) = &*cached {
+            if matches!(now.duration_since(*made), Ok(age) if age < STATS_REFRESH) {
+                return Ok(content.clone());
+            }
+        }
+        let conn = self.conn()?;
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let lookups = hits + self.cache_misses.load(Ordering::Relaxed);
+        let stats = Stats {
+            comics: Comic::count(&conn).ok_or(EIO)?,
+            episodes: Episode::count(&conn).ok_or(EIO)?,
+            files: File::count(&conn).ok_or(EIO)?,
+            tags: Tag::count(&conn).ok_or(EIO)?,
+            blob_bytes: File::blob_bytes(&conn).ok_or(EIO)?,
+            cache_hit_rate: match lookups {
+                0 => None,
+                lookups => Some(hits as f64 / lookups as f64),
+            },
+            uptime_secs: now
+                .duration_since(self.mounted_at)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let mut content = serde_json::to_vec_pretty(&stats).map_err(|_| EIO)?;
+        content.push(b'\n');
+        *cached = Some((now, content.clone()));
+        Ok(content)
+    }
+
+    /// `/.comicfs/stats` is read-only and has the time of the mount.
+    fn stats_attr(&self) -> Result<FileAttr, FsError> {
+        let size = u64::try_from(self.stats_content()?.len()).unwrap();
+        Ok(FileAttr {
+            ino: Self::STATS_ID,
+            size,
+            blocks: (size + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            kind: FileType::RegularFile,
+            perm: 0o444,
+            nlink: 1,
+            ..self.directory_attr(Inode::from(Self::STATS_ID), self.mounted_at)
+        })
+    }
+
+    /// File shown by the `cover` file `ino`, `ENOENT` for an episode without files.
+    fn cover_file(&self, ino: Inode) -> Result<File, FsError> {
+        let id = i32::try_from(ino.cover_owner().id()).unwrap();
+        let episode = Episode::find(id, &*self.conn()?).ok_or(ENOENT)?;
+        episode
+            .cover_file(&*self.conn()?)
+            .ok_or(FsError::Errno(ENOENT))
+    }
+
+    /// The `cover` file takes the attributes of the file it shows, but is never writable.
+    fn cover_attr(&self, ino: Inode) -> Result<FileAttr, FsError> {
+        let attr = self.find_file_attr(&self.cover_file(ino)?)?;
+        Ok(FileAttr {
+            ino: ino.0,
+            perm: 0o444,
+            nlink: 1,
+            ..attr
+        })
+    }
+
+    /// The `.reorder` file is always empty and shares the times of its episode.
+    fn reorder_attr(&self, ino: Inode) -> Result<FileAttr, FsError> {
+        let owner = ino.reorder_owner();
+        if owner.kind() != InodeKind::Eposide {
+            return Err(ENOENT.into());
+        }
+        Ok(FileAttr {
+            ino: ino.0,
+            size: 0,
+            blocks: 0,
+            kind: FileType::RegularFile,
+            perm: 0o200,
+            nlink: 1,
+            ..self.attr(owner)?
+        })
+    }
+
+    /// Renumber the files of the episode `ino` for a write to its `.reorder` file.
+    fn renumber_files(&self, ino: Inode) -> Result<(), FsError> {
+        if ino.kind() != InodeKind::Eposide {
+            return Err(ENOENT.into());
+        }
+        let renamed = File::renumber(i32::try_from(ino.id()).unwrap(), &*self.conn()?)?;
+        for (info, name) in renamed {
+            self.invalidate_lookup(ino, OsStr::new(&info.name));
+            self.invalidate_lookup(ino, OsStr::new(&name));
+            self.invalidate_inode(Inode::file(info.id));
+        }
+        self.invalidate_inode(ino.cover());
+        Ok(())
+    }
+
+    fn find_comic_by_inode(&self, inode: Inode) -> Option<FileAttr> {
+        Comic::find(i32::try_from(inode.id()).unwrap(), &*self.conn().ok()?).map(|info| {
+            self.directory_attr(Inode::comic(info.id), naive_to_systemtime(info.created_at))
+        })
+    }
+
+    fn find_eposide_by_inode(&self, inode: Inode) -> Option<FileAttr> {
+        let res = Episode::find(i32::try_from(inode.id()).unwrap(), &*self.conn().ok()?);
+        res.map(|info| {
+            self.directory_attr(
+                Inode::eposide(info.id),
+                naive_to_systemtime(info.created_at),
+            )
+        })
+    }
+
+    fn find_comic_by_name(&self, name: &str) -> Option<FileAttr> {
+        Comic::find_by_name(name, self.case_insensitive, &*self.conn().ok()?).map(|info| {
+            self.directory_attr(Inode::comic(info.id), naive_to_systemtime(info.created_at))
+        })
+    }
+
+    fn find_comic_eposide_by_name(&self, id: u64, name: &str) -> Option<FileAttr> {
+        let comic_id = i32::try_from(id).unwrap();
+        Episode::find_by_comic_and_name(comic_id, name, self.case_insensitive, &*self.conn().ok()?)
+            .map(|info| {
+                self.directory_attr(
+                    Inode::eposide(info.id),
+                    naive_to_systemtime(info.created_at),
+                )
+            })
+    }
+
+    fn find_tag_by_name(&self, name: &str) -> Option<FileAttr> {
+        Tag::find_by_name(name, &*self.conn().ok()?).map(|info| {
+            self.directory_attr(Inode::tag(info.id), naive_to_systemtime(info.created_at))
+        })
+    }
+
+    fn attr(&self, ino: Inode) -> Result<FileAttr, FsError> {
+        match ino.0 {
+            Self::ROOT_ID
+            | Self::COMIC_ID
+            | Self::TAGS_ID
+            | Self::SEARCH_ID
+            | Self::RECENT_ID
+            | Self::CONTROL_ID => Ok(self.directory_attr(ino, self.mounted_at)),
+            Self::TRASH_ID if self.trash => Ok(self.directory_attr(ino, self.mounted_at)),
+            Self::STATS_ID => self.stats_attr(),
+            _ => {
                 let kind = ino.kind();
                 let attr = match kind {
                     InodeKind::Comic => self.find_comic_by_inode(ino),
                     InodeKind::Eposide => self.find_eposide_by_inode(ino),
                     InodeKind::File => {
-                        let info = File::find(i32::try_from(ino.id()).unwrap(), &self.conn);
-                        info.and_then(|info| {
-                            let id = info.id;
-                            if info.content_hash == "" {
-                                return Some(file_attr(Inode::file(id)));
-                            }
-                            let path = generate_storage_path(&info.content_hash);
-                            let meta = fs::metadata(&path).ok()?;
-                            Some(convert_meta_to_attr(Inode::file(id).0, meta))
-                        })
+                        let info = File::find(i32::try_from(ino.id()).unwrap(), &*self.conn()?)
+                            .ok_or(ENOENT)?;
+                        return self.find_file_attr(&info);
                     }
                     InodeKind::Tag => {
-                        let info = Tag::find(i32::try_from(ino.id()).unwrap(), &self.conn);
+                        let info = Tag::find(i32::try_from(ino.id()).unwrap(), &*self.conn()?);
                         info.map(|info| {
                             let ino = Inode::tag(info.id);
-                            directory_attr(ino)
-                        })
-                    }
-                    InodeKind::Tagged => {
-                        let info = Taggable::find(i32::try_from(ino.id()).unwrap(), &self.conn);
-                        info!(?info);
-                        info.map(|info| {
-                            let target = match info.taggable_type.as_str() {
-                                "comic" => Inode::comic(info.taggable_id),
-                                "eposide" => Inode::eposide(info.taggable_id),
-                                "file" => Inode::file(info.taggable_id),
-                                _ => unreachable!(),
-                            };
-                            let path = self.resolve_inode(target).unwrap();
-                            let len = path.as_os_str().len();
-                            assert_eq!(len, path.as_os_str().as_bytes().len());
-                            let attr = symlink_attr(ino, len as u64);
-                            info!(?attr);
-                            attr
+                            self.directory_attr(ino, naive_to_systemtime(info.created_at))
                         })
                     }
-                    InodeKind::Special => unreachable!(),
-                };
-                match attr {
-                    Some(attr) => {
-                        reply.attr(&ONE_SEC, &attr);
+                    // Gone once the taggable or its target is removed
+                    InodeKind::Tagged | InodeKind::SearchResult => {
+                        let path = self.read_link(ino)?;
+                        Some(self.symlink_attr(ino, path.as_os_str().len() as u64))
                     }
-                    None => {
-                        reply.error(ENOENT);
+                    InodeKind::TagList => return self.tag_list_attr(ino),
+                    InodeKind::Cover => return self.cover_attr(ino),
+                    InodeKind::Reorder => return self.reorder_attr(ino),
+                    InodeKind::Search => {
+                        self.search_term(ino)?;
+                        Some(self.directory_attr(ino, SystemTime::UNIX_EPOCH))
                     }
-                }
+                    // Not one of the special directories
+                    InodeKind::Special => None,
+                };
+                attr.ok_or(FsError::Errno(ENOENT))
             }
         }
     }
 
-    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, _fh, reply))]
-    fn readdir(
+    fn set_attr(
         &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        match ino {
-            Self::ROOT_ID => {
-                if offset == 0 {
-                    reply.add(1, 1, FileType::Directory, ".");
-                    reply.add(1, 2, FileType::Directory, "..");
-                    reply.add(2, 3, FileType::Directory, "comics");
-                    reply.add(3, 4, FileType::Directory, "tags");
-                }
-            }
-            Self::COMIC_ID => {
-                use schema::comics::dsl;
-                if offset == 0 {
-                    let comics = dsl::comics.load::<Comic>(&self.conn);
-                    if let Ok(comics) = comics {
-                        for (i, comic) in comics.iter().enumerate() {
-                            let ino = Inode::comic(comic.id);
-                            reply.add(
-                                ino.0,
-                                (i + 1).try_into().unwrap(),
-                                FileType::Directory,
-                                &comic.name,
-                            );
-                        }
+        ino: Inode,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<FileAttr, FsError> {
+        match ino.kind() {
+            InodeKind::File => {}
+            InodeKind::Reorder => return self.attr(ino),
+            InodeKind::TagList | InodeKind::Cover => return Err(EPERM.into()),
+            _ => return Err(ENOSYS.into()),
+        }
+        let info = File::find(i32::try_from(ino.id()).unwrap(), &*self.conn()?).ok_or(ENOENT)?;
+        self.invalidate_inode(ino);
+        info.update_times(
+            atime.map(systemtime_to_naive),
+            mtime.map(systemtime_to_naive),
+            &*self.conn()?,
+        )?;
+        if size == Some(0) && info.content_hash.is_some() {
+            return self.truncate_file(ino).and_then(|_| self.attr(ino));
+        }
+        if let (Some(size), Some(_)) = (size, &info.content_hash) {
+            self.rewrite_content(ino, |file| file.set_len(size).map_err(FsError::from))?;
+        }
+        self.attr(ino)
+    }
+
+    /// Preallocate the content of `ino` for the range given by `fallocate`. A file without
+    /// content has no blob yet, its first write goes to a new copy anyway.
+    fn allocate(&mut self, ino: Inode, offset: i64, length: i64, mode: i32) -> Result<(), FsError> {
+        match ino.kind() {
+            InodeKind::File => {}
+            InodeKind::TagList | InodeKind::Cover | InodeKind::Reorder => return Err(EPERM.into()),
+            _ => return Err(ENOSYS.into()),
+        }
+        let mode = FallocateFlags::from_bits(mode).ok_or(EOPNOTSUPP)?;
+        if mode.contains(FallocateFlags::FALLOC_FL_PUNCH_HOLE) {
+            return Err(EOPNOTSUPP.into());
+        }
+        let info = File::find(i32::try_from(ino.id()).unwrap(), &*self.conn()?).ok_or(ENOENT)?;
+        if info.content_hash.is_none() {
+            return Ok(());
+        }
+        self.rewrite_content(ino, |file| {
+            fallocate(file.as_raw_fd(), mode, offset, length).map_err(FsError::from)
+        })
+    }
+
+    fn make_dir(&mut self, parent: Inode, name: &OsStr) -> Result<FileAttr, FsError> {
+        self.invalidate_lookup(parent, name);
+        match parent.kind() {
+            InodeKind::Special => match parent.0 {
+                Self::ROOT_ID | Self::SEARCH_ID | Self::RECENT_ID => Err(EPERM.into()),
+                Self::COMIC_ID => {
+                    let name = name.to_str().ok_or(EINVAL)?;
+                    // The unique index tells names differing in case apart
+                    if self.case_insensitive
+                        && Comic::find_by_name(name, true, &*self.conn()?).is_some()
+                    {
+                        return Err(EEXIST.into());
                     }
+                    let comic = models::NewComic { name };
+                    let conn = self.conn()?;
+                    let comic = conn.transaction::<_, diesel::result::Error, _>(|| {
+                        use schema::comics::dsl;
+
+                        diesel::insert_into(dsl::comics)
+                            .values(&comic)
+                            .execute(&conn)?;
+
+                        dsl::comics
+                            .order(dsl::id.desc())
+                            .first::<models::Comic>(&conn)
+                    })?;
+                    let ino = Inode::comic(comic.id);
+                    Ok(self.directory_attr(ino, naive_to_systemtime(comic.created_at)))
                 }
-            }
-            Self::TAGS_ID => {
-                if offset == 0 {
-                    if let Some(tags) = Tag::list(&self.conn) {
-                        for (i, tag) in tags.iter().enumerate() {
-                            let ino = Inode::tag(tag.id);
-                            reply.add(
-                                ino.0,
-                                (i + 1).try_into().unwrap(),
-                                FileType::Directory,
-                                &tag.name,
-                            );
-                        }
-                    }
+                Self::TAGS_ID => {
+                    let name = name.to_str().ok_or(EINVAL)?;
+                    let tag = NewTag { name };
+                    let tag = tag.insert(&*self.conn()?)?;
+                    let ino = Inode::tag(tag.id);
+                    Ok(self.directory_attr(ino, naive_to_systemtime(tag.created_at)))
                 }
-            }
-            ino => {
-                let ino = Inode::from(ino);
-                let kind = ino.kind();
-                match kind {
-                    InodeKind::Comic => {
-                        use schema::eposides::dsl;
-                        if offset == 0 {
-                            let eposides = dsl::eposides
-                                .filter(dsl::comic_id.eq(i32::try_from(ino.id()).unwrap()))
-                                .load::<Episode>(&self.conn);
-                            if let Ok(eposides) = eposides {
-                                for (i, eposide) in eposides.iter().enumerate() {
-                                    let ino = Inode::eposide(eposide.id);
-                                    reply.add(
-                                        ino.0,
-                                        (i + 1).try_into().unwrap(),
-                                        FileType::Directory,
-                                        &eposide.name,
-                                    );
-                                }
-                            }
-                        }
-                    }
-                    InodeKind::Eposide => {
-                        use schema::files::dsl;
-                        if offset == 0 {
-                            let files = dsl::files
-                                .filter(dsl::eposid_id.eq(i32::try_from(ino.id()).unwrap()))
-                                .load::<File>(&self.conn);
-                            if let Ok(files) = files {
-                                for (i, file) in files.iter().enumerate() {
-                                    let ino = Inode::file(file.id);
-                                    reply.add(
-                                        ino.0,
-                                        (i + 1).try_into().unwrap(),
-                                        FileType::RegularFile,
-                                        &file.name,
-                                    );
-                                }
-                            }
-                        }
-                    }
-                    InodeKind::Tag if offset == 0 => {
-                        let taggables =
-                            Taggables::taggables(ino.id().try_into().unwrap(), &self.conn);
-                        for (i, taggable) in taggables.iter().enumerate() {
-                            match taggable {
-                                Taggables::Comic { id, name, .. } => {
-                                    let ino = Inode::tagged(*id);
-                                    reply.add(
-                                        ino.0,
-                                        (i + 1).try_into().unwrap(),
-                                        FileType::Symlink,
-                                        &name,
-                                    );
-                                }
-                                Taggables::Episode { id, name, .. } => {
-                                    let ino = Inode::tagged(*id);
-                                    reply.add(
-                                        ino.0,
-                                        (i + 1).try_into().unwrap(),
-                                        FileType::Symlink,
-                                        &name,
-                                    );
-                                }
-                                Taggables::File { id, name, .. } => {
-                                    let ino = Inode::tagged(*id);
-                                    reply.add(
-                                        ino.0,
-                                        (i + 1).try_into().unwrap(),
-                                        FileType::Symlink,
-                                        &name,
-                                    );
-                                }
-                            }
-                        }
-                    }
-                    InodeKind::Tag => (),
-                    InodeKind::File | InodeKind::Special | InodeKind::Tagged => unreachable!(),
+                _ => Err(EPERM.into()),
+            },
+            InodeKind::Comic => {
+                if name == TAG_LIST_NAME {
+                    return Err(EEXIST.into());
                 }
+                let name = name.to_str().ok_or(EINVAL)?;
+                let comic_id = i32::try_from(parent.id()).unwrap();
+                if self.case_insensitive
+                    && Episode::find_by_comic_and_name(comic_id, name, true, &*self.conn()?)
+                        .is_some()
+                {
+                    return Err(EEXIST.into());
+                }
+                let eposide = models::NewEposide { name, comic_id };
+                let conn = self.conn()?;
+                let eposide = conn.transaction::<_, diesel::result::Error, _>(|| {
+                    use schema::eposides::dsl;
+
+                    diesel::insert_into(dsl::eposides)
+                        .values(&eposide)
+                        .execute(&conn)?;
+
+                    dsl::eposides
+                        .order(dsl::id.desc())
+                        .first::<models::Episode>(&conn)
+                })?;
+                let ino = Inode::eposide(eposide.id);
+                Ok(self.directory_attr(ino, naive_to_systemtime(eposide.created_at)))
             }
+            InodeKind::Eposide | InodeKind::Tag | InodeKind::Search => Err(EPERM.into()),
+            InodeKind::File
+            | InodeKind::Tagged
+            | InodeKind::TagList
+            | InodeKind::Cover
+            | InodeKind::Reorder
+            | InodeKind::SearchResult => Err(ENOTDIR.into()),
         }
-        reply.ok();
     }
 
-    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, _fh, reply))]
-    fn read(
+    fn create_file(&mut self, parent: Inode, name: &OsStr) -> Result<FileAttr, FsError> {
+        if parent.kind() != InodeKind::Eposide {
+            return Err(EPERM.into());
+        }
+        if is_reserved(name) {
+            return Err(EEXIST.into());
+        }
+        self.invalidate_lookup(parent, name);
+        self.invalidate_inode(parent.cover());
+        let name = name.to_str().ok_or(EINVAL)?;
+        let eposid_id = i32::try_from(parent.id()).unwrap();
+        if self.case_insensitive
+            && File::find_by_eposide_and_name(eposid_id, name, true, &*self.conn()?).is_some()
+        {
+            return Err(EEXIST.into());
+        }
+        let value = models::NewFile { name, eposid_id };
+        let file = value.insert(&*self.conn()?)?;
+        Ok(self.file_attr(&file, self.file_link_count(&file)))
+    }
+
+    /// Create the file `name` in `parent` and open it. Without `O_EXCL`, a file that already
+    /// exists is opened instead, and emptied for `O_TRUNC`.
+    fn create_or_open(
         &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        reply: ReplyData,
-    ) {
-        let ino = Inode::from(ino);
-        let kind = ino.kind();
-        if kind != InodeKind::File {
-            reply.error(EISDIR);
-            return;
+        parent: Inode,
+        name: &OsStr,
+        flags: u32,
+    ) -> Result<(FileAttr, u64), FsError> {
+        match self.create_file(parent, name) {
+            Ok(attr) => Ok((attr, self.insert_handle(Handle::default()))),
+            Err(err)
+                if err.errno() == EEXIST
+                    && !OFlag::from_bits_truncate(i32::try_from(flags).unwrap_or(0))
+                        .contains(OFlag::O_EXCL) =>
+            {
+                let ino = Inode::from(self.find_entry(parent.0, name)?.ino);
+                let fh = self.open_with_flags(ino, flags)?;
+                Ok((self.attr(ino)?, fh))
+            }
+            Err(err) => Err(err),
         }
-        let path = self.inode_to_storage(ino);
-        let path = match path {
-            Some(path) => path,
-            None => {
-                reply.error(ENOENT);
-                return;
+    }
+
+    /// Move the file `name` of the episode `parent` to the trash for `unlink`, or remove the file
+    /// `name` of `/trash` for good along with its blob once no other file refers to it. Only
+    /// with the `trash` mount option, nothing can be removed otherwise.
+    fn remove_entry(&mut self, parent: Inode, name: &OsStr) -> Result<(), FsError> {
+        if !self.trash {
+            return Err(ENOSYS.into());
+        }
+        let conn = self.conn()?;
+        let ino = if parent.0 == Self::TRASH_ID {
+            let ino = Self::trash_entry(name, &conn)?;
+            if ino.kind() != InodeKind::File {
+                return Err(EISDIR.into());
             }
-        };
-        let file = fs::File::open(&path);
-        match file {
-            Ok(file) => {
-                let mut buf = vec![0; usize::try_from(size).unwrap()];
-                match file.read_at(&mut buf, u64::try_from(offset).unwrap()) {
-                    Ok(size) => {
-                        reply.data(&buf[0..size]);
-                    }
-                    Err(_) => {
-                        reply.error(EIO);
-                    }
-                }
+            let info = File::find(i32::try_from(ino.id()).unwrap(), &conn).ok_or(ENOENT)?;
+            info.delete(&conn)?;
+            if let Some(hash) = &info.content_hash {
+                self.remove_unused_blob(hash, &conn);
             }
-            Err(_) => {
-                // TODO: decide to return error or empty content
-                reply.data(&[]);
+            ino
+        } else {
+            if parent.kind() != InodeKind::Eposide || is_reserved(name) {
+                return Err(EPERM.into());
             }
+            let info = File::find_by_eposide_and_name(
+                i32::try_from(parent.id()).unwrap(),
+                name.to_str().ok_or(EINVAL)?,
+                self.case_insensitive,
+                &conn,
+            )
+            .ok_or(ENOENT)?;
+            info.trash(systemtime_to_naive(self.clock.now()), &conn)?;
+            self.invalidate_inode(parent.cover());
+            Inode::file(info.id)
+        };
+        self.invalidate_lookup(parent, name);
+        self.invalidate_inode(ino);
+        Ok(())
+    }
+
+    /// Move the empty comic or episode `name` of `parent` to the trash for `rmdir`, or remove
+    /// the comic or episode `name` of `/trash` for good along with what's left in it. Like
+    /// `remove_entry`, only with the `trash` mount option.
+    fn remove_dir(&mut self, parent: Inode, name: &OsStr) -> Result<(), FsError> {
+        if !self.trash {
+            return Err(ENOSYS.into());
+        }
+        let conn = self.conn()?;
+        let deleted_at = systemtime_to_naive(self.clock.now());
+        let ino = if parent.0 == Self::TRASH_ID {
+            let ino = Self::trash_entry(name, &conn)?;
+            let id = i32::try_from(ino.id()).unwrap();
+            let hashes = match ino.kind() {
+                InodeKind::Comic => Comic::find(id, &conn).ok_or(ENOENT)?.delete(&conn)?,
+                InodeKind::Eposide => Episode::find(id, &conn).ok_or(ENOENT)?.delete(&conn)?,
+                _ => return Err(ENOTDIR.into()),
+            };
+            for hash in &hashes {
+                self.remove_unused_blob(hash, &conn);
+            }
+            ino
+        } else if parent.0 == Self::COMIC_ID {
+            let name = name.to_str().ok_or(EINVAL)?;
+            let info = Comic::find_by_name(name, self.case_insensitive, &conn).ok_or(ENOENT)?;
+            if info.episode_count(&conn).ok_or(EIO)? > 0 {
+                return Err(ENOTEMPTY.into());
+            }
+            info.trash(deleted_at, &conn)?;
+            Inode::comic(info.id)
+        } else if parent.kind() == InodeKind::Comic {
+            if name == TAG_LIST_NAME {
+                return Err(ENOTDIR.into());
+            }
+            let info = Episode::find_by_comic_and_name(
+                i32::try_from(parent.id()).unwrap(),
+                name.to_str().ok_or(EINVAL)?,
+                self.case_insensitive,
+                &conn,
+            )
+            .ok_or(ENOENT)?;
+            if info.file_count(&conn).ok_or(EIO)? > 0 {
+                return Err(ENOTEMPTY.into());
+            }
+            info.trash(deleted_at, &conn)?;
+            Inode::eposide(info.id)
+        } else {
+            return Err(EPERM.into());
+        };
+        self.invalidate_lookup(parent, name);
+        self.invalidate_inode(ino);
+        Ok(())
+    }
+
+    /// Take the entry `name` of `/trash` out of it to `newparent` as `newname`: a file goes to
+    /// an episode, an episode to a comic and a comic to `/comics`, which have to be live. A name
+    /// already taken fails with `EEXIST`, like `rename_entry`.
+    fn restore_entry(
+        &mut self,
+        name: &OsStr,
+        newparent: Inode,
+        newname: &OsStr,
+    ) -> Result<(), FsError> {
+        let conn = self.conn()?;
+        let ino = Self::trash_entry(name, &conn)?;
+        let id = i32::try_from(ino.id()).unwrap();
+        let newname = newname.to_str().ok_or(EINVAL)?;
+        let is_live = |deleted_at: Option<NaiveDateTime>| deleted_at.is_none();
+        match (ino.kind(), newparent.kind()) {
+            (InodeKind::File, InodeKind::Eposide) => {
+                if is_reserved(OsStr::new(newname)) {
+                    return Err(EEXIST.into());
+                }
+                let newparent_id = i32::try_from(newparent.id()).unwrap();
+                Episode::find(newparent_id, &conn)
+                    .filter(|episode| is_live(episode.deleted_at))
+                    .ok_or(ENOENT)?;
+                // Changing only the case of the name is fine
+                if self.case_insensitive
+                    && File::find_by_eposide_and_name(newparent_id, newname, true, &conn).is_some()
+                {
+                    return Err(EEXIST.into());
+                }
+                let info = File::find(id, &conn).ok_or(ENOENT)?;
+                info.restore(newparent_id, newname, &conn)?;
+                self.invalidate_inode(newparent.cover());
+            }
+            (InodeKind::Eposide, InodeKind::Comic) => {
+                if newname == TAG_LIST_NAME {
+                    return Err(EEXIST.into());
+                }
+                let newparent_id = i32::try_from(newparent.id()).unwrap();
+                Comic::find(newparent_id, &conn)
+                    .filter(|comic| is_live(comic.deleted_at))
+                    .ok_or(ENOENT)?;
+                if self.case_insensitive
+                    && Episode::find_by_comic_and_name(newparent_id, newname, true, &conn).is_some()
+                {
+                    return Err(EEXIST.into());
+                }
+                let info = Episode::find(id, &conn).ok_or(ENOENT)?;
+                info.restore(newparent_id, newname, &conn)?;
+            }
+            (InodeKind::Comic, InodeKind::Special) if newparent.0 == Self::COMIC_ID => {
+                if self.case_insensitive && Comic::find_by_name(newname, true, &conn).is_some() {
+                    return Err(EEXIST.into());
+                }
+                let info = Comic::find(id, &conn).ok_or(ENOENT)?;
+                info.restore(newname, &conn)?;
+            }
+            _ => return Err(EPERM.into()),
+        }
+        self.invalidate_lookup(Inode::from(Self::TRASH_ID), name);
+        self.invalidate_lookup(newparent, OsStr::new(newname));
+        self.invalidate_inode(ino);
+        Ok(())
+    }
+
+    /// Move the file `name` of the episode `parent` to the episode `newparent` as `newname`,
+    /// which may be of another comic. Its content stays the same. A file already there isn't
+    /// replaced, that fails with `EEXIST`, so `RENAME_NOREPLACE` changes nothing.
+    /// `RENAME_EXCHANGE` swaps the two files instead, see `exchange_entries`. Anything can be
+    /// moved out of `/trash`, see `restore_entry`.
+    fn rename_entry(
+        &mut self,
+        parent: Inode,
+        name: &OsStr,
+        newparent: Inode,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), FsError> {
+        if flags == libc::RENAME_EXCHANGE {
+            return self.exchange_entries(parent, name, newparent, newname);
+        }
+        if flags != 0 && flags != libc::RENAME_NOREPLACE {
+            return Err(EINVAL.into());
+        }
+        if parent.0 == Self::TRASH_ID {
+            return self.restore_entry(name, newparent, newname);
+        }
+        if parent.kind() != InodeKind::Eposide || newparent.kind() != InodeKind::Eposide {
+            return Err(EPERM.into());
+        }
+        if is_reserved(name) {
+            return Err(EPERM.into());
+        }
+        if is_reserved(newname) {
+            return Err(EEXIST.into());
+        }
+        let name = name.to_str().ok_or(EINVAL)?;
+        let newname = newname.to_str().ok_or(EINVAL)?;
+        let parent_id = i32::try_from(parent.id()).unwrap();
+        let newparent_id = i32::try_from(newparent.id()).unwrap();
+        let info =
+            File::find_by_eposide_and_name(parent_id, name, self.case_insensitive, &*self.conn()?)
+                .ok_or(ENOENT)?;
+        if parent == newparent && name == newname {
+            return Ok(());
+        }
+        // Changing only the case of the name is fine
+        if self.case_insensitive {
+            let other = File::find_by_eposide_and_name(newparent_id, newname, true, &*self.conn()?);
+            if matches!(other, Some(other) if other.id != info.id) {
+                return Err(EEXIST.into());
+            }
+        }
+        info.move_to(newparent_id, newname, &*self.conn()?)?;
+        self.invalidate_lookup(parent, OsStr::new(name));
+        self.invalidate_lookup(newparent, OsStr::new(newname));
+        self.invalidate_inode(Inode::file(info.id));
+        self.invalidate_inode(parent.cover());
+        self.invalidate_inode(newparent.cover());
+        Ok(())
+    }
+
+    /// Swap the files `name` of the episode `parent` and `newname` of the episode `newparent`,
+    /// both of which have to exist. Only files can be exchanged, anything else fails with
+    /// `EINVAL`.
+    fn exchange_entries(
+        &mut self,
+        parent: Inode,
+        name: &OsStr,
+        newparent: Inode,
+        newname: &OsStr,
+    ) -> Result<(), FsError> {
+        if parent.kind() != InodeKind::Eposide || newparent.kind() != InodeKind::Eposide {
+            return Err(EINVAL.into());
+        }
+        if is_reserved(name) || is_reserved(newname) {
+            return Err(EINVAL.into());
+        }
+        let name = name.to_str().ok_or(EINVAL)?;
+        let newname = newname.to_str().ok_or(EINVAL)?;
+        let conn = self.conn()?;
+        let find = |parent: Inode, name: &str| {
+            let parent_id = i32::try_from(parent.id()).unwrap();
+            File::find_by_eposide_and_name(parent_id, name, self.case_insensitive, &conn)
+                .ok_or(ENOENT)
+        };
+        let info = find(parent, name)?;
+        let other = find(newparent, newname)?;
+        info.exchange(&other, &conn)?;
+        drop(conn);
+        self.invalidate_lookup(parent, OsStr::new(name));
+        self.invalidate_lookup(newparent, OsStr::new(newname));
+        self.invalidate_inode(Inode::file(info.id));
+        self.invalidate_inode(Inode::file(other.id));
+        self.invalidate_inode(parent.cover());
+        self.invalidate_inode(newparent.cover());
+        Ok(())
+    }
+
+    /// Tag the comic, episode or file `ino` with `tag_ino`. Anything else can't be tagged.
+    fn tag_inode(&self, tag_ino: Inode, ino: Inode) -> Result<(Taggable, TaggableKind), FsError> {
+        if tag_ino.kind() != InodeKind::Tag {
+            return Err(EPERM.into());
+        }
+        let kind = match ino.kind() {
+            InodeKind::Comic => TaggableKind::Comic,
+            InodeKind::Eposide => TaggableKind::Eposide,
+            InodeKind::File => TaggableKind::File,
+            InodeKind::Special
+            | InodeKind::Tag
+            | InodeKind::Tagged
+            | InodeKind::TagList
+            | InodeKind::Cover
+            | InodeKind::Reorder
+            | InodeKind::Search
+            | InodeKind::SearchResult => return Err(EPERM.into()),
+        };
+        let info = Taggable::link(
+            tag_ino.id().try_into().unwrap(),
+            ino.id().try_into().unwrap(),
+            kind,
+            &*self.conn()?,
+        )?;
+        if kind != TaggableKind::File {
+            self.invalidate_inode(ino.tag_list());
+        }
+        Ok((info, kind))
+    }
+
+    /// Tag the comic `ino` and every episode of it with `tag_ino`, in one transaction so that
+    /// either all of them are tagged or none. Episodes already having the tag are skipped.
+    fn tag_comic_with_episodes(&self, tag_ino: Inode, ino: Inode) -> Result<Taggable, FsError> {
+        let tag_id = i32::try_from(tag_ino.id()).unwrap();
+        let comic_id = i32::try_from(ino.id()).unwrap();
+        let conn = self.conn()?;
+        let (info, episodes) = conn.transaction::<_, diesel::result::Error, _>(|| {
+            let episodes = Episode::list_for_comic(comic_id, &conn)?;
+            for episode in &episodes {
+                let tags = Taggable::tags_for(episode.id, TaggableKind::Eposide, &conn);
+                if !tags.iter().any(|tag| tag.id == tag_id) {
+                    Taggable::link(tag_id, episode.id, TaggableKind::Eposide, &conn)?;
+                }
+            }
+            let info = Taggable::link(tag_id, comic_id, TaggableKind::Comic, &conn)?;
+            Ok((info, episodes))
+        })?;
+        self.invalidate_inode(ino.tag_list());
+        for episode in &episodes {
+            self.invalidate_inode(Inode::eposide(episode.id).tag_list());
+        }
+        Ok(info)
+    }
+
+    fn link_entry(
+        &mut self,
+        ino: Inode,
+        tag_ino: Inode,
+        name: &OsStr,
+    ) -> Result<FileAttr, FsError> {
+        self.invalidate_lookup(tag_ino, name);
+        self.tag_inode(tag_ino, ino)?;
+        self.attr(ino)
+    }
+
+    /// Tag the target of `link`, which is resolved from the tag directory when relative. The
+    /// entry is named after the target like the rest of the tag directory, not after `name`.
+    /// A link to a comic tags all of its episodes as well, unlike `link`.
+    fn symlink_entry(
+        &mut self,
+        tag_ino: Inode,
+        name: &OsStr,
+        link: &Path,
+    ) -> Result<FileAttr, FsError> {
+        if tag_ino.kind() != InodeKind::Tag {
+            return Err(EPERM.into());
+        }
+        self.invalidate_lookup(tag_ino, name);
+        // A relative target starts from the tag directory, `join` keeps an absolute one as is
+        let path = self
+            .resolve_inode(tag_ino)
+            .ok_or(ENOENT)?
+            .join(link)
+            .clean();
+        // Only entries of this filesystem can be tagged
+        let target = match path.strip_prefix(&self.base) {
+            Ok(target) => target,
+            Err(_) => {
+                debug!(?path, "symlink target outside the mount");
+                return Err(EXDEV.into());
+            }
+        };
+        debug!(?target, "symlink target");
+        let ino = self.resolve(target).ok_or(ENOENT)?;
+        let (info, kind) = match ino.kind() {
+            InodeKind::Comic => (
+                self.tag_comic_with_episodes(tag_ino, ino)?,
+                TaggableKind::Comic,
+            ),
+            _ => self.tag_inode(tag_ino, ino)?,
+        };
+        let path = self.resolve_inode(ino).ok_or(ENOENT)?;
+        Ok(self.symlink_attr(
+            Inode::tagged_with_kind(info.id, kind),
+            path.as_os_str().len() as u64,
+        ))
+    }
+
+    fn find_entry(&self, parent: u64, name: &OsStr) -> Result<FileAttr, FsError> {
+        match parent {
+            Self::ROOT_ID => {
+                if name == "comics" {
+                    Ok(self.directory_attr(Inode::from(Self::COMIC_ID), self.mounted_at))
+                } else if name == "tags" {
+                    Ok(self.directory_attr(Inode::from(Self::TAGS_ID), self.mounted_at))
+                } else if name == "search" {
+                    Ok(self.directory_attr(Inode::from(Self::SEARCH_ID), self.mounted_at))
+                } else if name == "recent" {
+                    Ok(self.directory_attr(Inode::from(Self::RECENT_ID), self.mounted_at))
+                } else if name == ".comicfs" {
+                    Ok(self.directory_attr(Inode::from(Self::CONTROL_ID), self.mounted_at))
+                } else if name == "trash" && self.trash {
+                    Ok(self.directory_attr(Inode::from(Self::TRASH_ID), self.mounted_at))
+                } else {
+                    Err(ENOENT.into())
+                }
+            }
+            Self::CONTROL_ID => {
+                if name == "stats" {
+                    self.stats_attr()
+                } else {
+                    Err(ENOENT.into())
+                }
+            }
+            Self::TRASH_ID => {
+                let ino = Self::trash_entry(name, &*self.conn()?)?;
+                self.attr(ino)
+            }
+            Self::RECENT_ID => {
+                let (ino, _) = self
+                    .recent_results()?
+                    .into_iter()
+                    .find(|(_, result)| name == result.as_str())
+                    .ok_or(ENOENT)?;
+                let path = self.read_link(ino)?;
+                Ok(self.symlink_attr(ino, path.as_os_str().len() as u64))
+            }
+            Self::SEARCH_ID => {
+                let term = name.to_str().ok_or(EINVAL)?;
+                let ino = self.search_dir(term);
+                Ok(self.directory_attr(ino, SystemTime::UNIX_EPOCH))
+            }
+            Self::COMIC_ID => {
+                let name = name.to_str().ok_or(EINVAL)?;
+                self.find_comic_by_name(name).ok_or(FsError::Errno(ENOENT))
+            }
+            Self::TAGS_ID => {
+                let name = name.to_str().ok_or(EINVAL)?;
+                self.find_tag_by_name(name).ok_or(FsError::Errno(ENOENT))
+            }
+            ino => {
+                let ino = Inode::from(ino);
+                let kind = ino.kind();
+                if name == TAG_LIST_NAME && matches!(kind, InodeKind::Comic | InodeKind::Eposide) {
+                    return self.tag_list_attr(ino.tag_list());
+                }
+                if name == COVER_NAME && kind == InodeKind::Eposide {
+                    return self.cover_attr(ino.cover());
+                }
+                if name == REORDER_NAME && kind == InodeKind::Eposide {
+                    return self.reorder_attr(ino.reorder());
+                }
+                let attr = match kind {
+                    InodeKind::Comic => {
+                        let name = name.to_str().ok_or(EINVAL)?;
+                        self.find_comic_eposide_by_name(ino.id(), name)
+                    }
+                    InodeKind::Eposide => {
+                        let name = name.to_str().ok_or(EINVAL)?;
+                        let info = File::find_by_eposide_and_name(
+                            i32::try_from(ino.id()).unwrap(),
+                            name,
+                            self.case_insensitive,
+                            &*self.conn()?,
+                        );
+                        info.map(|info| self.find_file_attr(&info)).transpose()?
+                    }
+                    InodeKind::Search => {
+                        let results = self.search_results(&self.search_term(ino)?)?;
+                        let (ino, _) = results
+                            .into_iter()
+                            .find(|(_, result)| name == result.as_str())
+                            .ok_or(ENOENT)?;
+                        let path = self.read_link(ino)?;
+                        Some(self.symlink_attr(ino, path.as_os_str().len() as u64))
+                    }
+                    InodeKind::Special => None,
+                    InodeKind::File
+                    | InodeKind::Tagged
+                    | InodeKind::TagList
+                    | InodeKind::Cover
+                    | InodeKind::Reorder
+                    | InodeKind::SearchResult => return Err(ENOTDIR.into()),
+                    InodeKind::Tag => {
+                        let span = info_span!("lookop tagged");
+                        let _guard = span.enter();
+                        let expected_name = name.to_str().ok_or(EINVAL)?;
+                        info!(expected_name);
+                        let files =
+                            Taggables::taggables(i32::try_from(ino.id()).unwrap(), &*self.conn()?);
+                        info!(?files);
+                        let res = files.iter().find_map(|file| match file {
+                            Taggables::Comic { id, name, comic } => {
+                                if name == expected_name {
+                                    let id = *id;
+                                    info!(id, "found comic");
+                                    let path = self.resolve_inode(Inode::comic(comic.id))?;
+                                    Some((Inode::tagged_with_kind(id, TaggableKind::Comic), path))
+                                } else {
+                                    None
+                                }
+                            }
+                            Taggables::Episode { id, name, episode } => {
+                                if name == expected_name {
+                                    let id = *id;
+                                    info!(id, "found episode");
+                                    let path = self.resolve_inode(Inode::eposide(episode.id))?;
+                                    Some((Inode::tagged_with_kind(id, TaggableKind::Eposide), path))
+                                } else {
+                                    None
+                                }
+                            }
+                            Taggables::File { id, name, file } => {
+                                if name == expected_name {
+                                    let id = *id;
+                                    info!(id, "found file");
+                                    let path = self.resolve_inode(Inode::file(file.id))?;
+                                    Some((Inode::tagged_with_kind(id, TaggableKind::File), path))
+                                } else {
+                                    None
+                                }
+                            }
+                        });
+                        let (ino, path) = match res {
+                            Some(found) => found,
+                            None => {
+                                info!("not found");
+                                return Err(ENOENT.into());
+                            }
+                        };
+                        Some(self.symlink_attr(ino, path.as_os_str().len() as u64))
+                    }
+                };
+
+                attr.ok_or(FsError::Errno(ENOENT))
+            }
+        }
+    }
+
+    /// `find_entry` through the lookup cache. Entries are only reused for as long as the kernel
+    /// caches them, and names that weren't found for `negative_timeout`.
+    fn lookup_entry(&self, parent: Inode, name: &OsStr) -> Result<FileAttr, FsError> {
+        let cache = match &self.lookup_cache {
+            Some(cache) => cache,
+            None => return self.find_entry(parent.0, name),
+        };
+        let key = (parent.0, name.to_owned());
+        if let Some((attr, found)) = cache.lock().unwrap().get(&key) {
+            let timeout = match attr {
+                Some(_) => self.entry_timeout,
+                None => self.negative_timeout(),
+            };
+            if found.elapsed() < timeout {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return attr.ok_or_else(|| ENOENT.into());
+            }
+        }
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+        let attr = match self.find_entry(parent.0, name) {
+            Ok(attr) => Some(attr),
+            Err(err) if err.errno() == ENOENT && caches_missing(parent, name) => None,
+            Err(err) => return Err(err),
+        };
+        cache.lock().unwrap().put(key, (attr, Instant::now()));
+        attr.ok_or_else(|| ENOENT.into())
+    }
+
+    /// How long a missing name is cached, see `NEGATIVE_TIMEOUT`.
+    fn negative_timeout(&self) -> Duration {
+        NEGATIVE_TIMEOUT.min(self.entry_timeout)
+    }
+
+    /// Drop the cached lookup of `name` in `parent` after it's added or removed. Looking up
+    /// names ignoring case, those of other cases are dropped too.
+    fn invalidate_lookup(&self, parent: Inode, name: &OsStr) {
+        if let Some(cache) = &self.lookup_cache {
+            let mut cache = cache.lock().unwrap();
+            cache.pop(&(parent.0, name.to_owned()));
+            if self.case_insensitive {
+                let keys = cache
+                    .iter()
+                    .filter(|((dir, other), _)| {
+                        *dir == parent.0 && other.as_bytes().eq_ignore_ascii_case(name.as_bytes())
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>();
+                for key in keys {
+                    cache.pop(&key);
+                }
+            }
+        }
+    }
+
+    /// Drop the cached lookups resolving to `ino` after its attributes changed.
+    fn invalidate_inode(&self, ino: Inode) {
+        if let Some(cache) = &self.lookup_cache {
+            let mut cache = cache.lock().unwrap();
+            let keys = cache
+                .iter()
+                .filter(|(_, (attr, _))| matches!(attr, Some(attr) if attr.ino == ino.0))
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            for key in keys {
+                cache.pop(&key);
+            }
+        }
+    }
+
+    /// Drop the cached lookups and stats after the database was changed by another process.
+    /// Returns the entries whose lookups were dropped, for the kernel to drop them as well.
+    fn refresh(&self) -> Vec<(u64, OsString)> {
+        *self.stats.lock().unwrap() = None;
+        match &self.lookup_cache {
+            Some(cache) => {
+                let mut cache = cache.lock().unwrap();
+                let keys = cache.iter().map(|(key, _)| key.clone()).collect();
+                cache.clear();
+                keys
+            }
+            None => vec![],
+        }
+    }
+
+    /// `refresh`, then tell the kernel to drop the same entries and the attributes of their
+    /// directories along with the top ones, whose listing may have changed. Returns false once
+    /// the kernel is gone.
+    fn refresh_kernel(&self, notifier: &Notifier) -> bool {
+        let mut parents = vec![Self::ROOT_ID, Self::COMIC_ID, Self::TAGS_ID];
+        for (parent, name) in self.refresh() {
+            if !notified(notifier.inval_entry(parent, &name)) {
+                return false;
+            }
+            parents.push(parent);
+        }
+        parents.sort_unstable();
+        parents.dedup();
+        parents
+            .into_iter()
+            .all(|parent| notified(notifier.inval_inode(parent, 0, 0)))
+    }
+
+    /// Hash every blob again and compare it with the content hash of its files. Corrupted and
+    /// missing blobs are logged, and the number of files with a problem is returned.
+    fn fsck(&self) -> Result<usize, FsError> {
+        let files = File::all(&*self.conn()?)?;
+        let mut problems = 0;
+        for file in &files {
+            let hash = match &file.content_hash {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let path = match self.storage_path(hash) {
+                Ok(path) => path,
+                Err(_) => {
+                    problems += 1;
+                    continue;
+                }
+            };
+            match file.verify_blob(&path) {
+                Ok(true) => {}
+                Ok(false) => {
+                    problems += 1;
+                    error!(file.id, %hash, path = %path.display(), "corrupted blob");
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    problems += 1;
+                    error!(file.id, %hash, path = %path.display(), "missing blob");
+                }
+                Err(err) => {
+                    problems += 1;
+                    error!(file.id, %hash, %err, "fail to verify blob");
+                }
+            }
+        }
+        info!(files = files.len(), problems, "checked blobs");
+        Ok(problems)
+    }
+
+    /// Sets of files sharing a blob, with their content hash and their paths as
+    /// `comic/episode/file`.
+    fn dupes(&self) -> Result<Vec<(String, Vec<PathBuf>)>, FsError> {
+        let conn = self.conn()?;
+        let mut sets = vec![];
+        for hash in File::shared_hashes(&conn)? {
+            let mut paths = vec![];
+            for file in File::find_by_hash(&hash, &conn)? {
+                let episode = Episode::find(file.eposid_id, &conn).ok_or(EIO)?;
+                let comic = Comic::find(episode.comic_id, &conn).ok_or(EIO)?;
+                paths.push([comic.name, episode.name, file.name].iter().collect());
+            }
+            sets.push((hash, paths));
+        }
+        Ok(sets)
+    }
+
+    /// Import the directory tree `dir`, laid out as `comic/episode/file`, straight into the
+    /// database and the storage. Each comic is imported in one transaction, the blobs it stored
+    /// are removed again when it's rolled back. Comics and episodes already there get the new
+    /// files, files already there are skipped. Returns the number of files imported.
+    fn import_tree(&self, dir: &Path) -> Result<usize, FsError> {
+        let mut imported = 0;
+        for (name, path, kind) in import_entries(dir)? {
+            // Tags written by `export_tree`
+            if name == TAG_LIST_NAME {
+                continue;
+            }
+            if !kind.is_dir() {
+                warn!(path = %path.display(), "skip entry that isn't a comic directory");
+                continue;
+            }
+            let conn = self.conn()?;
+            let mut hashes = vec![];
+            let res = conn.transaction::<_, FsError, _>(|| {
+                self.import_comic(&name, &path, &conn, &mut hashes)
+            });
+            let files = match res {
+                Ok(files) => files,
+                Err(err) => {
+                    for hash in &hashes {
+                        self.remove_unused_blob(hash, &conn);
+                    }
+                    return Err(err);
+                }
+            };
+            info!(comic = %name, files, "imported comic");
+            imported += files;
+        }
+        Ok(imported)
+    }
+
+    /// Write the comics out to `dir` as `comic/episode/file`, the layout `import_tree` reads,
+    /// copying the blobs. With `with_tags`, every tag is a directory under `dir/.tags` holding
+    /// relative symlinks to what it tags, named like in `/tags`. `dir` has to be empty or
+    /// missing. Returns the number of files exported.
+    fn export_tree(&self, dir: &Path, with_tags: bool) -> Result<usize, FsError> {
+        if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+            return Err(ENOTEMPTY.into());
+        }
+        let conn = self.conn()?;
+        let mut exported = 0;
+        for comic in Comic::list(&conn)? {
+            let comic_dir = dir.join(&comic.name);
+            fs::create_dir_all(&comic_dir)?;
+            for episode in Episode::list_for_comic(comic.id, &conn)? {
+                let episode_dir = comic_dir.join(&episode.name);
+                fs::create_dir_all(&episode_dir)?;
+                for file in File::list_for_episode(episode.id, &conn)? {
+                    let path = episode_dir.join(&file.name);
+                    match &file.content_hash {
+                        Some(hash) => {
+                            fs::copy(self.storage_path(hash)?, &path)?;
+                        }
+                        None => {
+                            fs::File::create(&path)?;
+                        }
+                    }
+                    exported += 1;
+                }
+            }
+            info!(comic = %comic.name, "exported comic");
+        }
+        if with_tags {
+            self.export_tags(&dir.join(TAG_LIST_NAME))?;
+        }
+        Ok(exported)
+    }
+
+    fn export_tags(&self, dir: &Path) -> Result<(), FsError> {
+        let comics = self.base.join("comics");
+        for tag in Tag::list(&*self.conn()?).ok_or(EIO)? {
+            let tag_dir = dir.join(&tag.name);
+            fs::create_dir_all(&tag_dir)?;
+            for (ino, _, name) in self.children(Inode::tag(tag.id)) {
+                let target = self.read_link(Inode::from(ino))?;
+                let target = target.strip_prefix(&comics).map_err(|_| EIO)?;
+                // Up from `.tags/<tag>` to the comics
+                symlink(Path::new("../..").join(target), tag_dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Import the comic `name` from `dir` within the transaction of `import_tree`. `hashes`
+    /// gets the hash of every blob stored, for the blobs to be removed on a rollback.
+    fn import_comic(
+        &self,
+        name: &str,
+        dir: &Path,
+        conn: &SqliteConnection,
+        hashes: &mut Vec<String>,
+    ) -> Result<usize, FsError> {
+        let comic = match Comic::find_by_name(name, self.case_insensitive, conn) {
+            Some(comic) => comic,
+            None => models::NewComic { name }.insert(conn)?,
+        };
+        let mut imported = 0;
+        for (name, path, kind) in import_entries(dir)? {
+            if !kind.is_dir() || name == TAG_LIST_NAME {
+                warn!(path = %path.display(), "skip entry that isn't an episode directory");
+                continue;
+            }
+            let episode =
+                match Episode::find_by_comic_and_name(comic.id, &name, self.case_insensitive, conn)
+                {
+                    Some(episode) => episode,
+                    None => models::NewEposide {
+                        name: &name,
+                        comic_id: comic.id,
+                    }
+                    .insert(conn)?,
+                };
+            for (name, path, kind) in import_entries(&path)? {
+                if !kind.is_file() || is_reserved(OsStr::new(&name)) {
+                    warn!(path = %path.display(), "skip entry that isn't a file");
+                    continue;
+                }
+                let existing =
+                    File::find_by_eposide_and_name(episode.id, &name, self.case_insensitive, conn);
+                if existing.is_some() {
+                    debug!(path = %path.display(), "file is imported already");
+                    continue;
+                }
+                let mut pending = self.new_pending()?;
+                io::copy(&mut fs::File::open(&path)?, &mut pending)?;
+                let (hash, blob, _stored) = self.store_pending(pending)?;
+                hashes.push(hash.clone());
+                let mut head = [0; 16];
+                let len = blob.read_at(&mut head, 0)?;
+                let info = models::NewFile {
+                    name: &name,
+                    eposid_id: episode.id,
+                }
+                .insert(conn)?;
+                info.update_content_hash(&hash, conn)?;
+                info.update_size(blob.metadata()?.len(), conn)?;
+                info.update_mime_type(sniff_mime_type(&head[..len]), conn)?;
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Reply with the entry found or made by `lookup`, `mkdir`, `link` or `symlink`, cached by
+    /// the kernel for `entry_timeout`. The kernel takes a reference on the inode then.
+    fn reply_entry(&self, reply: ReplyEntry, res: Result<FileAttr, FsError>) {
+        match res {
+            Ok(attr) => {
+                self.remember(attr.ino);
+                reply.entry(&self.entry_timeout, &attr, 0);
+            }
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    /// Reply to a `lookup` of a missing name with an entry of inode 0, which the kernel caches
+    /// as missing for `negative_timeout` instead of asking again on every access.
+    fn reply_missing(&self, reply: ReplyEntry) {
+        let attr = self.directory_attr(Inode::from(0), self.mounted_at);
+        reply.entry(&self.negative_timeout(), &attr, 0);
+    }
+
+    /// Reply with the attributes of `getattr` or `setattr`, cached by the kernel for
+    /// `attr_timeout`.
+    fn reply_attr(&self, reply: ReplyAttr, res: Result<FileAttr, FsError>) {
+        match res {
+            Ok(attr) => reply.attr(&self.attr_timeout, &attr),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    /// Count a reference the kernel takes on `ino` by an entry reply.
+    fn remember(&self, ino: u64) {
+        *self.lookups.lock().unwrap().entry(ino).or_insert(0) += 1;
+    }
+
+    /// Drop `nlookup` references on `ino`. Every entry replied by `lookup`, `mkdir`, `create`,
+    /// `link` and `symlink` is a reference the kernel holds until it sends the same count back
+    /// by `forget`, so the state kept for `ino` is released once the count drops to zero. The
+    /// kernel doesn't forget the root, and inodes still referenced on unmount are never
+    /// forgotten.
+    fn forget_inode(&self, ino: Inode, nlookup: u64) {
+        let mut lookups = self.lookups.lock().unwrap();
+        let count = match lookups.get_mut(&ino.0) {
+            Some(count) => count,
+            None => return,
+        };
+        *count = count.saturating_sub(nlookup);
+        if *count > 0 {
+            return;
+        }
+        lookups.remove(&ino.0);
+        drop(lookups);
+        if ino.kind() == InodeKind::Search {
+            self.forget_search(ino);
+        }
+        if let Some(cache) = &self.lookup_cache {
+            let mut cache = cache.lock().unwrap();
+            let keys = cache
+                .iter()
+                .filter(|((parent, _), (attr, _))| {
+                    *parent == ino.0 || matches!(attr, Some(attr) if attr.ino == ino.0)
+                })
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            for key in keys {
+                cache.pop(&key);
+            }
+        }
+    }
+
+    /// Move the access time of `info` to now if it's stale, see `atime_is_stale`.
+    fn touch_atime(&self, ino: Inode, info: &File) {
+        if self.noatime {
+            return;
+        }
+        let now = systemtime_to_naive(self.clock.now());
+        if !atime_is_stale(info, now) {
+            return;
+        }
+        let res = self.conn().and_then(|conn| {
+            info.update_times(Some(now), None, &conn)
+                .map_err(Into::into)
+        });
+        match res {
+            Ok(()) => self.invalidate_inode(ino),
+            Err(err) => warn!(?err, "fail to update access time"),
+        }
+    }
+
+    /// Count a read of the file or cover `ino` and move its access time, see `touch_atime`. Only
+    /// the first read of a file is counted instead of every chunk of it.
+    fn record_read(&self, ino: Inode, offset: i64) {
+        if offset != 0 {
+            return;
+        }
+        let info = match ino.kind() {
+            InodeKind::File => self
+                .conn()
+                .ok()
+                .and_then(|conn| File::find(i32::try_from(ino.id()).unwrap(), &conn)),
+            InodeKind::Cover => self.cover_file(ino).ok(),
+            _ => None,
+        };
+        if let Some(info) = info {
+            let res = self
+                .conn()
+                .and_then(|conn| info.increment_access(&conn).map_err(Into::into));
+            if let Err(err) = res {
+                warn!(?err, "fail to increment access count");
+            }
+            self.touch_atime(Inode::file(info.id), &info);
+        }
+    }
+
+    /// Reply to `read` straight from the mapped blob, without copying it into a buffer first,
+    /// or with `read_data` when `fh` has nothing mapped.
+    fn reply_read(&self, ino: Inode, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        let mut reply = Some(reply);
+        if self
+            .read_mapped(fh, offset, size, |data| reply.take().unwrap().data(data))
+            .is_some()
+        {
+            self.record_read(ino, offset);
+            return;
+        }
+        match self.read_data(ino, fh, offset, size) {
+            Ok(data) => reply.unwrap().data(&data),
+            Err(err) => reply.unwrap().error(err.errno()),
+        }
+    }
+
+    /// Call `f` with up to `size` bytes at `offset` of the blob mapped by `fh`, `None` when
+    /// it isn't mapped or a new content is being written through it.
+    fn read_mapped<R>(
+        &self,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Option<R> {
+        let handles = self.handles.lock().unwrap();
+        let handle = handles.get(&fh)?;
+        match (&handle.pending, &handle.map) {
+            (None, Some(map)) => Some(f(map.slice(offset, size))),
+            _ => None,
+        }
+    }
+
+    fn read_data(&self, ino: Inode, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, FsError> {
+        match ino.kind() {
+            InodeKind::File => {}
+            InodeKind::TagList => {
+                return Ok(slice_content(&self.tag_list_content(ino)?, offset, size));
+            }
+            InodeKind::Special if ino.0 == Self::STATS_ID => {
+                return Ok(slice_content(&self.stats_content()?, offset, size));
+            }
+            InodeKind::Reorder => return Ok(vec![]),
+            // Read the file shown, the handle holds its blob
+            InodeKind::Cover => {
+                let info = self.cover_file(ino)?;
+                return self.read_data(Inode::file(info.id), fh, offset, size);
+            }
+            _ => return Err(EISDIR.into()),
+        }
+        let mut handles = self.handles.lock().unwrap();
+        if let Some(handle) = handles.get_mut(&fh) {
+            self.flush_buffer(handle)?;
+        }
+        let file = handles.get(&fh).and_then(|handle| match &handle.pending {
+            Some(pending) => Some(pending.as_file()),
+            None => handle.blob.as_ref(),
+        });
+        let data = if let Some(file) = file {
+            read_blob(file, offset, size)?
+        } else {
+            drop(handles);
+            let info =
+                File::find(i32::try_from(ino.id()).unwrap(), &*self.conn()?).ok_or(ENOENT)?;
+            match info.content_hash {
+                Some(hash) => read_blob(&self.open_blob(&hash)?, offset, size)?,
+                // Nothing written yet
+                None => vec![],
+            }
+        };
+        self.record_read(ino, offset);
+        Ok(data)
+    }
+
+    /// Names of the extended attributes exposed on `ino`, all of them read-only but
+    /// `user.comment`. The counts of directories are queried only when asked for, so `getattr`
+    /// stays cheap.
+    fn xattr_names(&self, ino: Inode) -> &'static [&'static str] {
+        match ino.kind() {
+            InodeKind::File => &[
+                "user.access_count",
+                "user.content_hash",
+                "user.mime_type",
+                "user.comment",
+            ],
+            InodeKind::Comic => &["user.episode_count"],
+            InodeKind::Eposide => &["user.file_count"],
+            _ => &[],
+        }
+    }
+
+    /// Names of `xattr_names` as `listxattr` replies them, each followed by a NUL.
+    fn xattr_list(&self, ino: Inode) -> Vec<u8> {
+        let mut names = vec![];
+        for name in self.xattr_names(ino) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        names
+    }
+
+    fn xattr_value(&self, ino: Inode, name: &OsStr) -> Result<Vec<u8>, FsError> {
+        let name = name.to_str().ok_or(ENODATA)?;
+        if !self.xattr_names(ino).contains(&name) {
+            return Err(ENODATA.into());
+        }
+        let id = i32::try_from(ino.id()).unwrap();
+        let conn = self.conn()?;
+        let value = match ino.kind() {
+            InodeKind::Comic => {
+                let comic = Comic::find(id, &conn).ok_or(ENOENT)?;
+                comic.episode_count(&conn).ok_or(EIO)?.to_string()
+            }
+            InodeKind::Eposide => {
+                let episode = Episode::find(id, &conn).ok_or(ENOENT)?;
+                episode.file_count(&conn).ok_or(EIO)?.to_string()
+            }
+            _ => {
+                let info = File::find(id, &conn).ok_or(ENOENT)?;
+                match name {
+                    "user.access_count" => info.access_count.to_string(),
+                    "user.content_hash" => info.content_hash.ok_or(ENODATA)?,
+                    "user.mime_type" => info.mime_type.ok_or(ENODATA)?,
+                    "user.comment" => info.comment.ok_or(ENODATA)?,
+                    _ => unreachable!(),
+                }
+            }
+        };
+        Ok(value.into_bytes())
+    }
+
+    /// Set the `user.comment` of the file `ino` for `setxattr`. `flags` may hold `XATTR_CREATE`
+    /// or `XATTR_REPLACE`, which fail if the comment is already set or not set yet.
+    fn set_xattr(&self, ino: Inode, name: &OsStr, value: &[u8], flags: u32) -> Result<(), FsError> {
+        let info = self.commented_file(ino, name)?;
+        if value.len() > MAX_COMMENT_SIZE {
+            return Err(E2BIG.into());
+        }
+        let comment = std::str::from_utf8(value).map_err(|_| EINVAL)?;
+        let flags = i32::try_from(flags).unwrap_or(0);
+        if flags & libc::XATTR_CREATE != 0 && info.comment.is_some() {
+            return Err(EEXIST.into());
+        }
+        if flags & libc::XATTR_REPLACE != 0 && info.comment.is_none() {
+            return Err(ENODATA.into());
+        }
+        info.update_comment(Some(comment), &*self.conn()?)?;
+        Ok(())
+    }
+
+    fn remove_xattr(&self, ino: Inode, name: &OsStr) -> Result<(), FsError> {
+        let info = self.commented_file(ino, name)?;
+        if info.comment.is_none() {
+            return Err(ENODATA.into());
+        }
+        info.update_comment(None, &*self.conn()?)?;
+        Ok(())
+    }
+
+    /// File `ino` for a change of its extended attribute `name`. Only `user.comment` of a file
+    /// can be changed, the other attributes fail with `EPERM` and unknown ones with
+    /// `EOPNOTSUPP`.
+    fn commented_file(&self, ino: Inode, name: &OsStr) -> Result<File, FsError> {
+        if ino.kind() != InodeKind::File || name != "user.comment" {
+            let known =
+                matches!(name.to_str(), Some(name) if self.xattr_names(ino).contains(&name));
+            return Err(if known { EPERM } else { EOPNOTSUPP }.into());
+        }
+        File::find(i32::try_from(ino.id()).unwrap(), &*self.conn()?).ok_or(FsError::Errno(ENOENT))
+    }
+
+    /// Directory of the search for `term`, registered on its first lookup until the kernel
+    /// forgets it, see `forget_search`.
+    fn search_dir(&self, term: &str) -> Inode {
+        let mut searches = self.searches.lock().unwrap();
+        let id = match searches.ids.get(term) {
+            Some(id) => *id,
+            None => {
+                let id = searches.next_id;
+                searches.next_id += 1;
+                searches.ids.insert(term.to_owned(), id);
+                searches.terms.insert(id, term.to_owned());
+                id
+            }
+        };
+        Inode::search(id)
+    }
+
+    /// Directory of the search for `term` if it's registered, without registering it.
+    fn find_search_dir(&self, term: &str) -> Option<Inode> {
+        let searches = self.searches.lock().unwrap();
+        searches.ids.get(term).map(|id| Inode::search(*id))
+    }
+
+    fn search_term(&self, ino: Inode) -> Result<String, FsError> {
+        self.searches
+            .lock()
+            .unwrap()
+            .terms
+            .get(&ino.id())
+            .cloned()
+            .ok_or(FsError::Errno(ENOENT))
+    }
+
+    /// Drop the term of the search directory `ino` once the kernel forgot it.
+    fn forget_search(&self, ino: Inode) {
+        let mut searches = self.searches.lock().unwrap();
+        if let Some(term) = searches.terms.remove(&ino.id()) {
+            searches.ids.remove(&term);
+        }
+    }
+
+    /// Comics, episodes and files with `term` in their name, ignoring the case of ASCII
+    /// letters. They are named like the entries of a tag directory.
+    fn search_results(&self, term: &str) -> Result<Vec<(Inode, String)>, FsError> {
+        let conn = self.conn()?;
+        let mut parents = Parents::default();
+        let mut results = vec![];
+        for comic in Comic::search(term, &conn)? {
+            results.push((
+                Inode::search_result(comic.id, TaggableKind::Comic),
+                comic.name,
+            ));
+        }
+        for episode in Episode::search(term, &conn)? {
+            let comic = parents.comic_name(episode.comic_id, &conn).ok_or(ENOENT)?;
+            results.push((
+                Inode::search_result(episode.id, TaggableKind::Eposide),
+                format!("{}_{}", comic, episode.name),
+            ));
+        }
+        for file in File::search(term, &conn)? {
+            results.push(Self::file_result(&file, &mut parents, &conn)?);
+        }
+        Ok(results)
+    }
+
+    /// The files created last, newest first, see `File::recent`.
+    fn recent_results(&self) -> Result<Vec<(Inode, String)>, FsError> {
+        let conn = self.conn()?;
+        let mut parents = Parents::default();
+        File::recent(self.recent_limit, &conn)?
+            .iter()
+            .map(|file| Self::file_result(file, &mut parents, &conn))
+            .collect()
+    }
+
+    /// Symlink to `file` named after its comic and episode, as it's listed out of them.
+    fn file_result(
+        file: &File,
+        parents: &mut Parents,
+        conn: &SqliteConnection,
+    ) -> Result<(Inode, String), FsError> {
+        let episode = parents.episode_name(file.eposid_id, conn).ok_or(ENOENT)?;
+        Ok((
+            Inode::search_result(file.id, TaggableKind::File),
+            format!("{}_{}", episode, file.name),
+        ))
+    }
+
+    /// What's in `/trash`, named after the comic and episode it was in like the search results.
+    /// The name starts with the kind and the id, `c`, `e` or `f`, since the same name can be
+    /// trashed more than once.
+    fn trash_entries(conn: &SqliteConnection) -> Result<Vec<(Inode, FileType, String)>, FsError> {
+        let mut parents = Parents::default();
+        let mut entries = vec![];
+        for comic in Comic::trashed(conn)? {
+            let name = format!("c{}_{}", comic.id, comic.name);
+            entries.push((Inode::comic(comic.id), FileType::Directory, name));
+        }
+        for episode in Episode::trashed(conn)? {
+            let comic = parents.comic_name(episode.comic_id, conn).ok_or(ENOENT)?;
+            let name = format!("e{}_{}_{}", episode.id, comic, episode.name);
+            entries.push((Inode::eposide(episode.id), FileType::Directory, name));
+        }
+        for file in File::trashed(conn)? {
+            let episode = parents.episode_name(file.eposid_id, conn).ok_or(ENOENT)?;
+            let name = format!("f{}_{}_{}", file.id, episode, file.name);
+            entries.push((Inode::file(file.id), FileType::RegularFile, name));
+        }
+        Ok(entries)
+    }
+
+    /// The entry `name` of `/trash`.
+    fn trash_entry(name: &OsStr, conn: &SqliteConnection) -> Result<Inode, FsError> {
+        Self::trash_entries(conn)?
+            .into_iter()
+            .find(|(_, _, entry)| name == entry.as_str())
+            .map(|(ino, _, _)| ino)
+            .ok_or_else(|| ENOENT.into())
+    }
+
+    /// Path of the target of a tagged inode or a search result.
+    fn read_link(&self, ino: Inode) -> Result<PathBuf, FsError> {
+        let target = match ino.kind() {
+            // The kind is in the inode, only the id of the target has to be looked up
+            InodeKind::Tagged => Taggable::find(ino.id().try_into().unwrap(), &*self.conn()?)
+                .and_then(|info| ino.tagged_target(info.taggable_id)),
+            InodeKind::SearchResult => ino.tagged_target(ino.id().try_into().unwrap()),
+            _ => return Err(EINVAL.into()),
+        };
+        self.resolve_inode_checked(target.ok_or(ENOENT)?)
+    }
+
+    fn resolve(&self, path: &Path) -> Option<Inode> {
+        let mut parent = Inode::from(1);
+        for component in path.components() {
+            let id = parent.0;
+            let kind = parent.kind();
+            let name = component.as_os_str();
+
+            match kind {
+                InodeKind::Special => match id {
+                    Self::ROOT_ID => {
+                        if name == "comics" {
+                            parent = Inode::from(Self::COMIC_ID);
+                        } else if name == "tags" {
+                            parent = Inode::from(Self::TAGS_ID);
+                        } else if name == "search" {
+                            parent = Inode::from(Self::SEARCH_ID);
+                        } else if name == "recent" {
+                            parent = Inode::from(Self::RECENT_ID);
+                        } else {
+                            return None;
+                        }
+                    }
+                    // Only the searches the kernel holds, `resolve` doesn't take a reference
+                    Self::SEARCH_ID => {
+                        parent = self.find_search_dir(name.to_str()?)?;
+                    }
+                    // Only symlinks in there
+                    Self::RECENT_ID => return None,
+                    Self::COMIC_ID => {
+                        let info = Comic::find_by_name(
+                            name.to_str()?,
+                            self.case_insensitive,
+                            &*self.conn().ok()?,
+                        )?;
+                        parent = Inode::comic(info.id);
+                    }
+                    Self::TAGS_ID => {
+                        let info = Tag::find_by_name(name.to_str()?, &*self.conn().ok()?)?;
+                        parent = Inode::tag(info.id)
+                    }
+                    _ => return None,
+                },
+                InodeKind::Comic => {
+                    let info = Episode::find_by_comic_and_name(
+                        parent.id().try_into().unwrap(),
+                        name.to_str()?,
+                        self.case_insensitive,
+                        &*self.conn().ok()?,
+                    )?;
+                    parent = Inode::eposide(info.id);
+                }
+                InodeKind::Eposide => {
+                    let info = File::find_by_eposide_and_name(
+                        parent.id().try_into().unwrap(),
+                        name.to_str()?,
+                        self.case_insensitive,
+                        &*self.conn().ok()?,
+                    )?;
+                    parent = Inode::file(info.id);
+                }
+                // Tagged entries and results are symlinks, nothing can be reached through them
+                InodeKind::Tag
+                | InodeKind::Search
+                | InodeKind::File
+                | InodeKind::Tagged
+                | InodeKind::TagList
+                | InodeKind::Cover
+                | InodeKind::Reorder
+                | InodeKind::SearchResult => return None,
+            }
+        }
+        Some(parent)
+    }
+
+    /// Path of `ino` under the mountpoint, `None` if it or one of its parents is gone.
+    fn resolve_inode(&self, ino: Inode) -> Option<PathBuf> {
+        self.resolve_inode_checked(ino).ok()
+    }
+
+    /// Like `resolve_inode`, but tells `ENOENT` for a missing row apart from `ELOOP` when the
+    /// parents don't reach the root. Every kind has a fixed parent kind, so that would be a bug.
+    fn resolve_inode_checked(&self, ino: Inode) -> Result<PathBuf, FsError> {
+        self.resolve_inode_within(ino, MAX_RESOLVE_DEPTH)
+    }
+
+    /// Walk up from `ino` through at most `max_depth` inodes, so a corrupted database can't
+    /// keep the walk going forever.
+    fn resolve_inode_within(&self, ino: Inode, max_depth: usize) -> Result<PathBuf, FsError> {
+        let mut next = Some(ino);
+        let mut components = vec![];
+
+        for _ in 0..max_depth {
+            let ino = match next {
+                Some(ino) => ino,
+                None => return Ok(components.into_iter().rev().collect::<PathBuf>()),
+            };
+            match ino.kind() {
+                InodeKind::Special => match ino.0 {
+                    Self::ROOT_ID => {
+                        components.push(self.base.clone());
+                        next = None;
+                    }
+                    Self::COMIC_ID => {
+                        components.push(PathBuf::from("comics".to_owned()));
+                        next = Some(Inode::from(Self::ROOT_ID));
+                    }
+                    Self::TAGS_ID => {
+                        components.push(PathBuf::from("tags".to_owned()));
+                        next = Some(Inode::from(Self::ROOT_ID));
+                    }
+                    Self::SEARCH_ID => {
+                        components.push(PathBuf::from("search".to_owned()));
+                        next = Some(Inode::from(Self::ROOT_ID));
+                    }
+                    Self::RECENT_ID => {
+                        components.push(PathBuf::from("recent".to_owned()));
+                        next = Some(Inode::from(Self::ROOT_ID));
+                    }
+                    Self::CONTROL_ID => {
+                        components.push(PathBuf::from(".comicfs".to_owned()));
+                        next = Some(Inode::from(Self::ROOT_ID));
+                    }
+                    Self::STATS_ID => {
+                        components.push(PathBuf::from("stats".to_owned()));
+                        next = Some(Inode::from(Self::CONTROL_ID));
+                    }
+                    Self::TRASH_ID => {
+                        components.push(PathBuf::from("trash".to_owned()));
+                        next = Some(Inode::from(Self::ROOT_ID));
+                    }
+                    _ => return Err(ENOENT.into()),
+                },
+                InodeKind::Comic => {
+                    let info =
+                        Comic::find(ino.id().try_into().unwrap(), &*self.conn()?).ok_or(ENOENT)?;
+                    components.push(PathBuf::from(info.name.clone()));
+                    next = Some(Inode::from(Self::COMIC_ID));
+                }
+                InodeKind::Eposide => {
+                    let info = Episode::find(ino.id().try_into().unwrap(), &*self.conn()?)
+                        .ok_or(ENOENT)?;
+                    components.push(PathBuf::from(info.name.clone()));
+                    next = Some(Inode::comic(info.comic_id));
+                }
+                InodeKind::File => {
+                    let info =
+                        File::find(ino.id().try_into().unwrap(), &*self.conn()?).ok_or(ENOENT)?;
+                    components.push(PathBuf::from(info.name.clone()));
+                    next = Some(Inode::eposide(info.eposid_id));
+                }
+                InodeKind::Tag => {
+                    let info =
+                        Tag::find(ino.id().try_into().unwrap(), &*self.conn()?).ok_or(ENOENT)?;
+                    components.push(PathBuf::from(info.name.clone()));
+                    next = Some(Inode::from(Self::TAGS_ID));
+                }
+                InodeKind::Search => {
+                    components.push(PathBuf::from(self.search_term(ino)?));
+                    next = Some(Inode::from(Self::SEARCH_ID));
+                }
+                // Entries that only live in a listing have no path of their own
+                InodeKind::Tagged
+                | InodeKind::TagList
+                | InodeKind::Cover
+                | InodeKind::Reorder
+                | InodeKind::SearchResult => return Err(ENOENT.into()),
+            }
+        }
+        warn!(?ino, max_depth, "parents of the inode don't reach the root");
+        Err(ELOOP.into())
+    }
+
+    fn parent_of(&self, ino: Inode) -> Option<Inode> {
+        match ino.kind() {
+            InodeKind::Special if ino.0 == Self::STATS_ID => None,
+            InodeKind::Special => Some(Inode::from(Self::ROOT_ID)),
+            InodeKind::Comic => Some(Inode::from(Self::COMIC_ID)),
+            InodeKind::Eposide => Episode::find(ino.id().try_into().unwrap(), &*self.conn().ok()?)
+                .map(|info| Inode::comic(info.comic_id)),
+            InodeKind::Tag => Some(Inode::from(Self::TAGS_ID)),
+            InodeKind::Search => Some(Inode::from(Self::SEARCH_ID)),
+            InodeKind::File
+            | InodeKind::Tagged
+            | InodeKind::TagList
+            | InodeKind::Cover
+            | InodeKind::Reorder
+            | InodeKind::SearchResult => None,
+        }
+    }
+
+    /// List the entries of a directory, starting with `.` and `..`, then `.tags` in comics and
+    /// episodes and `cover` in episodes with files, followed by the children in a fixed order,
+    /// so that the position of an entry is stable across `readdir` calls and can be used as the
+    /// resume offset.
+    fn dir_entries(&self, ino: Inode) -> Vec<(u64, FileType, String)> {
+        let parent = match self.parent_of(ino) {
+            Some(parent) => parent,
+            None => return vec![],
+        };
+        let mut entries = vec![
+            (ino.0, FileType::Directory, ".".to_owned()),
+            (parent.0, FileType::Directory, "..".to_owned()),
+        ];
+        if matches!(ino.kind(), InodeKind::Comic | InodeKind::Eposide) {
+            entries.push((
+                ino.tag_list().0,
+                FileType::RegularFile,
+                TAG_LIST_NAME.to_owned(),
+            ));
+        }
+        if ino.kind() == InodeKind::Eposide && self.cover_file(ino.cover()).is_ok() {
+            entries.push((ino.cover().0, FileType::RegularFile, COVER_NAME.to_owned()));
+        }
+        entries.extend(self.children(ino));
+        entries
+    }
+
+    fn open_dir(&mut self, ino: Inode) -> Result<u64, FsError> {
+        match ino.kind() {
+            InodeKind::Special if ino.0 == Self::STATS_ID => return Err(ENOTDIR.into()),
+            InodeKind::Special
+            | InodeKind::Comic
+            | InodeKind::Eposide
+            | InodeKind::Tag
+            | InodeKind::Search => {}
+            InodeKind::File
+            | InodeKind::Tagged
+            | InodeKind::TagList
+            | InodeKind::Cover
+            | InodeKind::Reorder
+            | InodeKind::SearchResult => return Err(ENOTDIR.into()),
+        }
+        self.attr(ino)?;
+        let entries = if Self::is_paged(ino) {
+            None
+        } else {
+            Some(self.dir_entries(ino))
+        };
+        Ok(self.insert_handle(Handle {
+            entries,
+            ..Handle::default()
+        }))
+    }
+
+    /// Whether `ino` is the comics or the tags, which can be too many to list at once on
+    /// `opendir`.
+    fn is_paged(ino: Inode) -> bool {
+        matches!(ino.0, Self::COMIC_ID | Self::TAGS_ID)
+    }
+
+    /// Fill `reply` from the entries listed by `opendir`, from the table a page at a time for
+    /// the comics and the tags, or list them again when `fh` has no snapshot.
+    fn read_dir(&self, ino: Inode, fh: u64, offset: i64, reply: &mut ReplyDirectory) {
+        if Self::is_paged(ino) {
+            self.read_dir_pages(ino, offset, reply);
+            return;
+        }
+        let handles = self.handles.lock().unwrap();
+        match handles.get(&fh).and_then(|handle| handle.entries.as_ref()) {
+            Some(entries) => fill_dir(reply, entries, offset),
+            None => {
+                drop(handles);
+                fill_dir(reply, &self.dir_entries(ino), offset);
+            }
+        }
+    }
+
+    /// Fill `reply` like `read_dir`, with the attributes of the entries. Those of the children
+    /// come from `child_attrs`, the others are found one by one. Every entry added but `.` and
+    /// `..` is a reference the kernel takes on the inode, like an entry replied to `lookup`.
+    fn read_dir_plus(&self, ino: Inode, fh: u64, offset: i64, reply: &mut ReplyDirectoryPlus) {
+        let mut index = usize::try_from(offset).unwrap();
+        if Self::is_paged(ino) {
+            loop {
+                let (entries, more) = self.dir_page(ino, index);
+                let attrs = self.child_attrs(ino, &entries).unwrap_or_default();
+                if self.add_dir_plus_entries(reply, &entries, &attrs, index, index) || !more {
+                    return;
+                }
+                index += entries.len();
+            }
+        }
+        let handles = self.handles.lock().unwrap();
+        let snapshot = handles
+            .get(&fh)
+            .and_then(|handle| Some((handle.entries.clone()?, handle.attrs.clone())));
+        drop(handles);
+        let (entries, attrs) = match snapshot {
+            Some((entries, Some(attrs))) => (entries, attrs),
+            Some((entries, None)) => {
+                let attrs = self.child_attrs(ino, &entries).unwrap_or_default();
+                if let Some(handle) = self.handles.lock().unwrap().get_mut(&fh) {
+                    handle.attrs = Some(attrs.clone());
+                }
+                (entries, attrs)
+            }
+            None => {
+                let entries = self.dir_entries(ino);
+                let attrs = self.child_attrs(ino, &entries).unwrap_or_default();
+                (entries, attrs)
+            }
+        };
+        self.add_dir_plus_entries(reply, &entries, &attrs, 0, index);
+    }
+
+    /// `add_dir_entries` with the attributes of the entries, from `attrs` or found one by one.
+    fn add_dir_plus_entries(
+        &self,
+        reply: &mut ReplyDirectoryPlus,
+        entries: &[(u64, FileType, String)],
+        attrs: &HashMap<u64, FileAttr>,
+        first: usize,
+        offset: usize,
+    ) -> bool {
+        for (i, (child, _, name)) in entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (first + i, entry))
+            .skip(offset.saturating_sub(first))
+        {
+            let attr = match attrs.get(child) {
+                Some(attr) => *attr,
+                None => match self.attr(Inode::from(*child)) {
+                    Ok(attr) => attr,
+                    // Gone since the directory was opened
+                    Err(_) => continue,
+                },
+            };
+            // i + 1 means the index of the next entry
+            let offset = (i + 1).try_into().unwrap();
+            if reply.add(offset, name, &self.entry_timeout, &attr, 0) {
+                return true;
+            }
+            if name != "." && name != ".." {
+                self.remember(attr.ino);
+            }
+        }
+        false
+    }
+
+    /// Attributes of the comics, tags, episodes or files among `entries` of `ino`, built from
+    /// their rows loaded at once instead of one `getattr` each. Episodes and files are loaded
+    /// for the whole directory, `entries` being its snapshot. Other directories have their
+    /// entries found one by one.
+    fn child_attrs(
+        &self,
+        ino: Inode,
+        entries: &[(u64, FileType, String)],
+    ) -> Result<HashMap<u64, FileAttr>, FsError> {
+        let conn = self.conn()?;
+        let ids = |kind| {
+            entries
+                .iter()
+                .map(|(ino, _, _)| Inode::from(*ino))
+                .filter(|ino| ino.kind() == kind)
+                .map(|ino| i32::try_from(ino.id()).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let attrs = match ino.0 {
+            Self::COMIC_ID => {
+                let ids = ids(InodeKind::Comic);
+                let episodes = Episode::counts_by_comic(&ids, &conn)?;
+                Comic::find_many(&ids, &conn)?
+                    .into_iter()
+                    .map(|comic| {
+                        let count = episodes.get(&comic.id).copied().unwrap_or(0);
+                        let created = naive_to_systemtime(comic.created_at);
+                        self.linked_directory_attr(Inode::comic(comic.id), created, 2 + count)
+                    })
+                    .collect()
+            }
+            Self::TAGS_ID => Tag::find_many(&ids(InodeKind::Tag), &conn)?
+                .into_iter()
+                .map(|tag| {
+                    let created = naive_to_systemtime(tag.created_at);
+                    self.directory_attr(Inode::tag(tag.id), created)
+                })
+                .collect(),
+            _ => match ino.kind() {
+                InodeKind::Comic => {
+                    Episode::list_for_comic(i32::try_from(ino.id()).unwrap(), &conn)?
+                        .into_iter()
+                        .map(|episode| {
+                            let created = naive_to_systemtime(episode.created_at);
+                            self.directory_attr(Inode::eposide(episode.id), created)
+                        })
+                        .collect()
+                }
+                InodeKind::Eposide => {
+                    let id = i32::try_from(ino.id()).unwrap();
+                    let tagged = Taggable::counts_for_episode_files(id, &conn)?;
+                    File::list_for_episode(id, &conn)?
+                        .iter()
+                        .filter_map(|file| {
+                            let nlink = 1 + tagged.get(&file.id).copied().unwrap_or(0);
+                            let nlink = u32::try_from(nlink).unwrap_or(u32::MAX);
+                            self.linked_file_attr(file, nlink).ok()
+                        })
+                        .collect()
+                }
+                _ => vec![],
+            },
+        };
+        Ok(attrs.into_iter().map(|attr| (attr.ino, attr)).collect())
+    }
+
+    /// Fill `reply` from `offset` with the comics or the tags, loading a page of them at a time
+    /// instead of the whole table. Comics or tags added or renamed in between may shift the
+    /// entries, like in a directory listed by `readdir(3)` while it changes.
+    fn read_dir_pages(&self, ino: Inode, offset: i64, reply: &mut ReplyDirectory) {
+        let mut index = usize::try_from(offset).unwrap();
+        loop {
+            let (entries, more) = self.dir_page(ino, index);
+            if add_dir_entries(reply, &entries, index, index) || !more {
+                return;
+            }
+            index += entries.len();
+        }
+    }
+
+    /// Entries of the comics or the tags from `index` on, with up to a page of them loaded from
+    /// the table, and whether there may be more. The entries are at the same positions as in
+    /// `dir_entries`.
+    fn dir_page(&self, ino: Inode, index: usize) -> (Vec<(u64, FileType, String)>, bool) {
+        let mut entries = vec![
+            (ino.0, FileType::Directory, ".".to_owned()),
+            (Self::ROOT_ID, FileType::Directory, "..".to_owned()),
+        ];
+        let head = entries.len();
+        entries.drain(..index.min(head));
+        let page = self.children_page(ino, index.saturating_sub(head), READDIR_PAGE);
+        let more = page.len() == READDIR_PAGE;
+        entries.extend(page);
+        (entries, more)
+    }
+
+    /// Up to `limit` of the comics or the tags, skipping the first `offset`, like `children`.
+    fn children_page(
+        &self,
+        ino: Inode,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<(u64, FileType, String)> {
+        let (limit, offset) = (
+            i64::try_from(limit).unwrap(),
+            i64::try_from(offset).unwrap(),
+        );
+        match ino.0 {
+            Self::COMIC_ID => self
+                .conn()
+                .ok()
+                .and_then(|conn| Comic::list_page(limit, offset, &conn).ok())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|comic| (Inode::comic(comic.id).0, FileType::Directory, comic.name))
+                .collect(),
+            Self::TAGS_ID => self
+                .conn()
+                .ok()
+                .and_then(|conn| Tag::list_page(limit, offset, &conn).ok())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|tag| (Inode::tag(tag.id).0, FileType::Directory, tag.name))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    fn release_dir(&mut self, fh: u64) {
+        self.handles.lock().unwrap().remove(&fh);
+    }
+
+    fn children(&self, ino: Inode) -> Vec<(u64, FileType, String)> {
+        match ino.0 {
+            Self::ROOT_ID => {
+                let mut entries = vec![
+                    (Self::COMIC_ID, FileType::Directory, "comics".to_owned()),
+                    (Self::TAGS_ID, FileType::Directory, "tags".to_owned()),
+                    (Self::SEARCH_ID, FileType::Directory, "search".to_owned()),
+                    (Self::RECENT_ID, FileType::Directory, "recent".to_owned()),
+                    (Self::CONTROL_ID, FileType::Directory, ".comicfs".to_owned()),
+                ];
+                if self.trash {
+                    entries.push((Self::TRASH_ID, FileType::Directory, "trash".to_owned()));
+                }
+                entries
+            }
+            Self::CONTROL_ID => vec![(Self::STATS_ID, FileType::RegularFile, "stats".to_owned())],
+            Self::TRASH_ID => self
+                .conn()
+                .ok()
+                .and_then(|conn| Self::trash_entries(&conn).ok())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(ino, kind, name)| (ino.0, kind, name))
+                .collect(),
+            // Searches are made by looking up the term, there is nothing to list
+            Self::SEARCH_ID => vec![],
+            Self::RECENT_ID => self
+                .recent_results()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(ino, name)| (ino.0, FileType::Symlink, name))
+                .collect(),
+            Self::COMIC_ID => self
+                .conn()
+                .ok()
+                .and_then(|conn| Comic::list(&conn).ok())
+                .map(|comics| {
+                    comics
+                        .into_iter()
+                        .map(|comic| (Inode::comic(comic.id).0, FileType::Directory, comic.name))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            Self::TAGS_ID => self
+                .conn()
+                .ok()
+                .and_then(|conn| Tag::list(&conn))
+                .map(|tags| {
+                    tags.into_iter()
+                        .map(|tag| (Inode::tag(tag.id).0, FileType::Directory, tag.name))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            _ => match ino.kind() {
+                InodeKind::Comic => {
+                    let id = i32::try_from(ino.id()).unwrap();
+                    self.conn()
+                        .ok()
+                        .and_then(|conn| Episode::list_for_comic(id, &conn).ok())
+                        .map(|eposides| {
+                            eposides
+                                .into_iter()
+                                .map(|eposide| {
+                                    (
+                                        Inode::eposide(eposide.id).0,
+                                        FileType::Directory,
+                                        eposide.name,
+                                    )
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default()
+                }
+                InodeKind::Eposide => {
+                    let id = i32::try_from(ino.id()).unwrap();
+                    self.conn()
+                        .ok()
+                        .and_then(|conn| File::list_for_episode(id, &conn).ok())
+                        .map(|files| {
+                            files
+                                .into_iter()
+                                .map(|file| {
+                                    (Inode::file(file.id).0, FileType::RegularFile, file.name)
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default()
+                }
+                InodeKind::Tag => self
+                    .conn()
+                    .map(|conn| Taggables::taggables(ino.id().try_into().unwrap(), &conn))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|taggable| {
+                        let kind = taggable.kind();
+                        let (id, name) = match taggable {
+                            Taggables::Comic { id, name, .. } => (id, name),
+                            Taggables::Episode { id, name, .. } => (id, name),
+                            Taggables::File { id, name, .. } => (id, name),
+                        };
+                        (Inode::tagged_with_kind(id, kind).0, FileType::Symlink, name)
+                    })
+                    .collect(),
+                InodeKind::Search => self
+                    .search_term(ino)
+                    .and_then(|term| self.search_results(&term))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(ino, name)| (ino.0, FileType::Symlink, name))
+                    .collect(),
+                InodeKind::File
+                | InodeKind::Special
+                | InodeKind::Tagged
+                | InodeKind::TagList
+                | InodeKind::Cover
+                | InodeKind::Reorder
+                | InodeKind::SearchResult => vec![],
+            },
+        }
+    }
+}
+
+impl Filesystem for ComicFS {
+    #[tracing::instrument(fields(unique = _req.unique()),skip(self, _req,  reply))]
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup_entry(Inode::from(parent), name) {
+            Err(err) if err.errno() == ENOENT && caches_missing(Inode::from(parent), name) => {
+                self.reply_missing(reply)
+            }
+            res => self.reply_entry(reply, res),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.forget_inode(Inode::from(ino), nlookup);
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Inode::from(ino)),skip(self, _req, ino, reply))]
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        self.reply_attr(reply, self.attr(Inode::from(ino)));
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: u32, reply: ReplyOpen) {
+        match self.open_dir(Inode::from(ino)) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, fh, reply))]
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        self.read_dir(Inode::from(ino), fh, offset, &mut reply);
+        reply.ok();
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, fh, reply))]
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        self.read_dir_plus(Inode::from(ino), fh, offset, &mut reply);
+        reply.ok();
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(_ino)),skip(self, _req, _ino, _flags, reply))]
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.release_dir(fh);
+        reply.ok();
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique()),skip(self, _req, _ino, reply))]
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let stat = match statvfs(&self.storage) {
+            Ok(stat) => stat,
+            Err(err) => {
+                reply.error(FsError::from(err).errno());
+                return;
+            }
+        };
+        let files = self
+            .conn()
+            .and_then(|conn| Self::total_inodes(&conn).map_err(Into::into));
+        let files = match files {
+            Ok(files) => files,
+            Err(err) => {
+                reply.error(err.errno());
+                return;
+            }
+        };
+        let to_blocks = |count: u64| count * stat.fragment_size() / BLOCK_SIZE;
+        reply.statfs(
+            to_blocks(stat.blocks()),
+            to_blocks(stat.blocks_free()),
+            to_blocks(stat.blocks_available()),
+            files,
+            FREE_INODES,
+            BLOCK_SIZE as u32,
+            NAME_MAX,
+            BLOCK_SIZE as u32,
+        );
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
+    fn access(&mut self, _req: &Request<'_>, ino: u64, mask: u32, reply: ReplyEmpty) {
+        match check_access(Inode::from(ino).kind(), mask) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        match self.xattr_value(Inode::from(ino), name) {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, value, reply))]
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: u32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.set_xattr(Inode::from(ino), name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_xattr(Inode::from(ino), name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        reply_xattr(reply, size, &self.xattr_list(Inode::from(ino)));
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, fh, reply))]
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        self.reply_read(Inode::from(ino), fh, offset, size, reply);
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        reply: ReplyEntry,
+    ) {
+        let res = self.make_dir(Inode::from(parent), name);
+        self.reply_entry(reply, res);
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        match self.create_or_open(Inode::from(parent), name, flags) {
+            Ok((attr, fh)) => {
+                self.remember(attr.ino);
+                reply.created(&self.entry_timeout, &attr, 0, fh, 0);
+            }
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_entry(Inode::from(parent), name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.remove_dir(Inode::from(parent), name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.rename_entry(
+            Inode::from(parent),
+            name,
+            Inode::from(newparent),
+            newname,
+            flags,
+        ) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: u32, reply: ReplyOpen) {
+        match self.open_with_flags(Inode::from(ino), flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        match self.sync_handle(Inode::from(ino), fh, datasync) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(_ino)),skip(self, _req, _ino, _lock_owner, reply))]
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        match self.flush_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, _flags, _lock_owner, _flush, reply))]
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.release_handle(Inode::from(ino), fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let res = self.set_attr(Inode::from(ino), size, atime, mtime);
+        self.reply_attr(reply, res);
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino), len = data.len(), head = %HexBytes(&data[..data.len().min(16)])),skip(self, _req, ino, data, _flags, reply))]
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        match self.write_data(Inode::from(ino), fh, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, _fh, reply))]
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        match self.allocate(Inode::from(ino), offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.read_link(Inode::from(ino)) {
+            Ok(path) => {
+                info!(path = %path.display(), path.len = path.as_os_str().len());
+                reply.data(path.as_os_str().as_bytes());
+            }
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let res = self.link_entry(Inode::from(ino), Inode::from(newparent), newname);
+        self.reply_entry(reply, res);
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let res = self.symlink_entry(Inode::from(parent), name, link);
+        self.reply_entry(reply, res);
+    }
+}
+
+/// Read up to `size` bytes at `offset`, fewer only at the end of the blob. A single `read_at`
+/// may stop short, so it's repeated until the buffer is full.
+fn read_blob(file: &fs::File, offset: i64, size: u32) -> Result<Vec<u8>, FsError> {
+    let offset = u64::try_from(offset).unwrap();
+    let mut buf = vec![0; usize::try_from(size).unwrap()];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return Err(EIO.into()),
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+/// Reply the length of `value` when the caller probes with a zero `size`, the value itself when
+/// it fits, or `ERANGE` otherwise.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    let len = u32::try_from(value.len()).unwrap();
+    if size == 0 {
+        reply.size(len);
+    } else if len <= size {
+        reply.data(value);
+    } else {
+        reply.error(ERANGE);
+    }
+}
+
+/// Add `entries` to `reply` starting from `offset` until the reply buffer is full. The cookie
+/// of each entry is its 1-based index, so the kernel resumes right after the last entry it got.
+fn fill_dir(reply: &mut ReplyDirectory, entries: &[(u64, FileType, String)], offset: i64) {
+    add_dir_entries(reply, entries, 0, usize::try_from(offset).unwrap());
+}
+
+/// Add the part of a listing in `entries`, which starts at index `first` of the directory,
+/// skipping the entries before `offset`. Return true once the reply buffer is full.
+fn add_dir_entries(
+    reply: &mut ReplyDirectory,
+    entries: &[(u64, FileType, String)],
+    first: usize,
+    offset: usize,
+) -> bool {
+    for (i, (ino, kind, name)) in entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (first + i, entry))
+        .skip(offset.saturating_sub(first))
+    {
+        // i + 1 means the index of the next entry
+        if reply.add(*ino, (i + 1).try_into().unwrap(), *kind, name) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Up to `size` bytes of `content` at `offset`, for the files made up on `read`.
+fn slice_content(content: &[u8], offset: i64, size: u32) -> Vec<u8> {
+    let start = usize::try_from(offset).unwrap().min(content.len());
+    let end = (start + usize::try_from(size).unwrap()).min(content.len());
+    content[start..end].to_vec()
+}
+
+/// Whether `name` is taken by one of the files every episode has, so no file can be created
+/// or renamed under it.
+fn is_reserved(name: &OsStr) -> bool {
+    name == TAG_LIST_NAME || name == COVER_NAME || name == REORDER_NAME
+}
+
+/// Whether a lookup of `name` in `parent` that found nothing can be cached. That's the case in
+/// the directories of comics, episodes and tags, where names only show up by being added, but
+/// not for the reserved names nor under `/search` and `/recent`, which follow other entries.
+fn caches_missing(parent: Inode, name: &OsStr) -> bool {
+    let listed = match parent.kind() {
+        InodeKind::Comic | InodeKind::Eposide | InodeKind::Tag => true,
+        _ => parent.0 == ComicFS::COMIC_ID || parent.0 == ComicFS::TAGS_ID,
+    };
+    listed && !is_reserved(name)
+}
+
+/// Everything can be read, but only files and `.reorder` files can be written and only
+/// directories can be searched. Directories are changed through `mkdir`/`symlink` rather than
+/// written as files, and `.tags` files follow the tags.
+fn check_access(kind: InodeKind, mask: u32) -> Result<(), FsError> {
+    let mask = i32::try_from(mask).unwrap();
+    let is_file = matches!(
+        kind,
+        InodeKind::File | InodeKind::TagList | InodeKind::Cover | InodeKind::Reorder
+    );
+    let is_writable = matches!(kind, InodeKind::File | InodeKind::Reorder);
+    if (mask & W_OK != 0 && !is_writable) || (mask & X_OK != 0 && is_file) {
+        return Err(EACCES.into());
+    }
+    Ok(())
+}
+
+/// Convert a timestamp stored in the database, which is in UTC, to a `SystemTime`.
+fn naive_to_systemtime(time: NaiveDateTime) -> SystemTime {
+    let nanos = Duration::from_nanos(u64::from(time.timestamp_subsec_nanos()));
+    match u64::try_from(time.timestamp()) {
+        Ok(secs) => SystemTime::UNIX_EPOCH + Duration::from_secs(secs) + nanos,
+        Err(_) => {
+            SystemTime::UNIX_EPOCH - Duration::from_secs(time.timestamp().unsigned_abs()) + nanos
+        }
+    }
+}
+
+/// Like the `relatime` mount option, a read only updates an access time that is older than
+/// the modification time, or older than a day, so most reads don't write to the database.
+fn atime_is_stale(info: &File, now: NaiveDateTime) -> bool {
+    let mtime = info.mtime.unwrap_or(info.created_at);
+    match info.atime {
+        Some(atime) => atime <= mtime || now - atime >= chrono::Duration::days(1),
+        None => true,
+    }
+}
+
+fn systemtime_to_naive(time: SystemTime) -> NaiveDateTime {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => NaiveDateTime::from_timestamp(
+            i64::try_from(since.as_secs()).unwrap(),
+            since.subsec_nanos(),
+        ),
+        Err(err) => {
+            let before = err.duration();
+            NaiveDateTime::from_timestamp(0, 0) - chrono::Duration::from_std(before).unwrap()
+        }
+    }
+}
+
+/// MIME type of the content starting with `data`, judged by the magic bytes of the images and
+/// archives comics are stored in.
+fn sniff_mime_type(data: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"PK\x03\x04", "application/vnd.comicbook+zip"),
+        (b"Rar!\x1a\x07", "application/vnd.comicbook-rar"),
+        (b"%PDF-", "application/pdf"),
+    ];
+    if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    SIGNATURES
+        .iter()
+        .find(|(magic, _)| data.starts_with(magic))
+        .map_or("application/octet-stream", |(_, mime_type)| mime_type)
+}
+
+fn convert_file_type(kind: fs::FileType) -> fuse::FileType {
+    if kind.is_dir() {
+        fuse::FileType::Directory
+    } else if kind.is_file() {
+        fuse::FileType::RegularFile
+    } else if kind.is_symlink() {
+        fuse::FileType::Symlink
+    } else {
+        unreachable!("doesn't support other file kind")
+    }
+}
+
+/// Mount the filesystem. `uid`/`gid` options set the owner of the files, and the custom
+/// `threads=`, `lookup_cache=`, `recent=`, `attr_timeout=`, `entry_timeout=`, `mmap`,
+/// `noatime`, `case_insensitive`, `trash` and `autorefresh` options are handled here, the rest are
+/// passed to FUSE. `connect` opens the pool with the number of connections it's given, one per
+/// worker thread as a handler holds at most one at a time. `database` is the file behind the
+/// pool, watched with `autorefresh`.
+pub fn mount<F>(
+    connect: F,
+    database: &Path,
+    mountpoint: &OsStr,
+    storage: &Path,
+    mut options: MountOptions,
+) -> io::Result<()>
+where
+    F: FnOnce(u32) -> Pool,
+{
+    let mut threads = DEFAULT_THREADS;
+    if let Some(count) = options.take_custom("threads") {
+        threads = count.parse().map_err(|_| invalid_option("threads"))?;
+    }
+    let pool = connect(u32::try_from(threads.max(1)).unwrap_or(u32::MAX));
+    let storage = env::current_dir()?.join(storage);
+    let mut builder = ComicFSBuilder::new(pool)
+        .base(fs::canonicalize(mountpoint)?)
+        .storage(storage);
+    if let Some(uid) = options.get_uid() {
+        builder = builder.uid(uid);
+    }
+    if let Some(gid) = options.get_gid() {
+        builder = builder.gid(gid);
+    }
+    let mut fs = builder.build();
+    if let Some(size) = options.take_custom("lookup_cache") {
+        fs = fs.with_lookup_cache(size.parse().map_err(|_| invalid_option("lookup_cache"))?);
+    }
+    if let Some(limit) = options.take_custom("recent") {
+        fs.recent_limit = limit.parse().map_err(|_| invalid_option("recent"))?;
+    }
+    if let Some(timeout) = options.take_custom("attr_timeout") {
+        fs.attr_timeout = parse_timeout(&timeout).ok_or_else(|| invalid_option("attr_timeout"))?;
+    }
+    if let Some(timeout) = options.take_custom("entry_timeout") {
+        fs.entry_timeout =
+            parse_timeout(&timeout).ok_or_else(|| invalid_option("entry_timeout"))?;
+    }
+    fs.mmap = options.take_custom_flag("mmap");
+    fs.noatime = options.take_custom_flag("noatime");
+    fs.case_insensitive = options.take_custom_flag("case_insensitive");
+    fs.trash = options.take_custom_flag("trash");
+    let autorefresh = options.take_custom_flag("autorefresh");
+    if options.get_fsname().is_none() {
+        options = options.fsname("comic");
+    }
+    let fuse_options = options.to_args();
+    let fuse_options = fuse_options
+        .iter()
+        .map(|o| o.as_os_str())
+        .collect::<Vec<_>>();
+    let mut session = fuse::Session::new(fs.clone(), Path::new(mountpoint), &fuse_options)?;
+    session.readdirplus = true;
+    session.set_signal_handlers()?;
+    if autorefresh {
+        let notifier = session.notifier();
+        watch_database(database, AUTOREFRESH_DEBOUNCE, move || {
+            fs.refresh_kernel(&notifier)
+        })?;
+    }
+    session.run_multithreaded(threads)
+}
+
+/// Check the blobs in `storage` against the database, see `ComicFS::fsck`. Returns true if
+/// every blob is intact.
+pub fn fsck(pool: Pool, storage: &Path) -> io::Result<bool> {
+    let storage = env::current_dir()?.join(storage);
+    let fs = ComicFS::new(pool, PathBuf::new(), storage);
+    let problems = fs
+        .fsck()
+        .map_err(|err| io::Error::from_raw_os_error(err.errno()))?;
+    Ok(problems == 0)
+}
+
+/// Sets of files sharing a blob, see `ComicFS::dupes`.
+pub fn dupes(pool: Pool) -> io::Result<Vec<(String, Vec<PathBuf>)>> {
+    let fs = ComicFS::new(pool, PathBuf::new(), PathBuf::new());
+    fs.dupes()
+        .map_err(|err| io::Error::from_raw_os_error(err.errno()))
+}
+
+/// Import the directory tree `dir` into the database and `storage`, see `ComicFS::import_tree`.
+/// Returns the number of files imported.
+pub fn import(pool: Pool, storage: &Path, dir: &Path) -> io::Result<usize> {
+    let storage = env::current_dir()?.join(storage);
+    let fs = ComicFS::new(pool, PathBuf::new(), storage);
+    fs.import_tree(dir)
+        .map_err(|err| io::Error::from_raw_os_error(err.errno()))
+}
+
+/// Export the comics to `dir`, see `ComicFS::export_tree`. Returns the number of files exported.
+pub fn export(pool: Pool, storage: &Path, dir: &Path, with_tags: bool) -> io::Result<usize> {
+    let storage = env::current_dir()?.join(storage);
+    let fs = ComicFS::new(pool, PathBuf::new(), storage);
+    fs.export_tree(dir, with_tags)
+        .map_err(|err| io::Error::from_raw_os_error(err.errno()))
+}
+
+/// Entries of a directory being imported, by name. Names have to be UTF-8 to be stored, other
+/// entries are skipped.
+fn import_entries(dir: &Path) -> io::Result<Vec<(String, PathBuf, fs::FileType)>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        match entry.file_name().into_string() {
+            Ok(name) => entries.push((name, entry.path(), entry.file_type()?)),
+            Err(name) => warn!(?name, "skip entry whose name isn't UTF-8"),
+        }
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+/// Whether the kernel may still take notifications after `res`. It fails those about entries
+/// it has nothing cached for with `ENOENT`, which is fine.
+fn notified(res: io::Result<()>) -> bool {
+    match res {
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EBADF) | Some(libc::ENODEV)) => false,
+        Err(err) => {
+            debug!(%err, "kernel refused invalidation");
+            true
+        }
+        Ok(()) => true,
+    }
+}
+
+/// Call `on_change` from a background thread each time the SQLite `database` or its
+/// write-ahead log was written to and then left alone for `debounce`, until it returns false.
+fn watch_database<F>(
+    database: &Path,
+    debounce: Duration,
+    mut on_change: F,
+) -> io::Result<thread::JoinHandle<()>>
+where
+    F: FnMut() -> bool + Send + 'static,
+{
+    let database = database.canonicalize()?;
+    let (dir, name) = match (database.parent(), database.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_owned()),
+        _ => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+    };
+    let mut wal = name.clone();
+    wal.push("-wal");
+    let inotify = Inotify::init(InitFlags::IN_CLOEXEC).map_err(nix_to_io)?;
+    let flags = AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO;
+    if let Err(err) = inotify.add_watch(dir, flags) {
+        let _ = close(inotify.as_raw_fd());
+        return Err(nix_to_io(err));
+    }
+    let timeout = debounce.as_millis().try_into().unwrap_or(i32::MAX);
+    Ok(thread::spawn(move || {
+        let mut changed = false;
+        loop {
+            let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, if changed { timeout } else { -1 }) {
+                Ok(0) => {
+                    changed = false;
+                    if !on_change() {
+                        break;
+                    }
+                }
+                Ok(_) => match inotify.read_events() {
+                    Ok(events) => {
+                        changed |= events
+                            .iter()
+                            .any(|event| matches!(&event.name, Some(n) if *n == name || *n == wal));
+                    }
+                    Err(err) => {
+                        error!(%err, "stop watching the database");
+                        break;
+                    }
+                },
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(err) => {
+                    error!(%err, "stop watching the database");
+                    break;
+                }
+            }
+        }
+        let _ = close(inotify.as_raw_fd());
+    }))
+}
+
+fn nix_to_io(err: nix::Error) -> io::Error {
+    io::Error::from_raw_os_error(FsError::from(err).errno())
+}
+
+/// Timeout given in seconds, possibly fractional, by a mount option.
+fn parse_timeout(secs: &str) -> Option<Duration> {
+    match secs.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs >= 0.0 => Some(Duration::from_secs_f64(secs)),
+        _ => None,
+    }
+}
+
+fn invalid_option(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid {} option", name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_access, fill_dir, naive_to_systemtime, parse_timeout, watch_database, Clock, ComicFS,
+        ComicFSBuilder, Handle, Inode, InodeKind, MAX_COMMENT_SIZE, READDIR_PAGE,
+    };
+    use crate::models::{
+        Comic, Episode, File, NewComic, NewEposide, NewFile, NewTag, NewTaggable, Parents, Pool,
+        Tag, Taggable, TaggableKind, Taggables,
+    };
+    use chrono::NaiveDateTime;
+    use diesel::{connection::SimpleConnection, prelude::*, r2d2::ConnectionManager};
+    use fuse::{
+        FileType, Reply, ReplyAttr, ReplyDirectory, ReplyDirectoryPlus, ReplyEntry, ReplySender,
+    };
+    use libc::{
+        E2BIG, EACCES, EBADF, EEXIST, EINVAL, EIO, ELOOP, ENODATA, ENOENT, ENOSYS, ENOTDIR,
+        ENOTEMPTY, EOPNOTSUPP, EPERM, EXDEV, O_EXCL, O_TRUNC, O_WRONLY, R_OK, W_OK,
+    };
+    use nix::fcntl::FallocateFlags;
+    use sha2::{Digest, Sha256};
+    use std::{
+        convert::TryInto,
+        env,
+        ffi::OsStr,
+        fs,
+        io::Write,
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc::{channel, Sender},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant, SystemTime},
+    };
+    use tempfile::NamedTempFile;
+
+    struct CaptureSender(Sender<Vec<u8>>);
+
+    impl ReplySender for CaptureSender {
+        fn send(&self, data: &[&[u8]]) {
+            self.0.send(data.concat()).unwrap();
+        }
+    }
+
+    /// Every connection of the pool has to see the same database, so it's kept in a temporary
+    /// file instead of in memory. The database is removed once the file is dropped.
+    fn test_pool() -> (NamedTempFile, Pool) {
+        let db = NamedTempFile::new().unwrap();
+        let manager = ConnectionManager::new(db.path().to_str().unwrap());
+        let pool = Pool::builder().build(manager).unwrap();
+        run_migrations(&pool.get().unwrap());
+        (db, pool)
+    }
+
+    /// The migrations built into the test binary, in order.
+    fn run_migrations(conn: &SqliteConnection) {
+        for sql in &[
+            include_str!("../migrations/2020-06-27-114404_create_files/up.sql"),
+            include_str!("../migrations/2020-06-27-115042_create_eposides/up.sql"),
+            include_str!("../migrations/2020-06-27-115103_create_comics/up.sql"),
+            include_str!("../migrations/2020-08-07-114519_create_tags/up.sql"),
+            include_str!("../migrations/2020-08-07-114615_create_taggables/up.sql"),
+            include_str!("../migrations/2020-09-12-093512_add_times_to_files/up.sql"),
+            include_str!("../migrations/2020-09-13-101530_make_content_hash_nullable/up.sql"),
+            include_str!("../migrations/2020-09-14-140215_add_unique_names/up.sql"),
+            include_str!("../migrations/2020-09-15-083044_add_size_to_files/up.sql"),
+            include_str!("../migrations/2020-09-16-121907_add_mime_type_to_files/up.sql"),
+            include_str!("../migrations/2020-09-17-093015_add_foreign_keys/up.sql"),
+            include_str!("../migrations/2020-09-18-141240_check_taggable_type/up.sql"),
+            include_str!("../migrations/2020-09-19-102236_add_comment_to_files/up.sql"),
+            include_str!("../migrations/2020-09-20-084512_index_files_content_hash/up.sql"),
+            include_str!("../migrations/2020-09-21-090215_add_deleted_at/up.sql"),
+        ] {
+            conn.batch_execute(sql).unwrap();
+        }
+    }
+
+    /// A filesystem over an in-memory database holding the comic `comic` tagged `finished`,
+    /// with the episode `ep1` and the empty file `001.jpg` in it. The connections of the pool
+    /// share the database by its name, unique to the call, and it's gone with the last of them.
+    fn test_fs() -> ComicFS {
+        static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+        let url = format!(
+            "file:comic-fs-test-{}?mode=memory&cache=shared",
+            NEXT_DB.fetch_add(1, Ordering::Relaxed)
+        );
+        let pool = Pool::builder().build(ConnectionManager::new(url)).unwrap();
+        run_migrations(&pool.get().unwrap());
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        fs.create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        let tag = fs
+            .make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+            .unwrap();
+        fs.tag_inode(Inode::from(tag.ino), Inode::from(comic.ino))
+            .unwrap();
+        fs
+    }
+
+    /// Blobs are content addressed, so every test can share one storage.
+    fn storage_base() -> PathBuf {
+        env::temp_dir().join("comic-fs-test")
+    }
+
+    /// Names of the entries in a raw `readdir` reply, skipping the `fuse_out_header`.
+    fn dirent_names(data: &[u8]) -> Vec<String> {
+        let mut names = vec![];
+        let mut pos = 16;
+        while pos < data.len() {
+            let namelen = u32::from_ne_bytes(data[pos + 16..pos + 20].try_into().unwrap()) as usize;
+            let name = &data[pos + 24..pos + 24 + namelen];
+            names.push(String::from_utf8(name.to_vec()).unwrap());
+            pos += (24 + namelen + 7) & !7;
+        }
+        names
+    }
+
+    #[test]
+    fn test_inode_id_fits_node_mask() {
+        let inode = Inode::checked(Inode::IS_FILE, Inode::NODE_MASK).unwrap();
+        assert_eq!(inode.kind(), InodeKind::File);
+        assert_eq!(inode.id(), Inode::NODE_MASK);
+        assert!(Inode::checked(Inode::IS_FILE, Inode::NODE_MASK + 1).is_none());
+        assert!(Inode::checked(Inode::IS_COMIC, Inode::IS_TAGGED).is_none());
+
+        let inode = Inode::tagged_with_kind(i32::MAX, TaggableKind::File);
+        assert_eq!(inode.kind(), InodeKind::Tagged);
+        assert_eq!(inode.id(), i32::MAX as u64);
+        assert_eq!(inode.tagged_kind(), Some(TaggableKind::File));
+    }
+
+    #[test]
+    fn test_inode_round_trips_through_serde() {
+        let episode = Inode::eposide(7);
+        let comic = Inode::comic(3);
+        for (inode, kind) in &[
+            (Inode::file(42), InodeKind::File),
+            (episode, InodeKind::Eposide),
+            (comic, InodeKind::Comic),
+            (Inode::tag(5), InodeKind::Tag),
+            (
+                Inode::tagged_with_kind(9, TaggableKind::Eposide),
+                InodeKind::Tagged,
+            ),
+            (comic.tag_list(), InodeKind::TagList),
+            (episode.tag_list(), InodeKind::TagList),
+            (Inode::search(2), InodeKind::Search),
+            (
+                Inode::search_result(42, TaggableKind::File),
+                InodeKind::SearchResult,
+            ),
+            (episode.cover(), InodeKind::Cover),
+            (episode.reorder(), InodeKind::Reorder),
+            (Inode::from(ComicFS::ROOT_ID), InodeKind::Special),
+        ] {
+            let json = serde_json::to_string(inode).unwrap();
+            assert_eq!(json, inode.0.to_string());
+            let back: Inode = serde_json::from_str(&json).unwrap();
+            assert_eq!((back, back.kind()), (*inode, *kind));
+        }
+
+        for value in &[
+            Inode::IS_FILE | Inode::IS_COMIC | 1,
+            Inode::comic(3).0 | Inode::IS_COVER,
+            Inode::IS_COVER | Inode::IS_REORDER | Inode::eposide(7).0,
+            Inode::file(42).0 | 1 << 57,
+        ] {
+            assert_eq!(Inode::try_new(*value), Err(EINVAL.into()));
+            assert!(serde_json::from_str::<Inode>(&value.to_string()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_inode_kind_names() {
+        assert_eq!("comic".parse::<InodeKind>(), Ok(InodeKind::Comic));
+        assert_eq!("episode".parse::<InodeKind>(), Err(()));
+        for kind in &[
+            InodeKind::File,
+            InodeKind::Eposide,
+            InodeKind::Comic,
+            InodeKind::Tag,
+            InodeKind::Tagged,
+            InodeKind::TagList,
+            InodeKind::Search,
+            InodeKind::SearchResult,
+            InodeKind::Cover,
+            InodeKind::Reorder,
+            InodeKind::Special,
+        ] {
+            assert_eq!(kind.to_string().parse::<InodeKind>(), Ok(*kind));
+        }
+        for kind in &[
+            TaggableKind::Comic,
+            TaggableKind::Eposide,
+            TaggableKind::File,
+        ] {
+            assert_eq!(InodeKind::from(*kind).to_string(), kind.as_ref());
+        }
+    }
+
+    #[test]
+    fn test_inode_is_special() {
+        let inode = Inode(1);
+        assert!(inode.is_special());
+    }
+
+    #[test]
+    fn test_readdir_paginates_past_one_reply() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        diesel::insert_into(crate::schema::comics::table)
+            .values(&NewComic { name: "comic" })
+            .execute(&conn)
+            .unwrap();
+        let comic = Comic::find_by_name("comic", false, &conn).unwrap();
+        let expected = (0..500)
+            .map(|i| format!("episode-{:03}", i))
+            .collect::<Vec<_>>();
+        for name in &expected {
+            diesel::insert_into(crate::schema::eposides::table)
+                .values(&NewEposide {
+                    name,
+                    comic_id: comic.id,
+                })
+                .execute(&conn)
+                .unwrap();
+        }
+        let fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let entries = fs.dir_entries(Inode::comic(comic.id));
+
+        let (tx, rx) = channel();
+        let mut names = vec![];
+        let mut replies = 0;
+        loop {
+            let mut reply = ReplyDirectory::new(0, CaptureSender(tx.clone()), 4096);
+            fill_dir(&mut reply, &entries, names.len().try_into().unwrap());
+            reply.ok();
+            let page = dirent_names(&rx.recv().unwrap());
+            if page.is_empty() {
+                break;
+            }
+            names.extend(page);
+            replies += 1;
+        }
+        assert!(replies > 1);
+        assert_eq!(names[..3], [".", "..", ".tags"]);
+        assert_eq!(names[3..], expected[..]);
+    }
+
+    #[test]
+    fn test_tags_are_listed_by_page() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let expected = (0..100)
+            .map(|i| format!("tag-{:03}", i))
+            .collect::<Vec<_>>();
+        for name in &expected {
+            NewTag { name }.insert(&conn).unwrap();
+        }
+        let page = Tag::list_page(25, 25, &conn).unwrap();
+        assert_eq!(
+            page.iter().map(|tag| tag.name.as_str()).collect::<Vec<_>>(),
+            expected[25..50]
+        );
+
+        // Without a snapshot, readdir goes through the pages
+        let fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let (tx, rx) = channel();
+        let mut names = vec![];
+        loop {
+            let mut reply = ReplyDirectory::new(0, CaptureSender(tx.clone()), 512);
+            let offset = names.len().try_into().unwrap();
+            fs.read_dir(Inode::from(ComicFS::TAGS_ID), 0, offset, &mut reply);
+            reply.ok();
+            let page = dirent_names(&rx.recv().unwrap());
+            if page.is_empty() {
+                break;
+            }
+            names.extend(page);
+        }
+        assert_eq!(names[..2], [".", ".."]);
+        assert_eq!(names[2..], expected[..]);
+    }
+
+    #[test]
+    fn test_opened_comics_are_listed_by_page() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let mut expected = (0..READDIR_PAGE + 6)
+            .map(|i| format!("comic-{:03}", i))
+            .collect::<Vec<_>>();
+        for name in &expected {
+            NewComic { name }.insert(&conn).unwrap();
+        }
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comics = Inode::from(ComicFS::COMIC_ID);
+        let fh = fs.open_dir(comics).unwrap();
+        assert!(fs.handles.lock().unwrap()[&fh].entries.is_none());
+
+        // A comic added after the first reply is listed, as the pages are read on readdir
+        let (tx, rx) = channel();
+        let mut names = vec![];
+        loop {
+            let mut reply = ReplyDirectory::new(0, CaptureSender(tx.clone()), 512);
+            let offset = names.len().try_into().unwrap();
+            fs.read_dir(comics, fh, offset, &mut reply);
+            reply.ok();
+            let page = dirent_names(&rx.recv().unwrap());
+            if page.is_empty() {
+                break;
+            }
+            if names.is_empty() {
+                NewComic { name: "zzz" }.insert(&conn).unwrap();
+                expected.push("zzz".to_owned());
+            }
+            names.extend(page);
+        }
+        assert_eq!(names[..2], [".", ".."]);
+        assert_eq!(names[2..], expected[..]);
+
+        // readdirplus pages the same way, with the attributes of each page
+        let mut inos = vec![];
+        loop {
+            let mut reply = ReplyDirectoryPlus::new(0, CaptureSender(tx.clone()), 4096);
+            let offset = inos.len().try_into().unwrap();
+            fs.read_dir_plus(comics, fh, offset, &mut reply);
+            reply.ok();
+            let data = rx.recv().unwrap();
+            if data.len() == 16 {
+                break;
+            }
+            let mut pos = 16;
+            while pos < data.len() {
+                let entry = &data[pos..];
+                let ino = u64::from_ne_bytes(entry[..8].try_into().unwrap());
+                let attr_ino = u64::from_ne_bytes(entry[40..48].try_into().unwrap());
+                let len = u32::from_ne_bytes(entry[144..148].try_into().unwrap()) as usize;
+                assert_eq!(ino, attr_ino);
+                inos.push(ino);
+                pos += (152 + len + 7) & !7;
+            }
+        }
+        fs.release_dir(fh);
+        let comics = Comic::list(&conn).unwrap();
+        assert_eq!(inos.len(), 2 + comics.len());
+        for (ino, comic) in inos[2..].iter().zip(&comics) {
+            assert_eq!(*ino, Inode::comic(comic.id).0);
+        }
+    }
+
+    #[test]
+    fn test_episodes_and_files_are_listed_by_name() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let mut fs = ComicFS::new(pool.clone(), PathBuf::from("/mnt"), storage_base());
+        let comic = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+                .unwrap()
+                .ino,
+        );
+        for name in &["ep2", "ep10", "ep1"] {
+            fs.make_dir(comic, OsStr::new(name)).unwrap();
+        }
+        let comic_id = comic.id().try_into().unwrap();
+        let episodes = Episode::list_for_comic(comic_id, &conn).unwrap();
+        assert_eq!(
+            episodes
+                .iter()
+                .map(|ep| ep.name.as_str())
+                .collect::<Vec<_>>(),
+            ["ep1", "ep10", "ep2"]
+        );
+
+        let episode = Inode::eposide(episodes[0].id);
+        for name in &["b.jpg", "c.jpg", "a.jpg"] {
+            fs.create_file(episode, OsStr::new(name)).unwrap();
+        }
+        let files = File::list_for_episode(episodes[0].id, &conn).unwrap();
+        assert_eq!(
+            files
+                .iter()
+                .map(|file| file.name.as_str())
+                .collect::<Vec<_>>(),
+            ["a.jpg", "b.jpg", "c.jpg"]
+        );
+        let names = fs
+            .children(episode)
+            .into_iter()
+            .map(|(_, _, name)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.jpg", "b.jpg", "c.jpg"]);
+    }
+
+    #[test]
+    fn test_comics_and_tags_are_listed_by_name() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        for name in &["zeta", "alpha", "mu"] {
+            fs.make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new(name))
+                .unwrap();
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new(name))
+                .unwrap();
+        }
+        for dir in &[ComicFS::COMIC_ID, ComicFS::TAGS_ID] {
+            let names = fs
+                .dir_entries(Inode::from(*dir))
+                .into_iter()
+                .map(|(_, _, name)| name)
+                .collect::<Vec<_>>();
+            assert_eq!(names, [".", "..", "alpha", "mu", "zeta"]);
+
+            let (tx, rx) = channel();
+            let mut reply = ReplyDirectory::new(0, CaptureSender(tx), 4096);
+            fs.read_dir(Inode::from(*dir), 0, 0, &mut reply);
+            reply.ok();
+            assert_eq!(dirent_names(&rx.recv().unwrap()), names);
+        }
+    }
+
+    #[test]
+    fn test_symlink_targets_are_taken_from_the_mount() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episodes = ["ep1", "ep2"]
+            .iter()
+            .map(|name| {
+                fs.make_dir(Inode::from(comic.ino), OsStr::new(name))
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let tag = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+                .unwrap()
+                .ino,
+        );
+
+        let targets = ["/mnt/comics/./comic/ep1", "../../comics/comic/ep2"];
+        for (target, episode) in targets.iter().zip(&episodes) {
+            let attr = fs
+                .symlink_entry(tag, OsStr::new("ep"), Path::new(target))
+                .unwrap();
+            let path = fs.read_link(Inode::from(attr.ino)).unwrap();
+            assert_eq!(
+                fs.resolve(path.strip_prefix("/mnt").unwrap()),
+                Some(Inode::from(episode.ino))
+            );
+        }
+        for target in &["/srv/comics/comic", "../../../../etc"] {
+            assert_eq!(
+                fs.symlink_entry(tag, OsStr::new("out"), Path::new(target)),
+                Err(EXDEV.into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_symlink_to_missing_target_fails() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let tag = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+                .unwrap()
+                .ino,
+        );
+        let cases = [
+            ("/var/lib/comics/comic", EXDEV),
+            ("/mnt/comics/missing", ENOENT),
+            ("../../elsewhere", ENOENT),
+            ("/mnt/search", EPERM),
+        ];
+        for (target, errno) in &cases {
+            assert_eq!(
+                fs.symlink_entry(tag, OsStr::new("link"), Path::new(target)),
+                Err((*errno).into()),
+                "{}",
+                target
+            );
+        }
+        assert_eq!(fs.dir_entries(tag).len(), 2);
+    }
+
+    #[test]
+    fn test_tagged_episode_of_deleted_comic_is_gone() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool.clone(), PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        let tag = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+                .unwrap()
+                .ino,
+        );
+        let tagged = Inode::from(
+            fs.link_entry(episode, tag, OsStr::new("ep1"))
+                .and_then(|_| fs.lookup_entry(tag, OsStr::new("comic_ep1")))
+                .unwrap()
+                .ino,
+        );
+        assert_eq!(
+            fs.resolve_inode_checked(episode),
+            Ok(PathBuf::from("/mnt/comics/comic/ep1"))
+        );
+
+        // Without foreign keys the episode is left behind, pointing at a missing comic
+        diesel::delete(crate::schema::comics::table)
+            .execute(&pool.get().unwrap())
+            .unwrap();
+        assert_eq!(fs.resolve_inode_checked(episode), Err(ENOENT.into()));
+        assert_eq!(fs.read_link(tagged), Err(ENOENT.into()));
+        assert_eq!(fs.attr(tagged), Err(ENOENT.into()));
+        assert_eq!(
+            fs.find_entry(tag.0, OsStr::new("comic_ep1")),
+            Err(ENOENT.into())
+        );
+    }
+
+    #[test]
+    fn test_resolve_inode_stops_at_depth_limit() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let file = Inode::from(
+            fs.create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+                .unwrap()
+                .ino,
+        );
+        // file, episode, comic, comics and the root, then the end of the walk
+        assert_eq!(
+            fs.resolve_inode_within(file, 6),
+            Ok(PathBuf::from("/mnt/comics/comic/ep1/001.jpg"))
+        );
+        // A walk that would go on longer, like through a cycle, is cut
+        assert_eq!(fs.resolve_inode_within(file, 5), Err(ELOOP.into()));
+    }
+
+    #[test]
+    fn test_access_mask() {
+        let file = Inode::file(1).kind();
+        let comic = Inode::comic(1).kind();
+        assert_eq!(check_access(file, R_OK as u32), Ok(()));
+        assert_eq!(check_access(file, (R_OK | W_OK) as u32), Ok(()));
+        assert_eq!(check_access(comic, R_OK as u32), Ok(()));
+        assert_eq!(check_access(comic, W_OK as u32), Err(EACCES.into()));
+    }
+
+    #[test]
+    fn test_open_same_file_twice_gets_distinct_handles() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        diesel::insert_into(crate::schema::comics::table)
+            .values(&NewComic { name: "comic" })
+            .execute(&conn)
+            .unwrap();
+        let comic = Comic::find_by_name("comic", false, &conn).unwrap();
+        diesel::insert_into(crate::schema::eposides::table)
+            .values(&NewEposide {
+                name: "ep1",
+                comic_id: comic.id,
+            })
+            .execute(&conn)
+            .unwrap();
+        let episode = Episode::find_by_comic_and_name(comic.id, "ep1", false, &conn).unwrap();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: episode.id,
+        }
+        .insert(&conn)
+        .unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+
+        let first = fs.open_handle(Inode::file(file.id)).unwrap();
+        let second = fs.open_handle(Inode::file(file.id)).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs.handles.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_read_increments_access_count() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&conn)
+        .unwrap();
+        assert_eq!(file.access_count, 0);
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let mut blob = tempfile::tempfile().unwrap();
+        blob.write_all(b"content").unwrap();
+        let fh = fs.insert_handle(Handle {
+            blob: Some(blob),
+            pending: None,
+            entries: None,
+            attrs: None,
+            map: None,
+            buffer: None,
+        });
+
+        let ino = Inode::file(file.id);
+        assert_eq!(fs.read_data(ino, fh, 0, 4).unwrap(), b"cont");
+        assert_eq!(fs.read_data(ino, fh, 4, 4).unwrap(), b"ent");
+        let file = File::find(file.id, &fs.conn().unwrap()).unwrap();
+        assert_eq!(file.access_count, 1);
+    }
+
+    #[test]
+    fn test_readdirplus_matches_getattr() {
+        let mut fs = test_fs();
+        let comics = Inode::from(ComicFS::COMIC_ID);
+        let comic = Inode::from(fs.lookup_entry(comics, OsStr::new("comic")).unwrap().ino);
+        let episode = Inode::from(fs.lookup_entry(comic, OsStr::new("ep1")).unwrap().ino);
+        let file = fs.create_file(episode, OsStr::new("002.jpg")).unwrap();
+        let tags = Inode::from(ComicFS::TAGS_ID);
+        let tag = fs.lookup_entry(tags, OsStr::new("finished")).unwrap();
+        fs.tag_inode(Inode::from(tag.ino), Inode::from(file.ino))
+            .unwrap();
+        let (tx, rx) = channel();
+
+        for (dir, expected) in &[
+            (comics, vec![".", "..", "comic"]),
+            (comic, vec![".", "..", ".tags", "ep1"]),
+            (
+                episode,
+                vec![".", "..", ".tags", "cover", "001.jpg", "002.jpg"],
+            ),
+            (tags, vec![".", "..", "finished"]),
+        ] {
+            let fh = fs.open_dir(*dir).unwrap();
+            let mut reply = ReplyDirectoryPlus::new(0, CaptureSender(tx.clone()), 4096);
+            fs.read_dir_plus(*dir, fh, 0, &mut reply);
+            reply.ok();
+            let data = rx.recv().unwrap();
+            fs.release_dir(fh);
+
+            // Each entry is the entry of a lookup, with the attributes from byte 40 on, then
+            // the directory entry with the name length at byte 144 and the name at byte 152
+            let mut names = vec![];
+            let mut pos = 16;
+            while pos < data.len() {
+                let entry = &data[pos..];
+                let ino = u64::from_ne_bytes(entry[..8].try_into().unwrap());
+                let len = u32::from_ne_bytes(entry[144..148].try_into().unwrap()) as usize;
+                let name = String::from_utf8(entry[152..152 + len].to_vec()).unwrap();
+                let attr = fs.attr(Inode::from(ino));
+                fs.reply_attr(ReplyAttr::new(0, CaptureSender(tx.clone())), attr);
+                assert_eq!(entry[40..128], rx.recv().unwrap()[32..], "{}", name);
+                names.push(name);
+                pos += (152 + len + 7) & !7;
+            }
+            assert_eq!(&names, expected);
+        }
+        assert_eq!(fs.lookups.lock().unwrap().get(&file.ino), Some(&1));
+    }
+
+    #[test]
+    fn test_replies_use_configured_timeouts() {
+        let mut fs = test_fs();
+        fs.attr_timeout = parse_timeout("2.5").unwrap();
+        fs.entry_timeout = parse_timeout("30").unwrap();
+        let (tx, rx) = channel();
+
+        let attr = fs.attr(Inode::from(ComicFS::COMIC_ID));
+        fs.reply_attr(ReplyAttr::new(0, CaptureSender(tx.clone())), attr);
+        let data = rx.recv().unwrap();
+        // attr_valid and attr_valid_nsec follow the header
+        assert_eq!(data[16..24], 2u64.to_ne_bytes());
+        assert_eq!(data[24..28], 500_000_000u32.to_ne_bytes());
+
+        let entry = fs.lookup_entry(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"));
+        fs.reply_entry(ReplyEntry::new(0, CaptureSender(tx)), entry);
+        let data = rx.recv().unwrap();
+        // entry_valid and attr_valid follow the header, the node id and the generation
+        assert_eq!(data[32..40], 30u64.to_ne_bytes());
+        assert_eq!(data[40..48], 30u64.to_ne_bytes());
+
+        // The lookup cache keeps the entry for as long as the kernel does
+        let key = (ComicFS::COMIC_ID, OsStr::new("comic").to_owned());
+        let cache = fs.lookup_cache.as_ref().unwrap();
+        cache.lock().unwrap().get_mut(&key).unwrap().1 -= Duration::from_secs(2);
+        let hits = fs.cache_hits.load(Ordering::Relaxed);
+        fs.lookup_entry(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        assert_eq!(fs.cache_hits.load(Ordering::Relaxed), hits + 1);
+
+        assert_eq!(parse_timeout("-1"), None);
+        assert_eq!(parse_timeout("inf"), None);
+    }
+
+    #[test]
+    fn test_attr_uses_configured_owner() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        fs.uid = 1234;
+        fs.gid = 5678;
+
+        let attr = fs.attr(Inode::from(ComicFS::ROOT_ID)).unwrap();
+        assert_eq!((attr.uid, attr.gid), (1234, 5678));
+    }
+
+    #[test]
+    fn test_naive_to_systemtime() {
+        let time = NaiveDateTime::from_timestamp(1_600_000_000, 500);
+        assert_eq!(
+            naive_to_systemtime(time),
+            SystemTime::UNIX_EPOCH + Duration::new(1_600_000_000, 500)
+        );
+        let time = NaiveDateTime::from_timestamp(-1, 0);
+        assert_eq!(
+            naive_to_systemtime(time),
+            SystemTime::UNIX_EPOCH - Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_setattr_mtime_is_kept() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&conn)
+        .unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let ino = Inode::file(file.id);
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        fs.set_attr(ino, None, None, Some(mtime)).unwrap();
+
+        let attr = fs.attr(ino).unwrap();
+        assert_eq!(attr.mtime, mtime);
+        assert_eq!(attr.crtime, attr.atime);
+    }
+
+    #[test]
+    fn test_write_in_chunks_hashes_whole_file() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&conn)
+        .unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let ino = Inode::file(file.id);
+        let fh = fs.insert_handle(Handle::default());
+
+        let content = (0..1024 * 1024)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        for (i, chunk) in content.chunks(64 * 1024).enumerate() {
+            let offset = (i * 64 * 1024).try_into().unwrap();
+            assert_eq!(fs.write_data(ino, fh, offset, chunk), Ok(64 * 1024));
+        }
+        fs.release_handle(ino, fh).unwrap();
+
+        let file = File::find(file.id, &fs.conn().unwrap()).unwrap();
+        let hash = file.content_hash.unwrap();
+        assert_eq!(hash, hex::encode(Sha256::digest(&content)));
+        let stored = fs::read(fs.storage_path(&hash).unwrap()).unwrap();
+        assert!(stored == content);
+    }
+
+    #[test]
+    fn test_overwrite_moves_blob_once_unused() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let first = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&conn)
+        .unwrap();
+        let second = NewFile {
+            name: "002.jpg",
+            eposid_id: 1,
+        }
+        .insert(&conn)
+        .unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        for file in &[&first, &second] {
+            let ino = Inode::file(file.id);
+            let fh = fs.insert_handle(Handle::default());
+            fs.write_data(ino, fh, 0, b"overwrite me").unwrap();
+            fs.release_handle(ino, fh).unwrap();
+        }
+        let old = File::find(first.id, &fs.conn().unwrap())
+            .unwrap()
+            .content_hash
+            .unwrap();
+        let old = fs.storage_path(&old).unwrap();
+
+        let ino = Inode::file(first.id);
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"OVER").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+        let file = File::find(first.id, &fs.conn().unwrap()).unwrap();
+        assert_eq!(
+            file.content_hash,
+            Some(hex::encode(Sha256::digest(b"OVERwrite me")))
+        );
+        // Still used by the second file
+        assert!(old.exists());
+
+        let ino = Inode::file(second.id);
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"OVER").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+        assert!(!old.exists());
+    }
+
+    #[test]
+    fn test_getattr_before_first_write() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&conn)
+        .unwrap();
+        assert_eq!(file.content_hash, None);
+        let fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+
+        let attr = fs.attr(Inode::file(file.id)).unwrap();
+        assert_eq!(attr.ino, Inode::file(file.id).0);
+        assert_eq!(attr.size, 0);
+    }
+
+    #[test]
+    fn test_malformed_hash_has_no_storage_path() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&conn)
+        .unwrap();
+        file.update_content_hash("a", &conn).unwrap();
+        let fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        assert_eq!(fs.storage_path(""), Err(EIO.into()));
+        assert_eq!(fs.storage_path("a"), Err(EIO.into()));
+        assert_eq!(fs.storage_path(&"Z".repeat(64)), Err(EIO.into()));
+
+        let ino = Inode::file(file.id);
+        assert_eq!(fs.attr(ino).unwrap_err().errno(), EIO);
+        assert_eq!(fs.read_data(ino, 0, 0, 4096), Err(EIO.into()));
+
+        let hash = hex::encode(Sha256::digest(b""));
+        let path = fs.storage_path(&hash).unwrap();
+        assert!(path.ends_with(format!("{}/{}", &hash[0..2], hash)));
+    }
+
+    #[test]
+    fn test_storage_is_per_filesystem() {
+        let (_db, pool) = test_pool();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&pool.get().unwrap())
+        .unwrap();
+        let first_root = tempfile::tempdir().unwrap();
+        let second_root = tempfile::tempdir().unwrap();
+        let mut first = ComicFS::new(
+            pool.clone(),
+            PathBuf::from("/mnt"),
+            first_root.path().to_path_buf(),
+        );
+        let second = ComicFS::new(
+            pool,
+            PathBuf::from("/mnt"),
+            second_root.path().to_path_buf(),
+        );
+        let ino = Inode::file(file.id);
+        let fh = first.insert_handle(Handle::default());
+        first.write_data(ino, fh, 0, b"first").unwrap();
+        first.release_handle(ino, fh).unwrap();
+
+        let hash = hex::encode(Sha256::digest(b"first"));
+        assert!(first
+            .storage_path(&hash)
+            .unwrap()
+            .starts_with(first_root.path()));
+        assert!(first.storage_path(&hash).unwrap().exists());
+        assert!(!second.storage_path(&hash).unwrap().exists());
+        assert_eq!(second.read_data(ino, 0, 0, 5), Err(EIO.into()));
+        assert_eq!(first.read_data(ino, 0, 0, 5).unwrap(), b"first");
+    }
+
+    #[test]
+    fn test_duplicate_name_is_eexist() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comics = Inode(ComicFS::COMIC_ID);
+
+        assert!(fs.make_dir(comics, OsStr::new("foo")).is_ok());
+        assert_eq!(
+            fs.make_dir(comics, OsStr::new("foo")).err(),
+            Some(EEXIST.into())
+        );
+        assert_eq!(
+            fs.make_dir(Inode(ComicFS::ROOT_ID), OsStr::new("foo"))
+                .err(),
+            Some(EPERM.into())
+        );
+    }
+
+    #[test]
+    fn test_exhausted_pool_is_eio() {
+        let db = NamedTempFile::new().unwrap();
+        let manager = ConnectionManager::new(db.path().to_str().unwrap());
+        let pool = Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(50))
+            .build(manager)
+            .unwrap();
+        run_migrations(&pool.get().unwrap());
+        let mut fs = ComicFS::new(pool.clone(), PathBuf::from("/mnt"), storage_base());
+        let comics = Inode(ComicFS::COMIC_ID);
+
+        let held = pool.get().unwrap();
+        assert_eq!(
+            fs.make_dir(comics, OsStr::new("foo")).err(),
+            Some(EIO.into())
+        );
+        drop(held);
+        assert!(fs.make_dir(comics, OsStr::new("foo")).is_ok());
+    }
+
+    #[test]
+    fn test_names_are_unique_within_parent() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let name = OsStr::new("001");
+
+        let tags = Inode(ComicFS::TAGS_ID);
+        assert!(fs.make_dir(tags, name).is_ok());
+        assert_eq!(fs.make_dir(tags, name).err(), Some(EEXIST.into()));
+
+        let comics = Inode(ComicFS::COMIC_ID);
+        let first = Inode(fs.make_dir(comics, OsStr::new("foo")).unwrap().ino);
+        let second = Inode(fs.make_dir(comics, OsStr::new("bar")).unwrap().ino);
+        let eposide = Inode(fs.make_dir(first, name).unwrap().ino);
+        assert_eq!(fs.make_dir(first, name).err(), Some(EEXIST.into()));
+        // Episodes of different comics may share a name
+        let other = Inode(fs.make_dir(second, name).unwrap().ino);
+
+        assert!(fs.create_file(eposide, name).is_ok());
+        assert_eq!(fs.create_file(eposide, name).err(), Some(EEXIST.into()));
+        assert!(fs.create_file(other, name).is_ok());
+    }
+
+    #[test]
+    fn test_clones_share_handles() {
+        let (_db, pool) = test_pool();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&pool.get().unwrap())
+        .unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let mut worker = fs.clone();
+        let ino = Inode::file(file.id);
+
+        let fh = fs.insert_handle(Handle::default());
+        worker.write_data(ino, fh, 0, b"shared").unwrap();
+        assert_eq!(fs.read_data(ino, fh, 0, 6).unwrap(), b"shared");
+        worker.release_handle(ino, fh).unwrap();
+        assert_eq!(fs.release_handle(ino, fh), Err(EBADF.into()));
+    }
+
+    #[test]
+    fn test_lookup_cache_until_invalidated() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        diesel::insert_into(crate::schema::comics::table)
+            .values(&NewComic { name: "comic" })
+            .execute(&conn)
+            .unwrap();
+        let comics = Inode::from(ComicFS::COMIC_ID);
+        let name = OsStr::new("comic");
+        let fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let attr = fs.lookup_entry(comics, name).unwrap();
+
+        diesel::delete(crate::schema::comics::table)
+            .execute(&conn)
+            .unwrap();
+        assert_eq!(fs.lookup_entry(comics, name), Ok(attr));
+        fs.invalidate_lookup(comics, name);
+        assert_eq!(fs.lookup_entry(comics, name), Err(ENOENT.into()));
+    }
+
+    #[test]
+    fn test_missing_name_is_cached_until_made() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comics = Inode::from(ComicFS::COMIC_ID);
+        let name = OsStr::new("comic");
+        assert_eq!(fs.lookup_entry(comics, name), Err(ENOENT.into()));
+
+        // Not asked again, so a row added behind its back isn't seen yet
+        NewComic { name: "comic" }.insert(&conn).unwrap();
+        assert_eq!(fs.lookup_entry(comics, name), Err(ENOENT.into()));
+
+        diesel::delete(crate::schema::comics::table)
+            .execute(&conn)
+            .unwrap();
+        let comic = fs.make_dir(comics, name).unwrap();
+        assert_eq!(fs.lookup_entry(comics, name), Ok(comic));
+    }
+
+    #[test]
+    fn test_forget_evicts_once_unreferenced() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comics = Inode::from(ComicFS::COMIC_ID);
+        let name = OsStr::new("comic");
+        let comic = fs.make_dir(comics, name).unwrap();
+        let cached = |fs: &ComicFS| {
+            let cache = fs.lookup_cache.as_ref().unwrap().lock().unwrap();
+            cache.contains(&(comics.0, name.to_owned()))
+        };
+
+        for _ in 0..2 {
+            let attr = fs.lookup_entry(comics, name).unwrap();
+            fs.remember(attr.ino);
+        }
+        assert!(cached(&fs));
+        fs.forget_inode(Inode(comic.ino), 1);
+        assert!(cached(&fs));
+        fs.forget_inode(Inode(comic.ino), 1);
+        assert!(!cached(&fs));
+        assert!(fs.lookups.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_verify_blob_detects_corruption() {
+        let (_db, pool) = test_pool();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&pool.get().unwrap())
+        .unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let ino = Inode::file(file.id);
+        let fh = fs.insert_handle(Handle::default());
+        fs.write_data(ino, fh, 0, b"page").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+
+        let file = File::find(file.id, &fs.conn().unwrap()).unwrap();
+        let path = fs
+            .storage_path(file.content_hash.as_ref().unwrap())
+            .unwrap();
+        assert!(file.verify_blob(&path).unwrap());
+        fs::write(&path, b"pagf").unwrap();
+        assert!(!file.verify_blob(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(file.verify_blob(&path).is_err());
+    }
+
+    #[test]
+    fn test_fsck_counts_corrupted_and_missing_blobs() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(
+            pool.clone(),
+            PathBuf::from("/mnt"),
+            root.path().to_path_buf(),
+        );
+        let mut paths = vec![];
+        for (name, content) in &[
+            ("001.jpg", b"one"),
+            ("002.jpg", b"two"),
+            ("003.jpg", b"333"),
+        ] {
+            let file = NewFile { name, eposid_id: 1 }.insert(&conn).unwrap();
+            let ino = Inode::file(file.id);
+            let fh = fs.insert_handle(Handle::default());
+            fs.write_data(ino, fh, 0, &content[..]).unwrap();
+            fs.release_handle(ino, fh).unwrap();
+            let hash = hex::encode(Sha256::digest(&content[..]));
+            paths.push(fs.storage_path(&hash).unwrap());
+        }
+        // Never written, nothing to check
+        NewFile {
+            name: "004.jpg",
+            eposid_id: 1,
+        }
+        .insert(&conn)
+        .unwrap();
+        assert_eq!(fs.fsck(), Ok(0));
+
+        fs::write(&paths[0], b"0ne").unwrap();
+        fs::remove_file(&paths[1]).unwrap();
+        assert_eq!(fs.fsck(), Ok(2));
+    }
+
+    #[test]
+    fn test_dupes_group_files_by_hash() {
+        let mut fs = test_fs();
+        let episode = fs.lookup_entry(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"));
+        let episode = fs.lookup_entry(Inode::from(episode.unwrap().ino), OsStr::new("ep1"));
+        let episode = Inode::from(episode.unwrap().ino);
+        fs.create_file(episode, OsStr::new("002.jpg")).unwrap();
+        fs.create_file(episode, OsStr::new("003.jpg")).unwrap();
+        let conn = fs.conn().unwrap();
+        for (name, hash) in &[("001.jpg", "aa"), ("002.jpg", "bb"), ("003.jpg", "aa")] {
+            use crate::schema::files::dsl;
+            diesel::update(dsl::files.filter(dsl::name.eq(name)))
+                .set(dsl::content_hash.eq(hash))
+                .execute(&conn)
+                .unwrap();
+        }
+
+        let names = |hash| {
+            File::find_by_hash(hash, &conn)
+                .unwrap()
+                .into_iter()
+                .map(|file| file.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("aa"), ["001.jpg", "003.jpg"]);
+        assert_eq!(names("bb"), ["002.jpg"]);
+        assert!(names("cc").is_empty());
+        assert_eq!(
+            fs.dupes().unwrap(),
+            [(
+                "aa".to_owned(),
+                vec![
+                    PathBuf::from("comic/ep1/001.jpg"),
+                    PathBuf::from("comic/ep1/003.jpg")
+                ]
+            )]
+        );
+    }
+
+    /// The unique indexes on `(comic_id, name)` and `(eposid_id, name)` of the live rows serve
+    /// the filters on their first column, in the order the listings want.
+    #[test]
+    fn test_listings_use_indexes() {
+        #[derive(QueryableByName, Debug)]
+        struct Step {
+            #[sql_type = "diesel::sql_types::Text"]
+            detail: String,
+        }
+
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        for (sql, index) in &[
+            (
+                "SELECT * FROM eposides WHERE comic_id = 1 AND deleted_at IS NULL ORDER BY name, id",
+                "eposides_comic_id_name",
+            ),
+            (
+                "SELECT * FROM files WHERE eposid_id = 1 AND deleted_at IS NULL ORDER BY name, id",
+                "files_eposid_id_name",
+            ),
+            (
+                "SELECT * FROM taggables WHERE tag_id = 1 ORDER BY id",
+                "taggables_tag_id",
+            ),
+        ] {
+            let plan = diesel::sql_query(format!("EXPLAIN QUERY PLAN {}", sql))
+                .load::<Step>(&conn)
+                .unwrap();
+            assert!(
+                plan.iter()
+                    .any(|step| step.detail.contains(&format!("INDEX {}", index))),
+                "{}: {:?}",
+                sql,
+                plan
+            );
+        }
+    }
+
+    #[test]
+    fn test_size_is_stored_with_content() {
+        let (_db, pool) = test_pool();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&pool.get().unwrap())
+        .unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let ino = Inode::file(file.id);
+        let fh = fs.insert_handle(Handle::default());
+        fs.write_data(ino, fh, 0, &[0; 1000]).unwrap();
+        fs.release_handle(ino, fh).unwrap();
+
+        let file = File::find(file.id, &fs.conn().unwrap()).unwrap();
+        assert_eq!(file.size, Some(1000));
+        fs::remove_file(
+            fs.storage_path(file.content_hash.as_ref().unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        let attr = fs.attr(ino).unwrap();
+        assert_eq!(attr.size, 1000);
+        assert_eq!(attr.blocks, 2);
+    }
+
+    #[test]
+    fn test_mime_type_is_sniffed_on_first_write() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let mut fs = ComicFS::new(pool.clone(), PathBuf::from("/mnt"), storage_base());
+        let contents: &[(&str, &[u8], &str)] = &[
+            ("001.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "image/png"),
+            ("002.jpg", b"\xff\xd8\xff\xe0\0\x10JFIF", "image/jpeg"),
+            ("003.txt", b"plain text", "application/octet-stream"),
+        ];
+        for (name, content, mime_type) in contents {
+            let file = NewFile { name, eposid_id: 1 }.insert(&conn).unwrap();
+            let ino = Inode::file(file.id);
+            assert_eq!(
+                fs.xattr_value(ino, OsStr::new("user.mime_type")),
+                Err(ENODATA.into())
+            );
+            let fh = fs.insert_handle(Handle::default());
+            fs.write_data(ino, fh, 0, content).unwrap();
+            // Later chunks don't change the type
+            fs.write_data(ino, fh, content.len() as i64, b"PK\x03\x04")
+                .unwrap();
+            fs.release_handle(ino, fh).unwrap();
+            assert_eq!(
+                fs.xattr_value(ino, OsStr::new("user.mime_type")).unwrap(),
+                mime_type.as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_directory_counts_are_xattrs() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        diesel::insert_into(crate::schema::comics::table)
+            .values(&NewComic { name: "comic" })
+            .execute(&conn)
+            .unwrap();
+        let comic = Comic::find_by_name("comic", false, &conn).unwrap();
+        for name in &["ep1", "ep2"] {
+            diesel::insert_into(crate::schema::eposides::table)
+                .values(&NewEposide {
+                    name,
+                    comic_id: comic.id,
+                })
+                .execute(&conn)
+                .unwrap();
+        }
+        let episode = Episode::find_by_comic_and_name(comic.id, "ep1", false, &conn).unwrap();
+        for name in &["001.jpg", "002.jpg", "003.jpg"] {
+            NewFile {
+                name,
+                eposid_id: episode.id,
+            }
+            .insert(&conn)
+            .unwrap();
+        }
+        let fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+
+        let comic = Inode::comic(comic.id);
+        let episode = Inode::eposide(episode.id);
+        assert_eq!(
+            fs.xattr_value(comic, OsStr::new("user.episode_count"))
+                .unwrap(),
+            b"2"
+        );
+        assert_eq!(
+            fs.xattr_value(episode, OsStr::new("user.file_count"))
+                .unwrap(),
+            b"3"
+        );
+        assert_eq!(
+            fs.xattr_value(comic, OsStr::new("user.file_count")),
+            Err(ENODATA.into())
+        );
+    }
+
+    #[test]
+    fn test_import_tree_inserts_comics_and_blobs() {
+        let (_db, pool) = test_pool();
+        let storage = tempfile::tempdir().unwrap();
+        let tree = tempfile::tempdir().unwrap();
+        for (path, content) in &[
+            ("A/ep1/001.jpg", &b"\xff\xd8\xff page"[..]),
+            ("A/ep1/002.jpg", b"other page"),
+            ("A/ep2/001.png", b"\x89PNG\r\n\x1a\n page"),
+            ("B/ep1/001.jpg", b"\xff\xd8\xff page"),
+            ("A/notes.txt", b"not in an episode"),
+        ] {
+            let path = tree.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let fs = ComicFS::new(
+            pool.clone(),
+            PathBuf::from("/mnt"),
+            storage.path().to_path_buf(),
+        );
+
+        assert_eq!(fs.import_tree(tree.path()), Ok(4));
+        let conn = pool.get().unwrap();
+        assert_eq!(Comic::count(&conn), Some(2));
+        assert_eq!(Episode::count(&conn), Some(3));
+        let comic = Comic::find_by_name("A", false, &conn).unwrap();
+        let episode = Episode::find_by_comic_and_name(comic.id, "ep2", false, &conn).unwrap();
+        let info = File::find_by_eposide_and_name(episode.id, "001.png", false, &conn).unwrap();
+        assert_eq!(info.size, Some(13));
+        assert_eq!(info.mime_type.as_deref(), Some("image/png"));
+        let path = fs
+            .storage_path(info.content_hash.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"\x89PNG\r\n\x1a\n page");
+        // The first pages of both comics share a blob
+        let blobs = File::all(&conn)
+            .unwrap()
+            .into_iter()
+            .filter_map(|info| info.content_hash)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(blobs.len(), 3);
+
+        assert_eq!(fs.import_tree(tree.path()), Ok(0));
+        assert_eq!(File::count(&conn), Some(4));
+    }
+
+    #[test]
+    fn test_import_tree_removes_blobs_of_rolled_back_comic() {
+        let (_db, pool) = test_pool();
+        let storage = tempfile::tempdir().unwrap();
+        let tree = tempfile::tempdir().unwrap();
+        for (path, content) in &[
+            ("A/ep1/001.jpg", &b"kept page"[..]),
+            ("B/ep1/001.jpg", b"kept page"),
+            ("B/ep1/002.jpg", b"new page"),
+            ("B/ep1/003.jpg", b"failing page"),
+        ] {
+            let path = tree.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let conn = pool.get().unwrap();
+        conn.batch_execute(
+            "CREATE TRIGGER fail_import BEFORE INSERT ON files WHEN NEW.name = '003.jpg'
+             BEGIN SELECT RAISE(ABORT, 'import failed'); END;",
+        )
+        .unwrap();
+        let fs = ComicFS::new(
+            pool.clone(),
+            PathBuf::from("/mnt"),
+            storage.path().to_path_buf(),
+        );
+
+        assert!(fs.import_tree(tree.path()).is_err());
+        assert_eq!(Comic::count(&conn), Some(1));
+        assert_eq!(File::count(&conn), Some(1));
+        // The blob of A is left alone, those stored for B alone are removed
+        let blob = |content: &[u8]| {
+            let hash = hex::encode(Sha256::digest(content));
+            fs.storage_path(&hash).unwrap().exists()
+        };
+        assert!(blob(b"kept page"));
+        assert!(!blob(b"new page"));
+        assert!(!blob(b"failing page"));
+    }
+
+    #[test]
+    fn test_export_tree_writes_layout() {
+        fn walk(dir: &Path, root: &Path, layout: &mut Vec<(String, String)>) {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                let name = path.strip_prefix(root).unwrap().display().to_string();
+                let file_type = std::fs::symlink_metadata(&path).unwrap().file_type();
+                if file_type.is_symlink() {
+                    let target = std::fs::read_link(&path).unwrap();
+                    layout.push((name, format!("-> {}", target.display())));
+                } else if file_type.is_dir() {
+                    layout.push((name + "/", String::new()));
+                    walk(&path, root, layout);
+                } else {
+                    let content = std::fs::read(&path).unwrap();
+                    layout.push((name, String::from_utf8(content).unwrap()));
+                }
+            }
+        }
+
+        let mut fs = test_fs();
+        let comic = fs
+            .find_entry(ComicFS::COMIC_ID, OsStr::new("comic"))
+            .unwrap();
+        let episode = Inode::from(fs.find_entry(comic.ino, OsStr::new("ep1")).unwrap().ino);
+        let ino = Inode::from(fs.create_file(episode, OsStr::new("002.jpg")).unwrap().ino);
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"page").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+        let out = tempfile::tempdir().unwrap();
+
+        assert_eq!(fs.export_tree(out.path(), true), Ok(2));
+        let mut layout = vec![];
+        walk(out.path(), out.path(), &mut layout);
+        layout.sort();
+        let expected = [
+            (".tags/", ""),
+            (".tags/finished/", ""),
+            (".tags/finished/comic", "-> ../../comic"),
+            ("comic/", ""),
+            ("comic/ep1/", ""),
+            ("comic/ep1/001.jpg", ""),
+            ("comic/ep1/002.jpg", "page"),
+        ];
+        assert_eq!(
+            layout,
+            expected
+                .iter()
+                .map(|(name, content)| (name.to_string(), content.to_string()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(fs.export_tree(out.path(), false), Err(ENOTEMPTY.into()));
+    }
+
+    #[test]
+    fn test_database_changes_refresh_lookups() {
+        let (db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comics = Inode::from(ComicFS::COMIC_ID);
+        fs.make_dir(comics, OsStr::new("comic")).unwrap();
+        fs.lookup_entry(comics, OsStr::new("comic")).unwrap();
+
+        let (tx, rx) = channel();
+        let watched = fs.clone();
+        let watcher = watch_database(db.path(), Duration::from_millis(50), move || {
+            tx.send(watched.refresh()).unwrap();
+            false
+        })
+        .unwrap();
+        // The crawler renames a comic and adds another one
+        let conn = SqliteConnection::establish(db.path().to_str().unwrap()).unwrap();
+        conn.batch_execute("UPDATE comics SET name = 'renamed'")
+            .unwrap();
+        NewComic { name: "comic2" }.insert(&conn).unwrap();
+
+        let dropped = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        watcher.join().unwrap();
+        assert_eq!(dropped, vec![(ComicFS::COMIC_ID, "comic".into())]);
+        assert_eq!(
+            fs.lookup_entry(comics, OsStr::new("comic"))
+                .unwrap_err()
+                .errno(),
+            ENOENT
+        );
+        let names = fs
+            .children(comics)
+            .into_iter()
+            .map(|(_, _, name)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["comic2", "renamed"]);
+    }
+
+    #[test]
+    fn test_case_insensitive_lookups() {
+        let mut fs = test_fs();
+        let comic = Inode::from(
+            fs.find_entry(ComicFS::COMIC_ID, OsStr::new("comic"))
+                .unwrap()
+                .ino,
+        );
+        assert_eq!(
+            fs.find_entry(ComicFS::COMIC_ID, OsStr::new("Comic")),
+            Err(ENOENT.into())
+        );
+        // Both cases can be created when they're told apart
+        fs.make_dir(comic, OsStr::new("EP1")).unwrap();
+
+        fs.case_insensitive = true;
+        let episode = fs.find_entry(comic.0, OsStr::new("Ep1")).unwrap();
+        assert_eq!(
+            Some(episode.ino),
+            fs.find_entry(comic.0, OsStr::new("ep1"))
+                .ok()
+                .map(|attr| attr.ino)
+        );
+        assert_ne!(
+            fs.find_entry(comic.0, OsStr::new("EP1")).unwrap().ino,
+            episode.ino
+        );
+        let path = fs.resolve(Path::new("comics/COMIC/ep1/001.JPG")).unwrap();
+        let file = fs.find_entry(episode.ino, OsStr::new("001.jpg")).unwrap();
+        assert_eq!(path.0, file.ino);
+        assert_eq!(
+            fs.make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("COMIC")),
+            Err(EEXIST.into())
+        );
+        assert_eq!(
+            fs.create_file(Inode::from(episode.ino), OsStr::new("001.JPG")),
+            Err(EEXIST.into())
+        );
+        // Not a wildcard
+        assert_eq!(
+            fs.find_entry(episode.ino, OsStr::new("00_.jpg")),
+            Err(ENOENT.into())
+        );
+        fs.rename_entry(
+            Inode::from(episode.ino),
+            OsStr::new("001.jpg"),
+            Inode::from(episode.ino),
+            OsStr::new("001.JPG"),
+            0,
+        )
+        .unwrap();
+        assert_eq!(
+            fs.resolve_inode_checked(Inode::from(file.ino)),
+            Ok(PathBuf::from("/mnt/comics/comic/ep1/001.JPG"))
+        );
+    }
+
+    #[test]
+    fn test_comment_xattr_round_trip() {
+        let fs = test_fs();
+        let comic = fs
+            .find_entry(ComicFS::COMIC_ID, OsStr::new("comic"))
+            .unwrap();
+        let episode = fs.find_entry(comic.ino, OsStr::new("ep1")).unwrap();
+        let ino = Inode::from(
+            fs.find_entry(episode.ino, OsStr::new("001.jpg"))
+                .unwrap()
+                .ino,
+        );
+        let name = OsStr::new("user.comment");
+
+        assert_eq!(fs.xattr_value(ino, name), Err(ENODATA.into()));
+        fs.set_xattr(ino, name, "two-page spread".as_bytes(), 0)
+            .unwrap();
+        assert_eq!(fs.xattr_value(ino, name).unwrap(), b"two-page spread");
+        assert_eq!(
+            fs.set_xattr(ino, name, b"again", libc::XATTR_CREATE as u32),
+            Err(EEXIST.into())
+        );
+        assert_eq!(
+            fs.set_xattr(ino, name, &[b'x'; MAX_COMMENT_SIZE + 1], 0),
+            Err(E2BIG.into())
+        );
+        assert_eq!(
+            fs.set_xattr(ino, OsStr::new("user.mime_type"), b"text/plain", 0),
+            Err(EPERM.into())
+        );
+
+        fs.remove_xattr(ino, name).unwrap();
+        assert_eq!(fs.xattr_value(ino, name), Err(ENODATA.into()));
+        assert_eq!(fs.remove_xattr(ino, name), Err(ENODATA.into()));
+    }
+
+    #[test]
+    fn test_xattr_list_depends_on_kind() {
+        let fs = test_fs();
+        let comic = Inode::from(
+            fs.find_entry(ComicFS::COMIC_ID, OsStr::new("comic"))
+                .unwrap()
+                .ino,
+        );
+        let episode = Inode::from(fs.find_entry(comic.0, OsStr::new("ep1")).unwrap().ino);
+        let file = Inode::from(fs.find_entry(episode.0, OsStr::new("001.jpg")).unwrap().ino);
+
+        assert_eq!(
+            fs.xattr_list(file),
+            &b"user.access_count\0user.content_hash\0user.mime_type\0user.comment\0"[..]
+        );
+        assert_eq!(fs.xattr_list(comic), b"user.episode_count\0");
+        assert_eq!(fs.xattr_list(Inode::from(ComicFS::TAGS_ID)), b"");
+    }
+
+    #[test]
+    fn test_tag_list_is_read_only() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        diesel::insert_into(crate::schema::comics::table)
+            .values(&NewComic { name: "comic" })
+            .execute(&conn)
+            .unwrap();
+        let comic = Comic::find_by_name("comic", false, &conn).unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = Inode::comic(comic.id);
+        for name in &["finished", "action"] {
+            let tag = fs
+                .make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new(name))
+                .unwrap();
+            fs.link_entry(comic, Inode::from(tag.ino), OsStr::new("comic"))
+                .unwrap();
+        }
+
+        let attr = fs.lookup_entry(comic, OsStr::new(".tags")).unwrap();
+        assert_eq!(attr.kind, fuse::FileType::RegularFile);
+        assert_eq!(attr.size, 16);
+        let ino = Inode::from(attr.ino);
+        assert_eq!(fs.attr(ino).unwrap(), attr);
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(
+            fs.read_data(ino, fh, 0, 4096).unwrap(),
+            b"finished\naction\n"
+        );
+        assert_eq!(fs.read_data(ino, fh, 9, 4096).unwrap(), b"action\n");
+        assert_eq!(fs.write_data(ino, fh, 0, b"x"), Err(EPERM.into()));
+        assert_eq!(
+            fs.set_attr(ino, Some(0), None, None).err(),
+            Some(EPERM.into())
+        );
+        assert_eq!(
+            fs.make_dir(comic, OsStr::new(".tags")).err(),
+            Some(EEXIST.into())
+        );
+        assert!(fs
+            .dir_entries(comic)
+            .iter()
+            .any(|(entry, _, name)| *entry == attr.ino && name == ".tags"));
+    }
+
+    #[test]
+    fn test_readdir_lists_snapshot_of_opendir() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        diesel::insert_into(crate::schema::comics::table)
+            .values(&NewComic { name: "comic" })
+            .execute(&conn)
+            .unwrap();
+        let comic = Comic::find_by_name("comic", false, &conn).unwrap();
+        let expected = (0..100)
+            .map(|i| format!("episode-{:03}", i))
+            .collect::<Vec<_>>();
+        for name in &expected {
+            diesel::insert_into(crate::schema::eposides::table)
+                .values(&NewEposide {
+                    name,
+                    comic_id: comic.id,
+                })
+                .execute(&conn)
+                .unwrap();
+        }
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = Inode::comic(comic.id);
+        let fh = fs.open_dir(comic).unwrap();
+
+        let (tx, rx) = channel();
+        let mut names = vec![];
+        loop {
+            let mut reply = ReplyDirectory::new(0, CaptureSender(tx.clone()), 512);
+            fs.read_dir(comic, fh, names.len().try_into().unwrap(), &mut reply);
+            reply.ok();
+            let page = dirent_names(&rx.recv().unwrap());
+            if page.is_empty() {
+                break;
+            }
+            if names.is_empty() {
+                fs.make_dir(comic, OsStr::new("episode-new")).unwrap();
+            }
+            names.extend(page);
+        }
+        assert_eq!(names[..3], [".", "..", ".tags"]);
+        assert_eq!(names[3..], expected[..]);
+
+        fs.release_dir(fh);
+        assert!(fs.handles.lock().unwrap().is_empty());
+        let fh = fs.open_dir(comic).unwrap();
+        let entries = fs.handles.lock().unwrap()[&fh].entries.clone().unwrap();
+        assert_eq!(entries.last().unwrap().2, "episode-new");
+        assert_eq!(fs.open_dir(Inode::file(1)), Err(ENOTDIR.into()));
+    }
+
+    #[test]
+    fn test_fallocate_grows_blob() {
+        let (_db, pool) = test_pool();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&pool.get().unwrap())
+        .unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let ino = Inode::file(file.id);
+        let fh = fs.insert_handle(Handle::default());
+        fs.write_data(ino, fh, 0, b"content").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+
+        fs.allocate(ino, 0, 4096, 0).unwrap();
+        let file = File::find(file.id, &fs.conn().unwrap()).unwrap();
+        let path = fs
+            .storage_path(file.content_hash.as_ref().unwrap())
+            .unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4096);
+        assert_eq!(fs.attr(ino).unwrap().size, 4096);
+
+        let keep_size = FallocateFlags::FALLOC_FL_KEEP_SIZE.bits();
+        fs.allocate(ino, 0, 8192, keep_size).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 4096);
+        let punch_hole =
+            (FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE).bits();
+        assert_eq!(
+            fs.allocate(ino, 0, 4096, punch_hole),
+            Err(EOPNOTSUPP.into())
+        );
+        assert_eq!(fs.allocate(Inode::comic(1), 0, 4096, 0), Err(ENOSYS.into()));
+    }
+
+    #[test]
+    fn test_content_survives_crash_after_fsync() {
+        let (_db, pool) = test_pool();
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
+        }
+        .insert(&pool.get().unwrap())
+        .unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(
+            pool.clone(),
+            PathBuf::from("/mnt"),
+            root.path().to_path_buf(),
+        );
+        let ino = Inode::file(file.id);
+        let fh = fs.insert_handle(Handle::default());
+        fs.write_data(ino, fh, 0, b"content").unwrap();
+        fs.flush_handle(fh).unwrap();
+        assert_eq!(
+            File::find(file.id, &fs.conn().unwrap())
+                .unwrap()
+                .content_hash,
+            None
+        );
+        fs.sync_handle(ino, fh, true).unwrap();
+        // Crash without releasing the handle
+        drop(fs);
+
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_data(ino, fh, 0, 4096).unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_total_inodes_counts_every_table() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        for name in &["001.jpg", "002.jpg"] {
+            fs.create_file(Inode::from(episode.ino), OsStr::new(name))
+                .unwrap();
+        }
+        fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+            .unwrap();
+
+        assert_eq!(ComicFS::total_inodes(&fs.conn().unwrap()), Ok(5));
+    }
+
+    #[test]
+    fn test_tag_directory_lists_every_taggable() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let file = fs
+            .create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        let tag = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+                .unwrap()
+                .ino,
+        );
+        let linked = fs
+            .link_entry(Inode::from(episode.ino), tag, OsStr::new("ep1"))
+            .unwrap();
+        assert_eq!(linked, episode);
+        let symlink = fs
+            .symlink_entry(
+                tag,
+                OsStr::new("001.jpg"),
+                Path::new("../../comics/comic/ep1/001.jpg"),
+            )
+            .unwrap();
+        assert_eq!(symlink.kind, fuse::FileType::Symlink);
+        assert_eq!(
+            fs.link_entry(Inode::from(ComicFS::TAGS_ID), tag, OsStr::new("tags")),
+            Err(EPERM.into())
+        );
+
+        // What `ls -l` does: list the directory, then look up and stat every entry
+        let entries = fs.dir_entries(tag);
+        let names = entries
+            .iter()
+            .map(|(_, _, name)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "comic_ep1", "comic_ep1_001.jpg"]);
+        for ((ino, kind, name), target) in entries[2..].iter().zip(&[episode, file]) {
+            assert_eq!(*kind, fuse::FileType::Symlink);
+            let attr = fs.lookup_entry(tag, OsStr::new(name)).unwrap();
+            assert_eq!(attr.ino, *ino);
+            assert_eq!(attr.kind, fuse::FileType::Symlink);
+            assert_eq!(fs.attr(Inode::from(*ino)).unwrap(), attr);
+            let path = fs.resolve_inode(Inode::from(target.ino)).unwrap();
+            assert_eq!(attr.size, path.as_os_str().len() as u64);
+        }
+        assert_eq!(entries[3].0, symlink.ino);
+    }
+
+    #[test]
+    fn test_symlink_to_comic_tags_its_episodes() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        for name in &["ep1", "ep2"] {
+            fs.make_dir(Inode::from(comic.ino), OsStr::new(name))
+                .unwrap();
+        }
+        let tag = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+                .unwrap()
+                .ino,
+        );
+        let ep1 = fs
+            .lookup_entry(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        fs.link_entry(Inode::from(ep1.ino), tag, OsStr::new("ep1"))
+            .unwrap();
+
+        fs.symlink_entry(tag, OsStr::new("comic"), Path::new("/mnt/comics/comic"))
+            .unwrap();
+        let names = fs
+            .dir_entries(tag)
+            .into_iter()
+            .map(|(_, _, name)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "comic_ep1", "comic_ep2", "comic"]);
+
+        // Linking only tags the comic itself
+        let other = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("reading"))
+                .unwrap()
+                .ino,
+        );
+        fs.link_entry(Inode::from(comic.ino), other, OsStr::new("comic"))
+            .unwrap();
+        assert_eq!(fs.dir_entries(other).len(), 3);
+    }
+
+    #[test]
+    fn test_search_matches_names_ignoring_case() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("One Piece"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("piece-01"))
+            .unwrap();
+        fs.create_file(Inode::from(episode.ino), OsStr::new("cover.jpg"))
+            .unwrap();
+        fs.create_file(Inode::from(episode.ino), OsStr::new("100%_PIECE.jpg"))
+            .unwrap();
+
+        let search = Inode::from(ComicFS::SEARCH_ID);
+        let dir = fs.lookup_entry(search, OsStr::new("PIECE")).unwrap();
+        let names = fs
+            .dir_entries(Inode::from(dir.ino))
+            .into_iter()
+            .map(|(_, _, name)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                ".",
+                "..",
+                "One Piece",
+                "One Piece_piece-01",
+                "One Piece_piece-01_100%_PIECE.jpg"
+            ]
+        );
+        let attr = fs
+            .lookup_entry(Inode::from(dir.ino), OsStr::new("One Piece_piece-01"))
+            .unwrap();
+        assert_eq!(attr.kind, fuse::FileType::Symlink);
+        assert_eq!(
+            fs.read_link(Inode::from(attr.ino)).unwrap(),
+            Path::new("/mnt/comics/One Piece/piece-01")
+        );
+        assert_eq!(fs.attr(Inode::from(attr.ino)).unwrap(), attr);
+
+        // Wildcards in the term match literally
+        let dir = fs.lookup_entry(search, OsStr::new("0%_")).unwrap();
+        assert_eq!(fs.dir_entries(Inode::from(dir.ino)).len(), 3);
+        assert_eq!(
+            fs.resolve_inode(Inode::from(dir.ino)).unwrap(),
+            Path::new("/mnt/search/0%_")
+        );
+    }
+
+    #[test]
+    fn test_forgotten_searches_are_dropped() {
+        let fs = test_fs();
+        let search = Inode::from(ComicFS::SEARCH_ID);
+        let dirs = (0..3)
+            .map(|i| {
+                let attr = fs.lookup_entry(search, OsStr::new(&i.to_string())).unwrap();
+                fs.remember(attr.ino);
+                Inode::from(attr.ino)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fs.subdirectory_count(search), 3);
+        assert_eq!(
+            fs.lookup_entry(search, OsStr::new("1")).unwrap().ino,
+            dirs[1].0
+        );
+
+        for dir in &dirs {
+            fs.forget_inode(*dir, 1);
+        }
+        assert_eq!(fs.subdirectory_count(search), 0);
+        assert_eq!(fs.search_term(dirs[1]), Err(ENOENT.into()));
+        assert_eq!(fs.resolve(Path::new("search/1")), None);
+
+        // A term looked up again gets a new directory, the old inode stays gone
+        let dir = fs.lookup_entry(search, OsStr::new("1")).unwrap();
+        assert_ne!(dir.ino, dirs[1].0);
+        assert_eq!(fs.search_term(Inode::from(dir.ino)).unwrap(), "1");
+        assert_eq!(
+            fs.resolve(Path::new("search/1")),
+            Some(Inode::from(dir.ino))
+        );
+    }
+
+    #[test]
+    fn test_recent_lists_newest_files() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        fs.recent_limit = 2;
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        for name in &["001.jpg", "002.jpg", "003.jpg"] {
+            fs.create_file(Inode::from(episode.ino), OsStr::new(name))
+                .unwrap();
+        }
+
+        let recent = Inode::from(ComicFS::RECENT_ID);
+        let names = fs
+            .dir_entries(recent)
+            .into_iter()
+            .map(|(_, _, name)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, [".", "..", "comic_ep1_003.jpg", "comic_ep1_002.jpg"]);
+        let attr = fs
+            .lookup_entry(recent, OsStr::new("comic_ep1_003.jpg"))
+            .unwrap();
+        assert_eq!(attr.kind, fuse::FileType::Symlink);
+        assert_eq!(
+            fs.read_link(Inode::from(attr.ino)).unwrap(),
+            Path::new("/mnt/comics/comic/ep1/003.jpg")
+        );
+        assert_eq!(
+            fs.lookup_entry(recent, OsStr::new("comic_ep1_001.jpg")),
+            Err(ENOENT.into())
+        );
+    }
+
+    #[test]
+    fn test_unexpected_inodes_reply_errors() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let file = fs
+            .create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        let tag = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+                .unwrap()
+                .ino,
+        );
+        let tagged = fs
+            .symlink_entry(
+                tag,
+                OsStr::new("001.jpg"),
+                Path::new("../../comics/comic/ep1/001.jpg"),
+            )
+            .unwrap();
+
+        // The taggable points at a file that isn't there
+        {
+            use crate::schema::taggables::dsl;
+            diesel::update(dsl::taggables)
+                .set(dsl::taggable_id.eq(i32::MAX))
+                .execute(&fs.conn().unwrap())
+                .unwrap();
+        }
+        assert_eq!(fs.attr(Inode::from(tagged.ino)), Err(ENOENT.into()));
+        assert_eq!(fs.read_link(Inode::from(tagged.ino)), Err(ENOENT.into()));
+        assert_eq!(
+            fs.lookup_entry(tag, OsStr::new("comic_ep1_001.jpg")),
+            Err(ENOENT.into())
+        );
+
+        assert_eq!(
+            fs.lookup_entry(Inode::from(file.ino), OsStr::new("any")),
+            Err(ENOTDIR.into())
+        );
+        assert_eq!(fs.attr(Inode::from(42)), Err(ENOENT.into()));
+        assert_eq!(fs.resolve(Path::new("nowhere")), None);
+        assert_eq!(fs.resolve_inode(Inode::from(tagged.ino)), None);
+    }
+
+    #[test]
+    fn test_non_utf8_names_are_invalid() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        // "café" in Latin-1
+        let name = OsStr::from_bytes(b"caf\xe9");
+
+        for parent in &[ComicFS::COMIC_ID, ComicFS::TAGS_ID, comic.ino, episode.ino] {
+            assert_eq!(
+                fs.lookup_entry(Inode::from(*parent), name),
+                Err(EINVAL.into())
+            );
+        }
+        assert_eq!(
+            fs.make_dir(Inode::from(ComicFS::COMIC_ID), name),
+            Err(EINVAL.into())
+        );
+        assert_eq!(
+            fs.create_file(Inode::from(episode.ino), name),
+            Err(EINVAL.into())
+        );
+        assert_eq!(fs.resolve(&Path::new("comics").join(name)), None);
+    }
+
+    #[test]
+    fn test_nlink_counts_links() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        assert_eq!(comic.nlink, 2);
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        assert_eq!(fs.attr(Inode::from(comic.ino)).unwrap().nlink, 3);
+        assert_eq!(fs.attr(Inode::from(ComicFS::COMIC_ID)).unwrap().nlink, 3);
+
+        let file = fs
+            .create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        assert_eq!(file.nlink, 1);
+        assert_eq!(fs.attr(Inode::from(episode.ino)).unwrap().nlink, 2);
+        for (count, name) in ["finished", "favorite"].iter().enumerate() {
+            let tag = fs
+                .make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new(name))
+                .unwrap();
+            fs.link_entry(
+                Inode::from(file.ino),
+                Inode::from(tag.ino),
+                OsStr::new("001.jpg"),
+            )
+            .unwrap();
+            let attr = fs.attr(Inode::from(file.ino)).unwrap();
+            assert_eq!(attr.nlink, count as u32 + 2);
+        }
+        assert_eq!(fs.attr(Inode::from(ComicFS::TAGS_ID)).unwrap().nlink, 4);
+    }
+
+    #[test]
+    fn test_cover_reads_first_file() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        assert_eq!(
+            fs.lookup_entry(episode, OsStr::new("cover")),
+            Err(ENOENT.into())
+        );
+
+        // Created out of order, the cover goes by name
+        for (name, content) in &[("002.jpg", b"second page"), ("001.jpg", b"first page!")] {
+            let file = fs.create_file(episode, OsStr::new(name)).unwrap();
+            let ino = Inode::from(file.ino);
+            let fh = fs.insert_handle(Handle::default());
+            fs.write_data(ino, fh, 0, *content).unwrap();
+            fs.release_handle(ino, fh).unwrap();
+        }
+        let cover = fs.lookup_entry(episode, OsStr::new("cover")).unwrap();
+        assert_eq!(cover.ino, episode.cover().0);
+        assert_eq!(cover.perm, 0o444);
+        assert_eq!(cover.size, 11);
+        assert!(fs
+            .dir_entries(episode)
+            .iter()
+            .any(|(ino, _, name)| *ino == cover.ino && name == "cover"));
+
+        let ino = Inode::from(cover.ino);
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_data(ino, fh, 0, 64).unwrap(), b"first page!");
+        assert_eq!(fs.write_data(ino, fh, 0, b"x"), Err(EPERM.into()));
+        fs.release_handle(ino, fh).unwrap();
+        assert_eq!(
+            fs.create_file(episode, OsStr::new("cover")),
+            Err(EEXIST.into())
+        );
+        assert_eq!(
+            check_access(InodeKind::Cover, W_OK as u32),
+            Err(EACCES.into())
+        );
+    }
+
+    #[test]
+    fn test_write_past_end_reads_zeros() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let file = fs
+            .create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        let ino = Inode::from(file.ino);
+        let mib = 1024 * 1024;
+
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"head").unwrap();
+        fs.write_data(ino, fh, mib, b"tail").unwrap();
+        // The size is known before the content is committed
+        assert_eq!(fs.attr(ino).unwrap().size, mib as u64 + 4);
+        assert_eq!(fs.read_data(ino, fh, mib / 2, 8).unwrap(), vec![0; 8]);
+        fs.release_handle(ino, fh).unwrap();
+
+        let mut content = vec![0; mib as usize + 4];
+        content[..4].copy_from_slice(b"head");
+        content[mib as usize..].copy_from_slice(b"tail");
+        let attr = fs.attr(ino).unwrap();
+        assert_eq!(attr.size, content.len() as u64);
+        assert_eq!(
+            fs.xattr_value(ino, OsStr::new("user.content_hash"))
+                .unwrap(),
+            hex::encode(Sha256::digest(&content)).into_bytes()
+        );
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(
+            fs.read_data(ino, fh, 0, content.len() as u32).unwrap(),
+            content
+        );
+        fs.release_handle(ino, fh).unwrap();
+    }
+
+    #[test]
+    fn test_deleting_comic_cascades() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool.clone(), PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let file = fs
+            .create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        let tag = fs
+            .make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+            .unwrap();
+        for ino in &[comic.ino, episode.ino, file.ino] {
+            fs.tag_inode(Inode::from(tag.ino), Inode::from(*ino))
+                .unwrap();
+        }
+
+        let conn = pool.get().unwrap();
+        conn.batch_execute("PRAGMA foreign_keys = ON;").unwrap();
+        diesel::delete(crate::schema::comics::table)
+            .execute(&conn)
+            .unwrap();
+        assert!(Episode::find(Inode::from(episode.ino).id().try_into().unwrap(), &conn).is_none());
+        assert!(File::find(Inode::from(file.ino).id().try_into().unwrap(), &conn).is_none());
+        let taggables = crate::schema::taggables::table
+            .count()
+            .get_result::<i64>(&conn)
+            .unwrap();
+        assert_eq!(taggables, 0);
+        assert_eq!(fs.attr(Inode::from(file.ino)), Err(ENOENT.into()));
+    }
+
+    #[test]
+    fn test_taggable_type_is_checked() {
+        let (_db, pool) = test_pool();
+        let conn = pool.get().unwrap();
+        let tag = NewTag { name: "finished" }.insert(&conn).unwrap();
+        for kind in &[
+            TaggableKind::Comic,
+            TaggableKind::Eposide,
+            TaggableKind::File,
+        ] {
+            let taggable = Taggable::link(tag.id, 1, *kind, &conn).unwrap();
+            assert_eq!(taggable.kind(), Some(*kind));
         }
+
+        let typo = NewTaggable {
+            tag_id: tag.id,
+            taggable_id: 1,
+            taggable_type: "episode",
+        };
+        let res = diesel::insert_into(crate::schema::taggables::table)
+            .values(&typo)
+            .execute(&conn);
+        assert!(res.is_err());
     }
 
-    fn mkdir(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        reply: ReplyEntry,
-    ) {
-        let parent = Inode::from(parent);
-        let kind = parent.kind();
-        match kind {
-            InodeKind::Special => {
-                match parent.0 {
-                    1 => {
-                        reply.error(EPERM);
-                    }
-                    // comics
-                    2 => {
-                        let name = name.to_str().unwrap();
-                        let comic = models::NewComic { name };
-                        let comic = self
-                            .conn
-                            .transaction::<_, diesel::result::Error, _>(|| {
-                                use schema::comics::dsl;
-
-                                diesel::insert_into(dsl::comics)
-                                    .values(&comic)
-                                    .execute(&self.conn)?;
-
-                                Ok(dsl::comics
-                                    .order(dsl::id.desc())
-                                    .first::<models::Comic>(&self.conn)?)
-                            })
-                            .expect("Fail to insert");
-                        let ino = Inode::comic(comic.id);
-                        reply.entry(&ONE_SEC, &directory_attr(ino), 0);
-                    }
-                    3 => {
-                        let name = name.to_str().unwrap();
-                        let tag = NewTag { name };
-                        let tag = tag.insert(&self.conn).expect("Fail to insert tag");
-                        let ino = Inode::tag(tag.id);
-                        reply.entry(&ONE_SEC, &directory_attr(ino), 0);
-                    }
-                    _ => unreachable!(),
-                }
-            }
-            InodeKind::Comic => {
-                let name = name.to_str().unwrap();
-                let eposide = models::NewEposide {
-                    name,
-                    comic_id: i32::try_from(parent.id()).unwrap(),
-                };
-                let eposide = self
-                    .conn
-                    .transaction::<_, diesel::result::Error, _>(|| {
-                        use schema::eposides::dsl;
+    #[test]
+    fn test_taggable_kinds_round_trip() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool.clone(), PathBuf::from("/mnt"), storage_base());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let file = fs
+            .create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        let tag = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("finished"))
+                .unwrap()
+                .ino,
+        );
+        let kinds = [
+            TaggableKind::Comic,
+            TaggableKind::Eposide,
+            TaggableKind::File,
+        ];
+        for (kind, target) in kinds.iter().zip(&[comic, episode, file]) {
+            assert_eq!(kind.as_ref().parse(), Ok(*kind));
+            let (taggable, linked) = fs.tag_inode(tag, Inode::from(target.ino)).unwrap();
+            assert_eq!(linked, *kind);
+            assert_eq!(taggable.kind(), Some(*kind));
+        }
 
-                        diesel::insert_into(dsl::eposides)
-                            .values(&eposide)
-                            .execute(&self.conn)?;
+        let taggables = Taggables::taggables(tag.id().try_into().unwrap(), &pool.get().unwrap());
+        assert_eq!(
+            taggables.iter().map(Taggables::kind).collect::<Vec<_>>(),
+            kinds
+        );
+        for (_, kind, name) in &fs.dir_entries(tag)[2..] {
+            assert_eq!(*kind, fuse::FileType::Symlink);
+            let attr = fs.lookup_entry(tag, OsStr::new(name)).unwrap();
+            assert_eq!(fs.attr(Inode::from(attr.ino)), Ok(attr));
+        }
+    }
 
-                        Ok(dsl::eposides
-                            .order(dsl::id.desc())
-                            .first::<models::Episode>(&self.conn)?)
-                    })
-                    .expect("Fail to insert");
-                let ino = Inode::eposide(eposide.id);
-                reply.entry(&ONE_SEC, &directory_attr(ino), 0);
-            }
-            InodeKind::Eposide | InodeKind::Tag => {
-                reply.error(EPERM);
-            }
-            InodeKind::File | InodeKind::Tagged => {
-                reply.error(ENOTDIR);
+    #[test]
+    fn test_tagged_files_share_parent_lookups() {
+        let (_db, pool) = test_pool();
+        let mut fs = ComicFS::new(pool.clone(), PathBuf::from("/mnt"), storage_base());
+        let tag = Inode::from(
+            fs.make_dir(Inode::from(ComicFS::TAGS_ID), OsStr::new("long"))
+                .unwrap()
+                .ino,
+        );
+        let mut expected = vec![];
+        for comic_name in &["a", "b"] {
+            let comic = fs
+                .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new(comic_name))
+                .unwrap();
+            let episode = fs
+                .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap();
+            for i in 0..50 {
+                let name = format!("{:03}.jpg", i);
+                let file = fs
+                    .create_file(Inode::from(episode.ino), OsStr::new(&name))
+                    .unwrap();
+                fs.tag_inode(tag, Inode::from(file.ino)).unwrap();
+                expected.push(format!("{}_ep1_{}", comic_name, name));
             }
         }
+        let names = fs.dir_entries(tag)[2..]
+            .iter()
+            .map(|(_, _, name)| name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, expected);
+
+        // Once looked up, a parent isn't queried again, even if it has changed since
+        let conn = pool.get().unwrap();
+        let comic = Comic::find_by_name("a", false, &conn).unwrap();
+        let episode = Episode::find_by_comic_and_name(comic.id, "ep1", false, &conn).unwrap();
+        let mut parents = Parents::default();
+        assert_eq!(
+            parents.episode_name(episode.id, &conn),
+            Some("a_ep1".to_owned())
+        );
+        {
+            use crate::schema::comics::dsl;
+            diesel::update(dsl::comics.find(comic.id))
+                .set(dsl::name.eq("renamed"))
+                .execute(&conn)
+                .unwrap();
+        }
+        assert_eq!(parents.comic_name(comic.id, &conn), Some("a".to_owned()));
+        assert_eq!(
+            Parents::default().comic_name(comic.id, &conn),
+            Some("renamed".to_owned())
+        );
     }
 
-    fn create(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _flags: u32,
-        reply: ReplyCreate,
-    ) {
-        let parent = Inode::from(parent);
-        if parent.kind() != InodeKind::Eposide {
-            reply.error(EPERM);
-            return;
+    #[test]
+    fn test_mapped_reads_match_read_at() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let mut files = vec![];
+        for (name, len) in &[("001.jpg", 3 * 4096 + 100), ("002.jpg", 100)] {
+            let ino = Inode::from(
+                fs.create_file(Inode::from(episode.ino), OsStr::new(name))
+                    .unwrap()
+                    .ino,
+            );
+            let content = (0..*len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let fh = fs.open_handle(ino).unwrap();
+            fs.write_data(ino, fh, 0, &content).unwrap();
+            fs.release_handle(ino, fh).unwrap();
+            files.push((ino, content.len()));
         }
-        let name = name.to_str().unwrap();
-        let value = models::NewFile {
-            name,
-            eposid_id: i32::try_from(parent.id()).unwrap(),
-            content_hash: "",
-        };
-        let file = value.insert(&self.conn).unwrap();
-        let ino = Inode::file(file.id);
-        reply.created(&ONE_SEC, &file_attr(ino), 0, 0, 0);
+
+        let (ino, len) = files[0];
+        let plain = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_mapped(plain, 0, 10, <[u8]>::to_vec), None);
+        fs.mmap = true;
+        let mapped = fs.open_handle(ino).unwrap();
+
+        // Mapped reads are counted and move the access time like the others
+        let id = ino.id().try_into().unwrap();
+        let before = File::find(id, &fs.conn().unwrap()).unwrap();
+        let (tx, rx) = channel();
+        fs.reply_read(ino, mapped, 0, 4096, Reply::new(0, CaptureSender(tx)));
+        assert_eq!(
+            rx.recv().unwrap()[16..],
+            fs.read_mapped(mapped, 0, 4096, <[u8]>::to_vec).unwrap()[..]
+        );
+        let after = File::find(id, &fs.conn().unwrap()).unwrap();
+        assert_eq!(after.access_count, before.access_count + 1);
+        assert_ne!(after.atime, before.atime);
+
+        for &(offset, size) in &[
+            (0, 4096),
+            (4000, 200),
+            (len as i64 - 10, 4096),
+            (len as i64, 1),
+        ] {
+            assert_eq!(
+                fs.read_mapped(mapped, offset, size, <[u8]>::to_vec),
+                Some(fs.read_data(ino, plain, offset, size).unwrap())
+            );
+        }
+
+        // Nothing is mapped once something is written through the handle
+        fs.write_data(ino, mapped, 0, b"new").unwrap();
+        assert_eq!(fs.read_mapped(mapped, 0, 3, <[u8]>::to_vec), None);
+        assert_eq!(fs.read_data(ino, mapped, 0, 3).unwrap(), b"new");
+        fs.release_handle(ino, mapped).unwrap();
+        fs.release_handle(ino, plain).unwrap();
+
+        // A blob smaller than a page is read with `read_at`
+        let (ino, _) = files[1];
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_mapped(fh, 0, 10, <[u8]>::to_vec), None);
+        fs.release_handle(ino, fh).unwrap();
     }
 
-    fn setattr(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        size: Option<u64>,
-        _atime: Option<SystemTime>,
-        _mtime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
-        _chgtime: Option<SystemTime>,
-        _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
-        reply: ReplyAttr,
-    ) {
-        let ino = Inode::from(ino);
-        if ino.kind() != InodeKind::File {
-            reply.error(ENOSYS);
-            return;
+    #[test]
+    fn test_byte_writes_are_buffered() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(
+            pool.clone(),
+            PathBuf::from("/mnt"),
+            root.path().to_path_buf(),
+        );
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let file = fs
+            .create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        let ino = Inode::from(file.ino);
+        let mut content = b"\xff\xd8\xff".to_vec();
+        content.extend((3..4096).map(|i| (i % 251) as u8));
+
+        let fh = fs.open_handle(ino).unwrap();
+        let start = Instant::now();
+        for (offset, byte) in content.iter().enumerate() {
+            fs.write_data(ino, fh, offset as i64, &[*byte]).unwrap();
         }
-        let info = match File::find(i32::try_from(ino.id()).unwrap(), &self.conn) {
-            Some(info) => info,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
+        let elapsed = start.elapsed();
+        // Nothing has reached the copy of the content yet, but the size is already known
+        let pending_len = {
+            let handles = fs.handles.lock().unwrap();
+            let pending = handles[&fh].pending.as_ref().unwrap();
+            pending.as_file().metadata().unwrap().len()
         };
-        if info.content_hash == "" {
-            reply.attr(&ONE_SEC, &file_attr(ino));
-            return;
+        assert_eq!(pending_len, 0);
+        assert_eq!(fs.attr(ino).unwrap().size, content.len() as u64);
+        assert!(elapsed < Duration::from_secs(5), "took {:?}", elapsed);
+        // Reading through the handle sees the buffered writes
+        assert_eq!(fs.read_data(ino, fh, 4090, 10).unwrap(), &content[4090..]);
+        fs.release_handle(ino, fh).unwrap();
+
+        let info = File::find(ino.id().try_into().unwrap(), &pool.get().unwrap()).unwrap();
+        assert_eq!(info.mime_type.as_deref(), Some("image/jpeg"));
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_data(ino, fh, 0, 8192).unwrap(), content);
+        fs.release_handle(ino, fh).unwrap();
+    }
+
+    /// Clock showing the time it's set to, shared by its clones.
+    #[derive(Clone)]
+    struct FakeClock(Arc<Mutex<SystemTime>>);
+
+    impl FakeClock {
+        fn new(now: SystemTime) -> Self {
+            Self(Arc::new(Mutex::new(now)))
         }
-        let path = generate_storage_path(&info.content_hash);
-        let fd = match open(&path, OFlag::O_WRONLY, Mode::empty()) {
-            Ok(fd) => fd,
-            Err(err) => {
-                reply.error(convert_nix_error(err));
-                return;
-            }
-        };
-        scopeguard::defer! {
-            let _ = close(fd);
+
+        fn set(&self, now: SystemTime) {
+            *self.0.lock().unwrap() = now;
         }
-        if let Some(size) = size {
-            if let Err(err) = ftruncate(fd, i64::try_from(size).unwrap()) {
-                reply.error(convert_nix_error(err));
-                return;
-            }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
         }
-        let meta = fs::metadata(&path).unwrap();
-        let attr = convert_meta_to_attr(ino.0, meta);
-        reply.attr(&ONE_SEC, &attr);
     }
 
-    fn write(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _flags: u32,
-        reply: ReplyWrite,
-    ) {
-        let ino = Inode::from(ino);
-        if ino.kind() != InodeKind::File {
-            reply.error(EISDIR);
-            return;
+    #[test]
+    fn test_builder_takes_storage_owner_and_clock() {
+        let (_db, pool) = test_pool();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let fs = ComicFSBuilder::new(pool)
+            .base(PathBuf::from("/mnt"))
+            .storage(PathBuf::from("/blobs"))
+            .uid(1234)
+            .gid(5678)
+            .clock(FakeClock::new(now))
+            .build();
+
+        for ino in &[ComicFS::ROOT_ID, ComicFS::COMIC_ID, ComicFS::RECENT_ID] {
+            let attr = fs.attr(Inode::from(*ino)).unwrap();
+            assert_eq!((attr.uid, attr.gid), (1234, 5678));
+            assert_eq!((attr.mtime, attr.crtime), (now, now));
         }
-        let info = match File::find(i32::try_from(ino.id()).unwrap(), &self.conn) {
-            Some(info) => info,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-        let file = if info.content_hash == "" {
-            let hash = Sha256::digest(data);
-            let res = hex::encode(&hash);
-            let path = generate_storage_path(&res);
-            fs::create_dir_all(path.parent().unwrap()).unwrap();
-            info.update_content_hash(&res, &self.conn);
-            fs::File::create(&path).unwrap()
-        } else {
-            let path = generate_storage_path(&info.content_hash);
-            fs::OpenOptions::new().write(true).open(&path).unwrap()
+        let attr = fs
+            .lookup_entry(Inode::from(ComicFS::ROOT_ID), OsStr::new("tags"))
+            .unwrap();
+        assert_eq!(attr.ctime, now);
+        let hash = hex::encode(Sha256::digest(b""));
+        assert!(fs.storage_path(&hash).unwrap().starts_with("/blobs"));
+    }
+
+    #[test]
+    fn test_lookup_on_seeded_fs() {
+        let fs = test_fs();
+        let root = Inode::from(ComicFS::ROOT_ID);
+        let comics = fs.lookup_entry(root, OsStr::new("comics")).unwrap();
+        let comic = fs
+            .lookup_entry(Inode::from(comics.ino), OsStr::new("comic"))
+            .unwrap();
+        assert_eq!(comic.kind, fuse::FileType::Directory);
+        let episode = fs
+            .lookup_entry(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let file = fs
+            .lookup_entry(Inode::from(episode.ino), OsStr::new("001.jpg"))
+            .unwrap();
+        assert_eq!((file.kind, file.size), (fuse::FileType::RegularFile, 0));
+        assert_eq!(
+            fs.lookup_entry(Inode::from(episode.ino), OsStr::new("002.jpg")),
+            Err(ENOENT.into())
+        );
+
+        let tags = fs.lookup_entry(root, OsStr::new("tags")).unwrap();
+        let tag = fs
+            .lookup_entry(Inode::from(tags.ino), OsStr::new("finished"))
+            .unwrap();
+        let tagged = fs
+            .lookup_entry(Inode::from(tag.ino), OsStr::new("comic"))
+            .unwrap();
+        assert_eq!(
+            fs.read_link(Inode::from(tagged.ino)),
+            Ok(PathBuf::from("/mnt/comics/comic"))
+        );
+
+        // Every call gets a database of its own
+        assert_eq!(
+            test_fs()
+                .lookup_entry(Inode::from(comics.ino), OsStr::new("comic"))
+                .map(|attr| attr.ino),
+            Ok(comic.ino)
+        );
+    }
+
+    #[test]
+    fn test_read_past_end_and_missing_blob() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let ino = Inode::from(
+            fs.create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+                .unwrap()
+                .ino,
+        );
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"content").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_data(ino, fh, 4, 100).unwrap(), b"ent");
+        assert_eq!(fs.read_data(ino, fh, 7, 100).unwrap(), b"");
+        assert_eq!(fs.read_data(ino, fh, 100, 100).unwrap(), b"");
+        fs.release_handle(ino, fh).unwrap();
+
+        let hash = hex::encode(Sha256::digest(b"content"));
+        fs::remove_file(fs.storage_path(&hash).unwrap()).unwrap();
+        assert_eq!(fs.open_handle(ino), Err(EIO.into()));
+        // Without a handle the blob is looked up on every read
+        assert_eq!(fs.read_data(ino, 0, 100, 100), Err(EIO.into()));
+    }
+
+    #[test]
+    fn test_reads_update_atime_like_relatime() {
+        check_reads_update_atime_like_relatime(false);
+    }
+
+    #[test]
+    fn test_mapped_reads_update_atime_like_relatime() {
+        check_reads_update_atime_like_relatime(true);
+    }
+
+    /// Read a file of two pages through `read` with the `mmap` mount option set to `mmap`.
+    fn check_reads_update_atime_like_relatime(mmap: bool) {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        // Files are created with the time of the database, the clock has to be past it
+        let start = SystemTime::now() + Duration::from_secs(60 * 60);
+        let clock = FakeClock::new(start);
+        let mut fs = ComicFSBuilder::new(pool)
+            .base(PathBuf::from("/mnt"))
+            .storage(root.path().to_path_buf())
+            .clock(clock.clone())
+            .build();
+        fs.mmap = mmap;
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = fs
+            .make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+            .unwrap();
+        let ino = Inode::from(
+            fs.create_file(Inode::from(episode.ino), OsStr::new("001.jpg"))
+                .unwrap()
+                .ino,
+        );
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, &[7; 2 * 4096]).unwrap();
+        fs.release_handle(ino, fh).unwrap();
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_mapped(fh, 0, 10, |_| ()).is_some(), mmap);
+        let (tx, rx) = channel();
+        let read = |fs: &ComicFS| {
+            fs.reply_read(ino, fh, 0, 10, Reply::new(0, CaptureSender(tx.clone())));
+            rx.recv().unwrap();
         };
-        let res = file.write_at(data, u64::try_from(offset).unwrap()).unwrap();
-        reply.written(u32::try_from(res).unwrap());
+        let atime = |fs: &ComicFS| fs.attr(ino).unwrap().atime;
+
+        read(&fs);
+        assert_eq!(atime(&fs), start);
+        clock.set(start + Duration::from_secs(60));
+        read(&fs);
+        assert_eq!(atime(&fs), start);
+        let later = start + Duration::from_secs(2 * 24 * 60 * 60);
+        clock.set(later);
+        read(&fs);
+        assert_eq!(atime(&fs), later);
+
+        fs.noatime = true;
+        clock.set(later + Duration::from_secs(2 * 24 * 60 * 60));
+        read(&fs);
+        assert_eq!(atime(&fs), later);
+        fs.release_handle(ino, fh).unwrap();
     }
 
-    #[tracing::instrument(fields(unique = _req.unique(), ino = ?Hex(ino)),skip(self, _req, ino, reply))]
-    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
-        let ino = Inode::from(ino);
-        if ino.kind() != InodeKind::Tagged {
-            reply.error(EINVAL);
-            return;
+    #[test]
+    fn test_truncate_leaves_shared_blob() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        let mut inodes = vec![];
+        for name in &["001.jpg", "002.jpg"] {
+            let ino = Inode::from(fs.create_file(episode, OsStr::new(name)).unwrap().ino);
+            let fh = fs.open_handle(ino).unwrap();
+            fs.write_data(ino, fh, 0, b"same page").unwrap();
+            fs.release_handle(ino, fh).unwrap();
+            inodes.push(ino);
         }
-        let info = match Taggable::find_info(ino.id().try_into().unwrap(), &self.conn) {
-            Some(info) => info,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-        info!(?ino, ?info);
-        let ino = match info {
-            Taggables::Comic { comic, .. } => Inode::comic(comic.id),
-            Taggables::Episode { episode, .. } => Inode::eposide(episode.id),
-            Taggables::File { file, .. } => Inode::file(file.id),
+        let blob = fs
+            .storage_path(&hex::encode(Sha256::digest(b"same page")))
+            .unwrap();
+        let flags = (O_WRONLY | O_TRUNC) as u32;
+
+        let fh = fs.open_with_flags(inodes[0], flags).unwrap();
+        assert_eq!(fs.attr(inodes[0]).unwrap().size, 0);
+        assert_eq!(fs.read_data(inodes[0], fh, 0, 64).unwrap(), b"");
+        fs.release_handle(inodes[0], fh).unwrap();
+        assert!(blob.exists());
+        let fh = fs.open_handle(inodes[1]).unwrap();
+        assert_eq!(fs.read_data(inodes[1], fh, 0, 64).unwrap(), b"same page");
+        fs.release_handle(inodes[1], fh).unwrap();
+
+        // Creating an existing file opens it, the last reference removes the blob
+        let (attr, fh) = fs
+            .create_or_open(episode, OsStr::new("002.jpg"), flags)
+            .unwrap();
+        assert_eq!((attr.ino, attr.size), (inodes[1].0, 0));
+        fs.release_handle(inodes[1], fh).unwrap();
+        assert!(!blob.exists());
+        assert_eq!(
+            fs.create_or_open(episode, OsStr::new("002.jpg"), flags | O_EXCL as u32)
+                .map(|(attr, _)| attr.ino),
+            Err(EEXIST.into())
+        );
+    }
+
+    #[test]
+    fn test_changing_shared_blob_copies_it() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        let mut inodes = vec![];
+        for name in &["001.jpg", "002.jpg", "003.jpg", "004.jpg"] {
+            let ino = Inode::from(fs.create_file(episode, OsStr::new(name)).unwrap().ino);
+            let fh = fs.open_handle(ino).unwrap();
+            fs.write_data(ino, fh, 0, b"same page").unwrap();
+            fs.release_handle(ino, fh).unwrap();
+            inodes.push(ino);
+        }
+        let read = |fs: &mut ComicFS, ino| {
+            let fh = fs.open_handle(ino).unwrap();
+            let data = fs.read_data(ino, fh, 0, 64).unwrap();
+            fs.release_handle(ino, fh).unwrap();
+            data
         };
-        let path = self.resolve_inode(ino).unwrap();
-        info!(path = %path.display(), path.len = path.as_os_str().len());
-        let bytes = path.as_os_str().as_bytes();
-        reply.data(bytes);
+
+        let fh = fs.open_handle(inodes[0]).unwrap();
+        fs.write_data(inodes[0], fh, 0, b"SAME").unwrap();
+        fs.release_handle(inodes[0], fh).unwrap();
+        fs.set_attr(inodes[1], Some(4), None, None).unwrap();
+        fs.allocate(inodes[2], 0, 16, 0).unwrap();
+
+        assert_eq!(read(&mut fs, inodes[0]), b"SAME page");
+        assert_eq!(read(&mut fs, inodes[1]), b"same");
+        assert_eq!(read(&mut fs, inodes[2]), b"same page\0\0\0\0\0\0\0");
+        assert_eq!(read(&mut fs, inodes[3]), b"same page");
+        // Every copy is stored under its own hash
+        for ino in &inodes {
+            let file = File::find(ino.id().try_into().unwrap(), &fs.conn().unwrap()).unwrap();
+            let hash = file.content_hash.unwrap();
+            assert_eq!(hash, hex::encode(Sha256::digest(&read(&mut fs, *ino))));
+            assert!(fs.storage_path(&hash).unwrap().exists());
+        }
     }
 
-    fn link(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        newparent: u64,
-        _newname: &OsStr,
-        reply: ReplyEntry,
-    ) {
-        let ino = Inode::from(ino);
-        let tag_ino = Inode::from(newparent);
-        match ino.kind() {
-            InodeKind::Special | InodeKind::Tag => {
-                reply.error(EPERM);
-                return;
-            }
-            InodeKind::Comic => {
-                Taggable::comic(
-                    ino.id().try_into().unwrap(),
-                    tag_ino.id().try_into().unwrap(),
-                    &self.conn,
-                )
-                .unwrap();
-                reply.entry(&ONE_SEC, &directory_attr(ino), 0);
-            }
-            InodeKind::Eposide => {
-                todo!();
-            }
-            InodeKind::File => {
-                todo!();
+    #[test]
+    fn test_same_content_is_stored_once() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        let content = (0..300_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for name in &["001.jpg", "002.jpg"] {
+            let ino = Inode::from(fs.create_file(episode, OsStr::new(name)).unwrap().ino);
+            let fh = fs.open_handle(ino).unwrap();
+            for (i, chunk) in content.chunks(4096).enumerate() {
+                fs.write_data(ino, fh, i as i64 * 4096, chunk).unwrap();
             }
-            InodeKind::Tagged => unreachable!(),
+            fs.release_handle(ino, fh).unwrap();
+            let fh = fs.open_handle(ino).unwrap();
+            assert_eq!(fs.read_data(ino, fh, 0, 300_000).unwrap(), content);
+            fs.release_handle(ino, fh).unwrap();
         }
+
+        let blobs = fs::read_dir(root.path())
+            .unwrap()
+            .map(|dir| dir.unwrap().path())
+            .filter(|dir| !dir.ends_with("tmp"))
+            .flat_map(|dir| fs::read_dir(dir).unwrap())
+            .count();
+        assert_eq!(blobs, 1);
+        assert_eq!(
+            fs::read_dir(root.path().join("tmp")).unwrap().count(),
+            0,
+            "the dropped copy is removed"
+        );
     }
 
-    fn symlink(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        _name: &OsStr,
-        link: &Path,
-        reply: ReplyEntry,
-    ) {
-        let tag_ino = Inode::from(parent);
-        if tag_ino.kind() != InodeKind::Tag {
-            reply.error(EPERM);
-            return;
-        }
-        let path = if link.is_absolute() {
-            link.to_owned()
-        } else {
-            let path = match self.resolve_inode(Inode::from(parent)) {
-                Some(path) => path,
-                None => {
-                    reply.error(ENOENT);
-                    return;
-                }
-            };
-            path.join(link).clean()
+    #[test]
+    fn test_stored_blob_isnt_removed_as_unused() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("comic"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        let old = Inode::from(fs.create_file(episode, OsStr::new("001.jpg")).unwrap().ino);
+        let new = Inode::from(fs.create_file(episode, OsStr::new("002.jpg")).unwrap().ino);
+        let content = b"same page";
+        let conn = fs.conn().unwrap();
+
+        // The blob is left unused by the file that stored it
+        let fh = fs.open_handle(old).unwrap();
+        fs.write_data(old, fh, 0, content).unwrap();
+        fs.release_handle(old, fh).unwrap();
+        let info = File::find(old.id().try_into().unwrap(), &conn).unwrap();
+        info.clear_content(&conn).unwrap();
+
+        // Another file finds the blob while it's removed as unused on another thread
+        let mut pending = fs.new_pending().unwrap();
+        pending.write_all(content).unwrap();
+        let (hash, _, stored) = fs.store_pending(pending).unwrap();
+        let (tx, rx) = channel();
+        let remover = {
+            let (fs, hash) = (fs.clone(), hash.clone());
+            thread::spawn(move || {
+                fs.remove_unused_blob(&hash, &fs.conn().unwrap());
+                tx.send(()).unwrap();
+            })
         };
-        let target = dbg!(path.strip_prefix(&self.base).unwrap());
-        let ino = match self.resolve(target) {
-            Some(ino) => ino,
-            None => {
-                reply.error(EPERM);
-                return;
-            }
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        let info = File::find(new.id().try_into().unwrap(), &conn).unwrap();
+        info.update_content_hash(&hash, &conn).unwrap();
+        drop(stored);
+        remover.join().unwrap();
+        assert!(fs.storage_path(&hash).unwrap().exists());
+
+        let fh = fs.open_handle(new).unwrap();
+        assert_eq!(fs.read_data(new, fh, 0, 64).unwrap(), content);
+        fs.release_handle(new, fh).unwrap();
+    }
+
+    #[test]
+    fn test_stats_file_reports_json() {
+        let mut fs = test_fs();
+        let clock = FakeClock::new(fs.mounted_at + Duration::from_secs(90));
+        fs.clock = Arc::new(clock.clone());
+        let root = Inode::from(ComicFS::ROOT_ID);
+        assert!(fs
+            .dir_entries(root)
+            .iter()
+            .any(|(_, _, name)| name == ".comicfs"));
+        let control = Inode::from(fs.lookup_entry(root, OsStr::new(".comicfs")).unwrap().ino);
+        let attr = fs.lookup_entry(control, OsStr::new("stats")).unwrap();
+        assert_eq!((attr.kind, attr.perm), (FileType::RegularFile, 0o444));
+        let ino = Inode::from(attr.ino);
+        let stats = |fs: &ComicFS| {
+            let data = fs.read_data(ino, 0, 0, 4096).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&data).unwrap()
         };
-        match ino.kind() {
-            InodeKind::Special | InodeKind::Tag | InodeKind::Tagged => {
-                reply.error(EPERM);
-                return;
-            }
-            InodeKind::Comic => {
-                let info = Taggable::comic(
-                    ino.id().try_into().unwrap(),
-                    tag_ino.id().try_into().unwrap(),
-                    &self.conn,
-                )
-                .unwrap();
 
-                let path = self
-                    .resolve_inode(Inode::comic(info.id.try_into().unwrap()))
-                    .unwrap();
-                reply.entry(
-                    &ONE_SEC,
-                    &symlink_attr(Inode::tagged(info.id), path.as_os_str().len() as u64),
-                    0,
-                );
-            }
-            InodeKind::Eposide => {
-                todo!();
-            }
-            InodeKind::File => {
-                todo!();
-            }
+        let value = stats(&fs);
+        assert_eq!(value["comics"], 1);
+        assert_eq!(value["episodes"], 1);
+        assert_eq!(value["files"], 1);
+        assert_eq!(value["tags"], 1);
+        assert_eq!(value["blob_bytes"], 0);
+        assert_eq!(value["cache_hit_rate"], 0.0);
+        assert_eq!(value["uptime_secs"], 90);
+
+        let file = Inode::from(
+            fs.lookup_entry(Inode::eposide(1), OsStr::new("001.jpg"))
+                .unwrap()
+                .ino,
+        );
+        let fh = fs.open_handle(file).unwrap();
+        fs.write_data(file, fh, 0, b"content").unwrap();
+        fs.release_handle(file, fh).unwrap();
+        // Counted again only once the content is old enough
+        assert_eq!(stats(&fs)["blob_bytes"], 0);
+        clock.set(fs.mounted_at + Duration::from_secs(100));
+        let value = stats(&fs);
+        assert_eq!(value["blob_bytes"], 7);
+        assert_eq!(value["uptime_secs"], 100);
+
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.write_data(ino, fh, 0, b"{}"), Err(EPERM.into()));
+        assert_eq!(fs.open_dir(ino), Err(ENOTDIR.into()));
+    }
+
+    #[test]
+    fn test_getattr_uses_stored_size_without_blob() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let file = NewFile {
+            name: "001.jpg",
+            eposid_id: 1,
         }
+        .insert(&fs.conn().unwrap())
+        .unwrap();
+        let ino = Inode::file(file.id);
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"content").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+
+        let hash = hex::encode(Sha256::digest(b"content"));
+        fs::remove_file(fs.storage_path(&hash).unwrap()).unwrap();
+        let attr = fs.attr(ino).unwrap();
+        assert_eq!((attr.size, attr.blocks), (7, 1));
+        assert_eq!(attr.mtime, naive_to_systemtime(file.created_at));
     }
-}
 
-fn convert_nix_error(err: nix::Error) -> i32 {
-    match err {
-        nix::Error::Sys(errno) => errno as i32,
-        _ => EIO,
+    #[test]
+    fn test_rename_moves_file_across_comics() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let mut episodes = vec![];
+        for (comic, episode) in &[("A", "ep1"), ("B", "ep2")] {
+            let comic = fs
+                .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new(comic))
+                .unwrap();
+            let episode = fs
+                .make_dir(Inode::from(comic.ino), OsStr::new(episode))
+                .unwrap();
+            episodes.push(Inode::from(episode.ino));
+        }
+        let name = OsStr::new("x.jpg");
+        let ino = Inode::from(fs.create_file(episodes[0], name).unwrap().ino);
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"content").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+
+        fs.rename_entry(episodes[0], name, episodes[1], name, 0)
+            .unwrap();
+        assert_eq!(fs.lookup_entry(episodes[0], name), Err(ENOENT.into()));
+        assert_eq!(fs.lookup_entry(episodes[1], name).unwrap().ino, ino.0);
+        assert_eq!(
+            fs.resolve_inode_checked(ino),
+            Ok(PathBuf::from("/mnt/comics/B/ep2/x.jpg"))
+        );
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_data(ino, fh, 0, 64).unwrap(), b"content");
+        fs.release_handle(ino, fh).unwrap();
+
+        fs.create_file(episodes[0], name).unwrap();
+        assert_eq!(
+            fs.rename_entry(episodes[0], name, episodes[1], name, 0),
+            Err(EEXIST.into())
+        );
+        assert_eq!(
+            fs.rename_entry(episodes[1], name, episodes[1], OsStr::new("cover"), 0),
+            Err(EEXIST.into())
+        );
+        assert_eq!(
+            fs.rename_entry(episodes[1], name, Inode::from(ComicFS::COMIC_ID), name, 0),
+            Err(EPERM.into())
+        );
     }
-}
 
-fn convert_meta_to_attr(ino: u64, meta: fs::Metadata) -> fuse::FileAttr {
-    FileAttr {
-        ino,
-        size: meta.len(),
-        nlink: 1,
-        perm: cast::u16(meta.mode()).unwrap(),
-        uid: meta.uid(),
-        gid: meta.gid(),
-        blocks: meta.blocks(),
-        atime: meta.accessed().unwrap(),
-        ctime: meta.created().unwrap(),
-        mtime: meta.modified().unwrap(),
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: convert_file_type(meta.file_type()),
-        rdev: 0,
-        flags: 0,
+    /// Names listed in `/trash`.
+    fn trash_names(fs: &ComicFS) -> Vec<String> {
+        fs.children(Inode::from(ComicFS::TRASH_ID))
+            .into_iter()
+            .map(|(_, _, name)| name)
+            .collect()
     }
-}
 
-fn generate_storage_path(content_hash: &str) -> PathBuf {
-    let mut path = STORAGE_BASE.clone();
-    path.push(&content_hash[0..2]);
-    path.push(&content_hash);
-    path
-}
+    #[test]
+    fn test_unlink_moves_file_to_trash_until_restored() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        fs.trash = true;
+        let trash = Inode::from(ComicFS::TRASH_ID);
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("A"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        let name = OsStr::new("x.jpg");
+        let ino = Inode::from(fs.create_file(episode, name).unwrap().ino);
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"content").unwrap();
+        fs.release_handle(ino, fh).unwrap();
 
-fn convert_file_type(kind: fs::FileType) -> fuse::FileType {
-    if kind.is_dir() {
-        fuse::FileType::Directory
-    } else if kind.is_file() {
-        fuse::FileType::RegularFile
-    } else if kind.is_symlink() {
-        fuse::FileType::Symlink
-    } else {
-        unreachable!("doesn't support other file kind")
+        fs.remove_entry(episode, name).unwrap();
+        assert_eq!(fs.lookup_entry(episode, name), Err(ENOENT.into()));
+        assert!(fs.children(episode).is_empty());
+        let trashed = format!("f{}_A_ep1_x.jpg", ino.id());
+        assert_eq!(trash_names(&fs), vec![trashed.clone()]);
+        assert_eq!(
+            fs.lookup_entry(trash, OsStr::new(&trashed)).unwrap().ino,
+            ino.0
+        );
+        // The name is free again, so restoring under it fails
+        fs.create_file(episode, name).unwrap();
+        assert_eq!(
+            fs.rename_entry(trash, OsStr::new(&trashed), episode, name, 0),
+            Err(EEXIST.into())
+        );
+
+        let restored = OsStr::new("y.jpg");
+        fs.rename_entry(trash, OsStr::new(&trashed), episode, restored, 0)
+            .unwrap();
+        assert!(trash_names(&fs).is_empty());
+        assert_eq!(fs.lookup_entry(episode, restored).unwrap().ino, ino.0);
+        let fh = fs.open_handle(ino).unwrap();
+        assert_eq!(fs.read_data(ino, fh, 0, 64).unwrap(), b"content");
+        fs.release_handle(ino, fh).unwrap();
     }
-}
 
-pub fn mount(conn: SqliteConnection, mountpoint: &OsStr) {
-    let options = ["-o", "rw", "-o", "fsname=comic"]
-        .iter()
-        .map(|o| o.as_ref())
-        .collect::<Vec<&OsStr>>();
-    fuse::mount(
-        ComicFS::new(
-            conn,
-            fs::canonicalize(mountpoint).expect("Fail to resolve mount point"),
-        ),
-        mountpoint,
-        &options,
-    )
-    .unwrap();
-}
+    #[test]
+    fn test_emptying_trash_removes_blobs() {
+        let mut fs = test_fs();
+        let comics = Inode::from(ComicFS::COMIC_ID);
+        let trash = Inode::from(ComicFS::TRASH_ID);
+        let comic = Inode::from(fs.lookup_entry(comics, OsStr::new("comic")).unwrap().ino);
+        let episode = Inode::from(fs.lookup_entry(comic, OsStr::new("ep1")).unwrap().ino);
+        let name = OsStr::new("001.jpg");
+        let ino = Inode::from(fs.lookup_entry(episode, name).unwrap().ino);
+        let fh = fs.open_handle(ino).unwrap();
+        fs.write_data(ino, fh, 0, b"content").unwrap();
+        fs.release_handle(ino, fh).unwrap();
+        let hash = File::find(ino.id().try_into().unwrap(), &fs.conn().unwrap())
+            .unwrap()
+            .content_hash
+            .unwrap();
+        let blob = fs.storage_path(&hash).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::Inode;
+        assert_eq!(fs.remove_entry(episode, name), Err(ENOSYS.into()));
+        assert_eq!(fs.remove_dir(comic, OsStr::new("ep1")), Err(ENOSYS.into()));
+        fs.trash = true;
+        assert_eq!(
+            fs.remove_dir(comic, OsStr::new("ep1")),
+            Err(ENOTEMPTY.into())
+        );
+        fs.remove_entry(episode, name).unwrap();
+        fs.remove_dir(comic, OsStr::new("ep1")).unwrap();
+        fs.remove_dir(comics, OsStr::new("comic")).unwrap();
+        assert_eq!(
+            fs.lookup_entry(comics, OsStr::new("comic")),
+            Err(ENOENT.into())
+        );
+        assert_eq!(trash_names(&fs).len(), 3);
+        assert!(blob.exists());
+
+        let trashed = format!("c{}_comic", comic.id());
+        fs.remove_dir(trash, OsStr::new(&trashed)).unwrap();
+        assert!(trash_names(&fs).is_empty());
+        assert_eq!(
+            File::find(ino.id().try_into().unwrap(), &fs.conn().unwrap()).map(|_| ()),
+            None
+        );
+        assert!(!blob.exists());
+    }
 
     #[test]
-    fn test_inode_is_special() {
-        let inode = Inode(1);
-        assert!(inode.is_special());
+    fn test_write_to_reorder_renumbers_files() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("A"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        let mut inodes = vec![];
+        for name in &["b.png", "01.jpg", "1a", "001.jpg"] {
+            inodes.push(fs.create_file(episode, OsStr::new(name)).unwrap().ino);
+        }
+
+        let ino = Inode::from(
+            fs.lookup_entry(episode, OsStr::new(".reorder"))
+                .unwrap()
+                .ino,
+        );
+        let fh = fs
+            .open_with_flags(ino, (O_WRONLY | O_TRUNC) as u32)
+            .unwrap();
+        assert_eq!(fs.write_data(ino, fh, 0, b"\n").unwrap(), 1);
+        fs.release_handle(ino, fh).unwrap();
+
+        for (name, index) in &[("001.jpg", 3), ("002.jpg", 1), ("003", 2), ("004.png", 0)] {
+            assert_eq!(
+                fs.lookup_entry(episode, OsStr::new(name)).unwrap().ino,
+                inodes[*index]
+            );
+        }
+        assert_eq!(
+            fs.lookup_entry(episode, OsStr::new("b.png")),
+            Err(ENOENT.into())
+        );
+        assert_eq!(
+            fs.create_file(episode, OsStr::new(".reorder")),
+            Err(EEXIST.into())
+        );
+    }
+
+    #[test]
+    fn test_rename_exchange_swaps_pages() {
+        let (_db, pool) = test_pool();
+        let root = tempfile::tempdir().unwrap();
+        let mut fs = ComicFS::new(pool, PathBuf::from("/mnt"), root.path().to_path_buf());
+        let comic = fs
+            .make_dir(Inode::from(ComicFS::COMIC_ID), OsStr::new("A"))
+            .unwrap();
+        let episode = Inode::from(
+            fs.make_dir(Inode::from(comic.ino), OsStr::new("ep1"))
+                .unwrap()
+                .ino,
+        );
+        let (first, second) = (OsStr::new("001.jpg"), OsStr::new("002.jpg"));
+        for (name, content) in &[(first, b"first"), (second, b"other")] {
+            let ino = Inode::from(fs.create_file(episode, name).unwrap().ino);
+            let fh = fs.open_handle(ino).unwrap();
+            fs.write_data(ino, fh, 0, *content).unwrap();
+            fs.release_handle(ino, fh).unwrap();
+        }
+
+        fs.rename_entry(episode, first, episode, second, libc::RENAME_EXCHANGE)
+            .unwrap();
+        for (name, content) in &[(first, b"other"), (second, b"first")] {
+            let ino = Inode::from(fs.lookup_entry(episode, name).unwrap().ino);
+            let fh = fs.open_handle(ino).unwrap();
+            assert_eq!(fs.read_data(ino, fh, 0, 64).unwrap(), *content);
+            fs.release_handle(ino, fh).unwrap();
+        }
+
+        assert_eq!(
+            fs.rename_entry(
+                episode,
+                first,
+                episode,
+                OsStr::new("003.jpg"),
+                libc::RENAME_EXCHANGE
+            ),
+            Err(ENOENT.into())
+        );
+        assert_eq!(
+            fs.rename_entry(
+                Inode::from(comic.ino),
+                OsStr::new("ep1"),
+                episode,
+                first,
+                libc::RENAME_EXCHANGE
+            ),
+            Err(EINVAL.into())
+        );
     }
 }
@@ -8,3 +8,106 @@ impl<T: LowerHex> fmt::Debug for Hex<T> {
         LowerHex::fmt(&self.0, f)
     }
 }
+
+/// Lowercase hex of a byte slice, two digits per byte and no prefix, like content hashes.
+pub struct HexBytes<'a>(pub &'a [u8]);
+
+impl fmt::Display for HexBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for HexBytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub fn encode_bytes(bytes: &[u8]) -> String {
+    HexBytes(bytes).to_string()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HexError {
+    /// The string has an odd number of digits, or not the number of bytes expected.
+    InvalidLength(usize),
+    /// The character at the index isn't a hex digit.
+    InvalidChar(usize),
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HexError::InvalidLength(len) => write!(f, "invalid hex length {}", len),
+            HexError::InvalidChar(index) => write!(f, "invalid hex digit at {}", index),
+        }
+    }
+}
+
+impl std::error::Error for HexError {}
+
+/// Parse hex digits, in either case, back to bytes.
+pub fn decode(s: &str) -> Result<Vec<u8>, HexError> {
+    if s.len() % 2 != 0 {
+        return Err(HexError::InvalidLength(s.len()));
+    }
+    let digit = |index: usize| {
+        (s.as_bytes()[index] as char)
+            .to_digit(16)
+            .map(|digit| digit as u8)
+            .ok_or(HexError::InvalidChar(index))
+    };
+    (0..s.len())
+        .step_by(2)
+        .map(|index| Ok(digit(index)? << 4 | digit(index + 1)?))
+        .collect()
+}
+
+/// Parse a SHA-256 digest, like a content hash.
+pub fn decode_32(s: &str) -> Result<[u8; 32], HexError> {
+    let bytes = decode(s)?;
+    let mut digest = [0; 32];
+    if bytes.len() != digest.len() {
+        return Err(HexError::InvalidLength(s.len()));
+    }
+    digest.copy_from_slice(&bytes);
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_32, encode_bytes, HexBytes, HexError};
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_encode_bytes() {
+        assert_eq!(encode_bytes(&[]), "");
+        assert_eq!(encode_bytes(&[0x00, 0x0f, 0xa0, 0xff]), "000fa0ff");
+        assert_eq!(encode_bytes(b"comic"), "636f6d6963");
+        assert_eq!(format!("{:?}", HexBytes(&[0xde, 0xad])), "dead");
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        for bytes in &[&b""[..], &[0x00, 0x0f, 0xa0, 0xff], b"comic"] {
+            assert_eq!(decode(&encode_bytes(bytes)).unwrap(), *bytes);
+            assert_eq!(decode(&hex::encode(bytes)).unwrap(), *bytes);
+        }
+        assert_eq!(decode("DEADbeef").unwrap(), [0xde, 0xad, 0xbe, 0xef]);
+
+        let digest = Sha256::digest(b"comic");
+        assert_eq!(decode_32(&encode_bytes(&digest)).unwrap()[..], digest[..]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode("abc"), Err(HexError::InvalidLength(3)));
+        assert_eq!(decode("0g"), Err(HexError::InvalidChar(1)));
+        assert_eq!(decode_32("00"), Err(HexError::InvalidLength(2)));
+        assert_eq!(decode_32(&"é".repeat(32)), Err(HexError::InvalidChar(0)));
+    }
+}
@@ -2,31 +2,132 @@
 
 #[macro_use]
 extern crate diesel;
+#[cfg(feature = "embed-migrations")]
+#[macro_use]
+extern crate diesel_migrations;
 
 use color_eyre::eyre::Result;
-use diesel::{Connection, SqliteConnection};
+use diesel::{
+    connection::SimpleConnection,
+    r2d2::{self, ConnectionManager, CustomizeConnection},
+    SqliteConnection,
+};
 use dotenv::dotenv;
-use std::{convert::AsRef, env, path::Path, process::Command};
+use fuse::MountOptions;
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process,
+};
+use structopt::StructOpt;
 use tracing::subscriber::set_global_default;
 use tracing_appender::{non_blocking, rolling};
 use tracing_error::ErrorLayer;
 use tracing_log::LogTracer;
 use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Registry};
 
+mod error;
 mod fs;
 mod hex;
 mod models;
 mod schema;
 
-pub fn establish_connection() -> SqliteConnection {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    SqliteConnection::establish(&database_url)
+/// The session runs on several threads. In WAL mode the readers don't block the writer, and the
+/// busy timeout makes writers wait for each other instead of failing with "database is locked".
+#[derive(Debug)]
+struct ConnectionOptions;
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        conn.batch_execute(
+            "PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000; PRAGMA foreign_keys = ON;",
+        )
+        .map_err(r2d2::Error::QueryError)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "comic-fs",
+    about = "Mount the comics in the database as a filesystem"
+)]
+struct Opt {
+    /// Directory to mount on
+    #[structopt(long = "mount", parse(from_os_str))]
+    mount: Option<PathBuf>,
+    /// Directory to mount on, same as --mount
+    #[structopt(parse(from_os_str))]
+    mountpoint: Option<PathBuf>,
+    /// SQLite database to use
+    #[structopt(long = "db", env = "DATABASE_URL")]
+    db: String,
+    /// Directory storing the file contents
+    #[structopt(long = "files", env = "FILES_PATH", parse(from_os_str))]
+    files: PathBuf,
+    /// Mount read-only
+    #[structopt(long = "read-only")]
+    read_only: bool,
+    /// Check the stored blobs against their content hash instead of mounting, exits with 1
+    /// when a blob is corrupted or missing
+    #[structopt(long = "fsck")]
+    fsck: bool,
+    /// List the sets of files sharing a blob instead of mounting
+    #[structopt(long = "dupes")]
+    dupes: bool,
+    /// Import a directory tree laid out as comic/episode/file instead of mounting
+    #[structopt(long = "import", parse(from_os_str))]
+    import: Option<PathBuf>,
+    /// Export the comics to a directory as comic/episode/file instead of mounting
+    #[structopt(long = "export", parse(from_os_str))]
+    export: Option<PathBuf>,
+    /// With --export, also write every tag as a directory of symlinks under .tags
+    #[structopt(long = "with-tags", requires = "export")]
+    with_tags: bool,
+    /// Mount options, like allow_other, uid=, gid=, threads=, lookup_cache=, recent=, mmap or
+    /// noatime
+    #[structopt(short = "o", number_of_values = 1)]
+    options: Vec<OsString>,
+}
+
+/// Connections of the pool used to migrate, check, import or export, r2d2's default. A mount
+/// opens its own pool sized from its worker threads.
+const POOL_SIZE: u32 = 10;
+
+pub fn establish_connection(database_url: &str, max_size: u32) -> models::Pool {
+    let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+    models::Pool::builder()
+        .max_size(max_size)
+        .connection_customizer(Box::new(ConnectionOptions))
+        .build(manager)
         .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
 }
 
+#[cfg(feature = "embed-migrations")]
+embed_migrations!();
+
+/// Bring the schema of the database up to date with the migrations built into the binary.
+#[cfg(feature = "embed-migrations")]
+fn run_migrations(pool: &models::Pool) -> Result<()> {
+    embedded_migrations::run(&pool.get()?)?;
+    Ok(())
+}
+
+/// Without the built in migrations, leave them to the `diesel` CLI next to the binary, if any.
+#[cfg(not(feature = "embed-migrations"))]
+fn run_migrations(_pool: &models::Pool) -> Result<()> {
+    use std::{path::Path, process::Command};
+
+    let diesel = Path::new("./diesel");
+    if diesel.metadata().is_ok() {
+        Command::new(diesel).args(&["setup"]).status()?;
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
     dotenv()?;
+    let opt = Opt::from_args();
     LogTracer::init().expect("Failed to set logger");
 
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
@@ -42,16 +143,107 @@ fn main() -> Result<()> {
         .with(formatting_layer)
         .with(file_layer);
     set_global_default(subscriber).expect("Failed to set subscriber");
-    ctrlc::set_handler(|| {
-        fuse::unmount("mnt".as_ref()).expect("Fail to unmount");
-    })?;
 
-    let diesel = AsRef::<Path>::as_ref("./diesel");
-    if diesel.metadata().is_ok() {
-        Command::new(diesel).args(&["setup"]).status().unwrap();
+    let pool = establish_connection(&opt.db, POOL_SIZE);
+    run_migrations(&pool)?;
+    if opt.fsck {
+        if !fs::fsck(pool, &opt.files)? {
+            process::exit(1);
+        }
+        return Ok(());
     }
-
-    let conn = establish_connection();
-    fs::mount(conn, "mnt".as_ref());
+    if opt.dupes {
+        for (hash, paths) in fs::dupes(pool)? {
+            println!("{}", hash);
+            for path in paths {
+                println!("  {}", path.display());
+            }
+        }
+        return Ok(());
+    }
+    if let Some(dir) = &opt.import {
+        fs::import(pool, &opt.files, dir)?;
+        return Ok(());
+    }
+    if let Some(dir) = &opt.export {
+        fs::export(pool, &opt.files, dir, opt.with_tags)?;
+        return Ok(());
+    }
+    let args = opt
+        .options
+        .iter()
+        .flat_map(|option| vec![OsStr::new("-o"), option.as_os_str()])
+        .collect::<Vec<_>>();
+    let mut options = MountOptions::parse(&args)?;
+    if opt.read_only {
+        options = options.read_only(true);
+    }
+    let mountpoint = opt
+        .mount
+        .or(opt.mountpoint)
+        .unwrap_or_else(|| PathBuf::from("mnt"));
+    drop(pool);
+    let db = &opt.db;
+    fs::mount(
+        |size| establish_connection(db, size),
+        Path::new(&opt.db),
+        mountpoint.as_os_str(),
+        &opt.files,
+        options,
+    )?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::establish_connection;
+    use diesel::{connection::SimpleConnection, prelude::*, sql_query, sql_types::BigInt};
+    use std::{path::Path, thread};
+    use tempfile::NamedTempFile;
+
+    #[derive(QueryableByName)]
+    struct Count {
+        #[sql_type = "BigInt"]
+        count: i64,
+    }
+
+    #[test]
+    fn test_concurrent_inserts_wait_for_each_other() {
+        let db = NamedTempFile::new().unwrap();
+        let pool = establish_connection(db.path().to_str().unwrap(), 4);
+        pool.get()
+            .unwrap()
+            .batch_execute("CREATE TABLE pages (id INTEGER PRIMARY KEY, name TEXT NOT NULL);")
+            .unwrap();
+
+        let writers = (0..4)
+            .map(|writer| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let conn = pool.get().unwrap();
+                    for page in 0..50 {
+                        conn.transaction::<_, diesel::result::Error, _>(|| {
+                            sql_query(format!(
+                                "INSERT INTO pages (name) VALUES ('{}-{}')",
+                                writer, page
+                            ))
+                            .execute(&conn)
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let count = sql_query("SELECT COUNT(*) AS count FROM pages")
+            .get_result::<Count>(&pool.get().unwrap())
+            .unwrap();
+        assert_eq!(count.count, 200);
+        let mut wal = db.path().as_os_str().to_owned();
+        wal.push("-wal");
+        assert!(Path::new(&wal).exists());
+    }
+}
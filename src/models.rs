@@ -1,30 +1,178 @@
-use crate::schema::{self, comics, eposides, files, taggables, tags};
+use crate::{
+    hex,
+    schema::{self, comics, eposides, files, taggables, tags},
+};
 use chrono::NaiveDateTime;
-use diesel::prelude::*;
+use diesel::{
+    prelude::*,
+    r2d2::{self, ConnectionManager},
+};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, convert::TryFrom, fs, io, path::Path, str::FromStr};
+
+pub type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
+pub type PooledConnection = r2d2::PooledConnection<ConnectionManager<SqliteConnection>>;
+
+/// Pattern matching `term` as is, to be used with `ESCAPE '\'`. SQLite compares ASCII letters
+/// case-insensitively with `LIKE`.
+fn escape_pattern(term: &str) -> String {
+    let mut pattern = String::new();
+    for c in term.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+/// Pattern matching names containing `term`, see `escape_pattern`.
+fn contains_pattern(term: &str) -> String {
+    format!("%{}%", escape_pattern(term))
+}
 
 #[derive(Queryable, Debug)]
 pub struct Comic {
     pub id: i32,
     pub name: String,
     pub created_at: NaiveDateTime,
+    /// When it was moved to the trash, `None` while it's live.
+    pub deleted_at: Option<NaiveDateTime>,
 }
 
 impl Comic {
+    /// Also finds a comic in the trash, the queries by name and the listings leave them out.
     pub fn find(id: i32, conn: &SqliteConnection) -> Option<Self> {
         use schema::comics::dsl;
 
         dsl::comics.find(id).first::<Comic>(conn).ok()
     }
 
-    pub fn find_by_name(name: &str, conn: &SqliteConnection) -> Option<Self> {
+    /// With `ignore_case`, the case of ASCII letters doesn't matter, the comic named exactly
+    /// `name` is still preferred.
+    pub fn find_by_name(name: &str, ignore_case: bool, conn: &SqliteConnection) -> Option<Self> {
         use schema::comics::dsl;
 
-        dsl::comics
-            .filter(dsl::name.eq(name))
+        let query = dsl::comics.filter(dsl::deleted_at.is_null()).into_boxed();
+        let query = if ignore_case {
+            query.filter(dsl::name.like(escape_pattern(name)).escape('\\'))
+        } else {
+            query.filter(dsl::name.eq(name))
+        };
+        query
+            .order((dsl::name.eq(name).desc(), dsl::id.asc()))
             .first::<Comic>(conn)
             .ok()
     }
+
+    pub fn search(term: &str, conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::comics::dsl;
+
+        dsl::comics
+            .filter(dsl::deleted_at.is_null())
+            .filter(dsl::name.like(contains_pattern(term)).escape('\\'))
+            .order(dsl::id.asc())
+            .load::<Comic>(conn)
+    }
+
+    /// Every comic at once, ordered by name. Listings of the comics page through `list_page`.
+    pub(crate) fn list(conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::comics::dsl;
+
+        dsl::comics
+            .filter(dsl::deleted_at.is_null())
+            .order(dsl::name.asc())
+            .load::<Comic>(conn)
+    }
+
+    /// At most `limit` comics ordered by name, skipping the first `offset`.
+    pub fn list_page(limit: i64, offset: i64, conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::comics::dsl;
+
+        dsl::comics
+            .filter(dsl::deleted_at.is_null())
+            .order(dsl::name.asc())
+            .limit(limit)
+            .offset(offset)
+            .load::<Comic>(conn)
+    }
+
+    /// The comics with the ids in `ids`, in no particular order.
+    pub fn find_many(ids: &[i32], conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::comics::dsl;
+
+        dsl::comics.filter(dsl::id.eq_any(ids)).load::<Comic>(conn)
+    }
+
+    pub fn episode_count(&self, conn: &SqliteConnection) -> Option<u64> {
+        Episode::count_by_comic(self.id, conn)
+    }
+
+    pub fn count(conn: &SqliteConnection) -> Option<u64> {
+        use schema::comics::dsl;
+
+        dsl::comics
+            .filter(dsl::deleted_at.is_null())
+            .count()
+            .get_result::<i64>(conn)
+            .ok()
+            .map(|count| u64::try_from(count).unwrap())
+    }
+
+    /// Comics in the trash, by id.
+    pub fn trashed(conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::comics::dsl;
+
+        dsl::comics
+            .filter(dsl::deleted_at.is_not_null())
+            .order(dsl::id.asc())
+            .load::<Comic>(conn)
+    }
+
+    /// Move the comic to the trash at `deleted_at`.
+    pub fn trash(&self, deleted_at: NaiveDateTime, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::comics::dsl;
+
+        diesel::update(dsl::comics.find(self.id))
+            .set(dsl::deleted_at.eq(deleted_at))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Take the comic out of the trash under `name`. Fails with a unique violation if a live
+    /// comic has that name.
+    pub fn restore(&self, name: &str, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::comics::dsl;
+
+        diesel::update(dsl::comics.find(self.id))
+            .set((
+                dsl::deleted_at.eq(None::<NaiveDateTime>),
+                dsl::name.eq(name),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Delete the comic along with its episodes and their files, without relying on the foreign
+    /// keys being enforced. Returns the content hashes of the files, whose blobs may be unused
+    /// now.
+    pub fn delete(&self, conn: &SqliteConnection) -> QueryResult<Vec<String>> {
+        use schema::comics::dsl;
+
+        conn.transaction(|| {
+            let episodes = eposides::table
+                .select(eposides::id)
+                .filter(eposides::comic_id.eq(self.id))
+                .load::<i32>(conn)?;
+            let hashes = File::hashes_for_episodes(&episodes, conn)?;
+            diesel::delete(files::table.filter(files::eposid_id.eq_any(&episodes)))
+                .execute(conn)?;
+            diesel::delete(eposides::table.filter(eposides::id.eq_any(&episodes))).execute(conn)?;
+            diesel::delete(dsl::comics.find(self.id)).execute(conn)?;
+            Ok(hashes)
+        })
+    }
 }
 
 #[derive(Queryable, Debug)]
@@ -33,68 +181,549 @@ pub struct Episode {
     pub name: String,
     pub comic_id: i32,
     pub created_at: NaiveDateTime,
+    /// When it was moved to the trash, `None` while it's live.
+    pub deleted_at: Option<NaiveDateTime>,
 }
 
 impl Episode {
+    /// Also finds an episode in the trash, like `Comic::find`.
     pub fn find(id: i32, conn: &SqliteConnection) -> Option<Self> {
         use schema::eposides::dsl;
 
         dsl::eposides.find(id).first::<Self>(conn).ok()
     }
 
+    /// `ignore_case` like `Comic::find_by_name`.
     pub fn find_by_comic_and_name(
         comic_id: i32,
         name: &str,
+        ignore_case: bool,
         conn: &SqliteConnection,
     ) -> Option<Self> {
         use schema::eposides::dsl;
 
-        dsl::eposides
+        let query = dsl::eposides
             .filter(dsl::comic_id.eq(comic_id))
-            .filter(dsl::name.eq(name))
+            .filter(dsl::deleted_at.is_null())
+            .into_boxed();
+        let query = if ignore_case {
+            query.filter(dsl::name.like(escape_pattern(name)).escape('\\'))
+        } else {
+            query.filter(dsl::name.eq(name))
+        };
+        query
+            .order((dsl::name.eq(name).desc(), dsl::id.asc()))
             .first::<Episode>(conn)
             .ok()
     }
+
+    pub fn search(term: &str, conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::eposides::dsl;
+
+        dsl::eposides
+            .filter(dsl::deleted_at.is_null())
+            .filter(dsl::name.like(contains_pattern(term)).escape('\\'))
+            .order(dsl::id.asc())
+            .load::<Episode>(conn)
+    }
+
+    /// Episodes of the comic `comic_id`, ordered by name.
+    pub fn list_for_comic(comic_id: i32, conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::eposides::dsl;
+
+        dsl::eposides
+            .filter(dsl::comic_id.eq(comic_id))
+            .filter(dsl::deleted_at.is_null())
+            .order((dsl::name.asc(), dsl::id.asc()))
+            .load::<Episode>(conn)
+    }
+
+    pub fn count(conn: &SqliteConnection) -> Option<u64> {
+        use schema::eposides::dsl;
+
+        dsl::eposides
+            .filter(dsl::deleted_at.is_null())
+            .count()
+            .get_result::<i64>(conn)
+            .ok()
+            .map(|count| u64::try_from(count).unwrap())
+    }
+
+    /// Number of episodes of each of the comics in `comic_ids` having some, by comic id.
+    pub fn counts_by_comic(
+        comic_ids: &[i32],
+        conn: &SqliteConnection,
+    ) -> QueryResult<HashMap<i32, u64>> {
+        use schema::eposides::dsl;
+
+        let mut counts = HashMap::new();
+        for comic_id in dsl::eposides
+            .select(dsl::comic_id)
+            .filter(dsl::comic_id.eq_any(comic_ids))
+            .filter(dsl::deleted_at.is_null())
+            .load::<i32>(conn)?
+        {
+            *counts.entry(comic_id).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    pub fn count_by_comic(comic_id: i32, conn: &SqliteConnection) -> Option<u64> {
+        use schema::eposides::dsl;
+
+        dsl::eposides
+            .filter(dsl::comic_id.eq(comic_id))
+            .filter(dsl::deleted_at.is_null())
+            .count()
+            .get_result::<i64>(conn)
+            .ok()
+            .map(|count| u64::try_from(count).unwrap())
+    }
+
+    /// The first file of the episode by name, shown as its cover.
+    pub fn cover_file(&self, conn: &SqliteConnection) -> Option<File> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::eposid_id.eq(self.id))
+            .filter(dsl::deleted_at.is_null())
+            .order((dsl::name.asc(), dsl::id.asc()))
+            .first::<File>(conn)
+            .ok()
+    }
+
+    pub fn file_count(&self, conn: &SqliteConnection) -> Option<u64> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::eposid_id.eq(self.id))
+            .filter(dsl::deleted_at.is_null())
+            .count()
+            .get_result::<i64>(conn)
+            .ok()
+            .map(|count| u64::try_from(count).unwrap())
+    }
+
+    /// Episodes in the trash, by id.
+    pub fn trashed(conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::eposides::dsl;
+
+        dsl::eposides
+            .filter(dsl::deleted_at.is_not_null())
+            .order(dsl::id.asc())
+            .load::<Episode>(conn)
+    }
+
+    /// Move the episode to the trash at `deleted_at`.
+    pub fn trash(&self, deleted_at: NaiveDateTime, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::eposides::dsl;
+
+        diesel::update(dsl::eposides.find(self.id))
+            .set(dsl::deleted_at.eq(deleted_at))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Take the episode out of the trash to the comic `comic_id` under `name`. Fails with a
+    /// unique violation if the comic has a live episode of that name.
+    pub fn restore(&self, comic_id: i32, name: &str, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::eposides::dsl;
+
+        diesel::update(dsl::eposides.find(self.id))
+            .set((
+                dsl::deleted_at.eq(None::<NaiveDateTime>),
+                dsl::comic_id.eq(comic_id),
+                dsl::name.eq(name),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Delete the episode along with its files, see `Comic::delete`.
+    pub fn delete(&self, conn: &SqliteConnection) -> QueryResult<Vec<String>> {
+        use schema::eposides::dsl;
+
+        conn.transaction(|| {
+            let hashes = File::hashes_for_episodes(&[self.id], conn)?;
+            diesel::delete(files::table.filter(files::eposid_id.eq(self.id))).execute(conn)?;
+            diesel::delete(dsl::eposides.find(self.id)).execute(conn)?;
+            Ok(hashes)
+        })
+    }
 }
 
 #[derive(Queryable, Identifiable, Debug)]
 pub struct File {
     pub id: i32,
     pub name: String,
-    pub content_hash: String,
+    /// `None` until the file gets its first content.
+    pub content_hash: Option<String>,
     pub eposid_id: i32,
     pub access_count: i32,
     pub created_at: NaiveDateTime,
+    pub mtime: Option<NaiveDateTime>,
+    pub atime: Option<NaiveDateTime>,
+    /// Size of the content, `None` for files written before it was stored.
+    pub size: Option<i64>,
+    /// Sniffed from the start of the content, `None` until the file gets its first content.
+    pub mime_type: Option<String>,
+    /// Set by the user through the `user.comment` extended attribute.
+    pub comment: Option<String>,
+    /// When it was moved to the trash, `None` while it's live.
+    pub deleted_at: Option<NaiveDateTime>,
 }
 
 impl File {
+    /// Also finds a file in the trash, like `Comic::find`.
     pub fn find(id: i32, conn: &SqliteConnection) -> Option<Self> {
         use schema::files::dsl;
 
         dsl::files.find(id).first::<File>(conn).ok()
     }
 
+    /// `ignore_case` like `Comic::find_by_name`.
     pub fn find_by_eposide_and_name(
         eposide_id: i32,
         name: &str,
+        ignore_case: bool,
         conn: &SqliteConnection,
     ) -> Option<Self> {
         use schema::files::dsl;
 
-        dsl::files
+        let query = dsl::files
             .filter(dsl::eposid_id.eq(eposide_id))
-            .filter(dsl::name.eq(name))
+            .filter(dsl::deleted_at.is_null())
+            .into_boxed();
+        let query = if ignore_case {
+            query.filter(dsl::name.like(escape_pattern(name)).escape('\\'))
+        } else {
+            query.filter(dsl::name.eq(name))
+        };
+        query
+            .order((dsl::name.eq(name).desc(), dsl::id.asc()))
             .first::<File>(conn)
             .ok()
     }
 
-    pub fn update_content_hash(&self, content_hash: &str, conn: &SqliteConnection) {
+    /// Files of the episode `episode_id`, ordered by name.
+    pub fn list_for_episode(episode_id: i32, conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::eposid_id.eq(episode_id))
+            .filter(dsl::deleted_at.is_null())
+            .order((dsl::name.asc(), dsl::id.asc()))
+            .load::<File>(conn)
+    }
+
+    pub fn increment_access(&self, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        diesel::update(self)
+            .set(dsl::access_count.eq(dsl::access_count + 1))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Store the times given by `setattr`, the ones left as `None` are kept.
+    pub fn update_times(
+        &self,
+        atime: Option<NaiveDateTime>,
+        mtime: Option<NaiveDateTime>,
+        conn: &SqliteConnection,
+    ) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        if let Some(atime) = atime {
+            diesel::update(self)
+                .set(dsl::atime.eq(atime))
+                .execute(conn)?;
+        }
+        if let Some(mtime) = mtime {
+            diesel::update(self)
+                .set(dsl::mtime.eq(mtime))
+                .execute(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn update_size(&self, size: u64, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        let size = i64::try_from(size).unwrap();
+        diesel::update(self).set(dsl::size.eq(size)).execute(conn)?;
+        Ok(())
+    }
+
+    pub fn update_mime_type(&self, mime_type: &str, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        diesel::update(self)
+            .set(dsl::mime_type.eq(mime_type))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    pub fn search(term: &str, conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::deleted_at.is_null())
+            .filter(dsl::name.like(contains_pattern(term)).escape('\\'))
+            .order(dsl::id.asc())
+            .load::<File>(conn)
+    }
+
+    /// The `limit` files created last, newest first.
+    pub fn recent(limit: usize, conn: &SqliteConnection) -> QueryResult<Vec<File>> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::deleted_at.is_null())
+            .order((dsl::created_at.desc(), dsl::id.desc()))
+            .limit(i64::try_from(limit).unwrap_or(i64::MAX))
+            .load::<File>(conn)
+    }
+
+    /// Every file, also the ones in the trash.
+    pub fn all(conn: &SqliteConnection) -> QueryResult<Vec<File>> {
+        use schema::files::dsl;
+
+        dsl::files.order(dsl::id.asc()).load::<File>(conn)
+    }
+
+    pub fn count(conn: &SqliteConnection) -> Option<u64> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::deleted_at.is_null())
+            .count()
+            .get_result::<i64>(conn)
+            .ok()
+            .map(|count| u64::try_from(count).unwrap())
+    }
+
+    /// Bytes taken by the blobs, each blob counted once however many files share it.
+    pub fn blob_bytes(conn: &SqliteConnection) -> Option<u64> {
+        use schema::files::dsl;
+
+        let blobs = dsl::files
+            .select((dsl::content_hash, dsl::size))
+            .filter(dsl::content_hash.is_not_null())
+            .distinct()
+            .load::<(Option<String>, Option<i64>)>(conn)
+            .ok()?;
+        Some(
+            blobs
+                .into_iter()
+                .filter_map(|(_, size)| size)
+                .map(|size| u64::try_from(size).unwrap())
+                .sum(),
+        )
+    }
+
+    /// Number of files sharing the blob of `content_hash`. Files in the trash count too, their
+    /// blob is kept until the trash is emptied.
+    pub fn count_by_content_hash(content_hash: &str, conn: &SqliteConnection) -> Option<u64> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::content_hash.eq(content_hash))
+            .count()
+            .get_result::<i64>(conn)
+            .ok()
+            .map(|count| u64::try_from(count).unwrap())
+    }
+
+    /// Files sharing the blob of `content_hash`, by id.
+    pub fn find_by_hash(content_hash: &str, conn: &SqliteConnection) -> QueryResult<Vec<File>> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::content_hash.eq(content_hash))
+            .order(dsl::id.asc())
+            .load::<File>(conn)
+    }
+
+    /// Content hashes shared by more than one file, in order.
+    pub fn shared_hashes(conn: &SqliteConnection) -> QueryResult<Vec<String>> {
+        use schema::files::dsl;
+
+        let hashes = dsl::files
+            .select(dsl::content_hash)
+            .filter(dsl::content_hash.is_not_null())
+            .order(dsl::content_hash.asc())
+            .load::<Option<String>>(conn)?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let mut shared: Vec<String> = vec![];
+        for pair in hashes.windows(2) {
+            if pair[0] == pair[1] && shared.last() != Some(&pair[0]) {
+                shared.push(pair[0].clone());
+            }
+        }
+        Ok(shared)
+    }
+
+    /// Hash the blob at `path` again and compare it with the stored hash. A file without content
+    /// has nothing to verify.
+    pub fn verify_blob(&self, path: &Path) -> io::Result<bool> {
+        let expected = match &self.content_hash {
+            Some(hash) => hex::decode_32(hash)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            None => return Ok(true),
+        };
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        Ok(hasher.finalize()[..] == expected[..])
+    }
+
+    pub fn update_content_hash(
+        &self,
+        content_hash: &str,
+        conn: &SqliteConnection,
+    ) -> QueryResult<()> {
         use schema::files::dsl;
 
         diesel::update(self)
             .set(dsl::content_hash.eq(content_hash))
-            .execute(conn)
-            .unwrap();
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Move the file to the episode `eposide_id` under `name`. Fails with a unique violation if
+    /// the episode has a file of that name already.
+    pub fn move_to(&self, eposide_id: i32, name: &str, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        diesel::update(self)
+            .set((dsl::eposid_id.eq(eposide_id), dsl::name.eq(name)))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Rename the files of the episode `episode_id` to sequential numbers padded to at least
+    /// three digits, `001`, `002`..., in the order of their names. Extensions are kept. Every
+    /// file is parked under a name no real file can have first, so that a new name can be taken
+    /// by a file renamed later. Returns the files as they were along with their new names.
+    pub fn renumber(episode_id: i32, conn: &SqliteConnection) -> QueryResult<Vec<(File, String)>> {
+        conn.transaction(|| {
+            let files = Self::list_for_episode(episode_id, conn)?;
+            let width = files.len().to_string().len().max(3);
+            for file in &files {
+                file.move_to(episode_id, &format!("/{}", file.id), conn)?;
+            }
+            let mut renamed = vec![];
+            for (index, file) in files.into_iter().enumerate() {
+                let number = format!("{:0width$}", index + 1, width = width);
+                let name = match Path::new(&file.name)
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                {
+                    Some(ext) => format!("{}.{}", number, ext),
+                    None => number,
+                };
+                file.move_to(episode_id, &name, conn)?;
+                renamed.push((file, name));
+            }
+            Ok(renamed)
+        })
+    }
+
+    /// Swap the episodes and names of the file and `other` in one transaction. The file is
+    /// parked under a name no real file can have first, so that the unique index on the names
+    /// of an episode holds in between.
+    pub fn exchange(&self, other: &File, conn: &SqliteConnection) -> QueryResult<()> {
+        conn.transaction(|| {
+            self.move_to(self.eposid_id, "/", conn)?;
+            other.move_to(self.eposid_id, &self.name, conn)?;
+            self.move_to(other.eposid_id, &other.name, conn)
+        })
+    }
+
+    /// Set the comment of the file, or remove it with `None`.
+    pub fn update_comment(
+        &self,
+        comment: Option<&str>,
+        conn: &SqliteConnection,
+    ) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        diesel::update(self)
+            .set(dsl::comment.eq(comment))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Detach the file from its blob, leaving it empty.
+    pub fn clear_content(&self, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        diesel::update(self)
+            .set((
+                dsl::content_hash.eq(None::<String>),
+                dsl::size.eq(Some(0i64)),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Files in the trash, by id.
+    pub fn trashed(conn: &SqliteConnection) -> QueryResult<Vec<File>> {
+        use schema::files::dsl;
+
+        dsl::files
+            .filter(dsl::deleted_at.is_not_null())
+            .order(dsl::id.asc())
+            .load::<File>(conn)
+    }
+
+    /// Move the file to the trash at `deleted_at`.
+    pub fn trash(&self, deleted_at: NaiveDateTime, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        diesel::update(self)
+            .set(dsl::deleted_at.eq(deleted_at))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Take the file out of the trash to the episode `eposide_id` under `name`, see `move_to`.
+    pub fn restore(&self, eposide_id: i32, name: &str, conn: &SqliteConnection) -> QueryResult<()> {
+        use schema::files::dsl;
+
+        diesel::update(self)
+            .set((
+                dsl::deleted_at.eq(None::<NaiveDateTime>),
+                dsl::eposid_id.eq(eposide_id),
+                dsl::name.eq(name),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Delete the file, its blob may be unused now.
+    pub fn delete(&self, conn: &SqliteConnection) -> QueryResult<()> {
+        diesel::delete(self).execute(conn)?;
+        Ok(())
+    }
+
+    /// Content hashes of the files of the episodes in `episode_ids`, each once.
+    fn hashes_for_episodes(
+        episode_ids: &[i32],
+        conn: &SqliteConnection,
+    ) -> QueryResult<Vec<String>> {
+        use schema::files::dsl;
+
+        Ok(dsl::files
+            .select(dsl::content_hash)
+            .filter(dsl::eposid_id.eq_any(episode_ids))
+            .distinct()
+            .load::<Option<String>>(conn)?
+            .into_iter()
+            .flatten()
+            .collect())
     }
 }
 
@@ -106,8 +735,28 @@ pub struct Tag {
 }
 
 impl Tag {
-    pub fn list(conn: &SqliteConnection) -> Option<Vec<Self>> {
-        tags::table.load::<Self>(conn).ok()
+    /// Every tag at once, ordered by name. Listings of the tags page through `list_page`.
+    pub(crate) fn list(conn: &SqliteConnection) -> Option<Vec<Self>> {
+        tags::table
+            .order(tags::dsl::name.asc())
+            .load::<Self>(conn)
+            .ok()
+    }
+
+    /// At most `limit` tags ordered by name, skipping the first `offset`.
+    pub fn list_page(limit: i64, offset: i64, conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        tags::table
+            .order(tags::dsl::name.asc())
+            .limit(limit)
+            .offset(offset)
+            .load::<Self>(conn)
+    }
+
+    /// The tags with the ids in `ids`, in no particular order.
+    pub fn find_many(ids: &[i32], conn: &SqliteConnection) -> QueryResult<Vec<Self>> {
+        tags::table
+            .filter(tags::dsl::id.eq_any(ids))
+            .load::<Self>(conn)
     }
 
     pub fn find(id: i32, conn: &SqliteConnection) -> Option<Self> {
@@ -125,6 +774,14 @@ impl Tag {
             .first::<Self>(conn)
             .ok()
     }
+
+    pub fn count(conn: &SqliteConnection) -> Option<u64> {
+        tags::table
+            .count()
+            .get_result::<i64>(conn)
+            .ok()
+            .map(|count| u64::try_from(count).unwrap())
+    }
 }
 
 #[derive(Queryable, Debug)]
@@ -136,22 +793,29 @@ pub struct Taggable {
 }
 
 impl Taggable {
+    /// Kind of the target, `None` for a type `TaggableKind` doesn't know.
+    pub fn kind(&self) -> Option<TaggableKind> {
+        self.taggable_type.parse().ok()
+    }
+
     pub fn find(id: i32, conn: &SqliteConnection) -> Option<Self> {
         use taggables::dsl;
 
         dsl::taggables.find(id).first::<Taggable>(conn).ok()
     }
 
-    pub fn find_info(id: i32, conn: &SqliteConnection) -> Option<Taggables> {
-        Taggables::from_taggable(&Self::find(id, conn)?, conn)
-    }
-
-    pub fn comic(tag_id: i32, comic_id: i32, conn: &SqliteConnection) -> Option<Self> {
+    /// Tag the comic, episode or file `taggable_id` with `tag_id`.
+    pub fn link(
+        tag_id: i32,
+        taggable_id: i32,
+        taggable_type: TaggableKind,
+        conn: &SqliteConnection,
+    ) -> QueryResult<Self> {
         use taggables::dsl;
         let value = NewTaggable {
             tag_id,
-            taggable_id: comic_id,
-            taggable_type: "comic",
+            taggable_id,
+            taggable_type: taggable_type.as_ref(),
         };
 
         conn.transaction::<_, diesel::result::Error, _>(|| {
@@ -163,18 +827,104 @@ impl Taggable {
                 .order(dsl::id.desc())
                 .first::<Taggable>(conn)?)
         })
-        .ok()
+    }
+    /// Tags pointing at the comic, episode or file `taggable_id`, ordered by id.
+    pub fn tags_for(
+        taggable_id: i32,
+        taggable_type: TaggableKind,
+        conn: &SqliteConnection,
+    ) -> Vec<Tag> {
+        use taggables::dsl;
+
+        let tag_ids = dsl::taggables
+            .filter(dsl::taggable_id.eq(taggable_id))
+            .filter(dsl::taggable_type.eq(taggable_type.as_ref()))
+            .select(dsl::tag_id);
+        tags::table
+            .filter(tags::dsl::id.eq_any(tag_ids))
+            .order(tags::dsl::id.asc())
+            .load::<Tag>(conn)
+            .unwrap_or_else(|_| Vec::new())
+    }
+
+    /// Number of times each file of the episode `eposide_id` is tagged, by file id. Files
+    /// without tags are left out.
+    pub fn counts_for_episode_files(
+        eposide_id: i32,
+        conn: &SqliteConnection,
+    ) -> QueryResult<HashMap<i32, u64>> {
+        use taggables::dsl;
+
+        let files = files::table
+            .select(files::id)
+            .filter(files::eposid_id.eq(eposide_id));
+        let mut counts = HashMap::new();
+        for file_id in dsl::taggables
+            .select(dsl::taggable_id)
+            .filter(dsl::taggable_type.eq(TaggableKind::File.as_ref()))
+            .filter(dsl::taggable_id.eq_any(files))
+            .load::<i32>(conn)?
+        {
+            *counts.entry(file_id).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Number of times the comic, episode or file `taggable_id` is tagged.
+    pub fn count_for(
+        taggable_id: i32,
+        taggable_type: TaggableKind,
+        conn: &SqliteConnection,
+    ) -> Option<u64> {
+        use taggables::dsl;
+
+        dsl::taggables
+            .filter(dsl::taggable_id.eq(taggable_id))
+            .filter(dsl::taggable_type.eq(taggable_type.as_ref()))
+            .count()
+            .get_result::<i64>(conn)
+            .ok()
+            .map(|count| u64::try_from(count).unwrap())
     }
 }
 
-#[derive(strum_macros::EnumString, Debug)]
-#[strum(serialize_all = "snake_case")]
-enum TaggableKind {
+/// Values of `taggables.taggable_type`, the only ones the column accepts. The migrations spell
+/// them out in SQL, everything else goes through these constants.
+pub const TAGGABLE_COMIC: &str = "comic";
+pub const TAGGABLE_EPISODE: &str = "eposide";
+pub const TAGGABLE_FILE: &str = "file";
+
+/// Written to and read from `taggables.taggable_type` with `as_ref` and `parse`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaggableKind {
     Comic,
     Eposide,
     File,
 }
 
+impl AsRef<str> for TaggableKind {
+    fn as_ref(&self) -> &str {
+        match self {
+            TaggableKind::Comic => TAGGABLE_COMIC,
+            TaggableKind::Eposide => TAGGABLE_EPISODE,
+            TaggableKind::File => TAGGABLE_FILE,
+        }
+    }
+}
+
+impl FromStr for TaggableKind {
+    type Err = ();
+
+    fn from_str(taggable_type: &str) -> Result<Self, ()> {
+        match taggable_type {
+            TAGGABLE_COMIC => Ok(TaggableKind::Comic),
+            TAGGABLE_EPISODE => Ok(TaggableKind::Eposide),
+            TAGGABLE_FILE => Ok(TaggableKind::File),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Taggables {
     Comic {
@@ -195,29 +945,46 @@ pub enum Taggables {
 }
 
 impl Taggables {
+    pub fn kind(&self) -> TaggableKind {
+        match self {
+            Taggables::Comic { .. } => TaggableKind::Comic,
+            Taggables::Episode { .. } => TaggableKind::Eposide,
+            Taggables::File { .. } => TaggableKind::File,
+        }
+    }
+
+    /// Everything tagged with the tag `id`, but for what's in the trash. The parents of the entries are looked up through
+    /// one `Parents`, so a tag with 100 files of the same episode takes 103 queries instead
+    /// of 301.
     pub fn taggables(id: i32, conn: &SqliteConnection) -> Vec<Self> {
         use taggables::dsl;
 
+        let mut parents = Parents::default();
         dsl::taggables
             .filter(dsl::tag_id.eq(id))
+            .order(dsl::id.asc())
             .load::<Taggable>(conn)
             .map(|taggables| {
                 taggables
                     .iter()
-                    .filter_map(|taggable| Self::from_taggable(taggable, conn))
+                    .filter_map(|taggable| Self::from_taggable(taggable, &mut parents, conn))
                     .collect::<Vec<_>>()
             })
             .unwrap_or_else(|_| Vec::new())
     }
 
-    fn from_taggable(taggable: &Taggable, conn: &SqliteConnection) -> Option<Self> {
-        let kind = taggable.taggable_type.parse::<TaggableKind>().ok()?;
-        match kind {
+    fn from_taggable(
+        taggable: &Taggable,
+        parents: &mut Parents,
+        conn: &SqliteConnection,
+    ) -> Option<Self> {
+        match taggable.kind()? {
             TaggableKind::Comic => {
                 use comics::dsl;
 
                 dsl::comics
                     .filter(dsl::id.eq(taggable.taggable_id))
+                    .filter(dsl::deleted_at.is_null())
                     .first::<Comic>(conn)
                     .ok()
                     .map(|comic| {
@@ -235,10 +1002,14 @@ impl Taggables {
 
                 let episode = dsl::eposides
                     .filter(dsl::id.eq(taggable.taggable_id))
+                    .filter(dsl::deleted_at.is_null())
                     .first::<Episode>(conn)
                     .ok()?;
-                let comic = Comic::find(episode.comic_id, conn)?;
-                let name = format!("{}_{}", comic.name, episode.name);
+                let name = format!(
+                    "{}_{}",
+                    parents.comic_name(episode.comic_id, conn)?,
+                    episode.name
+                );
                 Some(Taggables::Episode {
                     id: taggable.id,
                     episode,
@@ -250,11 +1021,14 @@ impl Taggables {
 
                 let file = dsl::files
                     .filter(dsl::id.eq(taggable.taggable_id))
+                    .filter(dsl::deleted_at.is_null())
                     .first::<File>(conn)
                     .ok()?;
-                let episode = Episode::find(file.eposid_id, conn)?;
-                let comic = Comic::find(episode.comic_id, conn)?;
-                let name = format!("{}_{}_{}", comic.name, episode.name, file.name);
+                let name = format!(
+                    "{}_{}",
+                    parents.episode_name(file.eposid_id, conn)?,
+                    file.name
+                );
                 Some(Taggables::File {
                     id: taggable.id,
                     file,
@@ -265,12 +1039,57 @@ impl Taggables {
     }
 }
 
+/// Names of the comics and episodes looked up while building a listing, so entries sharing a
+/// parent only query it once. It's never invalidated, so it's only kept for a single listing.
+#[derive(Default)]
+pub struct Parents {
+    comics: HashMap<i32, Option<String>>,
+    episodes: HashMap<i32, Option<String>>,
+}
+
+impl Parents {
+    /// Name of the comic `id`, `None` if it's gone.
+    pub fn comic_name(&mut self, id: i32, conn: &SqliteConnection) -> Option<String> {
+        self.comics
+            .entry(id)
+            .or_insert_with(|| Comic::find(id, conn).map(|comic| comic.name))
+            .clone()
+    }
+
+    /// Name of the episode `id` prefixed with the name of its comic, like it's listed out of
+    /// the comic, `None` if either is gone.
+    pub fn episode_name(&mut self, id: i32, conn: &SqliteConnection) -> Option<String> {
+        if let Some(name) = self.episodes.get(&id) {
+            return name.clone();
+        }
+        let name = Episode::find(id, conn).and_then(|episode| {
+            let comic = self.comic_name(episode.comic_id, conn)?;
+            Some(format!("{}_{}", comic, episode.name))
+        });
+        self.episodes.insert(id, name.clone());
+        name
+    }
+}
+
 #[derive(Deserialize, Insertable)]
 #[table_name = "comics"]
 pub struct NewComic<'a> {
     pub name: &'a str,
 }
 
+impl NewComic<'_> {
+    pub fn insert(self, conn: &SqliteConnection) -> Result<Comic, diesel::result::Error> {
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            use comics::dsl;
+
+            diesel::insert_into(comics::table)
+                .values(&self)
+                .execute(conn)?;
+            dsl::comics.order(dsl::id.desc()).first::<Comic>(conn)
+        })
+    }
+}
+
 #[derive(Deserialize, Insertable)]
 #[table_name = "eposides"]
 pub struct NewEposide<'a> {
@@ -278,11 +1097,23 @@ pub struct NewEposide<'a> {
     pub comic_id: i32,
 }
 
+impl NewEposide<'_> {
+    pub fn insert(self, conn: &SqliteConnection) -> Result<Episode, diesel::result::Error> {
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            use eposides::dsl;
+
+            diesel::insert_into(eposides::table)
+                .values(&self)
+                .execute(conn)?;
+            dsl::eposides.order(dsl::id.desc()).first::<Episode>(conn)
+        })
+    }
+}
+
 #[derive(Deserialize, Insertable)]
 #[table_name = "files"]
 pub struct NewFile<'a> {
     pub name: &'a str,
-    pub content_hash: &'a str,
     pub eposid_id: i32,
 }
 
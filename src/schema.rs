@@ -3,6 +3,7 @@ table! {
         id -> Integer,
         name -> Text,
         created_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -12,6 +13,7 @@ table! {
         name -> Text,
         comic_id -> Integer,
         created_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -19,10 +21,16 @@ table! {
     files (id) {
         id -> Integer,
         name -> Text,
-        content_hash -> Text,
+        content_hash -> Nullable<Text>,
         eposid_id -> Integer,
         access_count -> Integer,
         created_at -> Timestamp,
+        mtime -> Nullable<Timestamp>,
+        atime -> Nullable<Timestamp>,
+        size -> Nullable<BigInt>,
+        mime_type -> Nullable<Text>,
+        comment -> Nullable<Text>,
+        deleted_at -> Nullable<Timestamp>,
     }
 }
 
@@ -43,6 +51,10 @@ table! {
     }
 }
 
+joinable!(eposides -> comics (comic_id));
+joinable!(files -> eposides (eposid_id));
+joinable!(taggables -> tags (tag_id));
+
 allow_tables_to_appear_in_same_query!(
     comics,
     eposides,